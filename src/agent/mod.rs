@@ -0,0 +1,200 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::Client;
+use tracing::{error, info, warn};
+
+use crate::agent::state::State;
+use crate::client::ClientTls;
+use crate::discovery::{Discovery, Family, Source};
+use crate::error::DynIpError;
+
+pub mod state;
+
+const RETRY_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const CHANGE_SETTLE_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug)]
+pub struct AgentConfig {
+    /// Base URL of the dyn-ip server
+    pub server: String,
+    /// Record ID to update. Optional with a client certificate that maps to one record.
+    pub id: Option<String>,
+    pub interval: Duration,
+    /// How long an unchanged IP goes without being sent again, so the server
+    /// still sees the host within its expected interval and lease
+    pub refresh: Duration,
+    pub state_file: PathBuf,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub ca_cert: Option<PathBuf>,
+    /// Check once and exit, for cron
+    pub once: bool,
+    /// Which address to keep the record pointed at
+    pub family: Family,
+    /// Sources asked alongside the server's own `GET /`
+    pub sources: Vec<Source>,
+    /// How many sources must agree, 0 for a majority
+    pub quorum: usize,
+}
+
+/// Keeps a record pointed at this host's public IP, calling the server's
+/// update endpoint when the IP changes or it's due a refresh.
+pub struct Agent {
+    config: AgentConfig,
+    client: Client,
+    discovery: Discovery,
+    state: State,
+}
+
+impl Agent {
+    pub fn new(config: AgentConfig) -> Result<Agent, DynIpError> {
+        if config.id.is_none() && config.client_cert.is_none() {
+            return Err(DynIpError::Agent(
+                "A record ID or a client certificate is required".to_string(),
+            ));
+        }
+        let tls = ClientTls::load(
+            config.ca_cert.as_deref(),
+            config.client_cert.as_deref(),
+            config.client_key.as_deref(),
+        )?;
+        // Discovery needs its own clients pinned to one address family, with
+        // the same certificates so the server can be one of its sources
+        let builder = || tls.builder();
+        let client = builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(agent_error)?;
+        let mut sources = vec![Source::Http(format!(
+            "{}/",
+            config.server.trim_end_matches('/')
+        ))];
+        sources.extend(config.sources.iter().cloned());
+        let discovery = Discovery::with_client(sources, config.quorum, builder)?;
+        let state = State::load(&config.state_file, &config.server, config.id.as_deref());
+        Ok(Agent {
+            config,
+            client,
+            discovery,
+            state,
+        })
+    }
+
+    pub async fn run(mut self) -> Result<(), DynIpError> {
+        info!(
+            "Starting agent for {} every {:?}, {} sources with a quorum of {}",
+            self.config.server,
+            self.config.interval,
+            self.config.sources.len() + 1,
+            self.discovery.quorum()
+        );
+        loop {
+            let result = self.check_with_retries().await;
+            if self.config.once {
+                return result;
+            }
+            if let Err(e) = result {
+                error!("Giving up until the next check: {}", e);
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(self.config.interval) => {}
+                _ = self.discovery.changed() => {
+                    info!("Interface addresses changed");
+                    // Let a burst of events, e.g. duplicate address detection, settle
+                    tokio::time::sleep(CHANGE_SETTLE_DELAY).await;
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Stopping agent");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn check_with_retries(&mut self) -> Result<(), DynIpError> {
+        let mut delay = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match self.check().await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= RETRY_ATTEMPTS => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "Attempt {}/{} failed, retrying in {:?}: {}",
+                        attempt, RETRY_ATTEMPTS, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(self.config.interval);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn check(&mut self) -> Result<(), DynIpError> {
+        let ip = self.discover().await?;
+        if self.state.ip == Some(ip) {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            let age = now.saturating_sub(self.state.updated_at.unwrap_or_default());
+            if age < self.config.refresh.as_secs() {
+                info!("IP unchanged ({})", ip);
+                return Ok(());
+            }
+            info!("IP unchanged ({}), refreshing after {}s", ip, age);
+        } else {
+            info!(
+                "IP changed from {} to {}, updating",
+                self.state
+                    .ip
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_else(|| "unknown".to_string()),
+                ip
+            );
+        }
+        self.push(ip).await?;
+        self.state.record(ip);
+        self.state.save(&self.config.state_file)
+    }
+
+    async fn discover(&self) -> Result<IpAddr, DynIpError> {
+        self.discovery.discover(self.config.family).await
+    }
+
+    async fn push(&self, ip: IpAddr) -> Result<(), DynIpError> {
+        let ip = ip.to_string();
+        let request = match &self.config.id {
+            Some(id) => self
+                .client
+                .patch(self.url("/"))
+                .query(&[("id", id.as_str()), ("ip", ip.as_str())]),
+            None => self
+                .client
+                .patch(self.url("/api/domains"))
+                .query(&[("ip", ip.as_str())]),
+        };
+        let response = request.send().await.map_err(agent_error)?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        Err(DynIpError::Agent(format!(
+            "Update failed with status {}: {}",
+            status, text
+        )))
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.server.trim_end_matches('/'), path)
+    }
+}
+
+fn agent_error(e: impl std::fmt::Display) -> DynIpError {
+    DynIpError::Agent(e.to_string())
+}
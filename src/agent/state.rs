@@ -0,0 +1,74 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::error::DynIpError;
+
+/// What the agent last sent to the server, kept across restarts so an
+/// unchanged IP only triggers an update once it's due a refresh.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    /// Server and record ID the IP was sent for
+    #[serde(default)]
+    pub server: String,
+    #[serde(default)]
+    pub id: Option<String>,
+    pub ip: Option<IpAddr>,
+    /// Unix seconds of the last successful update
+    pub updated_at: Option<u64>,
+}
+
+impl State {
+    /// The state saved for `server` and `id`. A file left by another server or
+    /// record says nothing about this one, so it starts afresh.
+    pub fn load(path: &Path, server: &str, id: Option<&str>) -> State {
+        let server = server.trim_end_matches('/');
+        let fresh = || State {
+            server: server.to_string(),
+            id: id.map(str::to_string),
+            ..State::default()
+        };
+        let state: State = match std::fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(state) => state,
+                Err(e) => {
+                    warn!("Ignoring unreadable state file {:?}: {}", path, e);
+                    return fresh();
+                }
+            },
+            Err(_) => return fresh(),
+        };
+        if state.server != server || state.id.as_deref() != id {
+            info!(
+                "Ignoring state file {:?}, it was saved for {} {}",
+                path,
+                state.server,
+                state.id.as_deref().unwrap_or("(certificate)")
+            );
+            return fresh();
+        }
+        state
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), DynIpError> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Write then rename so a crash never leaves a truncated file behind
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self).unwrap_or_default())?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn record(&mut self, ip: IpAddr) {
+        self.ip = Some(ip);
+        self.updated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs());
+    }
+}
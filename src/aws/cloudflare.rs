@@ -1,28 +1,294 @@
-use crate::aws::record::{DisplayRecord, ListRecordsResponse, Record};
+use crate::aws::record::{
+    CloudflareRecord, DisplayRecord, ListRecordsResponse, Record, RecordResponse, RrType,
+};
 use crate::error::DynIpError;
-use log::info;
+use crate::server::metrics::metrics;
+use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Client;
-use serde_json::json;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::{info, instrument};
+
+/// Which kind of credential `api_key` is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthMode {
+    /// A scoped API token, sent as a Bearer token
+    Token,
+    /// The account's legacy Global API Key, which needs the account email
+    GlobalKey,
+}
+
+impl AuthMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthMode::Token => "token",
+            AuthMode::GlobalKey => "global-key",
+        }
+    }
+}
+
+impl std::str::FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AuthMode, String> {
+        match s.to_lowercase().as_str() {
+            "token" => Ok(AuthMode::Token),
+            "global-key" | "global_key" => Ok(AuthMode::GlobalKey),
+            _ => Err(format!(
+                "Invalid Cloudflare auth mode: {}, expected token or global-key",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Auth {
+    Token(String),
+    GlobalKey { email: String, key: String },
+}
+
+impl Auth {
+    pub fn mode(&self) -> AuthMode {
+        match self {
+            Auth::Token(_) => AuthMode::Token,
+            Auth::GlobalKey { .. } => AuthMode::GlobalKey,
+        }
+    }
+
+    /// The headers authenticating a request with these credentials.
+    pub fn headers(&self) -> Result<HeaderMap, DynIpError> {
+        let value = |v: String| {
+            HeaderValue::from_str(&v).map_err(|_| {
+                DynIpError::Cloudflare(
+                    "Credentials contain characters not allowed in a header".to_string(),
+                )
+            })
+        };
+        let mut headers = HeaderMap::new();
+        match self {
+            Auth::Token(token) => {
+                headers.insert("Authorization", value(format!("Bearer {}", token))?);
+            }
+            Auth::GlobalKey { email, key } => {
+                headers.insert("X-Auth-Email", value(email.clone())?);
+                headers.insert("X-Auth-Key", value(key.clone())?);
+            }
+        }
+        Ok(headers)
+    }
+}
+
+/// A request taking longer is abandoned, so a hung connection can't stall
+/// updates or probes.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the v4 API lives unless `cloudflare.api_url` says otherwise.
+pub const CLOUDFLARE_API_URL: &str = "https://api.cloudflare.com/client/v4";
 
 #[derive(Clone)]
 pub struct Cloudflare {
     pub client: Client,
-    pub api_key: String,
+    /// Without a trailing slash
+    pub api_url: String,
+    pub auth: Auth,
     pub zone_id: String,
     pub domain_name: String,
 }
 
 impl Cloudflare {
-    pub fn new(api_key: String, zone_id: String, _email: String, domain_name: String) -> Cloudflare {
+    pub fn new(api_url: String, auth: Auth, zone_id: String, domain_name: String) -> Cloudflare {
         Cloudflare {
-            client: Client::new(),
-            api_key,
+            client: Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            auth,
             zone_id,
             domain_name,
         }
     }
 
-    async fn handle_response(&self, response: reqwest::Response) -> Result<reqwest::Response, DynIpError> {
+    /// Checks the credentials can manage `domain_name`, looking up the zone ID when
+    /// it isn't given, so bad credentials or zone fail at startup rather than on
+    /// the first request.
+    pub async fn connect(
+        api_url: String,
+        auth: Auth,
+        zone_id: Option<String>,
+        domain_name: String,
+    ) -> Result<Cloudflare, DynIpError> {
+        let mut cloudflare =
+            Cloudflare::new(api_url, auth, zone_id.unwrap_or_default(), domain_name);
+        cloudflare.verify_credentials().await?;
+        if cloudflare.zone_id.is_empty() {
+            cloudflare.zone_id = cloudflare.find_zone_id().await?;
+        }
+        cloudflare.check_zone().await?;
+        Ok(cloudflare)
+    }
+
+    /// Checks Cloudflare is reachable, the credentials are valid and they can
+    /// read this zone's records.
+    pub async fn check(&self) -> Result<(), DynIpError> {
+        self.verify_credentials().await?;
+        self.check_zone().await
+    }
+
+    /// Checks the zone ID is this zone's and its records can be read, with
+    /// credentials already verified.
+    async fn check_zone(&self) -> Result<(), DynIpError> {
+        self.check_zone_id().await?;
+        let url = format!(
+            "{}/zones/{}/dns_records?per_page=1",
+            self.api_url, self.zone_id
+        );
+        self.get(&url).await?.map_err(|e| {
+            self.error(format!(
+                "the token can't read DNS records, it needs Zone.DNS Edit: {}",
+                e
+            ))
+        })?;
+        Ok(())
+    }
+
+    async fn verify_credentials(&self) -> Result<(), DynIpError> {
+        match self.auth.mode() {
+            AuthMode::Token => self.verify_token().await,
+            AuthMode::GlobalKey => self.verify_global_key().await,
+        }
+    }
+
+    async fn verify_token(&self) -> Result<(), DynIpError> {
+        let verify = self
+            .get(&format!("{}/user/tokens/verify", self.api_url))
+            .await?
+            .map_err(|e| {
+                self.error(format!(
+                    "the API token was rejected, create one with Zone.Zone Read and Zone.DNS Edit \
+                     at https://dash.cloudflare.com/profile/api-tokens: {}",
+                    e
+                ))
+            })?;
+        match verify["result"]["status"].as_str() {
+            Some("active") => Ok(()),
+            status => Err(self.error(format!(
+                "the API token is {}, not active",
+                status.unwrap_or("in an unknown state")
+            ))),
+        }
+    }
+
+    async fn verify_global_key(&self) -> Result<(), DynIpError> {
+        self.get(&format!("{}/user", self.api_url))
+            .await?
+            .map_err(|e| {
+                self.error(format!(
+                    "the Global API Key or its email was rejected, check both on \
+                     https://dash.cloudflare.com/profile/api-tokens: {}",
+                    e
+                ))
+            })?;
+        Ok(())
+    }
+
+    async fn find_zone_id(&self) -> Result<String, DynIpError> {
+        let url = format!("{}/zones?name={}", self.api_url, self.domain_name);
+        let zones = self.get(&url).await?.map_err(|e| {
+            self.error(format!(
+                "looking up the zone ID failed, set it explicitly or give the token Zone.Zone Read: {}",
+                e
+            ))
+        })?;
+        match zones["result"][0]["id"].as_str() {
+            Some(id) => {
+                info!(zone = %self.domain_name, zone_id = id, "Found zone ID");
+                Ok(id.to_string())
+            }
+            None => Err(self.error(
+                "no zone with this name is visible to the token, check domain_name is the \
+                 zone's apex and the token covers it"
+                    .to_string(),
+            )),
+        }
+    }
+
+    async fn check_zone_id(&self) -> Result<(), DynIpError> {
+        let url = format!("{}/zones/{}", self.api_url, self.zone_id);
+        let zone = self.get(&url).await?.map_err(|e| {
+            self.error(format!(
+                "zone ID {} isn't accessible with this token, leave it unset to look it up: {}",
+                self.zone_id, e
+            ))
+        })?;
+        match zone["result"]["name"].as_str() {
+            Some(name) if name.eq_ignore_ascii_case(&self.domain_name) => Ok(()),
+            name => Err(self.error(format!(
+                "zone ID {} belongs to {}, leave it unset to look it up",
+                self.zone_id,
+                name.unwrap_or("another zone")
+            ))),
+        }
+    }
+
+    /// A GET for the startup checks. Cloudflare refusing is the inner error,
+    /// with its own message, so callers can say what to fix.
+    #[instrument(skip_all, fields(otel.kind = "client", zone = %self.domain_name, url = %url))]
+    async fn get(&self, url: &str) -> Result<Result<Value, String>, DynIpError> {
+        let headers = self.auth.headers()?;
+        let response = self
+            .client
+            .get(url)
+            .headers(headers)
+            .header("Content-Type", "application/json")
+            .send()
+            .await
+            .map_err(|e| {
+                metrics().provider_request("error");
+                self.error(format!("can't reach Cloudflare: {}", e))
+            })?;
+        let status = response.status();
+        metrics().provider_request(status.as_str());
+        let text = response
+            .text()
+            .await
+            .map_err(|e| self.error(format!("reading the {} response failed: {}", status, e)))?;
+        let Ok(body) = serde_json::from_str::<Value>(&text) else {
+            let text = text.trim();
+            let text = match text.char_indices().nth(200) {
+                Some((end, _)) => format!("{}...", &text[..end]),
+                None => text.to_string(),
+            };
+            let reply = format!("{} with a body that isn't JSON: {}", status, text);
+            if status.is_success() {
+                return Err(self.error(format!("Cloudflare answered {}", reply)));
+            }
+            return Ok(Err(reply));
+        };
+        if status.is_success() {
+            return Ok(Ok(body));
+        }
+        let messages: Vec<_> = body["errors"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e["message"].as_str())
+            .collect();
+        Ok(Err(format!("{} {}", status, messages.join(", "))
+            .trim()
+            .to_string()))
+    }
+
+    fn error(&self, message: String) -> DynIpError {
+        DynIpError::Cloudflare(format!("{}: {}", self.domain_name, message))
+    }
+
+    async fn handle_response(
+        &self,
+        response: reqwest::Response,
+    ) -> Result<reqwest::Response, DynIpError> {
+        metrics().provider_request(response.status().as_str());
         if response.status().is_success() {
             Ok(response)
         } else {
@@ -38,81 +304,70 @@ impl Cloudflare {
         }
     }
 
+    #[instrument(skip_all, fields(otel.kind = "client", zone = %self.domain_name))]
     pub async fn update_record(&self, record: Record) -> Result<(), DynIpError> {
-        let Record {
-            record_type,
-            domain,
-            ip,
-            ttl,
-            source_id,
-        } = record;
-
-        info!("Updating Record: {} {} {} {}", record_type, domain, ip, ttl);
+        info!(
+            record = %record.domain,
+            record_type = %record.record_type,
+            value = %record.ip,
+            ttl = record.ttl,
+            "Updating record"
+        );
 
-        let source_id = source_id.ok_or(DynIpError::MissingId)?;
+        let source_id = record.source_id.as_ref().ok_or(DynIpError::MissingId)?;
         let url = format!(
-            "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
-            self.zone_id, source_id
+            "{}/zones/{}/dns_records/{}",
+            self.api_url, self.zone_id, source_id
         );
 
-        let body = json!({
-            "type": record_type,
-            "name": domain,
-            "content": ip,
-            "ttl": ttl,
-            "proxied": false
-        });
+        let body = record_body(&record);
 
+        let headers = self.auth.headers()?;
         let response = self
             .client
             .patch(&url)
-            .header("Authorization", format!("Bearer {}", &self.api_key))
+            .headers(headers)
             .header("Content-Type", "application/json")
             .json(&body)
             .send()
             .await
-            .map_err(|e| DynIpError::Cloudflare(e.to_string()))?;
-        
+            .map_err(request_failed)?;
+
         self.handle_response(response).await?;
         Ok(())
     }
 
-    pub async fn create_record(&self, record: Record) -> Result<(), DynIpError> {
-        let Record {
-            record_type,
-            domain,
-            ip,
-            ttl,
-            source_id: _,
-        } = record;
-
-        info!("Creating Record: {} {} {} {}", record_type, domain, ip, ttl);
-
-        let url = format!(
-            "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
-            self.zone_id
+    #[instrument(skip_all, fields(otel.kind = "client", zone = %self.domain_name))]
+    pub async fn create_record(&self, record: Record) -> Result<Record, DynIpError> {
+        info!(
+            record = %record.domain,
+            record_type = %record.record_type,
+            value = %record.ip,
+            ttl = record.ttl,
+            "Creating record"
         );
 
-        let body = json!({
-            "type": record_type,
-            "name": domain,
-            "content": ip,
-            "ttl": ttl,
-            "proxied": false
-        });
+        let url = format!("{}/zones/{}/dns_records", self.api_url, self.zone_id);
+
+        let body = record_body(&record);
 
+        let headers = self.auth.headers()?;
         let response = self
             .client
             .post(&url)
-            .header("Authorization", format!("Bearer {}", &self.api_key))
+            .headers(headers)
             .header("Content-Type", "application/json")
             .json(&body)
             .send()
             .await
-            .map_err(|e| DynIpError::Cloudflare(e.to_string()))?;
-        
-        self.handle_response(response).await?;
-        Ok(())
+            .map_err(request_failed)?;
+
+        let response = self.handle_response(response).await?;
+        let created = response
+            .json::<RecordResponse>()
+            .await
+            .map_err(|e| DynIpError::Cloudflare(format!("Failed to decode response: {}", e)))?;
+        Ok(created.result.into())
     }
 
     pub async fn list_display_records(&self, salt: &str) -> Result<Vec<DisplayRecord>, DynIpError> {
@@ -142,68 +397,140 @@ impl Cloudflare {
         Ok(all_records)
     }
 
+    #[instrument(skip_all, fields(otel.kind = "client", zone = %self.domain_name, page = page))]
     async fn fetch_records_page(&self, page: u32) -> Result<Vec<Record>, DynIpError> {
         info!("Fetching records page {}", page);
         let url = format!(
-            "https://api.cloudflare.com/client/v4/zones/{}/dns_records?page={}",
-            self.zone_id, page
+            "{}/zones/{}/dns_records?page={}",
+            self.api_url, self.zone_id, page
         );
 
+        let headers = self.auth.headers()?;
         let response = self
             .client
             .get(&url)
-            .header("Authorization", format!("Bearer {}", &self.api_key))
+            .headers(headers)
             .header("Content-Type", "application/json")
             .send()
             .await
-            .map_err(|e| DynIpError::Cloudflare(e.to_string()))?;
-        
+            .map_err(request_failed)?;
+
         let response = self.handle_response(response).await?;
-        
+
         // Get the response text first for debugging
-        let response_text = response.text().await
+        let response_text = response
+            .text()
+            .await
             .map_err(|e| DynIpError::Cloudflare(format!("Failed to get response text: {}", e)))?;
-        
+
         // Parse the text into JSON
-        let list_response = serde_json::from_str::<ListRecordsResponse>(&response_text)
-            .map_err(|e| DynIpError::Cloudflare(format!("Failed to decode response: {} - Raw response: {}", e, response_text)))?;
-            
+        let list_response =
+            serde_json::from_str::<ListRecordsResponse>(&response_text).map_err(|e| {
+                DynIpError::Cloudflare(format!(
+                    "Failed to decode response: {} - Raw response: {}",
+                    e, response_text
+                ))
+            })?;
+
         let filtered_records: Vec<Record> = list_response
             .result
             .into_iter()
-            .filter(|r| r.r#type == "A" || r.r#type == "CNAME")
+            .filter(|r| RrType::from_str(&r.r#type.to_string()).is_ok())
             .map(|r| r.into())
             .collect();
-            
-        info!("Retrieved {} records for page {}", filtered_records.len(), page);
+
+        info!(
+            "Retrieved {} records for page {}",
+            filtered_records.len(),
+            page
+        );
         Ok(filtered_records)
     }
 
-    pub async fn delete_record(&self, salt: &str, id_or_domain: &str) -> Result<(), DynIpError> {
-        let records = self.list_display_records(salt).await?;
-        if let Some(record) = records
-            .iter()
-            .find(|r| r.id == id_or_domain || r.domain == id_or_domain)
-        {
-            info!("Deleting record: {:?}", record);
-            let url = format!(
-                "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
-                self.zone_id, record.source_id
-            );
-
-            let response = self
-                .client
-                .delete(&url)
-                .header("Authorization", format!("Bearer {}", &self.api_key))
-                .header("Content-Type", "application/json")
-                .send()
-                .await
-                .map_err(|e| DynIpError::Cloudflare(e.to_string()))?;
-            
-            self.handle_response(response).await?;
-            Ok(())
-        } else {
-            Err(DynIpError::DomainHashNotFound)
+    /// TXT records with exactly this name, oldest first.
+    #[instrument(skip_all, fields(otel.kind = "client", zone = %self.domain_name))]
+    pub async fn list_txt_records(&self, name: &str) -> Result<Vec<CloudflareRecord>, DynIpError> {
+        let url = format!("{}/zones/{}/dns_records", self.api_url, self.zone_id);
+
+        let headers = self.auth.headers()?;
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("type", "TXT"), ("name", name), ("per_page", "100")])
+            .headers(headers)
+            .header("Content-Type", "application/json")
+            .send()
+            .await
+            .map_err(request_failed)?;
+
+        let response = self.handle_response(response).await?;
+        let mut records = response
+            .json::<ListRecordsResponse>()
+            .await
+            .map_err(|e| DynIpError::Cloudflare(format!("Failed to decode response: {}", e)))?
+            .result;
+        records.sort_by(|a, b| a.created_on.cmp(&b.created_on));
+        Ok(records)
+    }
+
+    #[instrument(skip_all, fields(otel.kind = "client", zone = %self.domain_name))]
+    pub async fn delete_record_by_source_id(&self, source_id: &str) -> Result<(), DynIpError> {
+        let url = format!(
+            "{}/zones/{}/dns_records/{}",
+            self.api_url, self.zone_id, source_id
+        );
+
+        let headers = self.auth.headers()?;
+        let response = self
+            .client
+            .delete(&url)
+            .headers(headers)
+            .header("Content-Type", "application/json")
+            .send()
+            .await
+            .map_err(request_failed)?;
+
+        self.handle_response(response).await?;
+        Ok(())
+    }
+}
+
+fn request_failed(e: reqwest::Error) -> DynIpError {
+    metrics().provider_request("error");
+    DynIpError::Cloudflare(e.to_string())
+}
+
+/// Cloudflare takes MX priority alongside `content`, and SRV/CAA as `data`.
+pub fn record_body(record: &Record) -> Value {
+    let mut body = json!({
+        "type": record.record_type,
+        "name": record.domain,
+        "ttl": record.ttl,
+        "proxied": record.proxied,
+        "comment": record.comment
+    });
+    let target = record.target.as_ref().unwrap_or(&record.ip);
+    match record.record_type.as_str() {
+        "MX" => {
+            body["content"] = json!(target);
+            body["priority"] = json!(record.priority);
+        }
+        "SRV" => {
+            body["data"] = json!({
+                "priority": record.priority,
+                "weight": record.weight,
+                "port": record.port,
+                "target": target
+            });
+        }
+        "CAA" => {
+            body["data"] = json!({
+                "flags": record.flags.unwrap_or(0),
+                "tag": record.tag,
+                "value": record.ip
+            });
         }
+        _ => body["content"] = json!(record.ip),
     }
+    body
 }
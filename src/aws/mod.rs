@@ -1,2 +1,3 @@
 pub mod cloudflare;
 pub mod record;
+pub mod zones;
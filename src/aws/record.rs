@@ -1,4 +1,14 @@
+use addr::parse_dns_name;
 use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// CAA tags Cloudflare accepts.
+const CAA_TAGS: [&str; 3] = ["issue", "issuewild", "iodef"];
+const MAX_TXT_LENGTH: usize = 2048;
+const MAX_COMMENT_LENGTH: usize = 100;
+/// Cloudflare's "automatic" TTL
+pub const AUTO_TTL: i64 = 1;
+const TTL_RANGE: std::ops::RangeInclusive<i64> = 30..=86400;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[allow(dead_code)]
@@ -24,6 +34,10 @@ pub struct CloudflareRecord {
     pub tags: Vec<String>,
     pub ttl: u32,
     pub r#type: RecordType,
+    /// MX priority
+    pub priority: Option<u16>,
+    /// Structured content for SRV and CAA
+    pub data: Option<serde_json::Value>,
     pub zone_id: Option<String>,
     pub zone_name: Option<String>,
 }
@@ -48,6 +62,15 @@ pub struct ListRecordsResponse {
     pub success: bool,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(dead_code)]
+pub struct RecordResponse {
+    pub errors: Vec<serde_json::Value>,
+    pub messages: Vec<serde_json::Value>,
+    pub result: CloudflareRecord,
+    pub success: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(try_from = "String")]
 pub enum RecordType {
@@ -56,6 +79,10 @@ pub enum RecordType {
     Cname,
     MX,
     Txt,
+    Srv,
+    Caa,
+    /// Types we don't manage (NS, PTR, ...) still need to parse for listing.
+    Other(String),
 }
 
 impl TryFrom<String> for RecordType {
@@ -68,7 +95,9 @@ impl TryFrom<String> for RecordType {
             "CNAME" => Ok(RecordType::Cname),
             "MX" => Ok(RecordType::MX),
             "TXT" => Ok(RecordType::Txt),
-            _ => Err(format!("Unknown record type: {}", s)),
+            "SRV" => Ok(RecordType::Srv),
+            "CAA" => Ok(RecordType::Caa),
+            other => Ok(RecordType::Other(other.to_string())),
         }
     }
 }
@@ -81,6 +110,9 @@ impl std::fmt::Display for RecordType {
             RecordType::Cname => write!(f, "CNAME"),
             RecordType::MX => write!(f, "MX"),
             RecordType::Txt => write!(f, "TXT"),
+            RecordType::Srv => write!(f, "SRV"),
+            RecordType::Caa => write!(f, "CAA"),
+            RecordType::Other(other) => write!(f, "{}", other),
         }
     }
 }
@@ -91,6 +123,9 @@ impl PartialEq<&str> for RecordType {
     }
 }
 
+/// A managed DNS record. `ip` holds the primary value of every type: the
+/// address for A, the host for CNAME, MX and SRV (also kept in `target`), the
+/// text for TXT and the value for CAA.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Record {
     pub domain: String,
@@ -98,6 +133,14 @@ pub struct Record {
     pub ip: String,
     pub ttl: i64,
     pub source_id: Option<String>,
+    pub priority: Option<u16>,
+    pub weight: Option<u16>,
+    pub port: Option<u16>,
+    pub target: Option<String>,
+    pub flags: Option<u8>,
+    pub tag: Option<String>,
+    pub proxied: bool,
+    pub comment: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -108,29 +151,80 @@ pub struct DisplayRecord {
     pub ttl: i64,
     pub id: String,
     pub source_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub proxied: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// Unix seconds of the last refresh through dyn-ip, changed or not
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<u64>,
+    /// Seconds the record's client is expected to update within
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_interval: Option<u64>,
+    /// Its client has missed the expected interval
+    #[serde(default)]
+    pub stale: bool,
+    /// Unix seconds the record's lease runs out unless it's updated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease_expires_at: Option<u64>,
 }
 
 impl From<CloudflareRecord> for Record {
     fn from(r: CloudflareRecord) -> Self {
-        Record {
-            domain: r.name,
-            record_type: r.r#type.to_string(),
-            ip: r.content,
-            ttl: r.ttl as i64,
-            source_id: Some(r.id),
-        }
+        (&r).into()
     }
 }
 
 impl From<&CloudflareRecord> for Record {
     fn from(r: &CloudflareRecord) -> Self {
-        Record {
+        let data = |key: &str| r.data.as_ref().and_then(|d| d.get(key));
+        let number = |key: &str| {
+            data(key)
+                .and_then(|v| v.as_u64())
+                .and_then(|v| u16::try_from(v).ok())
+        };
+        let text = |key: &str| data(key).and_then(|v| v.as_str()).map(|v| v.to_string());
+
+        let mut record = Record {
             domain: r.name.clone(),
             record_type: r.r#type.to_string(),
             ip: r.content.clone(),
             ttl: r.ttl as i64,
             source_id: Some(r.id.clone()),
+            priority: r.priority,
+            proxied: r.proxied,
+            comment: r.comment.clone(),
+            ..Record::default()
+        };
+        match r.r#type {
+            RecordType::MX => record.target = Some(r.content.clone()),
+            RecordType::Srv => {
+                record.priority = number("priority");
+                record.weight = number("weight");
+                record.port = number("port");
+                record.target = text("target");
+                record.ip = record.target.clone().unwrap_or_default();
+            }
+            RecordType::Caa => {
+                record.flags = number("flags").and_then(|f| u8::try_from(f).ok());
+                record.tag = text("tag");
+                record.ip = text("value").unwrap_or_default();
+            }
+            _ => {}
         }
+        record
     }
 }
 
@@ -142,6 +236,14 @@ impl From<&DisplayRecord> for Record {
             ip: r.ip.clone(),
             ttl: r.ttl,
             source_id: Some(r.source_id.clone()),
+            priority: r.priority,
+            weight: r.weight,
+            port: r.port,
+            target: r.target.clone(),
+            flags: r.flags,
+            tag: r.tag.clone(),
+            proxied: r.proxied,
+            comment: r.comment.clone(),
         }
     }
 }
@@ -153,13 +255,27 @@ impl Default for Record {
             ip: "0.0.0.0".to_string(),
             ttl: 60,
             source_id: None,
+            priority: None,
+            weight: None,
+            port: None,
+            target: None,
+            flags: None,
+            tag: None,
+            proxied: false,
+            comment: None,
         }
     }
 }
 
 impl Record {
     pub fn id(&self, salt: &str) -> String {
-        let md5 = md5::compute(format!("{}{}", salt, self.domain));
+        // A and CNAME keep the name-only hash so existing clients' IDs stay valid.
+        // Other types can share a name, so their ID follows the provider record.
+        let key = match (self.record_type.as_str(), &self.source_id) {
+            ("A" | "CNAME", _) | (_, None) => self.domain.clone(),
+            (record_type, Some(source_id)) => format!("{}{}", record_type, source_id),
+        };
+        let md5 = md5::compute(format!("{}{}", salt, key));
         format!("{:?}", md5)
     }
 
@@ -171,27 +287,146 @@ impl Record {
             ttl: self.ttl,
             id: self.id(salt),
             source_id: self.source_id.clone().expect("source_id is required"),
+            priority: self.priority,
+            weight: self.weight,
+            port: self.port,
+            target: self.target.clone(),
+            flags: self.flags,
+            tag: self.tag.clone(),
+            proxied: self.proxied,
+            comment: self.comment.clone(),
+            last_seen: None,
+            expected_interval: None,
+            stale: false,
+            lease_expires_at: None,
+        }
+    }
+
+    /// Sets the primary value, keeping `target` in step for MX and SRV.
+    pub fn set_value(&mut self, value: String) {
+        if matches!(self.record_type.as_str(), "MX" | "SRV") {
+            self.target = Some(value.clone());
+        }
+        self.ip = value;
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ttl != AUTO_TTL && !TTL_RANGE.contains(&self.ttl) {
+            return Err(format!(
+                "TTL must be {} (automatic) or {}-{} seconds",
+                AUTO_TTL,
+                TTL_RANGE.start(),
+                TTL_RANGE.end()
+            ));
+        }
+        if self.proxied && !matches!(self.record_type.as_str(), "A" | "AAAA" | "CNAME") {
+            return Err(format!("{} records can't be proxied", self.record_type));
+        }
+        if self
+            .comment
+            .as_ref()
+            .is_some_and(|c| c.len() > MAX_COMMENT_LENGTH)
+        {
+            return Err(format!(
+                "Comments are limited to {} characters",
+                MAX_COMMENT_LENGTH
+            ));
+        }
+        match RrType::from_str(&self.record_type)? {
+            RrType::A => self
+                .ip
+                .parse::<Ipv4Addr>()
+                .map(|_| ())
+                .map_err(|_| format!("{} is not an IPv4 address", self.ip)),
+            RrType::Aaaa => self
+                .ip
+                .parse::<Ipv6Addr>()
+                .map(|_| ())
+                .map_err(|_| format!("{} is not an IPv6 address", self.ip)),
+            RrType::Cname => validate_host(&self.ip),
+            RrType::Txt => {
+                if self.ip.is_empty() || self.ip.len() > MAX_TXT_LENGTH {
+                    Err(format!(
+                        "TXT content must be 1-{} characters",
+                        MAX_TXT_LENGTH
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+            RrType::Mx => {
+                self.priority.ok_or("MX requires a priority")?;
+                validate_host(&self.ip)
+            }
+            RrType::Srv => {
+                let mut labels = self.domain.split('.');
+                let service = labels.next().unwrap_or_default();
+                let protocol = labels.next().unwrap_or_default();
+                if !service.starts_with('_') || !protocol.starts_with('_') {
+                    return Err("SRV names must start with _service._proto".to_string());
+                }
+                self.priority.ok_or("SRV requires a priority")?;
+                self.weight.ok_or("SRV requires a weight")?;
+                self.port.ok_or("SRV requires a port")?;
+                // "." means the service is decidedly not available
+                if self.ip == "." {
+                    Ok(())
+                } else {
+                    validate_host(&self.ip)
+                }
+            }
+            RrType::Caa => {
+                let tag = self.tag.as_deref().ok_or("CAA requires a tag")?;
+                if !CAA_TAGS.contains(&tag) {
+                    return Err(format!("CAA tag must be one of {}", CAA_TAGS.join(", ")));
+                }
+                if self.ip.is_empty() {
+                    return Err("CAA requires a value".to_string());
+                }
+                Ok(())
+            }
         }
     }
 }
 
+fn validate_host(host: &str) -> Result<(), String> {
+    parse_dns_name(host.trim_end_matches('.'))
+        .map(|_| ())
+        .map_err(|e| format!("Invalid host {}: {}", host, e))
+}
+
 pub enum RrType {
     A,
+    Aaaa,
     Cname,
+    Mx,
+    Txt,
+    Srv,
+    Caa,
 }
 
 impl RrType {
     pub fn as_str(&self) -> &'static str {
         match self {
             RrType::A => "A",
+            RrType::Aaaa => "AAAA",
             RrType::Cname => "CNAME",
+            RrType::Mx => "MX",
+            RrType::Txt => "TXT",
+            RrType::Srv => "SRV",
+            RrType::Caa => "CAA",
         }
     }
 
     pub(crate) fn from_str(s: &str) -> Result<RrType, String> {
-        match s {
+        match s.to_uppercase().as_str() {
             "A" => Ok(RrType::A),
+            "AAAA" => Ok(RrType::Aaaa),
             "CNAME" => Ok(RrType::Cname),
+            "MX" => Ok(RrType::Mx),
+            "TXT" => Ok(RrType::Txt),
+            "SRV" => Ok(RrType::Srv),
+            "CAA" => Ok(RrType::Caa),
             _ => Err(format!("Invalid record type: {}", s)),
         }
     }
@@ -0,0 +1,202 @@
+use futures_util::future::join_all;
+use tracing::{info, warn};
+
+use crate::aws::cloudflare::Cloudflare;
+use crate::aws::record::{CloudflareRecord, DisplayRecord, Record};
+use crate::error::DynIpError;
+use crate::server::metrics::metrics;
+
+/// Every zone served by this instance. A record belongs to the zone whose
+/// apex is the longest suffix of its name.
+#[derive(Clone)]
+pub struct Zones {
+    zones: Vec<Cloudflare>,
+}
+
+impl Zones {
+    pub fn new(zones: Vec<Cloudflare>) -> Zones {
+        Zones { zones }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cloudflare> {
+        self.zones.iter()
+    }
+
+    pub fn apexes(&self) -> Vec<&str> {
+        self.zones.iter().map(|z| z.domain_name.as_str()).collect()
+    }
+
+    pub fn for_domain(&self, domain: &str) -> Result<&Cloudflare, DynIpError> {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        self.zones
+            .iter()
+            .filter(|z| domain == z.domain_name || domain.ends_with(&format!(".{}", z.domain_name)))
+            .max_by_key(|z| z.domain_name.len())
+            .ok_or(DynIpError::NoZone(domain))
+    }
+
+    /// The full name for `domain`, which may be relative to `zone`, or to the
+    /// only zone when there's just one.
+    pub fn qualify(&self, domain: &str, zone: Option<&str>) -> Result<String, DynIpError> {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        let apex = match zone {
+            Some(zone) => {
+                let zone = zone.trim_end_matches('.').to_lowercase();
+                self.zones
+                    .iter()
+                    .find(|z| z.domain_name == zone)
+                    .map(|z| z.domain_name.as_str())
+                    .ok_or(DynIpError::NoZone(zone))?
+            }
+            None if self.for_domain(&domain).is_ok() => return Ok(domain),
+            None => match self.zones.as_slice() {
+                [only] => only.domain_name.as_str(),
+                _ => return Err(DynIpError::NoZone(domain)),
+            },
+        };
+        if domain == apex || domain.ends_with(&format!(".{}", apex)) {
+            Ok(domain)
+        } else {
+            Ok(format!("{}.{}", domain, apex))
+        }
+    }
+
+    /// Records from every zone that answers. A zone that fails is logged and
+    /// named in the listing, it's only an error when none answer.
+    pub async fn list(&self) -> Result<Listing<Record>, DynIpError> {
+        let results = join_all(self.zones.iter().map(|z| z.list_records())).await;
+        let mut listing = Listing {
+            records: Vec::new(),
+            failed_zones: Vec::new(),
+            error: None,
+        };
+        for (zone, result) in self.zones.iter().zip(results) {
+            match result {
+                Ok(mut listed) => listing.records.append(&mut listed),
+                Err(e) => {
+                    warn!(zone = %zone.domain_name, "Listing records failed: {}", e);
+                    listing.failed_zones.push(zone.domain_name.clone());
+                    listing.error.get_or_insert(e);
+                }
+            }
+        }
+        if listing.failed_zones.len() == self.zones.len() {
+            if let Some(e) = listing.error {
+                return Err(e);
+            }
+        }
+        if listing.failed_zones.is_empty() {
+            metrics().records_listed(listing.records.len());
+        }
+        Ok(listing)
+    }
+
+    pub async fn list_display(&self, salt: &str) -> Result<Listing<DisplayRecord>, DynIpError> {
+        let listing = self.list().await?;
+        Ok(Listing {
+            records: listing
+                .records
+                .iter()
+                .map(|r| r.for_display(salt))
+                .collect(),
+            failed_zones: listing.failed_zones,
+            error: listing.error,
+        })
+    }
+
+    /// Records from every zone, an error when any zone fails so a record
+    /// that isn't found is really missing.
+    pub async fn list_records(&self) -> Result<Vec<Record>, DynIpError> {
+        self.list().await?.complete()
+    }
+
+    pub async fn list_display_records(&self, salt: &str) -> Result<Vec<DisplayRecord>, DynIpError> {
+        self.list_display(salt).await?.complete()
+    }
+
+    // The zone is looked up before awaiting, a `Result` held across an await
+    // would make these futures `!Send`.
+    pub async fn create_record(&self, record: Record) -> Result<Record, DynIpError> {
+        let zone = self.for_domain(&record.domain)?;
+        zone.create_record(record).await
+    }
+
+    pub async fn update_record(&self, record: Record) -> Result<(), DynIpError> {
+        let zone = self.for_domain(&record.domain)?;
+        zone.update_record(record).await
+    }
+
+    pub async fn list_txt_records(&self, name: &str) -> Result<Vec<CloudflareRecord>, DynIpError> {
+        let zone = self.for_domain(name)?;
+        zone.list_txt_records(name).await
+    }
+
+    /// Deletes the one record with this ID, or failing that this name. Same
+    /// name A records share an ID, so more than one match is refused rather
+    /// than deleting whichever is listed first.
+    pub async fn delete_record(
+        &self,
+        salt: &str,
+        id_or_domain: &str,
+    ) -> Result<DisplayRecord, DynIpError> {
+        let records = self.list_display_records(salt).await?;
+        let (by_id, others): (Vec<_>, Vec<_>) =
+            records.into_iter().partition(|r| r.id == id_or_domain);
+        let mut found = if by_id.is_empty() {
+            others
+                .into_iter()
+                .filter(|r| r.domain == id_or_domain)
+                .collect()
+        } else {
+            by_id
+        };
+        let record = match found.len() {
+            0 => return Err(DynIpError::DomainHashNotFound),
+            1 => found.remove(0),
+            n => return Err(DynIpError::AmbiguousRecord(id_or_domain.to_string(), n)),
+        };
+        info!(
+            record = %record.domain,
+            record_type = %record.record_type,
+            value = %record.ip,
+            "Deleting record"
+        );
+        self.delete_record_by_source_id(&record.domain, &record.source_id)
+            .await?;
+        Ok(record)
+    }
+
+    pub async fn delete_record_by_source_id(
+        &self,
+        domain: &str,
+        source_id: &str,
+    ) -> Result<(), DynIpError> {
+        let zone = self.for_domain(domain)?;
+        zone.delete_record_by_source_id(source_id).await
+    }
+}
+
+/// Records from the zones that answered, with the apexes of those that
+/// didn't. Records in a failed zone are missing without having been deleted.
+pub struct Listing<T> {
+    pub records: Vec<T>,
+    pub failed_zones: Vec<String>,
+    error: Option<DynIpError>,
+}
+
+impl<T> Listing<T> {
+    /// Whether the zone of `domain` answered, so its records are all here.
+    pub fn covers(&self, zones: &Zones, domain: &str) -> bool {
+        zones
+            .for_domain(domain)
+            .is_ok_and(|zone| !self.failed_zones.contains(&zone.domain_name))
+    }
+
+    /// The records, or the first zone's error when any failed.
+    pub fn complete(self) -> Result<Vec<T>, DynIpError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.records),
+        }
+    }
+}
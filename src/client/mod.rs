@@ -0,0 +1,252 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reqwest::{Certificate, Client, ClientBuilder, Identity, Method, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::aws::record::DisplayRecord;
+use crate::error::DynIpError;
+use crate::server::history::Change;
+
+pub mod records;
+pub mod table;
+
+/// Where to reach a dyn-ip server and how to authenticate. Flags and env
+/// vars override whatever the config file sets.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    pub server: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub ca_cert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl ClientConfig {
+    /// `$XDG_CONFIG_HOME/dyn-ip/client.toml`, falling back to `~/.config`.
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+            .map(|d| d.join("dyn-ip").join("client.toml"))
+    }
+
+    /// A missing file is only an error when it was asked for explicitly.
+    pub fn load(path: Option<&Path>) -> Result<ClientConfig, DynIpError> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match ClientConfig::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(ClientConfig::default()),
+            },
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| DynIpError::Client(format!("Invalid config {:?}: {}", path, e))),
+            Err(e) if explicit => Err(DynIpError::Client(format!(
+                "Can't read config {:?}: {}",
+                path, e
+            ))),
+            Err(_) => {
+                debug!("No client config at {:?}", path);
+                Ok(ClientConfig::default())
+            }
+        }
+    }
+
+    /// Fills anything unset here from `other`.
+    pub fn or(self, other: ClientConfig) -> ClientConfig {
+        ClientConfig {
+            server: self.server.or(other.server),
+            username: self.username.or(other.username),
+            password: self.password.or(other.password),
+            ca_cert: self.ca_cert.or(other.ca_cert),
+            client_cert: self.client_cert.or(other.client_cert),
+            client_key: self.client_key.or(other.client_key),
+        }
+    }
+}
+
+/// Extra CA and client certificate for reaching a server over (m)TLS.
+#[derive(Clone, Default)]
+pub struct ClientTls {
+    ca_cert: Option<Certificate>,
+    identity: Option<Identity>,
+}
+
+impl ClientTls {
+    /// The key defaults to the certificate file, for combined PEM bundles.
+    pub fn load(
+        ca_cert: Option<&Path>,
+        client_cert: Option<&Path>,
+        client_key: Option<&Path>,
+    ) -> Result<ClientTls, DynIpError> {
+        let ca_cert = match ca_cert {
+            Some(ca_cert) => Some(
+                Certificate::from_pem(&std::fs::read(ca_cert)?)
+                    .map_err(|e| DynIpError::Client(e.to_string()))?,
+            ),
+            None => None,
+        };
+        let identity = match client_cert {
+            Some(client_cert) => {
+                let cert = std::fs::read(client_cert)?;
+                let key = std::fs::read(client_key.unwrap_or(client_cert))?;
+                Some(
+                    Identity::from_pkcs8_pem(&cert, &key)
+                        .map_err(|e| DynIpError::Client(e.to_string()))?,
+                )
+            }
+            None => None,
+        };
+        Ok(ClientTls { ca_cert, identity })
+    }
+
+    pub fn builder(&self) -> ClientBuilder {
+        let mut builder = Client::builder();
+        if let Some(ca_cert) = &self.ca_cert {
+            builder = builder.add_root_certificate(ca_cert.clone());
+        }
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+        builder
+    }
+}
+
+/// Talks to a running server's `/api` routes.
+pub struct ApiClient {
+    server: String,
+    client: Client,
+    credentials: Option<(String, String)>,
+}
+
+impl ApiClient {
+    pub fn new(config: &ClientConfig) -> Result<ApiClient, DynIpError> {
+        let server = config.server.clone().ok_or_else(|| {
+            DynIpError::Client(
+                "No server configured, use --server, DYN_IP_SERVER or the config file".to_string(),
+            )
+        })?;
+        let client = ClientTls::load(
+            config.ca_cert.as_deref(),
+            config.client_cert.as_deref(),
+            config.client_key.as_deref(),
+        )?
+        .builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|e| DynIpError::Client(e.to_string()))?;
+        Ok(ApiClient {
+            server: server.trim_end_matches('/').to_string(),
+            client,
+            credentials: config.username.clone().zip(config.password.clone()),
+        })
+    }
+
+    pub async fn list(&self) -> Result<Vec<DisplayRecord>, DynIpError> {
+        self.send(self.request(Method::GET, "/api/domains")).await
+    }
+
+    pub async fn add(&self, params: &[(&str, String)]) -> Result<DisplayRecord, DynIpError> {
+        self.send(self.request(Method::POST, "/api/domains").query(params))
+            .await
+    }
+
+    pub async fn edit(
+        &self,
+        id: &str,
+        params: &[(&str, String)],
+    ) -> Result<DisplayRecord, DynIpError> {
+        let path = format!("/api/domains/{}", id);
+        self.send(self.request(Method::PUT, &path).query(params))
+            .await
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<(), DynIpError> {
+        let path = format!("/api/domains/{}", id);
+        self.send::<serde_json::Value>(self.request(Method::DELETE, &path))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn history(&self, params: &[(&str, String)]) -> Result<Vec<Change>, DynIpError> {
+        self.send(self.request(Method::GET, "/api/history").query(params))
+            .await
+    }
+
+    /// Finds a record by ID or name, e.g. `home.example.com` or just `home`.
+    pub async fn resolve(
+        &self,
+        id_or_name: &str,
+        record_type: Option<&str>,
+    ) -> Result<DisplayRecord, DynIpError> {
+        let records: Vec<DisplayRecord> = self
+            .list()
+            .await?
+            .into_iter()
+            .filter(|r| record_type.is_none_or(|t| r.record_type.eq_ignore_ascii_case(t)))
+            .collect();
+        if let Some(record) = records.iter().find(|r| r.id == id_or_name) {
+            return Ok(record.clone());
+        }
+        let exact: Vec<_> = records.iter().filter(|r| r.domain == id_or_name).collect();
+        let matches = if exact.is_empty() {
+            let prefix = format!("{}.", id_or_name);
+            records
+                .iter()
+                .filter(|r| r.domain.starts_with(&prefix))
+                .collect()
+        } else {
+            exact
+        };
+        match matches.as_slice() {
+            [record] => Ok((*record).clone()),
+            [] => Err(DynIpError::DomainHashNotFound),
+            _ => Err(DynIpError::Client(format!(
+                "{} matches several records, use an ID or --type: {}",
+                id_or_name,
+                matches
+                    .iter()
+                    .map(|r| format!("{} {} ({})", r.domain, r.record_type, r.id))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))),
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}{}", self.server, path));
+        match &self.credentials {
+            Some((username, password)) => request.basic_auth(username, Some(password)),
+            None => request,
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, DynIpError> {
+        let response = request
+            .send()
+            .await
+            .map_err(|e| DynIpError::Client(e.to_string()))?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| DynIpError::Client(e.to_string()))?;
+        if !status.is_success() {
+            return Err(DynIpError::Client(match body.trim() {
+                "" => status.to_string(),
+                body => format!("{}: {}", status, body),
+            }));
+        }
+        serde_json::from_str(&body)
+            .map_err(|e| DynIpError::Client(format!("Unexpected response: {}", e)))
+    }
+}
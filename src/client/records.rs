@@ -0,0 +1,341 @@
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand};
+use serde::Serialize;
+
+use crate::aws::record::{DisplayRecord, AUTO_TTL};
+use crate::client::{table, ApiClient, ClientConfig};
+use crate::error::DynIpError;
+
+#[derive(Args)]
+pub struct RecordsArgs {
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+    #[command(subcommand)]
+    pub command: RecordsCommand,
+}
+
+#[derive(Args)]
+pub struct ConnectionArgs {
+    /// Client config file, defaults to ~/.config/dyn-ip/client.toml
+    #[arg(long, env = "DYN_IP_CLIENT_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    /// Base URL of the dyn-ip server
+    #[arg(long, env = "DYN_IP_SERVER", global = true)]
+    pub server: Option<String>,
+    #[arg(long, env = "DYN_IP_USERNAME", global = true)]
+    pub username: Option<String>,
+    #[arg(long, env = "DYN_IP_PASSWORD", global = true, hide_env_values = true)]
+    pub password: Option<String>,
+    /// Extra CA to trust for the server certificate
+    #[arg(long, env = "DYN_IP_CA_CERT", global = true)]
+    pub ca_cert: Option<PathBuf>,
+    /// PEM client certificate for the mTLS listener
+    #[arg(long, env = "DYN_IP_CLIENT_CERT", global = true)]
+    pub client_cert: Option<PathBuf>,
+    /// PEM key for the client certificate, defaults to the certificate file
+    #[arg(long, env = "DYN_IP_CLIENT_KEY", global = true)]
+    pub client_key: Option<PathBuf>,
+    /// Print JSON instead of a table
+    #[arg(long, global = true)]
+    pub json: bool,
+}
+
+#[derive(Subcommand)]
+pub enum RecordsCommand {
+    /// List records
+    List {
+        /// Only names containing this
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long = "type")]
+        record_type: Option<String>,
+    },
+    /// Create a record
+    Add {
+        /// Subdomain or full name
+        name: String,
+        #[arg(long = "type", default_value = "A")]
+        record_type: String,
+        #[command(flatten)]
+        fields: RecordFields,
+        /// Seconds the record lives without an update, renewed by each one
+        #[arg(long)]
+        lease: Option<u64>,
+        /// Value to park the record on when the lease runs out, instead of deleting it
+        #[arg(long, requires = "lease")]
+        fallback: Option<String>,
+    },
+    /// Change a record's value or other fields
+    Update {
+        /// Record ID or name
+        record: String,
+        /// Record type, to pick between records sharing a name
+        #[arg(long = "type")]
+        record_type: Option<String>,
+        #[command(flatten)]
+        fields: RecordFields,
+    },
+    /// Delete a record
+    Delete {
+        /// Record ID or name
+        record: String,
+        /// Record type, to pick between records sharing a name
+        #[arg(long = "type")]
+        record_type: Option<String>,
+    },
+    /// Show recent changes
+    History {
+        /// Record ID or name, also matches deleted records
+        record: Option<String>,
+        #[arg(long = "type")]
+        record_type: Option<String>,
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+}
+
+#[derive(Args, Default)]
+pub struct RecordFields {
+    /// IP, target host, text or CAA value. A and AAAA records default to the caller's IP.
+    #[arg(long, visible_alias = "ip")]
+    pub value: Option<String>,
+    /// MX and SRV priority
+    #[arg(long)]
+    pub priority: Option<u16>,
+    /// SRV weight
+    #[arg(long)]
+    pub weight: Option<u16>,
+    /// SRV port
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// CAA flags
+    #[arg(long)]
+    pub flags: Option<u8>,
+    /// CAA tag
+    #[arg(long)]
+    pub tag: Option<String>,
+    /// Seconds, or 1 for automatic
+    #[arg(long)]
+    pub ttl: Option<i64>,
+    #[arg(long)]
+    pub proxied: Option<bool>,
+    /// An empty comment removes it
+    #[arg(long)]
+    pub comment: Option<String>,
+}
+
+impl RecordFields {
+    fn params(self) -> Vec<(&'static str, String)> {
+        let mut params = vec![];
+        let mut push = |key: &'static str, value: Option<String>| {
+            if let Some(value) = value {
+                params.push((key, value));
+            }
+        };
+        push("value", self.value);
+        push("priority", self.priority.map(|v| v.to_string()));
+        push("weight", self.weight.map(|v| v.to_string()));
+        push("port", self.port.map(|v| v.to_string()));
+        push("flags", self.flags.map(|v| v.to_string()));
+        push("tag", self.tag);
+        push("ttl", self.ttl.map(|v| v.to_string()));
+        push("proxied", self.proxied.map(|v| v.to_string()));
+        push("comment", self.comment);
+        params
+    }
+}
+
+pub async fn run(args: RecordsArgs) -> Result<(), DynIpError> {
+    let connection = args.connection;
+    let config = ClientConfig {
+        server: connection.server,
+        username: connection.username,
+        password: connection.password,
+        ca_cert: connection.ca_cert,
+        client_cert: connection.client_cert,
+        client_key: connection.client_key,
+    }
+    .or(ClientConfig::load(connection.config.as_deref())?);
+    let client = ApiClient::new(&config)?;
+    let json = connection.json;
+
+    match args.command {
+        RecordsCommand::List { name, record_type } => {
+            let name = name.map(|n| n.to_lowercase());
+            let records: Vec<DisplayRecord> = client
+                .list()
+                .await?
+                .into_iter()
+                .filter(|r| {
+                    name.as_ref()
+                        .is_none_or(|n| r.domain.to_lowercase().contains(n))
+                })
+                .filter(|r| {
+                    record_type
+                        .as_ref()
+                        .is_none_or(|t| r.record_type.eq_ignore_ascii_case(t))
+                })
+                .collect();
+            if json {
+                return print_json(&records);
+            }
+            print_records(&records);
+        }
+        RecordsCommand::Add {
+            name,
+            record_type,
+            fields,
+            lease,
+            fallback,
+        } => {
+            let record_type = record_type.to_uppercase();
+            let mut params = fields.params();
+            // The server takes A and AAAA addresses as ip and CNAME targets as host
+            for param in params.iter_mut().filter(|(k, _)| *k == "value") {
+                match record_type.as_str() {
+                    "A" | "AAAA" => param.0 = "ip",
+                    "CNAME" => param.0 = "host",
+                    _ => {}
+                }
+            }
+            params.push(("domain", name));
+            params.push(("record_type", record_type));
+            params.extend(lease.map(|l| ("lease", l.to_string())));
+            params.extend(fallback.map(|f| ("fallback", f)));
+            let record = client.add(&params).await?;
+            if json {
+                return print_json(&record);
+            }
+            print_records(&[record]);
+        }
+        RecordsCommand::Update {
+            record,
+            record_type,
+            fields,
+        } => {
+            let params = fields.params();
+            if params.is_empty() {
+                return Err(DynIpError::Client(
+                    "Nothing to update, pass --value or another field".to_string(),
+                ));
+            }
+            let record = client.resolve(&record, record_type.as_deref()).await?;
+            let record = client.edit(&record.id, &params).await?;
+            if json {
+                return print_json(&record);
+            }
+            print_records(&[record]);
+        }
+        RecordsCommand::Delete {
+            record,
+            record_type,
+        } => {
+            let record = client.resolve(&record, record_type.as_deref()).await?;
+            client.delete(&record.id).await?;
+            if json {
+                return print_json(&record);
+            }
+            println!("Deleted {} {}", record.domain, record.record_type);
+        }
+        RecordsCommand::History {
+            record,
+            record_type,
+            limit,
+        } => {
+            let mut params = vec![("limit", limit.to_string())];
+            if let Some(record) = record {
+                // IDs are md5 hex, anything else is a name
+                let is_id = record.len() == 32 && record.chars().all(|c| c.is_ascii_hexdigit());
+                params.push((if is_id { "id" } else { "domain" }, record));
+            }
+            if let Some(record_type) = record_type {
+                params.push(("record_type", record_type));
+            }
+            let changes = client.history(&params).await?;
+            if json {
+                return print_json(&changes);
+            }
+            let rows: Vec<Vec<String>> = changes
+                .iter()
+                .map(|c| {
+                    vec![
+                        table::format_timestamp(c.at),
+                        c.action.as_str().to_string(),
+                        c.domain.clone(),
+                        c.record_type.clone(),
+                        c.old_value.clone().unwrap_or_default(),
+                        c.new_value.clone().unwrap_or_default(),
+                        c.client.clone().unwrap_or_default(),
+                    ]
+                })
+                .collect();
+            table::print(
+                &[
+                    "TIME (UTC)",
+                    "ACTION",
+                    "NAME",
+                    "TYPE",
+                    "OLD",
+                    "NEW",
+                    "CLIENT",
+                ],
+                &rows,
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<(), DynIpError> {
+    let json =
+        serde_json::to_string_pretty(value).map_err(|e| DynIpError::Client(e.to_string()))?;
+    println!("{}", json);
+    Ok(())
+}
+
+fn print_records(records: &[DisplayRecord]) {
+    let rows: Vec<Vec<String>> = records
+        .iter()
+        .map(|r| {
+            vec![
+                r.id.clone(),
+                r.domain.clone(),
+                r.record_type.clone(),
+                describe_value(r),
+                match r.ttl {
+                    AUTO_TTL => "auto".to_string(),
+                    ttl => ttl.to_string(),
+                },
+                if r.proxied { "yes" } else { "no" }.to_string(),
+                r.comment.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    table::print(
+        &["ID", "NAME", "TYPE", "VALUE", "TTL", "PROXIED", "COMMENT"],
+        &rows,
+    );
+}
+
+/// The value as it would appear in a zone file, e.g. `10 mail.example.com` for MX.
+fn describe_value(record: &DisplayRecord) -> String {
+    let number = |n: Option<u16>| n.map(|n| n.to_string()).unwrap_or_default();
+    match record.record_type.as_str() {
+        "MX" => format!("{} {}", number(record.priority), record.ip),
+        "SRV" => format!(
+            "{} {} {} {}",
+            number(record.priority),
+            number(record.weight),
+            number(record.port),
+            record.ip
+        ),
+        "CAA" => format!(
+            "{} {} \"{}\"",
+            record.flags.unwrap_or_default(),
+            record.tag.clone().unwrap_or_default(),
+            record.ip
+        ),
+        _ => record.ip.clone(),
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::server::policy::RecordSettings;
+
+/// The TOML config file. Every field is optional here, required settings are
+/// checked once env vars and flags have been layered on top.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub cloudflare: CloudflareSection,
+    /// Further zones served alongside `[cloudflare]`
+    pub zones: Vec<ZoneSection>,
+    pub server: ServerSection,
+    pub auth: AuthSection,
+    pub tls: TlsSection,
+    pub mtls: MtlsSection,
+    pub policy: PolicySection,
+    /// Keyed by full domain name
+    pub records: BTreeMap<String, RecordSettings>,
+    pub webhooks: Vec<WebhookSection>,
+    pub notifiers: Vec<NotifierSection>,
+    pub mqtt: MqttSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CloudflareSection {
+    /// "token" (default) or "global-key"
+    pub auth: Option<String>,
+    pub zone_id: Option<String>,
+    pub api_key: Option<String>,
+    pub api_key_file: Option<PathBuf>,
+    pub email: Option<String>,
+    pub domain_name: Option<String>,
+    /// The v4 API base URL, Cloudflare's own by default
+    pub api_url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ZoneSection {
+    /// Only "cloudflare" for now
+    pub provider: Option<String>,
+    pub auth: Option<String>,
+    pub zone_id: Option<String>,
+    pub api_key: Option<String>,
+    pub api_key_file: Option<PathBuf>,
+    pub email: Option<String>,
+    pub domain_name: Option<String>,
+    /// The v4 API base URL, Cloudflare's own by default
+    pub api_url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSection {
+    /// Defaults to the URL
+    pub name: Option<String>,
+    pub url: Option<String>,
+    pub secret: Option<String>,
+    pub secret_file: Option<PathBuf>,
+    /// create, update, edit, delete and stale
+    pub events: Option<Vec<String>>,
+    pub domains: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifierSection {
+    pub name: Option<String>,
+    /// ntfy, gotify, matrix, http or smtp
+    pub kind: Option<String>,
+    pub url: Option<String>,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    /// Matrix room ID or alias
+    pub room: Option<String>,
+    /// http only
+    pub method: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+    pub content_type: Option<String>,
+    pub body: Option<String>,
+    /// smtp only
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub from: Option<String>,
+    pub to: Option<Vec<String>>,
+    /// Templates, see `notify::PLACEHOLDERS`
+    pub title: Option<String>,
+    pub message: Option<String>,
+    pub events: Option<Vec<String>>,
+    pub domains: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSection {
+    /// mqtt://host[:port] or mqtts://host[:port]
+    pub url: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub client_id: Option<String>,
+    pub topic_prefix: Option<String>,
+    pub discovery_prefix: Option<String>,
+    /// Accept updates on <topic_prefix>/<domain>/<type>/set
+    pub commands: Option<bool>,
+    /// Seconds without an update before a record is stale
+    pub stale_after: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub listen: Option<Listen>,
+    pub salt: Option<String>,
+    pub salt_file: Option<PathBuf>,
+    pub trust_proxy_headers: Option<bool>,
+    pub data_dir: Option<PathBuf>,
+}
+
+/// `listen = "0.0.0.0:8080"` or `listen = ["0.0.0.0:8080", "[::]:8080"]`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Listen {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Listen {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Listen::One(listen) => vec![listen],
+            Listen::Many(listen) => listen,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    pub listen: Option<String>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub acme: AcmeSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcmeSection {
+    pub domain: Option<String>,
+    pub email: Option<String>,
+    pub challenge: Option<String>,
+    pub directory: Option<String>,
+    pub ca_cert: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MtlsSection {
+    pub listen: Option<String>,
+    pub ca: Option<PathBuf>,
+    /// Certificate name to the extra domains it may update
+    pub identities: Option<BTreeMap<String, Vec<String>>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicySection {
+    pub public_ips_only: Option<bool>,
+    pub record_types: Option<Vec<String>>,
+    /// Seconds, for records without their own
+    pub expected_interval: Option<u64>,
+}
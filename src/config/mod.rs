@@ -0,0 +1,1288 @@
+//! Server settings. A TOML file given with `--config` is read first, then
+//! environment variables and command line flags override it, in that order.
+
+mod file;
+pub mod reload;
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use addr::{parse_dns_name, parse_domain_name};
+use clap::{Args, Parser};
+
+pub use file::FileConfig;
+use file::Listen;
+
+use crate::aws::cloudflare::{Auth as CloudflareAuth, AuthMode, CLOUDFLARE_API_URL};
+use crate::aws::record::{Record, RrType};
+use crate::notify::{self, Backend, Notifier, SmtpConfig};
+use crate::server::api::ApiConfig;
+use crate::server::auth::Auth;
+use crate::server::client_cert::IdentityMap;
+use crate::server::history::{Action, ChangeFilter};
+use crate::server::mqtt::{self, MqttConfig};
+use crate::server::policy::Policy;
+use crate::server::webhooks::Webhook;
+use crate::tls::acme::{AcmeConfig, ChallengeType, LETS_ENCRYPT_DIRECTORY};
+use crate::tls::{MtlsConfig, TlsConfig};
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:8080";
+const DEFAULT_DATA_DIR: &str = "data";
+const DEFAULT_MQTT_CLIENT_ID: &str = "dyn-ip";
+const DEFAULT_MQTT_TOPIC_PREFIX: &str = "dyn-ip";
+const DEFAULT_MQTT_DISCOVERY_PREFIX: &str = "homeassistant";
+const DEFAULT_MQTT_STALE_AFTER: u64 = 3600;
+
+#[derive(Args, Clone, Default)]
+pub struct ServeArgs {
+    /// TOML config file, env vars and flags override its values
+    #[arg(long, env = "DYN_IP_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Cloudflare zone ID
+    #[arg(long, env = "CLOUDFLARE_ZONE_ID")]
+    pub zone_id: Option<String>,
+    /// Cloudflare API token, or Global API Key
+    #[arg(long, env = "CLOUDFLARE_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+    /// File holding the API token, e.g. a Docker or Kubernetes secret
+    #[arg(long, env = "CLOUDFLARE_API_KEY_FILE")]
+    pub api_key_file: Option<String>,
+    /// Cloudflare credential kind: token, or global-key with --email
+    #[arg(long, env = "CLOUDFLARE_AUTH")]
+    pub cloudflare_auth: Option<String>,
+    /// Account email, for a Global API Key
+    #[arg(long, env = "CLOUDFLARE_EMAIL")]
+    pub email: Option<String>,
+    /// Apex domain of the zone
+    #[arg(long, env = "DOMAIN_NAME")]
+    pub domain_name: Option<String>,
+    /// Cloudflare API base URL, for a proxy or a test double
+    #[arg(long, env = "CLOUDFLARE_API_URL")]
+    pub cloudflare_api_url: Option<String>,
+    /// HTTP listen addresses, comma separated
+    #[arg(long, env = "LISTEN", value_delimiter = ',')]
+    pub listen: Vec<String>,
+    /// Salt for the record IDs
+    #[arg(long, env = "SALT", hide_env_values = true)]
+    pub salt: Option<String>,
+    #[arg(long, env = "SALT_FILE")]
+    pub salt_file: Option<String>,
+    /// Take the client IP from X-Real-IP / X-Forwarded-For, defaults to true without TLS
+    #[arg(long, env = "TRUST_PROXY_HEADERS")]
+    pub trust_proxy_headers: Option<String>,
+    /// ACME state and change history
+    #[arg(long, env = "DATA_DIR")]
+    pub data_dir: Option<String>,
+    #[arg(long, env = "BASIC_AUTH_USERNAME")]
+    pub username: Option<String>,
+    #[arg(long, env = "BASIC_AUTH_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+    #[arg(long, env = "BASIC_AUTH_PASSWORD_FILE")]
+    pub password_file: Option<String>,
+    #[arg(long, env = "TLS_LISTEN")]
+    pub tls_listen: Option<String>,
+    #[arg(long, env = "TLS_CERT")]
+    pub tls_cert: Option<String>,
+    #[arg(long, env = "TLS_KEY")]
+    pub tls_key: Option<String>,
+    #[arg(long, env = "ACME_DOMAIN")]
+    pub acme_domain: Option<String>,
+    #[arg(long, env = "ACME_EMAIL")]
+    pub acme_email: Option<String>,
+    /// http-01 or dns-01
+    #[arg(long, env = "ACME_CHALLENGE")]
+    pub acme_challenge: Option<String>,
+    #[arg(long, env = "ACME_DIRECTORY")]
+    pub acme_directory: Option<String>,
+    #[arg(long, env = "ACME_CA_CERT")]
+    pub acme_ca_cert: Option<String>,
+    #[arg(long, env = "MTLS_LISTEN")]
+    pub mtls_listen: Option<String>,
+    #[arg(long, env = "MTLS_CA")]
+    pub mtls_ca: Option<String>,
+    /// name=domain|domain,name=domain
+    #[arg(long, env = "MTLS_IDENTITY_MAP")]
+    pub mtls_identity_map: Option<String>,
+    /// Refuse private, loopback and CGNAT addresses
+    #[arg(long, env = "POLICY_PUBLIC_IPS_ONLY")]
+    pub public_ips_only: Option<String>,
+    /// Record types that may be created, comma separated
+    #[arg(long, env = "POLICY_RECORD_TYPES", value_delimiter = ',')]
+    pub record_types: Vec<String>,
+    /// Seconds between client updates, past which a record is stale
+    #[arg(long, env = "POLICY_EXPECTED_INTERVAL")]
+    pub expected_interval: Option<String>,
+    /// MQTT broker to publish records to, mqtt:// or mqtts://
+    #[arg(long, env = "MQTT_URL")]
+    pub mqtt_url: Option<String>,
+    #[arg(long, env = "MQTT_USERNAME")]
+    pub mqtt_username: Option<String>,
+    #[arg(long, env = "MQTT_PASSWORD", hide_env_values = true)]
+    pub mqtt_password: Option<String>,
+    #[arg(long, env = "MQTT_PASSWORD_FILE")]
+    pub mqtt_password_file: Option<String>,
+    #[arg(long, env = "MQTT_CLIENT_ID")]
+    pub mqtt_client_id: Option<String>,
+    #[arg(long, env = "MQTT_TOPIC_PREFIX")]
+    pub mqtt_topic_prefix: Option<String>,
+    /// Home Assistant discovery prefix
+    #[arg(long, env = "MQTT_DISCOVERY_PREFIX")]
+    pub mqtt_discovery_prefix: Option<String>,
+    /// Accept record updates over MQTT
+    #[arg(long, env = "MQTT_COMMANDS")]
+    pub mqtt_commands: Option<String>,
+    /// Seconds without an update before a record is stale
+    #[arg(long, env = "MQTT_STALE_AFTER")]
+    pub mqtt_stale_after: Option<String>,
+}
+
+impl ServeArgs {
+    /// Args for a bare `dyn-ip`, which serves with env vars still applied.
+    pub fn from_env() -> ServeArgs {
+        #[derive(Parser)]
+        struct Bare {
+            #[command(flatten)]
+            args: ServeArgs,
+        }
+        Bare::parse_from(["dyn-ip"]).args
+    }
+}
+
+/// Everything `serve` needs, validated.
+pub struct Settings {
+    pub zones: Vec<ZoneSettings>,
+    pub listen: Vec<SocketAddr>,
+    pub data_dir: PathBuf,
+    pub tls: Option<TlsConfig>,
+    pub api: ApiConfig,
+    /// Settings that are valid but probably not what was meant
+    pub warnings: Vec<String>,
+    /// The config file and secret files the settings were read from
+    pub files: Vec<PathBuf>,
+    pub mqtt: Option<MqttConfig>,
+}
+
+/// One Cloudflare zone and the credentials for it.
+#[derive(Clone, PartialEq)]
+pub struct ZoneSettings {
+    /// The v4 API base, without a trailing slash
+    pub api_url: String,
+    /// Looked up from `domain_name` at startup when unset
+    pub zone_id: Option<String>,
+    pub auth: CloudflareAuth,
+    /// The zone's apex
+    pub domain_name: String,
+}
+
+/// A setting's name in each layer, so errors can point at the one that was used.
+#[derive(Clone, Copy)]
+struct Key {
+    path: &'static str,
+    env: &'static str,
+    flag: &'static str,
+}
+
+const fn key(path: &'static str, env: &'static str, flag: &'static str) -> Key {
+    Key { path, env, flag }
+}
+
+const ZONE_ID: Key = key("cloudflare.zone_id", "CLOUDFLARE_ZONE_ID", "--zone-id");
+const API_KEY: Key = key("cloudflare.api_key", "CLOUDFLARE_API_KEY", "--api-key");
+const API_KEY_FILE: Key = key(
+    "cloudflare.api_key_file",
+    "CLOUDFLARE_API_KEY_FILE",
+    "--api-key-file",
+);
+const CLOUDFLARE_AUTH: Key = key("cloudflare.auth", "CLOUDFLARE_AUTH", "--cloudflare-auth");
+const EMAIL: Key = key("cloudflare.email", "CLOUDFLARE_EMAIL", "--email");
+const DOMAIN_NAME: Key = key("cloudflare.domain_name", "DOMAIN_NAME", "--domain-name");
+const API_URL: Key = key(
+    "cloudflare.api_url",
+    "CLOUDFLARE_API_URL",
+    "--cloudflare-api-url",
+);
+const LISTEN: Key = key("server.listen", "LISTEN", "--listen");
+const SALT: Key = key("server.salt", "SALT", "--salt");
+const SALT_FILE: Key = key("server.salt_file", "SALT_FILE", "--salt-file");
+const TRUST_PROXY_HEADERS: Key = key(
+    "server.trust_proxy_headers",
+    "TRUST_PROXY_HEADERS",
+    "--trust-proxy-headers",
+);
+const DATA_DIR: Key = key("server.data_dir", "DATA_DIR", "--data-dir");
+const USERNAME: Key = key("auth.username", "BASIC_AUTH_USERNAME", "--username");
+const PASSWORD: Key = key("auth.password", "BASIC_AUTH_PASSWORD", "--password");
+const PASSWORD_FILE: Key = key(
+    "auth.password_file",
+    "BASIC_AUTH_PASSWORD_FILE",
+    "--password-file",
+);
+const ACME_DOMAIN: Key = key("tls.acme.domain", "ACME_DOMAIN", "--acme-domain");
+const ACME_CHALLENGE: Key = key("tls.acme.challenge", "ACME_CHALLENGE", "--acme-challenge");
+const ACME_DIRECTORY: Key = key("tls.acme.directory", "ACME_DIRECTORY", "--acme-directory");
+const ACME_EMAIL: Key = key("tls.acme.email", "ACME_EMAIL", "--acme-email");
+const ACME_CA_CERT: Key = key("tls.acme.ca_cert", "ACME_CA_CERT", "--acme-ca-cert");
+const MTLS_LISTEN: Key = key("mtls.listen", "MTLS_LISTEN", "--mtls-listen");
+const MTLS_CA: Key = key("mtls.ca", "MTLS_CA", "--mtls-ca");
+const MTLS_IDENTITY_MAP: Key = key(
+    "mtls.identities",
+    "MTLS_IDENTITY_MAP",
+    "--mtls-identity-map",
+);
+const TLS_LISTEN: Key = key("tls.listen", "TLS_LISTEN", "--tls-listen");
+const TLS_CERT: Key = key("tls.cert", "TLS_CERT", "--tls-cert");
+const TLS_KEY: Key = key("tls.key", "TLS_KEY", "--tls-key");
+const PUBLIC_IPS_ONLY: Key = key(
+    "policy.public_ips_only",
+    "POLICY_PUBLIC_IPS_ONLY",
+    "--public-ips-only",
+);
+const RECORD_TYPES: Key = key(
+    "policy.record_types",
+    "POLICY_RECORD_TYPES",
+    "--record-types",
+);
+const EXPECTED_INTERVAL: Key = key(
+    "policy.expected_interval",
+    "POLICY_EXPECTED_INTERVAL",
+    "--expected-interval",
+);
+const MQTT_URL: Key = key("mqtt.url", "MQTT_URL", "--mqtt-url");
+const MQTT_USERNAME: Key = key("mqtt.username", "MQTT_USERNAME", "--mqtt-username");
+const MQTT_PASSWORD: Key = key("mqtt.password", "MQTT_PASSWORD", "--mqtt-password");
+const MQTT_PASSWORD_FILE: Key = key(
+    "mqtt.password_file",
+    "MQTT_PASSWORD_FILE",
+    "--mqtt-password-file",
+);
+const MQTT_CLIENT_ID: Key = key("mqtt.client_id", "MQTT_CLIENT_ID", "--mqtt-client-id");
+const MQTT_TOPIC_PREFIX: Key = key(
+    "mqtt.topic_prefix",
+    "MQTT_TOPIC_PREFIX",
+    "--mqtt-topic-prefix",
+);
+const MQTT_DISCOVERY_PREFIX: Key = key(
+    "mqtt.discovery_prefix",
+    "MQTT_DISCOVERY_PREFIX",
+    "--mqtt-discovery-prefix",
+);
+const MQTT_COMMANDS: Key = key("mqtt.commands", "MQTT_COMMANDS", "--mqtt-commands");
+const MQTT_STALE_AFTER: Key = key("mqtt.stale_after", "MQTT_STALE_AFTER", "--mqtt-stale-after");
+
+/// Ordered lowest precedence first.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Origin {
+    File,
+    Env,
+    Flag,
+}
+
+struct Resolver {
+    path: Option<PathBuf>,
+    errors: Vec<String>,
+    /// Secret files read, for the reloader to watch
+    files: Vec<PathBuf>,
+}
+
+impl Resolver {
+    /// The flag or env var when set, otherwise the file's value. Empty values
+    /// count as unset, as the samples leave most of them blank.
+    fn get(&self, key: Key, arg: Option<String>, file: Option<String>) -> Option<(String, Origin)> {
+        match arg.filter(|a| !a.is_empty()) {
+            Some(arg) => {
+                let origin = if std::env::var(key.env).is_ok_and(|v| v == arg) {
+                    Origin::Env
+                } else {
+                    Origin::Flag
+                };
+                Some((arg, origin))
+            }
+            None => file.filter(|f| !f.is_empty()).map(|v| (v, Origin::File)),
+        }
+    }
+
+    /// `get` for comma separated values.
+    fn list(
+        &self,
+        key: Key,
+        arg: Vec<String>,
+        file: Option<Vec<String>>,
+    ) -> Option<(Vec<String>, Origin)> {
+        let arg: Vec<_> = arg.into_iter().filter(|a| !a.trim().is_empty()).collect();
+        if arg.is_empty() {
+            return file.map(|v| (v, Origin::File));
+        }
+        let origin = if std::env::var(key.env).is_ok() {
+            Origin::Env
+        } else {
+            Origin::Flag
+        };
+        Some((arg, origin))
+    }
+
+    /// A secret given directly or as a file holding it, whichever comes from
+    /// the higher layer. Files are read on every load, so reloads see rotations.
+    fn secret(
+        &mut self,
+        (key, file_key): (Key, Key),
+        (arg, arg_file): (Option<String>, Option<String>),
+        (file, file_file): (Option<String>, Option<String>),
+    ) -> Option<String> {
+        let value = self.get(key, arg, file);
+        let path = self.get(file_key, arg_file, file_file);
+        let read = match (value, path) {
+            (Some((value, origin)), Some((path, path_origin))) => {
+                if origin == path_origin {
+                    self.error(
+                        file_key,
+                        path_origin,
+                        format!("conflicts with {}, set only one", key.path),
+                    );
+                    return None;
+                }
+                if origin > path_origin {
+                    return Some(value);
+                }
+                (path, path_origin)
+            }
+            (Some((value, _)), None) => return Some(value),
+            (None, Some(path)) => path,
+            (None, None) => return None,
+        };
+        let (path, origin) = read;
+        match self.read_secret(&path) {
+            Ok(secret) => Some(secret),
+            Err(e) => {
+                self.error(file_key, origin, e);
+                None
+            }
+        }
+    }
+
+    fn read_secret(&mut self, path: &str) -> Result<String, String> {
+        self.files.push(PathBuf::from(path));
+        match std::fs::read_to_string(path) {
+            Ok(contents) if !contents.trim().is_empty() => Ok(contents.trim().to_string()),
+            Ok(_) => Err(format!("{} is empty", path)),
+            Err(e) => Err(format!("{}: {}", path, e)),
+        }
+    }
+
+    /// An error for a key only the config file has, e.g. inside `[[zones]]`.
+    fn file_error(&mut self, path: String, message: impl fmt::Display) {
+        let location = match &self.path {
+            Some(file) => format!("{} in {}", path, file.display()),
+            None => path,
+        };
+        self.errors.push(format!("{}: {}", location, message));
+    }
+
+    /// A secret only the config file has, given directly or as a file.
+    fn file_secret(
+        &mut self,
+        (key, file_key): (String, String),
+        value: Option<String>,
+        file: Option<PathBuf>,
+    ) -> Option<String> {
+        match (value.filter(|v| !v.is_empty()), path_string(file)) {
+            (Some(_), Some(_)) => {
+                self.file_error(file_key, format!("conflicts with {}, set only one", key));
+                None
+            }
+            (Some(value), None) => Some(value),
+            (None, Some(path)) => match self.read_secret(&path) {
+                Ok(secret) => Some(secret),
+                Err(e) => {
+                    self.file_error(file_key, e);
+                    None
+                }
+            },
+            (None, None) => None,
+        }
+    }
+
+    fn string(&self, key: Key, arg: Option<String>, file: Option<String>) -> Option<String> {
+        self.get(key, arg, file).map(|(v, _)| v)
+    }
+
+    fn parse<T>(
+        &mut self,
+        key: Key,
+        arg: Option<String>,
+        file: Option<String>,
+        parse: impl FnOnce(&str) -> Result<T, String>,
+    ) -> Option<T> {
+        let (value, origin) = self.get(key, arg, file)?;
+        match parse(&value) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.error(key, origin, e);
+                None
+            }
+        }
+    }
+
+    fn required(&mut self, key: Key, value: Option<String>) -> String {
+        value.unwrap_or_else(|| {
+            self.errors.push(format!(
+                "{} is required (or {} / {})",
+                key.path, key.env, key.flag
+            ));
+            String::new()
+        })
+    }
+
+    fn error(&mut self, key: Key, origin: Origin, message: impl fmt::Display) {
+        let location = match (origin, &self.path) {
+            (Origin::File, Some(path)) => format!("{} in {}", key.path, path.display()),
+            (Origin::File, None) => key.path.to_string(),
+            (Origin::Env, _) => key.env.to_string(),
+            (Origin::Flag, _) => key.flag.to_string(),
+        };
+        self.errors.push(format!("{}: {}", location, message));
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => Err(format!("expected true or false, got {:?}", value)),
+    }
+}
+
+/// A topic prefix, without wildcards or a trailing `/`.
+fn parse_topic(value: &str) -> Result<String, String> {
+    let topic = value.trim().trim_end_matches('/');
+    if topic.is_empty() || topic.contains(['+', '#']) {
+        return Err(format!("{:?} is not a topic prefix", value));
+    }
+    Ok(topic.to_string())
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    match value.trim().parse::<u64>() {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("expected a number of seconds, got {:?}", value)),
+    }
+}
+
+fn parse_listen(value: &str) -> Result<SocketAddr, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|e| format!("{:?} is not an address: {}", value, e))
+}
+
+/// A subscriber's `events` and `domains`.
+fn change_filter(
+    r: &mut Resolver,
+    events_key: &str,
+    events: Option<Vec<String>>,
+    domains: Option<Vec<String>>,
+) -> ChangeFilter {
+    let mut filter = ChangeFilter::default();
+    for event in events.unwrap_or_default() {
+        match event.parse::<Action>() {
+            Ok(action) => filter.events.push(action),
+            Err(e) => r.file_error(events_key.to_string(), e),
+        }
+    }
+    filter.domains = domains
+        .unwrap_or_default()
+        .into_iter()
+        .map(|d| d.trim_end_matches('.').to_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect();
+    filter
+}
+
+fn parse_api_url(value: &str) -> Result<String, String> {
+    match reqwest::Url::parse(value) {
+        Ok(url) if ["http", "https"].contains(&url.scheme()) => {
+            Ok(value.trim_end_matches('/').to_string())
+        }
+        Ok(_) => Err("must be http or https".to_string()),
+        Err(e) => Err(format!("{:?} is not a URL: {}", value, e)),
+    }
+}
+
+fn path_string(path: Option<PathBuf>) -> Option<String> {
+    path.map(|p| p.to_string_lossy().into_owned())
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<FileConfig, String> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+impl Settings {
+    /// Layers the config file, env vars and flags, collecting every problem
+    /// rather than stopping at the first.
+    pub fn load(args: ServeArgs) -> Result<Settings, Vec<String>> {
+        let file = match &args.config {
+            Some(path) => FileConfig::load(path).map_err(|e| vec![e])?,
+            None => FileConfig::default(),
+        };
+        let mut r = Resolver {
+            path: args.config.clone(),
+            errors: Vec::new(),
+            files: args.config.iter().cloned().collect(),
+        };
+        let mut warnings = Vec::new();
+
+        // [cloudflare] and its env vars are the zone for single-zone setups,
+        // [[zones]] adds more
+        let cloudflare = &file.cloudflare;
+        let primary = [
+            &args.zone_id,
+            &args.api_key,
+            &args.api_key_file,
+            &args.cloudflare_auth,
+            &args.email,
+            &args.domain_name,
+        ]
+        .iter()
+        .any(|v| v.as_ref().is_some_and(|v| !v.is_empty()))
+            || cloudflare.zone_id.is_some()
+            || cloudflare.api_key.is_some()
+            || cloudflare.api_key_file.is_some()
+            || cloudflare.auth.is_some()
+            || cloudflare.email.is_some()
+            || cloudflare.domain_name.is_some();
+        // Also the default for [[zones]]
+        let api_url = r
+            .parse(
+                API_URL,
+                args.cloudflare_api_url,
+                file.cloudflare.api_url,
+                parse_api_url,
+            )
+            .unwrap_or_else(|| CLOUDFLARE_API_URL.to_string());
+        let mut zones = Vec::new();
+        if primary || file.zones.is_empty() {
+            let zone_id = r.string(ZONE_ID, args.zone_id, file.cloudflare.zone_id);
+            let api_key = r.secret(
+                (API_KEY, API_KEY_FILE),
+                (args.api_key, args.api_key_file),
+                (
+                    file.cloudflare.api_key,
+                    path_string(file.cloudflare.api_key_file),
+                ),
+            );
+            let api_key = r.required(API_KEY, api_key);
+            let mode = r.parse(
+                CLOUDFLARE_AUTH,
+                args.cloudflare_auth,
+                file.cloudflare.auth,
+                |v| v.parse::<AuthMode>(),
+            );
+            let email = r.string(EMAIL, args.email, file.cloudflare.email);
+            let auth = match (mode.unwrap_or(AuthMode::Token), email) {
+                (AuthMode::GlobalKey, email) => CloudflareAuth::GlobalKey {
+                    email: r.required(EMAIL, email),
+                    key: api_key,
+                },
+                (AuthMode::Token, Some(_)) => {
+                    warnings.push(format!(
+                        "{} is only used with {} = \"global-key\"",
+                        EMAIL.path, CLOUDFLARE_AUTH.path
+                    ));
+                    CloudflareAuth::Token(api_key)
+                }
+                (AuthMode::Token, None) => CloudflareAuth::Token(api_key),
+            };
+            let domain_name =
+                match r.get(DOMAIN_NAME, args.domain_name, file.cloudflare.domain_name) {
+                    Some((value, origin)) => match parse_domain_name(&value) {
+                        Ok(domain) => domain.to_string(),
+                        Err(e) => {
+                            r.error(DOMAIN_NAME, origin, e);
+                            String::new()
+                        }
+                    },
+                    None => r.required(DOMAIN_NAME, None),
+                };
+            zones.push(ZoneSettings {
+                api_url: api_url.clone(),
+                zone_id,
+                auth,
+                domain_name,
+            });
+        }
+        for (i, zone) in file.zones.into_iter().enumerate() {
+            let key = |name: &str| format!("zones[{}].{}", i, name);
+            if let Some(provider) = zone.provider.filter(|p| p != "cloudflare") {
+                r.file_error(
+                    key("provider"),
+                    format!("{:?} isn't supported, only \"cloudflare\"", provider),
+                );
+            }
+            let zone_id = zone.zone_id.filter(|v| !v.is_empty());
+            let email = zone.email.filter(|v| !v.is_empty());
+            let domain_name = zone.domain_name.unwrap_or_default();
+            if domain_name.is_empty() {
+                r.file_error(key("domain_name"), "is required");
+            }
+            let api_key = match (zone.api_key, path_string(zone.api_key_file)) {
+                (Some(_), Some(_)) => {
+                    r.file_error(key("api_key_file"), "conflicts with api_key, set only one");
+                    String::new()
+                }
+                (Some(api_key), None) => api_key,
+                (None, Some(path)) => r.read_secret(&path).unwrap_or_else(|e| {
+                    r.file_error(key("api_key_file"), e);
+                    String::new()
+                }),
+                (None, None) => {
+                    r.file_error(key("api_key"), "is required");
+                    String::new()
+                }
+            };
+            let domain_name = match parse_domain_name(&domain_name) {
+                Ok(domain) => domain.to_string(),
+                Err(_) if domain_name.is_empty() => domain_name,
+                Err(e) => {
+                    r.file_error(key("domain_name"), e);
+                    String::new()
+                }
+            };
+            let mode = match zone.auth.as_deref().map(str::parse::<AuthMode>) {
+                Some(Err(e)) => {
+                    r.file_error(key("auth"), e);
+                    AuthMode::Token
+                }
+                Some(Ok(mode)) => mode,
+                None => AuthMode::Token,
+            };
+            let auth = match (mode, email) {
+                (AuthMode::GlobalKey, Some(email)) => CloudflareAuth::GlobalKey {
+                    email,
+                    key: api_key,
+                },
+                (AuthMode::GlobalKey, None) => {
+                    r.file_error(key("email"), "is required with auth = \"global-key\"");
+                    CloudflareAuth::Token(api_key)
+                }
+                (AuthMode::Token, email) => {
+                    if email.is_some() {
+                        warnings.push(format!(
+                            "{} is only used with auth = \"global-key\"",
+                            key("email")
+                        ));
+                    }
+                    CloudflareAuth::Token(api_key)
+                }
+            };
+            let api_url = match zone.api_url.as_deref().map(parse_api_url) {
+                Some(Ok(url)) => url,
+                Some(Err(e)) => {
+                    r.file_error(key("api_url"), e);
+                    String::new()
+                }
+                None => api_url.clone(),
+            };
+            zones.push(ZoneSettings {
+                api_url,
+                zone_id,
+                auth,
+                domain_name,
+            });
+        }
+        let mut apexes: Vec<_> = zones.iter().map(|z| z.domain_name.as_str()).collect();
+        apexes.sort();
+        for pair in apexes.windows(2) {
+            if !pair[0].is_empty() && pair[0] == pair[1] {
+                r.errors
+                    .push(format!("zones: {} is configured more than once", pair[0]));
+            }
+        }
+
+        let listen_values = r.list(
+            LISTEN,
+            args.listen,
+            file.server.listen.map(Listen::into_vec),
+        );
+        let listen = match listen_values {
+            Some((values, origin)) => {
+                let mut listen = Vec::new();
+                let mut error = None;
+                for value in values.iter().filter(|v| !v.trim().is_empty()) {
+                    match parse_listen(value) {
+                        Ok(addr) => listen.push(addr),
+                        Err(e) => error = error.or(Some(e)),
+                    }
+                }
+                match error {
+                    Some(e) => r.error(LISTEN, origin, e),
+                    None if listen.is_empty() => {
+                        r.error(LISTEN, origin, "at least one address is required")
+                    }
+                    None => {}
+                }
+                listen
+            }
+            None => vec![DEFAULT_LISTEN.parse().expect("default listen address")],
+        };
+        let salt = r
+            .secret(
+                (SALT, SALT_FILE),
+                (args.salt, args.salt_file),
+                (file.server.salt, path_string(file.server.salt_file)),
+            )
+            .unwrap_or_default();
+        let trust_proxy_headers = r.parse(
+            TRUST_PROXY_HEADERS,
+            args.trust_proxy_headers,
+            file.server.trust_proxy_headers.map(|b| b.to_string()),
+            parse_bool,
+        );
+        let data_dir = PathBuf::from(
+            r.string(DATA_DIR, args.data_dir, path_string(file.server.data_dir))
+                .unwrap_or_else(|| DEFAULT_DATA_DIR.to_string()),
+        );
+
+        let username = r.string(USERNAME, args.username, file.auth.username);
+        let password = r.secret(
+            (PASSWORD, PASSWORD_FILE),
+            (args.password, args.password_file),
+            (file.auth.password, path_string(file.auth.password_file)),
+        );
+        if username.is_some() != password.is_some() {
+            warnings.push(
+                "auth.username and auth.password must both be set, basic auth is off".to_string(),
+            );
+        }
+
+        let acme_domain = r.string(ACME_DOMAIN, args.acme_domain, file.tls.acme.domain);
+        let challenge = r.parse(
+            ACME_CHALLENGE,
+            args.acme_challenge,
+            file.tls.acme.challenge,
+            |v| v.parse::<ChallengeType>(),
+        );
+        let acme = acme_domain.map(|domain| AcmeConfig {
+            directory_url: r
+                .string(ACME_DIRECTORY, args.acme_directory, file.tls.acme.directory)
+                .unwrap_or_else(|| LETS_ENCRYPT_DIRECTORY.to_string()),
+            email: r.string(ACME_EMAIL, args.acme_email, file.tls.acme.email),
+            challenge: challenge.unwrap_or(ChallengeType::Http01),
+            ca_cert: r
+                .string(
+                    ACME_CA_CERT,
+                    args.acme_ca_cert,
+                    path_string(file.tls.acme.ca_cert),
+                )
+                .map(PathBuf::from),
+            account_key_path: data_dir.join("acme").join("account.pk8"),
+            domain,
+        });
+
+        let mtls_listen = r.parse(
+            MTLS_LISTEN,
+            args.mtls_listen,
+            file.mtls.listen,
+            parse_listen,
+        );
+        let mtls_ca = r.string(MTLS_CA, args.mtls_ca, path_string(file.mtls.ca));
+        let mtls = mtls_listen.map(|listen| MtlsConfig {
+            listen,
+            ca_path: PathBuf::from(r.required(MTLS_CA, mtls_ca)),
+        });
+        let client_identities = match args.mtls_identity_map.filter(|m| !m.is_empty()) {
+            Some(map) => r
+                .parse(MTLS_IDENTITY_MAP, Some(map), None, IdentityMap::parse)
+                .unwrap_or_default(),
+            None => IdentityMap::new(file.mtls.identities.unwrap_or_default()),
+        };
+
+        let tls_listen = r.parse(TLS_LISTEN, args.tls_listen, file.tls.listen, parse_listen);
+        let tls_cert = r.string(TLS_CERT, args.tls_cert, path_string(file.tls.cert));
+        let tls_key = r.string(TLS_KEY, args.tls_key, path_string(file.tls.key));
+        let tls = match tls_listen {
+            Some(listen) => {
+                let acme_path = |ext: &str| {
+                    acme.as_ref()
+                        .map(|a| data_dir.join("acme").join(format!("{}.{}", a.domain, ext)))
+                };
+                let cert_path = tls_cert.map(PathBuf::from).or_else(|| acme_path("crt"));
+                let key_path = tls_key.map(PathBuf::from).or_else(|| acme_path("key"));
+                match (cert_path, key_path) {
+                    (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                        listen,
+                        cert_path,
+                        key_path,
+                        acme,
+                        mtls,
+                    }),
+                    (cert_path, _) => {
+                        let missing = if cert_path.is_none() {
+                            TLS_CERT
+                        } else {
+                            TLS_KEY
+                        };
+                        r.errors.push(format!(
+                            "{} (or {}) is required unless tls.acme.domain is set",
+                            missing.path, missing.env
+                        ));
+                        None
+                    }
+                }
+            }
+            None => {
+                if mtls.is_some() {
+                    r.errors
+                        .push("mtls.listen requires tls.listen (or TLS_LISTEN)".to_string());
+                }
+                if acme.is_some() {
+                    warnings.push("tls.acme.domain has no effect without tls.listen".to_string());
+                }
+                None
+            }
+        };
+
+        // Clients reach a native TLS listener directly, so their headers are theirs
+        let trust_proxy_headers = trust_proxy_headers.unwrap_or(tls.is_none());
+
+        let mut policy = Policy {
+            public_ips_only: r
+                .parse(
+                    PUBLIC_IPS_ONLY,
+                    args.public_ips_only,
+                    file.policy.public_ips_only.map(|b| b.to_string()),
+                    parse_bool,
+                )
+                .unwrap_or(false),
+            expected_interval: r.parse(
+                EXPECTED_INTERVAL,
+                args.expected_interval,
+                file.policy.expected_interval.map(|s| s.to_string()),
+                parse_seconds,
+            ),
+            ..Policy::default()
+        };
+        let (record_types, origin) = r
+            .list(RECORD_TYPES, args.record_types, file.policy.record_types)
+            .unwrap_or((Vec::new(), Origin::File));
+        let mut types_error = None;
+        for record_type in record_types
+            .iter()
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+        {
+            match RrType::from_str(&record_type.to_uppercase()) {
+                Ok(t) => policy.record_types.push(t.as_str().to_string()),
+                Err(e) => types_error = types_error.or(Some(e)),
+            }
+        }
+        if let Some(e) = types_error {
+            r.error(RECORD_TYPES, origin, e);
+        }
+
+        for (name, settings) in file.records {
+            let location = match &args.config {
+                Some(path) => format!("records.\"{}\" in {}", name, path.display()),
+                None => format!("records.\"{}\"", name),
+            };
+            let domain = name.trim_end_matches('.').to_lowercase();
+            if let Err(e) = parse_dns_name(&domain) {
+                r.errors.push(format!("{}: {}", location, e));
+                continue;
+            }
+            let in_zone = zones.iter().any(|z| {
+                domain == z.domain_name || domain.ends_with(&format!(".{}", z.domain_name))
+            });
+            if !in_zone {
+                warnings.push(format!("{}: not in any zone", location));
+            }
+            // Checked the same way the API checks a record's fields
+            let check = Record {
+                domain: domain.clone(),
+                record_type: "A".to_string(),
+                ip: "192.0.2.1".to_string(),
+                ttl: settings.ttl.unwrap_or(Record::default().ttl),
+                proxied: settings.proxied.unwrap_or_default(),
+                comment: settings.comment.clone(),
+                ..Record::default()
+            };
+            if let Err(e) = check.validate() {
+                r.errors.push(format!("{}: {}", location, e));
+                continue;
+            }
+            if settings.expected_interval == Some(0) {
+                r.errors
+                    .push(format!("{}: expected_interval must be above 0", location));
+                continue;
+            }
+            policy.records.insert(domain, settings);
+        }
+
+        let mut webhooks: Vec<Webhook> = Vec::new();
+        for (i, webhook) in file.webhooks.into_iter().enumerate() {
+            let key = |name: &str| format!("webhooks[{}].{}", i, name);
+            let url = webhook.url.filter(|u| !u.is_empty()).unwrap_or_default();
+            match reqwest::Url::parse(&url) {
+                _ if url.is_empty() => r.file_error(key("url"), "is required"),
+                Ok(parsed) if ["http", "https"].contains(&parsed.scheme()) => {}
+                Ok(_) => r.file_error(key("url"), "must be http or https"),
+                Err(e) => r.file_error(key("url"), e),
+            }
+            let name = webhook
+                .name
+                .filter(|n| !n.is_empty())
+                .unwrap_or_else(|| url.clone());
+            if webhooks.iter().any(|w| w.name == name) {
+                r.file_error(key("name"), format!("{:?} is used more than once", name));
+            }
+            let secret = r.file_secret(
+                (key("secret"), key("secret_file")),
+                webhook.secret,
+                webhook.secret_file,
+            );
+            if secret.is_none() {
+                warnings.push(format!(
+                    "{}: no secret, payloads won't be signed",
+                    key("url")
+                ));
+            }
+            let filter = change_filter(&mut r, &key("events"), webhook.events, webhook.domains);
+            webhooks.push(Webhook {
+                name,
+                url,
+                secret,
+                filter,
+            });
+        }
+
+        let mut notifiers: Vec<Notifier> = Vec::new();
+        for (i, notifier) in file.notifiers.into_iter().enumerate() {
+            let key = |name: &str| format!("notifiers[{}].{}", i, name);
+            let name = notifier.name.filter(|n| !n.is_empty()).unwrap_or_else(|| {
+                r.file_error(key("name"), "is required");
+                String::new()
+            });
+            if !name.is_empty() && notifiers.iter().any(|n| n.name == name) {
+                r.file_error(key("name"), format!("{:?} is used more than once", name));
+            }
+            let url = notifier.url.filter(|u| !u.is_empty()).unwrap_or_else(|| {
+                r.file_error(key("url"), "is required");
+                String::new()
+            });
+            let kind = notifier.kind.unwrap_or_default().to_lowercase();
+            if kind != "smtp" && !url.is_empty() {
+                match reqwest::Url::parse(&url) {
+                    Ok(parsed) if ["http", "https"].contains(&parsed.scheme()) => {}
+                    Ok(_) => r.file_error(key("url"), "must be http or https"),
+                    Err(e) => r.file_error(key("url"), e),
+                }
+            }
+            let token = r.file_secret(
+                (key("token"), key("token_file")),
+                notifier.token,
+                notifier.token_file,
+            );
+            let mut required = |field: &str, value: Option<String>| {
+                value.filter(|v| !v.is_empty()).unwrap_or_else(|| {
+                    r.file_error(key(field), format!("is required for {}", kind));
+                    String::new()
+                })
+            };
+            let backend = match kind.as_str() {
+                "ntfy" => Backend::Ntfy { url, token },
+                "gotify" => Backend::Gotify {
+                    url,
+                    token: required("token", token),
+                },
+                "matrix" => Backend::Matrix {
+                    url,
+                    room: required("room", notifier.room),
+                    token: required("token", token),
+                },
+                "http" => Backend::Http {
+                    url,
+                    method: notifier.method.unwrap_or_else(|| "POST".to_string()),
+                    token,
+                    headers: notifier.headers.unwrap_or_default().into_iter().collect(),
+                    content_type: notifier
+                        .content_type
+                        .unwrap_or_else(|| "text/plain".to_string()),
+                    body: notifier
+                        .body
+                        .unwrap_or_else(|| notify::DEFAULT_MESSAGE.to_string()),
+                },
+                "smtp" => {
+                    let from = required("from", notifier.from);
+                    let to = notifier.to.unwrap_or_default();
+                    if to.is_empty() {
+                        r.file_error(key("to"), "is required for smtp");
+                    }
+                    for address in to.iter().chain([&from]).filter(|a| !a.is_empty()) {
+                        if let Err(e) = notify::check_address(address) {
+                            r.file_error(key(if *address == from { "from" } else { "to" }), e);
+                        }
+                    }
+                    if let Err(e) = notify::check_url(&url) {
+                        if !url.is_empty() {
+                            r.file_error(key("url"), e);
+                        }
+                    }
+                    let password = r.file_secret(
+                        (key("password"), key("password_file")),
+                        notifier.password,
+                        notifier.password_file,
+                    );
+                    Backend::Smtp(SmtpConfig {
+                        url,
+                        username: notifier.username.filter(|u| !u.is_empty()),
+                        password,
+                        from,
+                        to,
+                    })
+                }
+                _ => {
+                    r.file_error(
+                        key("kind"),
+                        format!(
+                            "{:?} isn't supported, expected ntfy, gotify, matrix, http or smtp",
+                            kind
+                        ),
+                    );
+                    continue;
+                }
+            };
+            let title = notifier
+                .title
+                .unwrap_or_else(|| notify::DEFAULT_TITLE.to_string());
+            let message = notifier
+                .message
+                .unwrap_or_else(|| notify::DEFAULT_MESSAGE.to_string());
+            let mut templates = vec![("title", &title), ("message", &message)];
+            if let Backend::Http { body, headers, .. } = &backend {
+                templates.push(("body", body));
+                templates.extend(headers.iter().map(|(_, v)| ("headers", v)));
+            }
+            for (field, template) in templates {
+                if let Err(e) = notify::check_template(template) {
+                    r.file_error(key(field), e);
+                }
+            }
+            let filter = change_filter(&mut r, &key("events"), notifier.events, notifier.domains);
+            notifiers.push(Notifier {
+                name,
+                backend,
+                filter,
+                title,
+                message,
+            });
+        }
+
+        let mut subscriptions: Vec<_> = policy
+            .records
+            .iter()
+            .filter_map(|(domain, settings)| Some((domain, settings.notify.as_ref()?)))
+            .collect();
+        subscriptions.sort();
+        for (domain, names) in subscriptions {
+            for name in names
+                .iter()
+                .filter(|n| !notifiers.iter().any(|x| &x.name == *n))
+            {
+                let location = match &args.config {
+                    Some(path) => format!("records.\"{}\".notify in {}", domain, path.display()),
+                    None => format!("records.\"{}\".notify", domain),
+                };
+                r.errors
+                    .push(format!("{}: no notifier is named {:?}", location, name));
+            }
+        }
+
+        let mqtt_url = r.parse(MQTT_URL, args.mqtt_url, file.mqtt.url, |v| {
+            mqtt::check_url(v).map(|_| v.to_string())
+        });
+        let mqtt_username = r.string(MQTT_USERNAME, args.mqtt_username, file.mqtt.username);
+        let mqtt_password = r.secret(
+            (MQTT_PASSWORD, MQTT_PASSWORD_FILE),
+            (args.mqtt_password, args.mqtt_password_file),
+            (file.mqtt.password, path_string(file.mqtt.password_file)),
+        );
+        let client_id = r
+            .string(MQTT_CLIENT_ID, args.mqtt_client_id, file.mqtt.client_id)
+            .unwrap_or_else(|| DEFAULT_MQTT_CLIENT_ID.to_string());
+        let topic_prefix = r.parse(
+            MQTT_TOPIC_PREFIX,
+            args.mqtt_topic_prefix,
+            file.mqtt.topic_prefix,
+            parse_topic,
+        );
+        let discovery_prefix = r.parse(
+            MQTT_DISCOVERY_PREFIX,
+            args.mqtt_discovery_prefix,
+            file.mqtt.discovery_prefix,
+            parse_topic,
+        );
+        let commands = r.parse(
+            MQTT_COMMANDS,
+            args.mqtt_commands,
+            file.mqtt.commands.map(|b| b.to_string()),
+            parse_bool,
+        );
+        let stale_after = r.parse(
+            MQTT_STALE_AFTER,
+            args.mqtt_stale_after,
+            file.mqtt.stale_after.map(|s| s.to_string()),
+            parse_seconds,
+        );
+        if mqtt_password.is_some() && mqtt_username.is_none() {
+            warnings.push(format!(
+                "{} is only used with {}",
+                MQTT_PASSWORD.path, MQTT_USERNAME.path
+            ));
+        }
+        let mqtt = mqtt_url.map(|url| MqttConfig {
+            url,
+            username: mqtt_username,
+            password: mqtt_password,
+            client_id,
+            topic_prefix: topic_prefix.unwrap_or_else(|| DEFAULT_MQTT_TOPIC_PREFIX.to_string()),
+            discovery_prefix: discovery_prefix
+                .unwrap_or_else(|| DEFAULT_MQTT_DISCOVERY_PREFIX.to_string()),
+            commands: commands.unwrap_or(false),
+            stale_after: stale_after.unwrap_or(Duration::from_secs(DEFAULT_MQTT_STALE_AFTER)),
+        });
+
+        if !r.errors.is_empty() {
+            return Err(r.errors);
+        }
+        Ok(Settings {
+            zones,
+            listen,
+            data_dir,
+            tls,
+            api: ApiConfig {
+                salt,
+                auth: Auth { username, password },
+                trust_proxy_headers,
+                client_identities,
+                policy,
+                webhooks,
+                notifiers,
+            },
+            warnings,
+            files: r.files,
+            mqtt,
+        })
+    }
+}
+
+/// A secret-free summary for `config check`.
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let listen: Vec<_> = self.listen.iter().map(|l| l.to_string()).collect();
+        for zone in &self.zones {
+            let zone_id = zone.zone_id.as_deref().unwrap_or("ID looked up at startup");
+            let via = match zone.api_url.as_str() {
+                CLOUDFLARE_API_URL => String::new(),
+                url => format!(", via {}", url),
+            };
+            writeln!(
+                f,
+                "zone:            {} ({}, {} auth{})",
+                zone.domain_name,
+                zone_id,
+                zone.auth.mode().as_str(),
+                via
+            )?;
+        }
+        writeln!(f, "listen:          {}", listen.join(", "))?;
+        if let Some(tls) = &self.tls {
+            match &tls.acme {
+                Some(acme) => writeln!(
+                    f,
+                    "tls:             {} (ACME for {})",
+                    tls.listen, acme.domain
+                )?,
+                None => writeln!(
+                    f,
+                    "tls:             {} ({})",
+                    tls.listen,
+                    tls.cert_path.display()
+                )?,
+            }
+            if let Some(mtls) = &tls.mtls {
+                writeln!(
+                    f,
+                    "mtls:            {} ({})",
+                    mtls.listen,
+                    mtls.ca_path.display()
+                )?;
+            }
+        }
+        writeln!(f, "data dir:        {}", self.data_dir.display())?;
+        writeln!(
+            f,
+            "basic auth:      {}",
+            if self.api.auth.has_credentials() {
+                "on"
+            } else {
+                "off"
+            }
+        )?;
+        let policy = &self.api.policy;
+        writeln!(
+            f,
+            "record types:    {}",
+            if policy.record_types.is_empty() {
+                "any".to_string()
+            } else {
+                policy.record_types.join(", ")
+            }
+        )?;
+        writeln!(f, "public IPs only: {}", policy.public_ips_only)?;
+        if let Some(interval) = policy.expected_interval {
+            writeln!(f, "heartbeat:       every {}s", interval.as_secs())?;
+        }
+        writeln!(f, "pinned records:  {}", policy.records.len())?;
+        for webhook in &self.api.webhooks {
+            let events: Vec<_> = webhook.filter.events.iter().map(|e| e.as_str()).collect();
+            writeln!(
+                f,
+                "webhook:         {} ({}, {})",
+                webhook.name,
+                if events.is_empty() {
+                    "every event".to_string()
+                } else {
+                    events.join(", ")
+                },
+                if webhook.secret.is_some() {
+                    "signed"
+                } else {
+                    "unsigned"
+                }
+            )?;
+        }
+        for notifier in &self.api.notifiers {
+            writeln!(
+                f,
+                "notifier:        {} ({})",
+                notifier.name,
+                notifier.backend.kind()
+            )?;
+        }
+        if let Some(mqtt) = &self.mqtt {
+            writeln!(
+                f,
+                "mqtt:            {} ({}/, discovery under {}/, commands {})",
+                mqtt::display_url(&mqtt.url),
+                mqtt.topic_prefix,
+                mqtt.discovery_prefix,
+                if mqtt.commands { "on" } else { "off" }
+            )?;
+        }
+        Ok(())
+    }
+}
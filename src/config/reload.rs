@@ -0,0 +1,313 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tracing::{error, info, warn};
+
+use crate::config::{ServeArgs, Settings};
+use crate::server::health::Readiness;
+use crate::server::state::{Live, Runtime};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Re-reads the settings on SIGHUP or when the config file or a secret file
+/// changes, and swaps in a new [`Runtime`]. An invalid config is logged and
+/// the running one kept.
+pub struct Reloader {
+    args: ServeArgs,
+    live: Arc<Live>,
+    /// Cleared on each change, so probes see the new zones straight away
+    readiness: Readiness,
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    /// Listeners and paths are bound once at startup
+    restart_only: Vec<(&'static str, String)>,
+    #[cfg(unix)]
+    hangups: Option<tokio::signal::unix::Signal>,
+}
+
+impl Reloader {
+    /// Registers for SIGHUP straight away, so an early one doesn't kill the server.
+    pub fn new(
+        args: ServeArgs,
+        settings: &Settings,
+        live: Arc<Live>,
+        readiness: Readiness,
+    ) -> Reloader {
+        #[cfg(unix)]
+        let hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(hangups) => Some(hangups),
+            Err(e) => {
+                warn!("Can't reload on SIGHUP: {}", e);
+                None
+            }
+        };
+        Reloader {
+            args,
+            live,
+            readiness,
+            files: modified(&settings.files),
+            restart_only: restart_only(settings),
+            #[cfg(unix)]
+            hangups,
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = self.hangup() => {
+                    info!("SIGHUP, reloading config");
+                    self.reload().await;
+                }
+                _ = interval.tick() => {
+                    let now = modified(&self.paths());
+                    if let Some((path, _)) = now.iter().zip(&self.files).find(|(a, b)| a != b) {
+                        info!("{} changed, reloading config", path.0.display());
+                        self.reload().await;
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    async fn hangup(&mut self) {
+        let Some(hangups) = &mut self.hangups else {
+            return std::future::pending().await;
+        };
+        if hangups.recv().await.is_none() {
+            std::future::pending().await
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn hangup(&mut self) {
+        std::future::pending().await
+    }
+
+    /// The config file and the secret files the running settings were read from.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.files.iter().map(|(path, _)| path.clone()).collect()
+    }
+
+    /// Loads the settings now, as a SIGHUP would.
+    pub async fn reload(&mut self) {
+        let mut settings = match Settings::load(self.args.clone()) {
+            Ok(settings) => settings,
+            Err(errors) => {
+                error!("Keeping the running config, the new one is invalid:");
+                for e in errors {
+                    error!("  {}", e);
+                }
+                // Wait for the next edit rather than retrying this one
+                self.files = modified(&self.paths());
+                return;
+            }
+        };
+        self.files = modified(&settings.files);
+        for warning in &settings.warnings {
+            warn!("{}", warning);
+        }
+        for ((key, before), (_, now)) in self.restart_only.iter().zip(restart_only(&settings)) {
+            if *before != now {
+                warn!("{} changed, restart to apply it", key);
+            }
+        }
+        // Every record ID is derived from the salt, swapping it under running
+        // agents and subscribers would orphan them all at once
+        settings.api.salt = self.live.load().config.salt.clone();
+
+        let runtime = match Runtime::connect(&settings).await {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!("Keeping the running config: {}", e);
+                return;
+            }
+        };
+        let changes = changes(&self.live.load(), &runtime);
+        if changes.is_empty() {
+            info!("Config reloaded, nothing changed");
+            return;
+        }
+        self.live.store(Arc::new(runtime));
+        self.readiness.clear().await;
+        for change in changes {
+            info!("Config changed: {}", change);
+        }
+    }
+}
+
+fn modified(files: &[PathBuf]) -> Vec<(PathBuf, Option<SystemTime>)> {
+    files
+        .iter()
+        .map(|path| {
+            let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+            (path.clone(), modified)
+        })
+        .collect()
+}
+
+fn restart_only(settings: &Settings) -> Vec<(&'static str, String)> {
+    let listen: Vec<_> = settings.listen.iter().map(|l| l.to_string()).collect();
+    let tls = settings.tls.as_ref();
+    let mtls = tls.and_then(|t| t.mtls.as_ref());
+    let acme = tls.and_then(|t| t.acme.as_ref());
+    vec![
+        ("server.listen", listen.join(",")),
+        ("server.data_dir", settings.data_dir.display().to_string()),
+        (
+            "tls.listen",
+            tls.map(|t| t.listen.to_string()).unwrap_or_default(),
+        ),
+        (
+            "tls.cert",
+            tls.map(|t| t.cert_path.display().to_string())
+                .unwrap_or_default(),
+        ),
+        (
+            "tls.key",
+            tls.map(|t| t.key_path.display().to_string())
+                .unwrap_or_default(),
+        ),
+        (
+            "tls.acme",
+            acme.map(|a| format!("{} {} {}", a.domain, a.directory_url, a.challenge.as_str()))
+                .unwrap_or_default(),
+        ),
+        (
+            "mtls.listen",
+            mtls.map(|m| m.listen.to_string()).unwrap_or_default(),
+        ),
+        (
+            "mtls.ca",
+            mtls.map(|m| m.ca_path.display().to_string())
+                .unwrap_or_default(),
+        ),
+        // Only compared, so the password and salt can be in it
+        ("mqtt", format!("{:?}", settings.mqtt)),
+        ("server.salt", settings.api.salt.clone()),
+    ]
+}
+
+/// What differs between two runtimes, without printing secrets.
+pub fn changes(before: &Runtime, now: &Runtime) -> Vec<String> {
+    let mut changes = Vec::new();
+    let mut value = |key: &str, before: String, now: String| {
+        if before != now {
+            changes.push(format!("{}: {:?} -> {:?}", key, before, now));
+        }
+    };
+    value(
+        "auth.username",
+        before.config.auth.username.clone().unwrap_or_default(),
+        now.config.auth.username.clone().unwrap_or_default(),
+    );
+    value(
+        "server.trust_proxy_headers",
+        before.config.trust_proxy_headers.to_string(),
+        now.config.trust_proxy_headers.to_string(),
+    );
+    value(
+        "policy.public_ips_only",
+        before.config.policy.public_ips_only.to_string(),
+        now.config.policy.public_ips_only.to_string(),
+    );
+    value(
+        "policy.record_types",
+        before.config.policy.record_types.join(","),
+        now.config.policy.record_types.join(","),
+    );
+    value(
+        "policy.expected_interval",
+        format!("{:?}", before.config.policy.expected_interval),
+        format!("{:?}", now.config.policy.expected_interval),
+    );
+
+    for zone in now.zones.iter() {
+        match before
+            .zones
+            .iter()
+            .find(|z| z.domain_name == zone.domain_name)
+        {
+            None => changes.push(format!("zone {} added", zone.domain_name)),
+            Some(old) if old.zone_id != zone.zone_id => changes.push(format!(
+                "zone {} ID: {:?} -> {:?}",
+                zone.domain_name, old.zone_id, zone.zone_id
+            )),
+            Some(old) if old.api_url != zone.api_url => changes.push(format!(
+                "zone {} API URL: {} -> {}",
+                zone.domain_name, old.api_url, zone.api_url
+            )),
+            Some(old) if old.auth != zone.auth => {
+                changes.push(format!("zone {} credentials changed", zone.domain_name))
+            }
+            _ => {}
+        }
+    }
+    for zone in before.zones.iter() {
+        if !now.zones.apexes().contains(&zone.domain_name.as_str()) {
+            changes.push(format!("zone {} removed", zone.domain_name));
+        }
+    }
+    if before.config.auth.password != now.config.auth.password {
+        changes.push("auth.password changed".to_string());
+    }
+    if before.config.client_identities != now.config.client_identities {
+        changes.push("mtls.identities changed".to_string());
+    }
+
+    for webhook in &now.config.webhooks {
+        match before
+            .config
+            .webhooks
+            .iter()
+            .find(|w| w.name == webhook.name)
+        {
+            None => changes.push(format!("webhook {} added", webhook.name)),
+            Some(old) if old != webhook => {
+                changes.push(format!("webhook {} changed", webhook.name))
+            }
+            _ => {}
+        }
+    }
+    for webhook in &before.config.webhooks {
+        if !now.config.webhooks.iter().any(|w| w.name == webhook.name) {
+            changes.push(format!("webhook {} removed", webhook.name));
+        }
+    }
+
+    for notifier in &now.config.notifiers {
+        match before
+            .config
+            .notifiers
+            .iter()
+            .find(|n| n.name == notifier.name)
+        {
+            None => changes.push(format!("notifier {} added", notifier.name)),
+            Some(old) if old != notifier => {
+                changes.push(format!("notifier {} changed", notifier.name))
+            }
+            _ => {}
+        }
+    }
+    for notifier in &before.config.notifiers {
+        if !now.config.notifiers.iter().any(|n| n.name == notifier.name) {
+            changes.push(format!("notifier {} removed", notifier.name));
+        }
+    }
+
+    let (old, new) = (&before.config.policy.records, &now.config.policy.records);
+    let mut names: Vec<_> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    for name in names {
+        match (old.get(name), new.get(name)) {
+            (Some(_), None) => changes.push(format!("records.\"{}\" removed", name)),
+            (None, Some(_)) => changes.push(format!("records.\"{}\" added", name)),
+            (Some(a), Some(b)) if a != b => changes.push(format!("records.\"{}\" changed", name)),
+            _ => {}
+        }
+    }
+    changes
+}
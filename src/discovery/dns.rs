@@ -0,0 +1,120 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::net::UdpSocket;
+
+use crate::discovery::Family;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const ATTEMPTS: u32 = 3;
+const RESEND_AFTER: Duration = Duration::from_millis(1500);
+
+/// Asks `resolver` for the `A` or `AAAA` record of `name`, for resolvers like
+/// OpenDNS that answer `myip.opendns.com` with the address of the asker.
+pub async fn query(resolver: &SocketAddr, name: &str, family: Family) -> Result<IpAddr, String> {
+    let socket = UdpSocket::bind(SocketAddr::new(family.unspecified(), 0))
+        .await
+        .map_err(|e| e.to_string())?;
+    socket.connect(resolver).await.map_err(|e| e.to_string())?;
+
+    let mut id = [0u8; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| "no randomness available".to_string())?;
+    let qtype = match family {
+        Family::V4 => TYPE_A,
+        Family::V6 => TYPE_AAAA,
+    };
+    let mut request = Vec::with_capacity(32 + name.len());
+    request.extend_from_slice(&id);
+    // Recursion desired, one question
+    request.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid DNS name {}", name));
+        }
+        request.push(label.len() as u8);
+        request.extend_from_slice(label.as_bytes());
+    }
+    request.push(0);
+    request.extend_from_slice(&qtype.to_be_bytes());
+    request.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    let mut buf = [0u8; 1500];
+    for _ in 0..ATTEMPTS {
+        socket.send(&request).await.map_err(|e| e.to_string())?;
+        let deadline = tokio::time::Instant::now() + RESEND_AFTER;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let len = received.map_err(|e| e.to_string())?;
+            if len >= 2 && buf[..2] == id {
+                return parse_response(&buf[..len], qtype);
+            }
+        }
+    }
+    Err("no response".to_string())
+}
+
+fn parse_response(packet: &[u8], qtype: u16) -> Result<IpAddr, String> {
+    let truncated = || "truncated DNS response".to_string();
+    let u16_at = |offset: usize| -> Result<u16, String> {
+        packet
+            .get(offset..offset + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(truncated)
+    };
+    let rcode = u16_at(2)? & 0x000f;
+    if rcode != 0 {
+        return Err(format!("resolver answered with rcode {}", rcode));
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(packet, offset)? + 4;
+    }
+    for _ in 0..answers {
+        offset = skip_name(packet, offset)?;
+        let kind = u16_at(offset)?;
+        let class = u16_at(offset + 2)?;
+        let len = u16_at(offset + 8)? as usize;
+        let data = packet
+            .get(offset + 10..offset + 10 + len)
+            .ok_or_else(truncated)?;
+        offset += 10 + len;
+        if class != CLASS_IN || kind != qtype {
+            continue;
+        }
+        match (kind, data.len()) {
+            (TYPE_A, 4) => {
+                return Ok(IpAddr::V4(Ipv4Addr::new(
+                    data[0], data[1], data[2], data[3],
+                )));
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = data.try_into().expect("sixteen octets");
+                return Ok(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => return Err("malformed address record".to_string()),
+        }
+    }
+    Err("no address in DNS response".to_string())
+}
+
+/// Returns the offset just past the name starting at `offset`.
+fn skip_name(packet: &[u8], mut offset: usize) -> Result<usize, String> {
+    loop {
+        let len = *packet
+            .get(offset)
+            .ok_or_else(|| "truncated DNS name".to_string())? as usize;
+        match len {
+            0 => return Ok(offset + 1),
+            // A compression pointer always ends the name
+            l if l & 0xc0 == 0xc0 => return Ok(offset + 2),
+            l => offset += 1 + l,
+        }
+    }
+}
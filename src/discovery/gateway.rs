@@ -0,0 +1,189 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use igd_next::SearchOptions;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::net::UdpSocket;
+use tracing::warn;
+
+/// NAT-PMP and PCP share a port on the gateway.
+pub const NAT_PMP_PORT: u16 = 5351;
+
+const UPNP_SEARCH_TIMEOUT: Duration = Duration::from_secs(4);
+const ATTEMPTS: u32 = 3;
+const RESEND_AFTER: Duration = Duration::from_millis(1000);
+const PCP_VERSION: u8 = 2;
+const PCP_MAP: u8 = 1;
+const PCP_MAP_LIFETIME: u32 = 30;
+const UDP: u8 = 17;
+
+/// Asks the first UPnP Internet Gateway Device to answer an SSDP search for
+/// its `GetExternalIPAddress`.
+pub async fn upnp() -> Result<IpAddr, String> {
+    let gateway = igd_next::aio::tokio::search_gateway(SearchOptions {
+        timeout: Some(UPNP_SEARCH_TIMEOUT),
+        ..Default::default()
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    let ip = gateway.get_external_ip().await.map_err(|e| e.to_string())?;
+    check_wan_address(ip, "UPnP")
+}
+
+/// RFC 6886 external address request.
+pub async fn nat_pmp(gateway: SocketAddr) -> Result<IpAddr, String> {
+    let response = exchange(gateway, &[0, 0], |r| r.len() >= 12 && r[1] == 128).await?;
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        return Err(format!(
+            "NAT-PMP request failed with result code {}",
+            result
+        ));
+    }
+    let ip = IpAddr::V4(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ));
+    check_wan_address(ip, "NAT-PMP")
+}
+
+/// RFC 6887 has no plain "what is my address" request, so this maps the
+/// socket's own UDP port for a few seconds and reads the assigned external
+/// address off the answer, then deletes the mapping again.
+pub async fn pcp(gateway: SocketAddr) -> Result<IpAddr, String> {
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified(&gateway), 0))
+        .await
+        .map_err(|e| e.to_string())?;
+    socket.connect(gateway).await.map_err(|e| e.to_string())?;
+    let local = socket.local_addr().map_err(|e| e.to_string())?;
+
+    let mut nonce = [0u8; 12];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| "no randomness available".to_string())?;
+    let request = |lifetime: u32| {
+        let mut request = Vec::with_capacity(60);
+        request.extend_from_slice(&[PCP_VERSION, PCP_MAP, 0, 0]);
+        request.extend_from_slice(&lifetime.to_be_bytes());
+        request.extend_from_slice(&mapped(local.ip()).octets());
+        request.extend_from_slice(&nonce);
+        request.extend_from_slice(&[UDP, 0, 0, 0]);
+        request.extend_from_slice(&local.port().to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        request
+    };
+    let is_answer = |r: &[u8]| r.len() >= 60 && r[1] == 0x80 | PCP_MAP && r[24..36] == nonce;
+
+    let response = exchange_on(&socket, &request(PCP_MAP_LIFETIME), is_answer).await?;
+    if response[0] != PCP_VERSION {
+        return Err("gateway only speaks NAT-PMP".to_string());
+    }
+    if response[3] != 0 {
+        return Err(format!(
+            "PCP request failed with result code {}",
+            response[3]
+        ));
+    }
+    let octets: [u8; 16] = response[44..60].try_into().expect("sixteen octets");
+    let ip = Ipv6Addr::from(octets);
+    let ip = ip
+        .to_ipv4_mapped()
+        .map(IpAddr::V4)
+        .unwrap_or(IpAddr::V6(ip));
+    // Best effort, the mapping expires by itself anyway
+    let _ = socket.send(&request(0)).await;
+    check_wan_address(ip, "PCP")
+}
+
+/// The IPv4 default route's gateway, read from `/proc/net/route`.
+pub fn default_gateway() -> Result<IpAddr, String> {
+    let routes = std::fs::read_to_string("/proc/net/route")
+        .map_err(|e| format!("can't find the default gateway, set one explicitly: {}", e))?;
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let flags = u16::from_str_radix(fields.get(3)?, 16).ok()?;
+            // RTF_UP | RTF_GATEWAY on the 0.0.0.0/0 route
+            if *fields.get(1)? != "00000000" || flags & 0x3 != 0x3 {
+                return None;
+            }
+            let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(gateway.to_le_bytes())))
+        })
+        .next()
+        .ok_or_else(|| "no IPv4 default route".to_string())
+}
+
+/// A router whose own WAN address is private or in the CGNAT range sits
+/// behind another NAT, so nothing published for it will be reachable.
+fn check_wan_address(ip: IpAddr, protocol: &str) -> Result<IpAddr, String> {
+    let behind_nat = match ip {
+        IpAddr::V4(v4) => v4.is_private() || is_shared(&v4),
+        IpAddr::V6(_) => false,
+    };
+    if !behind_nat {
+        return Ok(ip);
+    }
+    warn!(
+        "The router reports WAN address {} over {}, it is behind a carrier-grade or second NAT and the published IP won't be reachable from the internet",
+        ip, protocol
+    );
+    Err(format!("{} is behind another NAT", ip))
+}
+
+/// 100.64.0.0/10, RFC 6598 shared address space used by CGNAT.
+fn is_shared(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    a == 100 && (b & 0xc0) == 64
+}
+
+fn mapped(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+fn unspecified(addr: &SocketAddr) -> IpAddr {
+    match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+async fn exchange(
+    gateway: SocketAddr,
+    request: &[u8],
+    is_answer: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, String> {
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified(&gateway), 0))
+        .await
+        .map_err(|e| e.to_string())?;
+    socket.connect(gateway).await.map_err(|e| e.to_string())?;
+    exchange_on(&socket, request, is_answer).await
+}
+
+async fn exchange_on(
+    socket: &UdpSocket,
+    request: &[u8],
+    is_answer: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, String> {
+    let mut buf = [0u8; 1100];
+    for _ in 0..ATTEMPTS {
+        socket.send(request).await.map_err(|e| e.to_string())?;
+        let deadline = tokio::time::Instant::now() + RESEND_AFTER;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let len = received.map_err(|e| e.to_string())?;
+            // A NAT-PMP only gateway answers PCP with a version 0 error
+            if is_answer(&buf[..len]) || (len >= 4 && buf[0] == 0 && request[0] != 0) {
+                return Ok(buf[..len].to_vec());
+            }
+        }
+    }
+    Err("no response from gateway".to_string())
+}
@@ -0,0 +1,210 @@
+use std::net::Ipv6Addr;
+
+/// Address flags from linux/if_addr.h, as netlink reports them.
+pub const IFA_F_TEMPORARY: u32 = 0x01;
+pub const IFA_F_DADFAILED: u32 = 0x08;
+pub const IFA_F_DEPRECATED: u32 = 0x20;
+pub const IFA_F_TENTATIVE: u32 = 0x40;
+
+/// Addresses that are about to go away, privacy extensions that rotate
+/// daily, or not usable yet are never published.
+const UNSTABLE: u32 = IFA_F_TEMPORARY | IFA_F_DEPRECATED | IFA_F_TENTATIVE | IFA_F_DADFAILED;
+
+/// Which interface addresses may be published.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InterfaceFilter {
+    /// Interface name, a trailing `*` matches any suffix
+    pub name: Option<String>,
+    /// Only addresses inside this prefix. Unique local addresses are skipped
+    /// unless a prefix explicitly asks for them.
+    pub prefix: Option<(Ipv6Addr, u8)>,
+}
+
+impl InterfaceFilter {
+    /// `[name][@prefix/len]`, e.g. `eth0`, `wl*@2001:db8::/32` or `@fd00::/8`
+    pub fn parse(spec: &str) -> Result<InterfaceFilter, String> {
+        let (name, prefix) = spec.split_once('@').unwrap_or((spec, ""));
+        let prefix = match prefix {
+            "" => None,
+            prefix => {
+                let (addr, len) = prefix.split_once('/').unwrap_or((prefix, "128"));
+                let addr: Ipv6Addr = addr
+                    .parse()
+                    .map_err(|_| format!("Invalid IPv6 prefix {}", prefix))?;
+                let len: u8 = len
+                    .parse()
+                    .ok()
+                    .filter(|l| *l <= 128)
+                    .ok_or_else(|| format!("Invalid IPv6 prefix length in {}", prefix))?;
+                Some((addr, len))
+            }
+        };
+        Ok(InterfaceFilter {
+            name: Some(name.to_string()).filter(|n| !n.is_empty()),
+            prefix,
+        })
+    }
+
+    /// Whether `ip` on the interface called `name`, with these `IFA_F_*`
+    /// `flags`, may be published.
+    pub fn accepts(&self, name: Option<&str>, ip: &Ipv6Addr, flags: u32) -> bool {
+        flags & UNSTABLE == 0 && self.matches_name(name) && self.matches_address(ip)
+    }
+
+    fn matches_name(&self, name: Option<&str>) -> bool {
+        match (&self.name, name) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(filter), Some(name)) => match filter.strip_suffix('*') {
+                Some(start) => name.starts_with(start),
+                None => name == filter,
+            },
+        }
+    }
+
+    fn matches_address(&self, ip: &Ipv6Addr) -> bool {
+        if ip.is_loopback() || ip.is_multicast() || ip.is_unspecified() {
+            return false;
+        }
+        let segments = ip.segments();
+        let link_local = segments[0] & 0xffc0 == 0xfe80;
+        let unique_local = segments[0] & 0xfe00 == 0xfc00;
+        if link_local || ip.to_ipv4_mapped().is_some() {
+            return false;
+        }
+        match self.prefix {
+            Some((prefix, len)) => {
+                let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+                u128::from(*ip) & mask == u128::from(prefix) & mask
+            }
+            None => !unique_local,
+        }
+    }
+}
+
+impl std::fmt::Display for InterfaceFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name.as_deref().unwrap_or_default())?;
+        if let Some((prefix, len)) = self.prefix {
+            write!(f, "@{}/{}", prefix, len)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::{global_address, watch};
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv6Addr};
+    use std::sync::Arc;
+
+    use futures_util::{StreamExt, TryStreamExt};
+    use netlink_packet_route::address::Nla as AddressNla;
+    use netlink_packet_route::link::nlas::Nla as LinkNla;
+    use netlink_packet_route::{AF_INET6, RTNLGRP_IPV6_IFADDR, RT_SCOPE_UNIVERSE};
+    use netlink_sys::{AsyncSocket, SocketAddr};
+    use rtnetlink::new_connection;
+    use tokio::sync::Notify;
+    use tracing::{debug, warn};
+
+    use super::InterfaceFilter;
+
+    /// The stable global IPv6 address of a matching interface, the lowest one
+    /// when several qualify so repeated lookups agree.
+    pub async fn global_address(filter: &InterfaceFilter) -> Result<IpAddr, String> {
+        let (connection, handle, _) = new_connection().map_err(|e| e.to_string())?;
+        tokio::spawn(connection);
+
+        let names: HashMap<u32, String> = handle
+            .link()
+            .get()
+            .execute()
+            .try_filter_map(|link| async move {
+                Ok(link.nlas.iter().find_map(|nla| match nla {
+                    LinkNla::IfName(name) => Some((link.header.index, name.clone())),
+                    _ => None,
+                }))
+            })
+            .try_collect()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut candidates = vec![];
+        let mut addresses = handle.address().get().execute();
+        while let Some(message) = addresses.try_next().await.map_err(|e| e.to_string())? {
+            if message.header.family as u16 != AF_INET6 || message.header.scope != RT_SCOPE_UNIVERSE
+            {
+                continue;
+            }
+            let mut flags = message.header.flags as u32;
+            let mut address = None;
+            for nla in &message.nlas {
+                match nla {
+                    AddressNla::Flags(f) => flags = *f,
+                    AddressNla::Address(bytes) if address.is_none() => address = Some(bytes),
+                    // On point-to-point links IFA_LOCAL is ours, IFA_ADDRESS the peer's
+                    AddressNla::Local(bytes) => address = Some(bytes),
+                    _ => {}
+                }
+            }
+            let Some(Ok(octets)) = address.map(|a| <[u8; 16]>::try_from(a.as_slice())) else {
+                continue;
+            };
+            let ip = Ipv6Addr::from(octets);
+            let name = names.get(&message.header.index).map(String::as_str);
+            if !filter.accepts(name, &ip, flags) {
+                continue;
+            }
+            debug!("Candidate address {} on {}", ip, name.unwrap_or("?"));
+            candidates.push(ip);
+        }
+        candidates
+            .into_iter()
+            .min()
+            .map(IpAddr::V6)
+            .ok_or_else(|| "no stable global IPv6 address".to_string())
+    }
+
+    /// Notifies `changes` whenever an IPv6 address is added or removed.
+    pub async fn watch(changes: Arc<Notify>) {
+        let (mut connection, _handle, mut messages) = match new_connection() {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Can't watch interface addresses: {}", e);
+                return;
+            }
+        };
+        let groups = SocketAddr::new(0, 1 << (RTNLGRP_IPV6_IFADDR - 1));
+        if let Err(e) = connection.socket_mut().socket_mut().bind(&groups) {
+            warn!("Can't watch interface addresses: {}", e);
+            return;
+        }
+        tokio::spawn(connection);
+        // Only address events are subscribed to, any message is a change
+        while messages.next().await.is_some() {
+            changes.notify_one();
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use other::{global_address, watch};
+
+#[cfg(not(target_os = "linux"))]
+mod other {
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    use tokio::sync::Notify;
+
+    use super::InterfaceFilter;
+
+    pub async fn global_address(_filter: &InterfaceFilter) -> Result<IpAddr, String> {
+        Err("interface addresses are only supported on Linux".to_string())
+    }
+
+    pub async fn watch(_changes: Arc<Notify>) {}
+}
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use reqwest::{Client, ClientBuilder};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::error::DynIpError;
+
+mod dns;
+mod gateway;
+mod interface;
+mod stun;
+
+pub use interface::{
+    InterfaceFilter, IFA_F_DADFAILED, IFA_F_DEPRECATED, IFA_F_TEMPORARY, IFA_F_TENTATIVE,
+};
+
+pub const SOURCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Public sources used for `--source default`. All of them answer over both
+/// IPv4 and IPv6.
+pub const DEFAULT_SOURCES: [&str; 5] = [
+    "https://api64.ipify.org",
+    "https://icanhazip.com",
+    "stun:stun.l.google.com:19302",
+    "stun:stun.cloudflare.com:3478",
+    "dns:resolver1.opendns.com",
+];
+
+/// Name that OpenDNS resolvers answer with the address of whoever asked.
+pub const OPENDNS_MYIP: &str = "myip.opendns.com";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(ip: &IpAddr) -> Family {
+        match ip {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+
+    /// Binding to the unspecified address of a family restricts a socket, and
+    /// so the address the other side sees, to that family.
+    pub fn unspecified(&self) -> IpAddr {
+        match self {
+            Family::V4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            Family::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        }
+    }
+}
+
+impl std::str::FromStr for Family {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Family, String> {
+        match s.to_lowercase().as_str() {
+            "4" | "v4" | "ipv4" => Ok(Family::V4),
+            "6" | "v6" | "ipv6" => Ok(Family::V6),
+            _ => Err(format!("Unknown address family {}, expected v4 or v6", s)),
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Family::V4 => "IPv4",
+            Family::V6 => "IPv6",
+        })
+    }
+}
+
+/// Somewhere that can tell us the address our traffic comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// An HTTP(S) endpoint returning the caller's IP as plain text, such as
+    /// dyn-ip's own `GET /`
+    Http(String),
+    /// A STUN server, `host:port`
+    Stun(String),
+    /// A resolver that answers `name` with the caller's address, `host:port`
+    Dns { resolver: String, name: String },
+    /// The router's WAN address over UPnP IGD
+    Upnp,
+    /// The router's WAN address over NAT-PMP, the default gateway unless given
+    NatPmp(Option<String>),
+    /// The router's WAN address over PCP, the default gateway unless given
+    Pcp(Option<String>),
+    /// A stable global IPv6 address configured on this host
+    Interface(InterfaceFilter),
+}
+
+impl std::str::FromStr for Source {
+    type Err = String;
+
+    /// `http(s)://...`, `stun:host[:port]`, `dns:host[:port][/name]`, `upnp`,
+    /// `natpmp[:gateway]`, `pcp[:gateway]` or `interface[:name][@prefix]`
+    fn from_str(s: &str) -> Result<Source, String> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            return Ok(Source::Http(s.to_string()));
+        }
+        let (kind, rest) = s.split_once(':').unwrap_or((s, ""));
+        let gateway = Some(rest)
+            .filter(|g| !g.is_empty())
+            .map(|g| with_default_port(g, gateway::NAT_PMP_PORT));
+        match kind {
+            "upnp" => return Ok(Source::Upnp),
+            "natpmp" => return Ok(Source::NatPmp(gateway)),
+            "pcp" => return Ok(Source::Pcp(gateway)),
+            "interface" => return Ok(Source::Interface(InterfaceFilter::parse(rest)?)),
+            _ => {}
+        }
+        if let Some(server) = s.strip_prefix("stun:") {
+            return Ok(Source::Stun(with_default_port(server, 3478)));
+        }
+        if let Some(resolver) = s.strip_prefix("dns:") {
+            let (resolver, name) = resolver.split_once('/').unwrap_or((resolver, OPENDNS_MYIP));
+            return Ok(Source::Dns {
+                resolver: with_default_port(resolver, 53),
+                name: name.to_string(),
+            });
+        }
+        Err(format!(
+            "Unknown IP source {}, expected http(s)://, stun:, dns:, upnp, natpmp, pcp or interface",
+            s
+        ))
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Http(url) => f.write_str(url),
+            Source::Stun(server) => write!(f, "stun:{}", server),
+            Source::Dns { resolver, name } => write!(f, "dns:{}/{}", resolver, name),
+            Source::Upnp => f.write_str("upnp"),
+            Source::NatPmp(None) => f.write_str("natpmp"),
+            Source::NatPmp(Some(gateway)) => write!(f, "natpmp:{}", gateway),
+            Source::Pcp(None) => f.write_str("pcp"),
+            Source::Pcp(Some(gateway)) => write!(f, "pcp:{}", gateway),
+            Source::Interface(filter) => write!(f, "interface:{}", filter),
+        }
+    }
+}
+
+impl Source {
+    /// Parses a list of sources, expanding `default` to [`DEFAULT_SOURCES`].
+    pub fn parse_list<S: AsRef<str>>(specs: &[S]) -> Result<Vec<Source>, String> {
+        let mut sources = vec![];
+        for spec in specs {
+            match spec.as_ref().trim() {
+                "" => {}
+                "default" => {
+                    for default in DEFAULT_SOURCES {
+                        sources.push(default.parse()?);
+                    }
+                }
+                spec => sources.push(spec.parse()?),
+            }
+        }
+        Ok(sources)
+    }
+}
+
+/// Addresses found for each family, `None` where no quorum was reached.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Addresses {
+    pub v4: Option<IpAddr>,
+    pub v6: Option<IpAddr>,
+}
+
+/// Asks every source for our public address and only trusts an answer that
+/// at least `quorum` of them agree on.
+pub struct Discovery {
+    sources: Vec<Source>,
+    quorum: usize,
+    timeout: Duration,
+    http_v4: Client,
+    http_v6: Client,
+    changes: Arc<Notify>,
+    watching: OnceLock<()>,
+}
+
+impl Discovery {
+    /// A quorum of 0 means a majority of the sources.
+    pub fn new(sources: Vec<Source>, quorum: usize) -> Result<Discovery, DynIpError> {
+        Discovery::with_client(sources, quorum, Client::builder)
+    }
+
+    /// Like [`Discovery::new`], with HTTP clients based on `builder`, e.g. to
+    /// present a client certificate to the dyn-ip server.
+    pub fn with_client(
+        sources: Vec<Source>,
+        quorum: usize,
+        builder: impl Fn() -> ClientBuilder,
+    ) -> Result<Discovery, DynIpError> {
+        if sources.is_empty() {
+            return Err(DynIpError::Discovery(
+                "No IP sources configured".to_string(),
+            ));
+        }
+        let quorum = if quorum == 0 {
+            sources.len() / 2 + 1
+        } else {
+            quorum
+        };
+        if quorum > sources.len() {
+            return Err(DynIpError::Discovery(format!(
+                "A quorum of {} can never be reached with {} sources",
+                quorum,
+                sources.len()
+            )));
+        }
+        let client = |family: Family| {
+            builder()
+                .timeout(SOURCE_TIMEOUT)
+                .local_address(family.unspecified())
+                .build()
+                .map_err(|e| DynIpError::Discovery(e.to_string()))
+        };
+        Ok(Discovery {
+            http_v4: client(Family::V4)?,
+            http_v6: client(Family::V6)?,
+            sources,
+            quorum,
+            timeout: SOURCE_TIMEOUT,
+            changes: Arc::new(Notify::new()),
+            watching: OnceLock::new(),
+        })
+    }
+
+    pub fn quorum(&self) -> usize {
+        self.quorum
+    }
+
+    pub async fn discover(&self, family: Family) -> Result<IpAddr, DynIpError> {
+        let answers = join_all(self.sources.iter().map(|source| async move {
+            let result = tokio::time::timeout(self.timeout, self.query(source, family))
+                .await
+                .unwrap_or_else(|_| Err("timed out".to_string()))
+                .and_then(|ip| match Family::of(&ip) == family {
+                    true => Ok(ip),
+                    false => Err(format!("answered with {} address {}", Family::of(&ip), ip)),
+                });
+            (source, result)
+        }))
+        .await;
+
+        let mut votes: HashMap<IpAddr, usize> = HashMap::new();
+        for (source, result) in &answers {
+            match result {
+                Ok(ip) => {
+                    debug!("{} reports {}", source, ip);
+                    *votes.entry(*ip).or_default() += 1;
+                }
+                Err(e) => debug!("{} failed for {}: {}", source, family, e),
+            }
+        }
+        if votes.len() > 1 {
+            warn!("IP sources disagree on the {} address: {:?}", family, votes);
+        }
+        match votes.iter().max_by_key(|(_, count)| **count) {
+            Some((ip, count)) if *count >= self.quorum => Ok(*ip),
+            _ => Err(DynIpError::Discovery(format!(
+                "No {} address reached a quorum of {}: {}",
+                family,
+                self.quorum,
+                answers
+                    .iter()
+                    .map(|(source, result)| match result {
+                        Ok(ip) => format!("{} => {}", source, ip),
+                        Err(e) => format!("{} => {}", source, e),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))),
+        }
+    }
+
+    /// Resolves once something a source watches has changed, currently an
+    /// interface address, so a new lookup is worth doing right away. Never
+    /// resolves without interface sources.
+    pub async fn changed(&self) {
+        let watches = self
+            .sources
+            .iter()
+            .any(|s| matches!(s, Source::Interface(_)));
+        if !watches {
+            return std::future::pending().await;
+        }
+        if self.watching.set(()).is_ok() {
+            tokio::spawn(interface::watch(self.changes.clone()));
+        }
+        self.changes.notified().await
+    }
+
+    /// Looks up both families, a host without IPv6 simply gets `v6: None`.
+    pub async fn discover_all(&self) -> Addresses {
+        let (v4, v6) = tokio::join!(self.discover(Family::V4), self.discover(Family::V6));
+        Addresses {
+            v4: v4.ok(),
+            v6: v6.ok(),
+        }
+    }
+
+    async fn query(&self, source: &Source, family: Family) -> Result<IpAddr, String> {
+        match source {
+            Source::Http(url) => {
+                let client = match family {
+                    Family::V4 => &self.http_v4,
+                    Family::V6 => &self.http_v6,
+                };
+                let body = client
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| e.to_string())?
+                    .text()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                body.trim()
+                    .parse()
+                    .map_err(|_| format!("invalid IP in response: {}", body.trim()))
+            }
+            Source::Stun(server) => stun::query(&resolve(server, family).await?, family).await,
+            Source::Dns { resolver, name } => {
+                dns::query(&resolve(resolver, family).await?, name, family).await
+            }
+            Source::Interface(_) if family == Family::V4 => Err("only reports IPv6".to_string()),
+            Source::Interface(filter) => interface::global_address(filter).await,
+            // Routers only NAT IPv4, an IPv6 address is the host's own
+            _ if family == Family::V6 => Err("only reports IPv4".to_string()),
+            Source::Upnp => gateway::upnp().await,
+            Source::NatPmp(gw) => gateway::nat_pmp(resolve_gateway(gw.as_deref()).await?).await,
+            Source::Pcp(gw) => gateway::pcp(resolve_gateway(gw.as_deref()).await?).await,
+        }
+    }
+}
+
+async fn resolve_gateway(gateway: Option<&str>) -> Result<SocketAddr, String> {
+    match gateway {
+        Some(gateway) => resolve(gateway, Family::V4).await,
+        None => Ok(SocketAddr::new(
+            gateway::default_gateway()?,
+            gateway::NAT_PMP_PORT,
+        )),
+    }
+}
+
+async fn resolve(host: &str, family: Family) -> Result<SocketAddr, String> {
+    tokio::net::lookup_host(host)
+        .await
+        .map_err(|e| e.to_string())?
+        .find(|addr| Family::of(&addr.ip()) == family)
+        .ok_or_else(|| format!("{} has no {} address", host, family))
+}
+
+fn with_default_port(host: &str, port: u16) -> String {
+    let has_port = match host.rsplit_once(':') {
+        // A bare IPv6 address is full of colons, only a bracketed one can carry a port
+        Some((addr, p)) => p.parse::<u16>().is_ok() && (!addr.contains(':') || addr.ends_with(']')),
+        None => false,
+    };
+    match has_port {
+        true => host.to_string(),
+        false if host.contains(':') && !host.starts_with('[') => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    }
+}
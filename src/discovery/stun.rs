@@ -0,0 +1,115 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::net::UdpSocket;
+
+use crate::discovery::Family;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTEMPTS: u32 = 3;
+const RESEND_AFTER: Duration = Duration::from_millis(1500);
+
+/// Sends an RFC 5389 Binding request and returns the reflexive address the
+/// server saw it come from.
+pub async fn query(server: &SocketAddr, family: Family) -> Result<IpAddr, String> {
+    let socket = UdpSocket::bind(SocketAddr::new(family.unspecified(), 0))
+        .await
+        .map_err(|e| e.to_string())?;
+    socket.connect(server).await.map_err(|e| e.to_string())?;
+
+    let mut transaction_id = [0u8; 12];
+    SystemRandom::new()
+        .fill(&mut transaction_id)
+        .map_err(|_| "no randomness available".to_string())?;
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+
+    // UDP may drop either packet, so resend a few times before giving up
+    let mut buf = [0u8; 1024];
+    for _ in 0..ATTEMPTS {
+        socket.send(&request).await.map_err(|e| e.to_string())?;
+        let deadline = tokio::time::Instant::now() + RESEND_AFTER;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let len = received.map_err(|e| e.to_string())?;
+            if let Some(ip) = parse_response(&buf[..len], &transaction_id)? {
+                return Ok(ip);
+            }
+        }
+    }
+    Err("no response".to_string())
+}
+
+/// `Ok(None)` for packets that aren't the answer to our request.
+fn parse_response(packet: &[u8], transaction_id: &[u8; 12]) -> Result<Option<IpAddr>, String> {
+    if packet.len() < 20
+        || packet[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || &packet[8..20] != transaction_id
+    {
+        return Ok(None);
+    }
+    let message_type = u16::from_be_bytes([packet[0], packet[1]]);
+    if message_type != BINDING_SUCCESS {
+        return Err(format!(
+            "unexpected STUN response type {:#06x}",
+            message_type
+        ));
+    }
+    let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    let attributes = packet
+        .get(20..20 + length)
+        .ok_or_else(|| "truncated STUN response".to_string())?;
+
+    let mut mapped = None;
+    let mut offset = 0;
+    while offset + 4 <= attributes.len() {
+        let kind = u16::from_be_bytes([attributes[offset], attributes[offset + 1]]);
+        let len = u16::from_be_bytes([attributes[offset + 2], attributes[offset + 3]]) as usize;
+        let value = attributes
+            .get(offset + 4..offset + 4 + len)
+            .ok_or_else(|| "truncated STUN attribute".to_string())?;
+        match kind {
+            XOR_MAPPED_ADDRESS => return parse_address(value, Some(transaction_id)).map(Some),
+            // Only pre-RFC 5389 servers send the plain form, keep looking for the XOR one
+            MAPPED_ADDRESS => mapped = Some(parse_address(value, None)?),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of four bytes
+        offset += 4 + len.div_ceil(4) * 4;
+    }
+    mapped
+        .map(Some)
+        .ok_or_else(|| "STUN response has no mapped address".to_string())
+}
+
+fn parse_address(value: &[u8], xor_with: Option<&[u8; 12]>) -> Result<IpAddr, String> {
+    let mut mask = [0u8; 16];
+    if let Some(transaction_id) = xor_with {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction_id);
+    }
+    let address = |len: usize| -> Result<Vec<u8>, String> {
+        let bytes = value
+            .get(4..4 + len)
+            .ok_or_else(|| "truncated STUN address".to_string())?;
+        Ok(bytes.iter().zip(mask).map(|(b, m)| b ^ m).collect())
+    };
+    match value.get(1) {
+        Some(0x01) => {
+            let octets: [u8; 4] = address(4)?.try_into().expect("four octets");
+            Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        Some(0x02) => {
+            let octets: [u8; 16] = address(16)?.try_into().expect("sixteen octets");
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => Err("unknown STUN address family".to_string()),
+    }
+}
@@ -1,3 +1,4 @@
+use actix_web::http::StatusCode;
 use std::env::VarError;
 use thiserror::Error;
 
@@ -9,8 +10,9 @@ pub enum DynIpError {
     DomainParse(String),
     #[error("Socker Error: {0}")]
     SocketAddr(#[from] std::net::AddrParseError),
+    /// Kept as its message, as `actix_web::Error` isn't `Send`
     #[error("Actix Error: {0}")]
-    ActixError(#[from] actix_web::Error),
+    ActixError(String),
     #[error("IO Error {0}")]
     FileIO(#[from] std::io::Error),
     #[error("Missing Update IP Address")]
@@ -21,5 +23,56 @@ pub enum DynIpError {
     DomainHashNotFound,
     #[error("Cloudflare Error: {0}")]
     Cloudflare(String),
+    #[error("TLS Error: {0}")]
+    Tls(String),
+    #[error("ACME Error: {0}")]
+    Acme(String),
+    #[error("Record Not Permitted For Client Certificate")]
+    RecordNotPermitted,
+    #[error("Record Is Locked")]
+    RecordLocked,
+    #[error("Client Certificate Matches {0} Records")]
+    AmbiguousClientCertificate(usize),
+    #[error("{0} Matches {1} Records")]
+    AmbiguousRecord(String, usize),
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Invalid ACME Challenge: {0}")]
+    InvalidChallenge(String),
+    #[error("Invalid Record: {0}")]
+    InvalidRecord(String),
+    #[error("Agent Error: {0}")]
+    Agent(String),
+    #[error("IP Discovery Error: {0}")]
+    Discovery(String),
+    #[error("API Client Error: {0}")]
+    Client(String),
+    #[error("No Zone For {0}")]
+    NoZone(String),
+    #[error("Config Error: {0}")]
+    Config(String),
+}
+impl From<actix_web::Error> for DynIpError {
+    fn from(e: actix_web::Error) -> Self {
+        DynIpError::ActixError(e.to_string())
+    }
+}
+
+impl actix_web::error::ResponseError for DynIpError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DynIpError::RecordNotPermitted | DynIpError::RecordLocked => StatusCode::FORBIDDEN,
+            DynIpError::AmbiguousClientCertificate(_) | DynIpError::AmbiguousRecord(..) => {
+                StatusCode::CONFLICT
+            }
+            DynIpError::Unauthorized => StatusCode::UNAUTHORIZED,
+            DynIpError::DomainHashNotFound => StatusCode::NOT_FOUND,
+            DynIpError::InvalidChallenge(_)
+            | DynIpError::InvalidRecord(_)
+            | DynIpError::MissingId
+            | DynIpError::MissingIp
+            | DynIpError::NoZone(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
-impl actix_web::error::ResponseError for DynIpError {}
@@ -0,0 +1,16 @@
+//! Dynamic DNS for Cloudflare. The binary runs the API server or the agent,
+//! the library exposes the same pieces, e.g. [`discovery`] for finding this
+//! host's public IP.
+
+pub mod agent;
+pub mod aws;
+pub mod client;
+pub mod config;
+pub mod discovery;
+pub mod error;
+pub mod notify;
+pub mod server;
+pub mod telemetry;
+pub mod tls;
+
+pub use error::DynIpError;
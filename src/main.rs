@@ -1,51 +1,210 @@
 extern crate core;
 extern crate dotenv;
 
-use crate::aws::cloudflare::Cloudflare;
-use crate::DynIpError::DomainParse;
+use clap::{Args, Parser, Subcommand};
 use dotenv::dotenv;
-use env_logger::Env;
-use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
 
-use crate::error::DynIpError;
-use crate::server::api::ApiConfig;
-use crate::server::auth::Auth;
+use dyn_ip::agent::{Agent, AgentConfig};
+use dyn_ip::client::records::{self, RecordsArgs};
+use dyn_ip::config::reload::Reloader;
+use dyn_ip::config::{ServeArgs, Settings};
+use dyn_ip::discovery::{Family, Source};
+use dyn_ip::error::DynIpError;
+use dyn_ip::notify::Notifiers;
+use dyn_ip::server::health::Readiness;
+use dyn_ip::server::heartbeat::Heartbeats;
+use dyn_ip::server::history::History;
+use dyn_ip::server::mqtt::Mqtt;
+use dyn_ip::server::state::{Live, Runtime};
+use dyn_ip::server::webhooks::Webhooks;
+use dyn_ip::telemetry::{self, LogFormat};
 
-mod aws;
-mod error;
-mod server;
+#[derive(Parser)]
+#[command(version, about = "Dynamic DNS for Cloudflare")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Log as text or JSON lines, filtered by RUST_LOG
+    #[arg(long, env = "LOG_FORMAT", default_value = "text", global = true)]
+    log_format: LogFormat,
+    /// OTLP/HTTP collector to export traces to, e.g. http://localhost:4318
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT", global = true)]
+    otlp_endpoint: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the API server (default)
+    Serve(ServeArgs),
+    /// Work with the server's config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Keep a record pointed at this host's public IP
+    Agent(AgentArgs),
+    /// Manage records on a running server
+    Records(RecordsArgs),
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Validate the config file with env vars and flags applied
+    Check(ServeArgs),
+}
+
+#[derive(Args)]
+struct AgentArgs {
+    /// Base URL of the dyn-ip server
+    #[arg(long, env = "DYN_IP_SERVER")]
+    server: String,
+    /// Record ID to update, not needed when a client certificate maps to one record
+    #[arg(long, env = "DYN_IP_ID")]
+    id: Option<String>,
+    /// Seconds between checks
+    #[arg(long, env = "DYN_IP_INTERVAL", default_value_t = 300)]
+    interval: u64,
+    /// Seconds after which an unchanged IP is sent again, keep it below the
+    /// record's expected interval and lease on the server
+    #[arg(long, env = "DYN_IP_REFRESH", default_value_t = 1800)]
+    refresh: u64,
+    /// Where the last pushed IP is remembered between runs
+    #[arg(long, env = "DYN_IP_STATE_FILE", default_value = "dyn-ip-agent.json")]
+    state_file: PathBuf,
+    /// PEM client certificate for the mTLS listener
+    #[arg(long, env = "DYN_IP_CLIENT_CERT")]
+    client_cert: Option<PathBuf>,
+    /// PEM key for the client certificate, defaults to the certificate file
+    #[arg(long, env = "DYN_IP_CLIENT_KEY")]
+    client_key: Option<PathBuf>,
+    /// Extra CA to trust for the server certificate
+    #[arg(long, env = "DYN_IP_CA_CERT")]
+    ca_cert: Option<PathBuf>,
+    /// Check once and exit, for running from cron
+    #[arg(long)]
+    once: bool,
+    /// Address family to keep the record pointed at, v4 or v6
+    #[arg(long, env = "DYN_IP_FAMILY", default_value = "v4")]
+    family: Family,
+    /// Extra IP sources that must agree with the server: http(s)://..., stun:host[:port],
+    /// dns:host[:port][/name], upnp, natpmp[:gateway], pcp[:gateway],
+    /// interface[:name][@prefix], or "default" for a set of public ones
+    #[arg(long = "source", env = "DYN_IP_SOURCES", value_delimiter = ',')]
+    sources: Vec<String>,
+    /// How many sources must agree on the IP, defaults to a majority
+    #[arg(long, env = "DYN_IP_QUORUM", default_value_t = 0)]
+    quorum: usize,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), DynIpError> {
     dotenv().ok();
-    env_logger::Builder::from_env(Env::default().default_filter_or("dyn_ip=info")).init();
-    let zone_id = std::env::var("CLOUDFLARE_ZONE_ID")?;
-    let api_key = std::env::var("CLOUDFLARE_API_KEY")?;
-    let email = std::env::var("CLOUDFLARE_EMAIL")?;
-    let domain_name = addr::parse_domain_name(&std::env::var("DOMAIN_NAME")?)
-        .map_err(|e| DomainParse(e.to_string()))?
-        .to_string();
-    let listen: SocketAddr = std::env::var("LISTEN")
-        .unwrap_or_else(|_| "0.0.0.0:8080".to_string())
-        .parse()?;
-    let salt = std::env::var("SALT").unwrap_or_default();
-    let username = std::env::var("BASIC_AUTH_USERNAME")
-        .ok()
-        .filter(|u| !u.is_empty());
-    let password = std::env::var("BASIC_AUTH_PASSWORD")
-        .ok()
-        .filter(|p| !p.is_empty());
-
-    let r53 = Cloudflare::new(api_key, zone_id, email, domain_name);
-    server::api::start(
-        &listen,
-        r53,
-        ApiConfig {
-            salt,
-            auth: Auth { username, password },
-        },
+    let cli = Cli::parse();
+    let _telemetry = telemetry::init(cli.log_format, cli.otlp_endpoint.as_deref())?;
+    let command = match cli.command {
+        Some(command) => command,
+        None => Command::Serve(ServeArgs::from_env()),
+    };
+    match command {
+        Command::Serve(args) => serve(args).await,
+        Command::Config {
+            command: ConfigCommand::Check(args),
+        } => {
+            check_config(args);
+            Ok(())
+        }
+        Command::Agent(args) => {
+            Agent::new(AgentConfig {
+                server: args.server,
+                id: args.id,
+                interval: Duration::from_secs(args.interval.max(1)),
+                refresh: Duration::from_secs(args.refresh),
+                state_file: args.state_file,
+                client_cert: args.client_cert,
+                client_key: args.client_key,
+                ca_cert: args.ca_cert,
+                once: args.once,
+                family: args.family,
+                sources: Source::parse_list(&args.sources).map_err(DynIpError::Discovery)?,
+                quorum: args.quorum,
+            })?
+            .run()
+            .await
+        }
+        Command::Records(args) => records::run(args).await,
+    }
+}
+
+async fn serve(args: ServeArgs) -> Result<(), DynIpError> {
+    let settings =
+        Settings::load(args.clone()).map_err(|errors| DynIpError::Config(errors.join("; ")))?;
+    for warning in &settings.warnings {
+        warn!("{}", warning);
+    }
+    let live = Arc::new(Live::from_pointee(Runtime::connect(&settings).await?));
+    let readiness = Readiness::new(settings.data_dir.clone());
+    self_check(&readiness)?;
+    tokio::spawn(Reloader::new(args, &settings, live.clone(), readiness.clone()).run());
+    let history = History::load(settings.data_dir.join("history.jsonl"));
+    let heartbeats = Heartbeats::load(settings.data_dir.join("heartbeats.json"), &history);
+    tokio::spawn(heartbeats.clone().run(live.clone(), history.clone()));
+    let webhooks = Webhooks::load(settings.data_dir.join("webhooks.json"));
+    tokio::spawn(webhooks.clone().run(live.clone(), history.subscribe()));
+    tokio::spawn(Notifiers::default().run(live.clone(), history.subscribe()));
+    if let Some(mqtt) = settings.mqtt.clone() {
+        tokio::spawn(Mqtt::run(
+            mqtt,
+            live.clone(),
+            history.clone(),
+            heartbeats.clone(),
+        ));
+    }
+    dyn_ip::server::api::start(
+        &settings.listen,
+        settings.tls,
+        live,
+        history.clone(),
+        readiness,
+        webhooks.clone(),
+        heartbeats.clone(),
     )
     .await?;
+    history.flush();
+    webhooks.flush();
+    heartbeats.flush();
 
     Ok(())
 }
+
+/// Checks the data directory before listening, so a broken setup stops here
+/// instead of failing probes. Zones were checked as they connected.
+fn self_check(readiness: &Readiness) -> Result<(), DynIpError> {
+    readiness
+        .check_data_dir()
+        .map_err(|e| DynIpError::Config(format!("Self-check failed: data_dir: {}", e)))?;
+    info!("Self-check data_dir: ok");
+    Ok(())
+}
+
+/// Prints every problem with the layered config, exiting non-zero if any.
+fn check_config(args: ServeArgs) {
+    match Settings::load(args) {
+        Ok(settings) => {
+            for warning in &settings.warnings {
+                eprintln!("warning: {}", warning);
+            }
+            println!("{}", settings);
+            println!("Configuration is valid");
+        }
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("error: {}", error);
+            }
+            std::process::exit(1);
+        }
+    }
+}
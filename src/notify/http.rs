@@ -0,0 +1,132 @@
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Method, RequestBuilder};
+use serde_json::json;
+
+use crate::notify::{render, render_escaped, Message};
+use crate::server::history::Change;
+use crate::server::trace::random_id;
+
+pub fn ntfy(
+    client: &reqwest::Client,
+    url: &str,
+    token: Option<&str>,
+    message: &Message,
+) -> RequestBuilder {
+    let request = client
+        .post(url)
+        .header("Title", &message.title)
+        .body(message.body.clone());
+    match token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+pub fn gotify(
+    client: &reqwest::Client,
+    url: &str,
+    token: &str,
+    message: &Message,
+) -> RequestBuilder {
+    client
+        .post(format!("{}/message", url.trim_end_matches('/')))
+        .header("X-Gotify-Key", token)
+        .json(&json!({ "title": message.title, "message": message.body }))
+}
+
+pub fn matrix(
+    client: &reqwest::Client,
+    url: &str,
+    room: &str,
+    token: &str,
+    message: &Message,
+) -> RequestBuilder {
+    client
+        .put(format!(
+            "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            url.trim_end_matches('/'),
+            encode(room),
+            random_id()
+        ))
+        .bearer_auth(token)
+        .json(&json!({
+            "msgtype": "m.text",
+            "body": format!("{}\n{}", message.title, message.body),
+        }))
+}
+
+/// Headers and the body are templates, rendered for the change. Values in a
+/// JSON or form body are escaped so they can't break out of it.
+#[allow(clippy::too_many_arguments)]
+pub fn custom(
+    client: &reqwest::Client,
+    url: &str,
+    method: &str,
+    token: Option<&str>,
+    headers: &[(String, String)],
+    content_type: &str,
+    body: &str,
+    change: &Change,
+) -> Result<RequestBuilder, String> {
+    let method = Method::from_bytes(method.as_bytes()).map_err(|e| e.to_string())?;
+    let mut request = client
+        .request(method, url)
+        .header(CONTENT_TYPE, content_type)
+        .body(render_escaped(body, change, escaper(content_type)));
+    for (name, value) in headers {
+        request = request.header(name, render(value, change));
+    }
+    let has_authorization = headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case(AUTHORIZATION.as_str()));
+    Ok(match token {
+        Some(token) if !has_authorization => request.bearer_auth(token),
+        _ => request,
+    })
+}
+
+/// Escapes values for the body's content type, as written inside a JSON
+/// string or as a form value. Anything else takes them as they are.
+fn escaper(content_type: &str) -> fn(&str) -> String {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if essence == "application/json" || essence.ends_with("+json") {
+        |value| {
+            let quoted = serde_json::to_string(value).unwrap_or_default();
+            quoted[1..quoted.len() - 1].to_string()
+        }
+    } else if essence == "application/x-www-form-urlencoded" {
+        encode
+    } else {
+        str::to_string
+    }
+}
+
+/// Anything but a 2xx is an error.
+pub async fn send(request: RequestBuilder) -> Result<(), String> {
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        let body = response.text().await.unwrap_or_default();
+        Err(format!("{}: {}", status, body.trim()))
+    }
+}
+
+/// Percent-encodes all but unreserved characters. Room IDs and aliases
+/// start with `!` or `#` and hold a `:`.
+fn encode(room: &str) -> String {
+    room.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
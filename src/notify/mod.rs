@@ -0,0 +1,325 @@
+//! Human readable notices of record changes, sent to chat, push and email
+//! services. Unlike webhooks they're sent once, without a queue.
+
+mod http;
+mod smtp;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::info;
+use tracing::{error, warn};
+
+use crate::server::history::{Action, Change, ChangeFilter};
+use crate::server::metrics::metrics;
+use crate::server::policy::Policy;
+use crate::server::state::Live;
+
+pub use smtp::{check_address, check_url, SmtpConfig};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Placeholders a template can use.
+pub const PLACEHOLDERS: &[&str] = &[
+    "action",
+    "domain",
+    "record_type",
+    "old_value",
+    "new_value",
+    "summary",
+    "client",
+    "at",
+];
+pub const DEFAULT_TITLE: &str = "{domain} {action}";
+pub const DEFAULT_MESSAGE: &str = "{record_type} {domain}: {summary}";
+
+/// A destination from `[[notifiers]]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notifier {
+    pub name: String,
+    pub backend: Backend,
+    pub filter: ChangeFilter,
+    pub title: String,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Backend {
+    /// `url` is the topic URL
+    Ntfy {
+        url: String,
+        token: Option<String>,
+    },
+    /// `url` is the server, the token an application token
+    Gotify {
+        url: String,
+        token: String,
+    },
+    /// `url` is the homeserver, the token an access token for a member of `room`
+    Matrix {
+        url: String,
+        room: String,
+        token: String,
+    },
+    /// `body` is a template too
+    Http {
+        url: String,
+        method: String,
+        token: Option<String>,
+        headers: Vec<(String, String)>,
+        content_type: String,
+        body: String,
+    },
+    Smtp(smtp::SmtpConfig),
+}
+
+impl Backend {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Backend::Ntfy { .. } => "ntfy",
+            Backend::Gotify { .. } => "gotify",
+            Backend::Matrix { .. } => "matrix",
+            Backend::Http { .. } => "http",
+            Backend::Smtp(_) => "smtp",
+        }
+    }
+}
+
+/// A rendered notice.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub title: String,
+    pub body: String,
+}
+
+impl Notifier {
+    pub fn message(&self, change: &Change) -> Message {
+        Message {
+            title: render(&self.title, change),
+            body: render(&self.message, change),
+        }
+    }
+
+    /// Whether the change goes to this notifier. A record that lists its
+    /// notifiers in `[records."…"]` only goes to those, whatever their
+    /// `domains`, though their `events` still apply.
+    pub fn wants(&self, change: &Change, policy: &Policy) -> bool {
+        match policy
+            .record(&change.domain)
+            .and_then(|s| s.notify.as_ref())
+        {
+            Some(names) => names.contains(&self.name) && self.filter.wants(change.action),
+            None => self.filter.matches(change),
+        }
+    }
+
+    async fn send(&self, notifiers: &Notifiers, change: &Change) -> Result<(), String> {
+        let message = self.message(change);
+        let client = &notifiers.client;
+        let request = match &self.backend {
+            Backend::Ntfy { url, token } => http::ntfy(client, url, token.as_deref(), &message),
+            Backend::Gotify { url, token } => http::gotify(client, url, token, &message),
+            Backend::Matrix { url, room, token } => {
+                http::matrix(client, url, room, token, &message)
+            }
+            Backend::Http {
+                url,
+                method,
+                token,
+                headers,
+                content_type,
+                body,
+            } => http::custom(
+                client,
+                url,
+                method,
+                token.as_deref(),
+                headers,
+                content_type,
+                body,
+                change,
+            )?,
+            Backend::Smtp(config) => {
+                let transport = notifiers.transport(&self.name, config)?;
+                return smtp::send(&transport, config, &message).await;
+            }
+        };
+        http::send(request).await
+    }
+}
+
+/// Fills in `{placeholder}`s, leaving anything else as written.
+pub fn render(template: &str, change: &Change) -> String {
+    render_escaped(template, change, str::to_string)
+}
+
+/// [`render`] with each value passed through `escape`, for templates in a
+/// format such as JSON.
+pub fn render_escaped(template: &str, change: &Change, escape: impl Fn(&str) -> String) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after
+            .find('}')
+            .and_then(|end| Some((value(&after[..end], change)?, end)))
+        {
+            Some((value, end)) => {
+                out.push_str(&escape(&value));
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The first unknown placeholder in a template, if any.
+pub fn check_template(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('}') else {
+            break;
+        };
+        let name = &after[..end];
+        if !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !PLACEHOLDERS.contains(&name)
+        {
+            return Err(format!(
+                "unknown placeholder {{{}}}, expected one of {}",
+                name,
+                PLACEHOLDERS.join(", ")
+            ));
+        }
+        rest = &after[end + 1..];
+    }
+    Ok(())
+}
+
+fn value(name: &str, change: &Change) -> Option<String> {
+    let value = |v: &Option<String>| v.clone().unwrap_or_default();
+    Some(match name {
+        "action" => change.action.as_str().to_string(),
+        "domain" => change.domain.clone(),
+        "record_type" => change.record_type.clone(),
+        "old_value" => value(&change.old_value),
+        "new_value" => value(&change.new_value),
+        "client" => value(&change.client),
+        "at" => change.at.to_string(),
+        "summary" => match (change.action, &change.old_value, &change.new_value) {
+            (Action::Delete, Some(old), _) => format!("deleted, was {}", old),
+            (Action::Stale, _, Some(value)) => {
+                format!("no update in its expected interval, still {}", value)
+            }
+            (_, Some(old), Some(new)) if old != new => format!("{} -> {}", old, new),
+            (_, _, Some(new)) => format!("now {}", new),
+            _ => change.action.as_str().to_string(),
+        },
+        _ => return None,
+    })
+}
+
+/// Sends each recorded change to the notifiers that want it, taken from the
+/// live config so reloads apply straight away.
+#[derive(Clone)]
+pub struct Notifiers {
+    client: reqwest::Client,
+    /// By notifier name, with the settings they were built from
+    transports: Arc<Mutex<HashMap<String, (SmtpConfig, smtp::Transport)>>>,
+}
+
+impl Default for Notifiers {
+    fn default() -> Notifiers {
+        Notifiers {
+            client: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .user_agent(concat!("dyn-ip/", env!("CARGO_PKG_VERSION")))
+                .build()
+                .unwrap_or_default(),
+            transports: Arc::default(),
+        }
+    }
+}
+
+impl Notifiers {
+    /// Sends to every notifier that wants the change at once, returning the
+    /// failures by notifier name.
+    pub async fn notify(
+        &self,
+        notifiers: &[Notifier],
+        policy: &Policy,
+        change: &Change,
+    ) -> Vec<(String, String)> {
+        let sends =
+            notifiers
+                .iter()
+                .filter(|n| n.wants(change, policy))
+                .map(|notifier| async move {
+                    let result = notifier.send(self, change).await;
+                    match &result {
+                        Ok(()) => {
+                            metrics().notification(&notifier.name, "sent");
+                            info!(
+                                notifier = %notifier.name,
+                                kind = notifier.backend.kind(),
+                                record = %change.domain,
+                                event = change.action.as_str(),
+                                "Notification sent"
+                            );
+                        }
+                        Err(e) => {
+                            metrics().notification(&notifier.name, "failed");
+                            warn!("Notifier {} failed: {}", notifier.name, e);
+                        }
+                    }
+                    result.err().map(|e| (notifier.name.clone(), e))
+                });
+        join_all(sends).await.into_iter().flatten().collect()
+    }
+
+    /// The notifier's transport, built again only when a reload changed it.
+    fn transport(&self, name: &str, config: &SmtpConfig) -> Result<smtp::Transport, String> {
+        let mut transports = self.transports.lock().expect("notifiers lock poisoned");
+        if let Some((built_from, transport)) = transports.get(name) {
+            if built_from == config {
+                return Ok(transport.clone());
+            }
+        }
+        let transport = smtp::transport(config)?;
+        transports.insert(name.to_string(), (config.clone(), transport.clone()));
+        Ok(transport)
+    }
+
+    /// Each change is sent by its own task, so a slow service can't make this
+    /// subscriber fall behind.
+    pub async fn run(self, live: Arc<Live>, mut changes: broadcast::Receiver<Change>) {
+        loop {
+            match changes.recv().await {
+                Ok(change) => {
+                    let (notifiers, runtime) = (self.clone(), live.load_full());
+                    tokio::spawn(async move {
+                        let config = &runtime.config;
+                        notifiers
+                            .notify(&config.notifiers, &config.policy, &change)
+                            .await
+                    });
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    error!("Notifiers fell behind, {} changes weren't sent", missed)
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message as Email, Tokio1Executor};
+
+use crate::notify::{Message, TIMEOUT};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmtpConfig {
+    /// `smtps://host`, `smtp://host:587?tls=required`, or plain `smtp://host`
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+pub fn check_url(url: &str) -> Result<(), String> {
+    AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// `name@example.com` or `Name <name@example.com>`.
+pub fn check_address(address: &str) -> Result<(), String> {
+    address
+        .parse::<Mailbox>()
+        .map(|_| ())
+        .map_err(|e| format!("{:?}: {}", address, e))
+}
+
+/// A transport for `config`, built once per notifier and reused for every
+/// message it sends.
+pub type Transport = AsyncSmtpTransport<Tokio1Executor>;
+
+pub fn transport(config: &SmtpConfig) -> Result<Transport, String> {
+    let mut transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.url)
+        .map_err(|e| e.to_string())?
+        .timeout(Some(TIMEOUT));
+    if let Some(username) = &config.username {
+        transport = transport.credentials(Credentials::new(
+            username.clone(),
+            config.password.clone().unwrap_or_default(),
+        ));
+    }
+    Ok(transport.build())
+}
+
+/// The title is the subject.
+pub async fn send(
+    transport: &Transport,
+    config: &SmtpConfig,
+    message: &Message,
+) -> Result<(), String> {
+    let mut email = Email::builder()
+        .from(parse(&config.from)?)
+        .subject(&message.title)
+        .header(ContentType::TEXT_PLAIN);
+    for to in &config.to {
+        email = email.to(parse(to)?);
+    }
+    let email = email
+        .body(message.body.clone())
+        .map_err(|e| e.to_string())?;
+    transport
+        .send(email)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn parse(address: &str) -> Result<Mailbox, String> {
+    address.parse().map_err(|e| format!("{:?}: {}", address, e))
+}
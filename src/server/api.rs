@@ -1,34 +1,65 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use actix_web::dev::ServiceRequest;
-use actix_web::middleware::{Condition, Logger};
+use actix_web::http::Method;
 use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_httpauth::extractors::basic::BasicAuth;
 use actix_web_httpauth::extractors::{basic, AuthenticationError};
 use actix_web_httpauth::middleware::HttpAuthentication;
-use log::info;
+use tracing::{info, warn};
 
-use crate::aws::cloudflare::Cloudflare;
+use crate::notify::Notifier;
 use crate::server::auth::Auth;
+use crate::server::client_cert::{self, ClientIdentity, IdentityMap};
+use crate::server::health::Readiness;
+use crate::server::heartbeat::Heartbeats;
+use crate::server::history::History;
 use crate::server::ip::get_ip_from_request;
+use crate::server::metrics::metrics;
+use crate::server::policy::Policy;
 use crate::server::routes;
 use crate::server::routes::admin;
+use crate::server::state::Live;
+use crate::server::trace;
+use crate::server::webhooks::{Webhook, Webhooks};
+use crate::tls::acme::{HttpChallenges, Solver};
+use crate::tls::certs::CertStore;
+use crate::tls::{acme, certs, TlsConfig};
 use crate::DynIpError;
 
 #[derive(Clone)]
 pub struct ApiConfig {
     pub salt: String,
     pub auth: Auth,
+    /// Take the client IP from `X-Real-IP`/`X-Forwarded-For`. Only safe behind a
+    /// reverse proxy that sets them.
+    pub trust_proxy_headers: bool,
+    pub client_identities: IdentityMap,
+    pub policy: Policy,
+    pub webhooks: Vec<Webhook>,
+    pub notifiers: Vec<Notifier>,
 }
 
-async fn validator(
+/// Lets requests through with valid credentials, or with a client certificate
+/// for updates, counting everything it refuses.
+pub async fn validator(
     req: ServiceRequest,
-    credentials: BasicAuth,
+    credentials: Option<BasicAuth>,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    if let Some(api_config) = req.app_data::<web::Data<ApiConfig>>() {
-        if api_config.auth.check_credentials(credentials) {
+    if let Some(live) = req.app_data::<web::Data<Live>>() {
+        let auth = live.load().config.auth.clone();
+        let authorized = match credentials {
+            // Credentials can be turned on and off by a reload, so this runs regardless
+            _ if !auth.has_credentials() => true,
+            Some(credentials) => auth.check_credentials(credentials),
+            // Certificate holders may only update, and only their own records
+            None => req.method() == Method::PATCH && req.conn_data::<ClientIdentity>().is_some(),
+        };
+        if authorized {
             Ok(req)
         } else {
+            metrics().auth_failure();
             let config = req
                 .app_data::<basic::Config>()
                 .cloned()
@@ -37,36 +68,106 @@ async fn validator(
             Err((AuthenticationError::from(config).into(), req))
         }
     } else {
-        panic!("ApiConfig data not found.")
+        panic!("Runtime data not found.")
     }
 }
 
-pub async fn start<'a>(
-    listen: &SocketAddr,
-    route_53: Cloudflare,
-    api_config: ApiConfig,
+pub async fn start(
+    listen: &[SocketAddr],
+    tls: Option<TlsConfig>,
+    live: Arc<Live>,
+    history: History,
+    readiness: Readiness,
+    webhooks: Webhooks,
+    heartbeats: Heartbeats,
 ) -> Result<(), DynIpError> {
+    let readiness = web::Data::new(readiness);
+    let challenges = HttpChallenges::default();
+    let tls_listener = match tls {
+        Some(tls) => {
+            let store = Arc::new(CertStore::new(tls.cert_path, tls.key_path));
+            if let Err(e) = store.reload() {
+                if tls.acme.is_none() {
+                    return Err(e);
+                }
+                warn!("No TLS certificate yet, waiting for ACME: {}", e);
+            }
+            tokio::spawn(store.clone().watch());
+            if let Some(acme_config) = tls.acme {
+                let solver = Solver {
+                    challenge: acme_config.challenge,
+                    http_challenges: challenges.clone(),
+                    live: live.clone(),
+                };
+                tokio::spawn(acme::run(acme_config, store.clone(), solver));
+            }
+            let mtls = match tls.mtls {
+                Some(mtls) => Some((
+                    mtls.listen,
+                    certs::server_config(store.clone(), Some(&mtls.ca_path))?,
+                )),
+                None => None,
+            };
+            Some((tls.listen, certs::server_config(store, None)?, mtls))
+        }
+        None => None,
+    };
+
     info!("Starting server on {:?}", listen);
-    HttpServer::new(move || {
-        let auth = HttpAuthentication::basic(validator);
+    let mut server = HttpServer::new(move || {
+        let auth = HttpAuthentication::with_fn(validator);
         App::new()
-            .wrap(Logger::default())
-            .app_data(web::Data::new(api_config.clone()))
-            .app_data(web::Data::new(route_53.clone()))
+            .wrap_fn(trace::trace_request)
+            .app_data(web::Data::from(live.clone()))
+            .app_data(web::Data::new(challenges.clone()))
+            .app_data(web::Data::new(history.clone()))
+            .app_data(readiness.clone())
+            .app_data(web::Data::new(webhooks.clone()))
+            .app_data(web::Data::new(heartbeats.clone()))
+            .route("/healthz", web::get().to(routes::health::healthz))
+            .route("/readyz", web::get().to(routes::health::readyz))
+            .route(
+                "/.well-known/acme-challenge/{token}",
+                web::get().to(routes::acme::http_challenge),
+            )
+            // DNS-01 helper for lego's httpreq and acme-dns clients
+            .service(
+                web::scope("/acme")
+                    .route("/present", web::post().to(routes::acme::present))
+                    .route("/cleanup", web::post().to(routes::acme::cleanup))
+                    .route("/update", web::post().to(routes::acme::acme_dns_update)),
+            )
+            .service(
+                web::scope("/metrics")
+                    .wrap(HttpAuthentication::with_fn(validator))
+                    .route("", web::get().to(routes::metrics::index)),
+            )
             .service(
                 web::scope("/api")
-                    .wrap(Condition::new(api_config.auth.has_credentials(), auth))
+                    .wrap(auth)
                     .route("/admin", web::get().to(admin::index))
+                    .route("/events", web::get().to(routes::events::index))
+                    .route("/readyz", web::get().to(routes::health::report))
+                    .route("/history", web::get().to(routes::history::index))
+                    .route(
+                        "/webhooks/deliveries",
+                        web::get().to(routes::webhooks::deliveries),
+                    )
                     .service(
                         web::scope("/domains")
                             .route("", web::get().to(routes::domains::index))
                             .route("", web::post().to(routes::domains::add))
+                            .route(
+                                "",
+                                web::patch().to(routes::domains::update_from_client_certificate),
+                            )
                             .service(
                                 web::scope("/{id}")
                                     .route(
                                         "",
                                         web::patch().to(routes::domains::update_with_peer_address),
                                     )
+                                    .route("", web::put().to(routes::domains::edit))
                                     .route("", web::delete().to(routes::domains::destroy)),
                             )
                             .route(
@@ -86,8 +187,16 @@ pub async fn start<'a>(
                 }),
             )
     })
-    .bind(listen)?
-    .run()
-    .await?;
+    .on_connect(client_cert::on_connect)
+    .bind(listen)?;
+    if let Some((tls_listen, server_config, mtls)) = tls_listener {
+        info!("Starting TLS server on {:?}", tls_listen);
+        server = server.bind_rustls_0_23(tls_listen, server_config)?;
+        if let Some((mtls_listen, mtls_config)) = mtls {
+            info!("Starting mTLS server on {:?}", mtls_listen);
+            server = server.bind_rustls_0_23(mtls_listen, mtls_config)?;
+        }
+    }
+    server.run().await?;
     Ok(())
 }
@@ -0,0 +1,115 @@
+use std::any::Any;
+use std::collections::HashMap;
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+/// Names presented by a verified client certificate: the subject CN and DNS SANs.
+#[derive(Clone, Debug)]
+pub struct ClientIdentity {
+    pub names: Vec<String>,
+}
+
+impl ClientIdentity {
+    pub fn from_der(der: &[u8]) -> Option<ClientIdentity> {
+        let (_, cert) = parse_x509_certificate(der).ok()?;
+        let mut names: Vec<String> = cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(|cn| cn.to_lowercase())
+            .collect();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                if let GeneralName::DNSName(dns) = name {
+                    names.push(dns.to_lowercase());
+                }
+            }
+        }
+        names.sort();
+        names.dedup();
+        (!names.is_empty()).then_some(ClientIdentity { names })
+    }
+}
+
+/// Extra records a certificate name may update, beyond a record whose domain
+/// matches the name itself.
+#[derive(Clone, Default, PartialEq)]
+pub struct IdentityMap {
+    entries: HashMap<String, Vec<String>>,
+}
+
+impl IdentityMap {
+    /// Parses `name=domain|domain,name=domain`.
+    pub fn parse(s: &str) -> Result<IdentityMap, String> {
+        let mut entries = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, domains) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid identity mapping: {}", entry))?;
+            entries.push((
+                name.to_string(),
+                domains.split('|').map(str::to_string).collect(),
+            ));
+        }
+        Ok(IdentityMap::new(entries))
+    }
+
+    pub fn new(entries: impl IntoIterator<Item = (String, Vec<String>)>) -> IdentityMap {
+        let entries = entries
+            .into_iter()
+            .map(|(name, domains)| {
+                let domains = domains
+                    .iter()
+                    .map(|d| d.trim().trim_end_matches('.').to_lowercase())
+                    .filter(|d| !d.is_empty())
+                    .collect();
+                (name.trim().to_lowercase(), domains)
+            })
+            .collect();
+        IdentityMap { entries }
+    }
+
+    pub fn permits(&self, identity: &ClientIdentity, domain: &str) -> bool {
+        identity.names.iter().any(|name| {
+            name_matches(name, domain)
+                || self
+                    .entries
+                    .get(name)
+                    .is_some_and(|domains| domains.iter().any(|d| name_matches(d, domain)))
+        })
+    }
+}
+
+/// Exact match, or a `*.` wildcard covering exactly one label.
+fn name_matches(pattern: &str, domain: &str) -> bool {
+    if pattern.eq_ignore_ascii_case(domain) {
+        return true;
+    }
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => domain
+            .to_lowercase()
+            .strip_suffix(suffix)
+            .and_then(|label| label.strip_suffix('.'))
+            .is_some_and(|label| !label.is_empty() && !label.contains('.')),
+        None => false,
+    }
+}
+
+/// Stores the client certificate identity on the connection so handlers can
+/// read it with `req.conn_data::<ClientIdentity>()`.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        let (_, session) = stream.get_ref();
+        if let Some(identity) = session
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| ClientIdentity::from_der(cert.as_ref()))
+        {
+            data.insert(identity);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::future::join_all;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::server::state::Runtime;
+
+/// How long a readiness result is reused, so probes don't hammer Cloudflare.
+const CACHE_FOR: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Component {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Component {
+    fn from_result<E: ToString>(result: Result<(), E>) -> Component {
+        match result {
+            Ok(()) => Component {
+                status: Status::Ok,
+                error: None,
+            },
+            Err(e) => Component {
+                status: Status::Error,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    /// Ok only when every component is
+    pub status: Status,
+    /// Unix seconds
+    pub checked_at: u64,
+    /// `zone:<apex>` for each zone, and `data_dir`
+    pub components: BTreeMap<String, Component>,
+}
+
+/// Checks what the server needs to do its job: each zone's credentials and
+/// a writable data directory.
+#[derive(Clone)]
+pub struct Readiness {
+    data_dir: PathBuf,
+    cache_for: Duration,
+    last: Arc<Mutex<Option<(Instant, Report)>>>,
+}
+
+impl Readiness {
+    pub fn new(data_dir: PathBuf) -> Readiness {
+        Readiness {
+            data_dir,
+            cache_for: CACHE_FOR,
+            last: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Reuses reports for `cache_for` rather than 30 seconds.
+    pub fn caching_for(self, cache_for: Duration) -> Readiness {
+        Readiness { cache_for, ..self }
+    }
+
+    /// Drops the cached report, e.g. when a reload changed the zones.
+    pub async fn clear(&self) {
+        *self.last.lock().await = None;
+    }
+
+    /// The last report if it's recent, otherwise a new one. Concurrent probes
+    /// wait for the same check.
+    pub async fn check(&self, runtime: &Runtime) -> Report {
+        let mut last = self.last.lock().await;
+        if let Some((at, report)) = last.as_ref() {
+            if at.elapsed() < self.cache_for {
+                return report.clone();
+            }
+        }
+        let report = self.run(runtime).await;
+        *last = Some((Instant::now(), report.clone()));
+        report
+    }
+
+    async fn run(&self, runtime: &Runtime) -> Report {
+        let zones = join_all(runtime.zones.iter().map(|zone| async move {
+            (
+                format!("zone:{}", zone.domain_name),
+                Component::from_result(zone.check().await),
+            )
+        }))
+        .await;
+        let mut components: BTreeMap<_, _> = zones.into_iter().collect();
+        components.insert(
+            "data_dir".to_string(),
+            Component::from_result(self.check_data_dir()),
+        );
+        let status = if components.values().all(|c| c.status == Status::Ok) {
+            Status::Ok
+        } else {
+            Status::Error
+        };
+        Report {
+            status,
+            checked_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            components,
+        }
+    }
+
+    /// History and ACME state are written here.
+    pub fn check_data_dir(&self) -> Result<(), String> {
+        let probe = self.data_dir.join(".readyz");
+        std::fs::create_dir_all(&self.data_dir)
+            .and_then(|_| std::fs::write(&probe, b""))
+            .and_then(|_| std::fs::remove_file(&probe))
+            .map_err(|e| format!("{} is not writable: {}", self.data_dir.display(), e))
+    }
+}
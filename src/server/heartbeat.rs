@@ -0,0 +1,485 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::aws::record::{DisplayRecord, Record};
+use crate::server::history::{Action, Change, History, HistoryQuery};
+use crate::server::policy::Policy;
+use crate::server::state::{Live, Runtime};
+
+/// How often records are checked against their expected interval.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// The client named in the history for changes made when a lease runs out.
+const LEASE_CLIENT: &str = "lease";
+
+/// When a record was last refreshed, by an update whether or not its value
+/// changed, or by being created or edited.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub domain: String,
+    pub record_type: String,
+    /// Unix seconds
+    pub last_seen: u64,
+    /// Set once the stale alert has gone out, cleared by the next refresh
+    #[serde(default)]
+    pub stale: bool,
+}
+
+/// Asked for when a record is created, it runs out unless the record is
+/// refreshed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Lease {
+    /// Seconds
+    pub duration: u64,
+    /// The value an expired record is parked on, it's deleted without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<String>,
+}
+
+/// A record's lease, kept by the provider's ID for the record so records of
+/// the same name each have their own.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Leased {
+    pub source_id: String,
+    pub domain: String,
+    pub record_type: String,
+    #[serde(flatten)]
+    pub lease: Lease,
+    /// Unix seconds of the creation or the last refresh
+    pub renewed_at: u64,
+    /// Set once the expired lease has parked the record, cleared by the next refresh
+    #[serde(default)]
+    pub parked: bool,
+}
+
+impl Heartbeat {
+    fn new(domain: &str, record_type: &str, last_seen: u64) -> Heartbeat {
+        Heartbeat {
+            domain: domain.to_string(),
+            record_type: record_type.to_string(),
+            last_seen,
+            stale: false,
+        }
+    }
+
+    /// Whether it's gone longer than `interval` without a refresh.
+    pub fn missed(&self, interval: Duration, now: u64) -> bool {
+        now.saturating_sub(self.last_seen) > interval.as_secs()
+    }
+}
+
+/// Each record's last refresh and lease, saved to a JSON file so they survive
+/// restarts. Records with an expected interval are alerted on, with a `stale`
+/// change, when their client misses it, and leased records are deleted or
+/// parked once their lease runs out.
+#[derive(Clone, Default)]
+pub struct Heartbeats {
+    writer: Option<mpsc::Sender<Job>>,
+    inner: Arc<Mutex<Inner>>,
+    /// Unix seconds of the load. Clients can't have reached a server that
+    /// wasn't running, so nothing is overdue, and no lease runs out, until a
+    /// full interval or lease after it.
+    started: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    beats: BTreeMap<(String, String), Heartbeat>,
+    /// By source ID
+    leases: BTreeMap<String, Leased>,
+}
+
+/// File work for the writer thread, so a refresh never waits on disk.
+enum Job {
+    /// Replace the file with this snapshot
+    Save(Saved),
+    Flush(mpsc::Sender<()>),
+}
+
+/// The file.
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    heartbeats: Vec<Heartbeat>,
+    leases: Vec<Leased>,
+}
+
+impl Heartbeats {
+    /// Starts from the change history when there's no file yet.
+    pub fn load(path: PathBuf, history: &History) -> Heartbeats {
+        let (writer, jobs) = mpsc::channel();
+        let heartbeats = Heartbeats {
+            writer: Some(writer),
+            started: now(),
+            ..Heartbeats::default()
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<Saved>(&contents) {
+                Ok(saved) => {
+                    let mut inner = heartbeats.lock();
+                    for beat in saved.heartbeats {
+                        inner
+                            .beats
+                            .insert((beat.domain.clone(), beat.record_type.clone()), beat);
+                    }
+                    for leased in saved.leases {
+                        inner.leases.insert(leased.source_id.clone(), leased);
+                    }
+                }
+                Err(e) => warn!("Discarding unreadable heartbeats {:?}: {}", path, e),
+            },
+            Err(_) => heartbeats.seed(history),
+        }
+        std::thread::spawn(move || write_heartbeats(path, jobs));
+        heartbeats
+    }
+
+    /// Waits until every refresh so far is on disk.
+    pub fn flush(&self) {
+        if let Some(writer) = &self.writer {
+            let (done, wait) = mpsc::channel();
+            if writer.send(Job::Flush(done)).is_ok() {
+                let _ = wait.recv();
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("heartbeats lock poisoned")
+    }
+
+    fn seed(&self, history: &History) {
+        let mut inner = self.lock();
+        for change in history.list(&HistoryQuery::default()).into_iter().rev() {
+            let beat = Heartbeat::new(&change.domain, &change.record_type, change.at);
+            let key = (change.domain, change.record_type);
+            match change.action {
+                Action::Delete => {
+                    inner.beats.remove(&key);
+                }
+                Action::Stale => {}
+                _ => {
+                    inner.beats.insert(key, beat);
+                }
+            }
+        }
+    }
+
+    /// Refreshes the record, renewing its lease. Records of the same name and
+    /// type share an ID, so an update renews the leases of all of them.
+    pub fn beat(&self, domain: &str, record_type: &str) {
+        let now = now();
+        let mut inner = self.lock();
+        inner.touch(domain, record_type, now);
+        for leased in inner.leases.values_mut() {
+            if leased.domain == domain && leased.record_type == record_type {
+                leased.renewed_at = now;
+                leased.parked = false;
+            }
+        }
+        self.save(&inner);
+    }
+
+    /// Refreshes a new record's name and starts its lease, leaving the leases
+    /// of other records of the same name alone.
+    pub fn created(&self, record: &DisplayRecord, lease: Option<Lease>) {
+        let now = now();
+        let mut inner = self.lock();
+        inner.touch(&record.domain, &record.record_type, now);
+        if let Some(lease) = lease {
+            let leased = Leased {
+                source_id: record.source_id.clone(),
+                domain: record.domain.clone(),
+                record_type: record.record_type.clone(),
+                lease,
+                renewed_at: now,
+                parked: false,
+            };
+            inner.leases.insert(record.source_id.clone(), leased);
+        }
+        self.save(&inner);
+    }
+
+    pub fn forget(&self, domain: &str, record_type: &str) {
+        let mut inner = self.lock();
+        if inner
+            .beats
+            .remove(&(domain.to_string(), record_type.to_string()))
+            .is_some()
+        {
+            self.save(&inner);
+        }
+    }
+
+    /// Drops the lease of a record that's gone.
+    pub fn end_lease(&self, source_id: &str) {
+        let mut inner = self.lock();
+        if inner.leases.remove(source_id).is_some() {
+            self.save(&inner);
+        }
+    }
+
+    pub fn get(&self, domain: &str, record_type: &str) -> Option<Heartbeat> {
+        self.lock()
+            .beats
+            .get(&(domain.to_string(), record_type.to_string()))
+            .cloned()
+    }
+
+    pub fn list(&self) -> Vec<Heartbeat> {
+        self.lock().beats.values().cloned().collect()
+    }
+
+    pub fn lease(&self, source_id: &str) -> Option<Leased> {
+        self.lock().leases.get(source_id).cloned()
+    }
+
+    /// Unix seconds, no earlier than a full lease after the load.
+    pub fn lease_expires_at(&self, leased: &Leased) -> u64 {
+        leased
+            .renewed_at
+            .max(self.started)
+            .saturating_add(leased.lease.duration)
+    }
+
+    /// Fills in each record's last refresh, expected interval, staleness and
+    /// lease expiry.
+    pub fn annotate(&self, records: &mut [DisplayRecord], policy: &Policy) {
+        let now = now();
+        for record in records {
+            let beat = self.get(&record.domain, &record.record_type);
+            let interval = policy.expected_interval(&record.domain);
+            record.last_seen = beat.as_ref().map(|b| b.last_seen);
+            record.lease_expires_at = self
+                .lease(&record.source_id)
+                .map(|leased| self.lease_expires_at(&leased));
+            record.expected_interval = interval.map(|i| i.as_secs());
+            record.stale = beat
+                .zip(interval)
+                .is_some_and(|(beat, interval)| beat.missed(interval, now));
+        }
+    }
+
+    /// Records that have missed their interval since the load and haven't
+    /// been alerted on.
+    pub fn overdue(&self, policy: &Policy, now: u64) -> Vec<Heartbeat> {
+        self.lock()
+            .beats
+            .values()
+            .filter(|b| !b.stale)
+            .filter(|b| {
+                policy.expected_interval(&b.domain).is_some_and(|interval| {
+                    b.missed(interval, now) && now.saturating_sub(self.started) > interval.as_secs()
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Leases that have run out on records that haven't been parked yet.
+    pub fn expired(&self, now: u64) -> Vec<Leased> {
+        self.lock()
+            .leases
+            .values()
+            .filter(|l| !l.parked && now > self.lease_expires_at(l))
+            .cloned()
+            .collect()
+    }
+
+    fn mark_stale(&self, domain: &str, record_type: &str) {
+        let mut inner = self.lock();
+        if let Some(beat) = inner
+            .beats
+            .get_mut(&(domain.to_string(), record_type.to_string()))
+        {
+            beat.stale = true;
+            self.save(&inner);
+        }
+    }
+
+    fn mark_parked(&self, source_id: &str) {
+        let mut inner = self.lock();
+        if let Some(leased) = inner.leases.get_mut(source_id) {
+            leased.parked = true;
+            self.save(&inner);
+        }
+    }
+
+    /// Alerts on overdue records and expires leases every half minute, taking
+    /// the intervals from the live config. Records deleted outside dyn-ip are
+    /// forgotten instead.
+    pub async fn run(self, live: Arc<Live>, history: History) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.check(&live.load_full(), &history, now()).await;
+        }
+    }
+
+    /// One round of [`Heartbeats::run`], as of `now`.
+    pub async fn check(&self, runtime: &Runtime, history: &History, now: u64) {
+        let overdue = self.overdue(&runtime.config.policy, now);
+        let expired = self.expired(now);
+        if overdue.is_empty() && expired.is_empty() {
+            return;
+        }
+        let zones = &runtime.zones;
+        let listing = match zones.list_display(&runtime.config.salt).await {
+            Ok(listing) => listing,
+            Err(e) => {
+                error!("Failed to list records for the heartbeat check: {}", e);
+                return;
+            }
+        };
+        let records = &listing.records;
+        for beat in overdue {
+            let Some(record) = records
+                .iter()
+                .find(|r| r.domain == beat.domain && r.record_type == beat.record_type)
+            else {
+                // Missing from a zone that failed isn't deleted
+                if listing.covers(zones, &beat.domain) {
+                    self.forget(&beat.domain, &beat.record_type);
+                }
+                continue;
+            };
+            warn!(
+                "{} {} missed its heartbeat, last seen {}s ago",
+                record.record_type,
+                record.domain,
+                now.saturating_sub(beat.last_seen)
+            );
+            self.mark_stale(&beat.domain, &beat.record_type);
+            history.record(Change::new(Action::Stale, record, None, None));
+        }
+        for leased in expired {
+            let Some(record) = records.iter().find(|r| r.source_id == leased.source_id) else {
+                if listing.covers(zones, &leased.domain) {
+                    self.end_lease(&leased.source_id);
+                }
+                continue;
+            };
+            // Others of the same name keep the heartbeat
+            let alone = records
+                .iter()
+                .filter(|r| r.domain == record.domain && r.record_type == record.record_type)
+                .count()
+                == 1;
+            self.expire(runtime, history, record, leased.lease.fallback, alone)
+                .await;
+        }
+    }
+
+    /// Parks the record on the fallback, or deletes it without one.
+    async fn expire(
+        &self,
+        runtime: &Runtime,
+        history: &History,
+        record: &DisplayRecord,
+        fallback: Option<String>,
+        alone: bool,
+    ) {
+        let (domain, record_type) = (&record.domain, &record.record_type);
+        let Some(fallback) = fallback else {
+            match runtime
+                .zones
+                .delete_record_by_source_id(domain, &record.source_id)
+                .await
+            {
+                Ok(()) => {
+                    warn!("{} {} lease expired, deleted", record_type, domain);
+                    self.end_lease(&record.source_id);
+                    if alone {
+                        self.forget(domain, record_type);
+                    }
+                    history.record(Change::new(
+                        Action::Delete,
+                        record,
+                        Some(record.ip.clone()),
+                        Some(LEASE_CLIENT.to_string()),
+                    ));
+                }
+                Err(e) => error!("Failed to delete {} {}: {}", record_type, domain, e),
+            }
+            return;
+        };
+        if record.ip == fallback {
+            self.mark_parked(&record.source_id);
+            return;
+        }
+        let mut parked: Record = record.into();
+        parked.set_value(fallback);
+        match runtime.zones.update_record(parked.clone()).await {
+            Ok(()) => {
+                warn!(
+                    "{} {} lease expired, parked on {}",
+                    record_type, domain, parked.ip
+                );
+                self.mark_parked(&record.source_id);
+                history.record(Change::new(
+                    Action::Update,
+                    &parked.for_display(&runtime.config.salt),
+                    Some(record.ip.clone()),
+                    Some(LEASE_CLIENT.to_string()),
+                ));
+            }
+            Err(e) => error!("Failed to park {} {}: {}", record_type, domain, e),
+        }
+    }
+
+    /// Queued under the lock so the last snapshot written is the newest.
+    fn save(&self, inner: &Inner) {
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(Job::Save(Saved {
+                heartbeats: inner.beats.values().cloned().collect(),
+                leases: inner.leases.values().cloned().collect(),
+            }));
+        }
+    }
+}
+
+impl Inner {
+    fn touch(&mut self, domain: &str, record_type: &str, now: u64) {
+        let beat = self
+            .beats
+            .entry((domain.to_string(), record_type.to_string()))
+            .or_insert_with(|| Heartbeat::new(domain, record_type, now));
+        beat.last_seen = now;
+        beat.stale = false;
+    }
+}
+
+/// Runs until every `Heartbeats` clone is gone.
+fn write_heartbeats(path: PathBuf, jobs: mpsc::Receiver<Job>) {
+    for job in jobs {
+        match job {
+            Job::Save(saved) => {
+                if let Err(e) = write(&path, &saved) {
+                    error!("Failed to save heartbeats to {:?}: {}", path, e);
+                }
+            }
+            Job::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+fn write(path: &PathBuf, saved: &Saved) -> std::io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(saved).unwrap_or_default())?;
+    std::fs::rename(tmp, path)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,325 @@
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{error, warn};
+
+use crate::aws::record::DisplayRecord;
+
+/// Changes kept in memory and served by `/api/history`.
+const MAX_ENTRIES: usize = 1000;
+/// Changes a slow subscriber can fall behind by before it misses some.
+const SUBSCRIBER_BACKLOG: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Create,
+    Update,
+    Edit,
+    Delete,
+    /// A record's client missed its expected interval
+    Stale,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Create => "create",
+            Action::Update => "update",
+            Action::Edit => "edit",
+            Action::Delete => "delete",
+            Action::Stale => "stale",
+        }
+    }
+}
+
+impl std::str::FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Action, String> {
+        match s.to_lowercase().as_str() {
+            "create" => Ok(Action::Create),
+            "update" => Ok(Action::Update),
+            "edit" => Ok(Action::Edit),
+            "delete" => Ok(Action::Delete),
+            "stale" => Ok(Action::Stale),
+            _ => Err(format!(
+                "Invalid action: {}, expected create, update, edit, delete or stale",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Change {
+    /// Increases by one with each change, assigned when it's recorded
+    pub seq: u64,
+    /// Unix seconds
+    pub at: u64,
+    pub action: Action,
+    pub id: String,
+    pub domain: String,
+    pub record_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_value: Option<String>,
+    /// Address of the client that made the change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+}
+
+impl Change {
+    pub fn new(
+        action: Action,
+        record: &DisplayRecord,
+        old_value: Option<String>,
+        client: Option<String>,
+    ) -> Change {
+        Change {
+            seq: 0,
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            action,
+            id: record.id.clone(),
+            domain: record.domain.clone(),
+            record_type: record.record_type.clone(),
+            old_value,
+            new_value: match action {
+                Action::Delete => None,
+                _ => Some(record.ip.clone()),
+            },
+            client,
+        }
+    }
+}
+
+/// Which changes a subscriber wants, from its `events` and `domains` settings.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChangeFilter {
+    /// Every action but `stale` when empty, alerts have to be asked for
+    pub events: Vec<Action>,
+    /// Names with their subdomains, every name when empty
+    pub domains: Vec<String>,
+}
+
+impl ChangeFilter {
+    pub fn matches(&self, change: &Change) -> bool {
+        let event = self.wants(change.action);
+        let domain = self.domains.is_empty()
+            || self
+                .domains
+                .iter()
+                .any(|d| change.domain == *d || change.domain.ends_with(&format!(".{}", d)));
+        event && domain
+    }
+
+    pub fn wants(&self, action: Action) -> bool {
+        if self.events.is_empty() {
+            action != Action::Stale
+        } else {
+            self.events.contains(&action)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct HistoryQuery {
+    pub id: Option<String>,
+    pub domain: Option<String>,
+    pub record_type: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Record changes made through the API, appended to a JSON lines file so they
+/// survive restarts. Each change is also sent to subscribers.
+#[derive(Clone)]
+pub struct History {
+    writer: Option<mpsc::Sender<Job>>,
+    inner: Arc<Mutex<Inner>>,
+    changes: broadcast::Sender<Change>,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History {
+            writer: None,
+            inner: Arc::default(),
+            changes: broadcast::channel(SUBSCRIBER_BACKLOG).0,
+        }
+    }
+}
+
+/// File work for the writer thread, so recording a change never waits on disk.
+enum Job {
+    Append(Change),
+    /// Replace the file with these entries
+    Rewrite(Vec<Change>),
+    Flush(mpsc::Sender<()>),
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: VecDeque<Change>,
+    lines_on_disk: usize,
+    last_seq: u64,
+}
+
+impl History {
+    pub fn load(path: PathBuf) -> History {
+        let mut inner = Inner::default();
+        if let Ok(contents) = std::fs::read_to_string(&path) {
+            for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+                inner.lines_on_disk += 1;
+                match serde_json::from_str::<Change>(line) {
+                    Ok(change) => {
+                        inner.last_seq = inner.last_seq.max(change.seq);
+                        inner.push(change)
+                    }
+                    Err(e) => warn!("Skipping unreadable history entry in {:?}: {}", path, e),
+                }
+            }
+        }
+        let (writer, jobs) = mpsc::channel();
+        std::thread::spawn(move || write_changes(path, jobs));
+        History {
+            writer: Some(writer),
+            inner: Arc::new(Mutex::new(inner)),
+            ..History::default()
+        }
+    }
+
+    /// Changes recorded from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+
+    pub fn record(&self, mut change: Change) {
+        let mut inner = self.inner.lock().expect("history lock poisoned");
+        inner.last_seq += 1;
+        change.seq = inner.last_seq;
+        // Sent under the lock so subscribers see changes in order. Nobody
+        // listening isn't an error.
+        let _ = self.changes.send(change.clone());
+        let Some(writer) = &self.writer else {
+            inner.push(change);
+            return;
+        };
+        // Rewrite once the file holds twice what is kept, so it stays bounded
+        let job = if inner.lines_on_disk >= MAX_ENTRIES * 2 {
+            inner.push(change);
+            inner.lines_on_disk = inner.entries.len();
+            Job::Rewrite(inner.entries.iter().cloned().collect())
+        } else {
+            inner.push(change.clone());
+            inner.lines_on_disk += 1;
+            Job::Append(change)
+        };
+        // Queued under the lock so the file keeps the order of `seq`
+        let _ = writer.send(job);
+    }
+
+    /// Waits until every change recorded so far is on disk.
+    pub fn flush(&self) {
+        if let Some(writer) = &self.writer {
+            let (done, wait) = mpsc::channel();
+            if writer.send(Job::Flush(done)).is_ok() {
+                let _ = wait.recv();
+            }
+        }
+    }
+
+    /// Newest first.
+    pub fn list(&self, query: &HistoryQuery) -> Vec<Change> {
+        let inner = self.inner.lock().expect("history lock poisoned");
+        inner
+            .entries
+            .iter()
+            .rev()
+            .filter(|c| query.id.as_ref().is_none_or(|id| &c.id == id))
+            .filter(|c| query.domain.as_ref().is_none_or(|d| &c.domain == d))
+            .filter(|c| {
+                query
+                    .record_type
+                    .as_ref()
+                    .is_none_or(|t| c.record_type.eq_ignore_ascii_case(t))
+            })
+            .take(query.limit.unwrap_or(MAX_ENTRIES))
+            .cloned()
+            .collect()
+    }
+
+    /// The newest change's `seq`, 0 before any.
+    pub fn last_seq(&self) -> u64 {
+        self.inner.lock().expect("history lock poisoned").last_seq
+    }
+
+    /// Changes still held after `seq`, oldest first, for subscribers catching up.
+    pub fn since(&self, seq: u64) -> Vec<Change> {
+        let inner = self.inner.lock().expect("history lock poisoned");
+        inner
+            .entries
+            .iter()
+            .filter(|c| c.seq > seq)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Inner {
+    fn push(&mut self, change: Change) {
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(change);
+    }
+}
+
+/// Runs until every `History` clone is gone.
+fn write_changes(path: PathBuf, jobs: mpsc::Receiver<Job>) {
+    for job in jobs {
+        let result = match job {
+            Job::Append(change) => append(&path, &change),
+            Job::Rewrite(entries) => rewrite(&path, &entries),
+            Job::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to write history to {:?}: {}", path, e);
+        }
+    }
+}
+
+fn rewrite(path: &PathBuf, entries: &[Change]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut contents = String::new();
+    for change in entries {
+        contents.push_str(&serde_json::to_string(change).unwrap_or_default());
+        contents.push('\n');
+    }
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(tmp, path)
+}
+
+fn append(path: &PathBuf, change: &Change) -> std::io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(
+        file,
+        "{}",
+        serde_json::to_string(change).unwrap_or_default()
+    )
+}
@@ -1,10 +1,17 @@
 use actix_web::HttpRequest;
 
+use crate::server::state::Current;
+
 pub fn get_ip_from_request(req: &HttpRequest) -> Option<String> {
+    let trust_proxy_headers = Current::of(req)
+        .map(|current| current.config.trust_proxy_headers)
+        .unwrap_or(true);
     let headers = req.headers();
     let ip = headers
         .get("X-Real-IP")
         .or_else(|| headers.get("X-Forwarded-For"))
+        .filter(|_| trust_proxy_headers)
         .and_then(|h| h.to_str().ok().map(|s| s.to_string()));
-    ip
+    // Without a reverse proxy in front (e.g. native TLS) the peer is the client.
+    ip.or_else(|| req.peer_addr().map(|addr| addr.ip().to_string()))
 }
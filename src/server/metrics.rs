@@ -0,0 +1,184 @@
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use tracing::error;
+
+use crate::server::heartbeat::Heartbeats;
+
+/// How long a record count is served before `/metrics` lists the records again.
+const RECORDS_MAX_AGE: Duration = Duration::from_secs(60);
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// The process wide metrics, counted from wherever the event happens.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    registry: Registry,
+    updates: IntCounterVec,
+    provider_requests: IntCounterVec,
+    auth_failures: IntCounter,
+    webhook_deliveries: IntCounterVec,
+    notifications: IntCounterVec,
+    request_duration: HistogramVec,
+    records: IntGauge,
+    since_update: GaugeVec,
+    records_listed: Mutex<Option<Instant>>,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let updates = IntCounterVec::new(
+            Opts::new("dyn_ip_updates_total", "IP updates by result"),
+            &["result"],
+        )
+        .unwrap();
+        let provider_requests = IntCounterVec::new(
+            Opts::new(
+                "dyn_ip_provider_requests_total",
+                "DNS provider API calls by HTTP status, or \"error\" when there was no response",
+            ),
+            &["status"],
+        )
+        .unwrap();
+        let auth_failures = IntCounter::new(
+            "dyn_ip_auth_failures_total",
+            "Requests refused for missing or wrong credentials",
+        )
+        .unwrap();
+        let webhook_deliveries = IntCounterVec::new(
+            Opts::new(
+                "dyn_ip_webhook_deliveries_total",
+                "Webhook delivery attempts by result",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let notifications = IntCounterVec::new(
+            Opts::new(
+                "dyn_ip_notifications_total",
+                "Notifications by notifier and result",
+            ),
+            &["notifier", "result"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "dyn_ip_http_request_duration_seconds",
+                "HTTP request latency by route",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let records = IntGauge::new("dyn_ip_records", "Records in all zones").unwrap();
+        let since_update = GaugeVec::new(
+            Opts::new(
+                "dyn_ip_record_seconds_since_update",
+                "Seconds since a record was last created, updated or edited through dyn-ip",
+            ),
+            &["domain", "record_type"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(updates.clone())).unwrap();
+        registry
+            .register(Box::new(provider_requests.clone()))
+            .unwrap();
+        registry.register(Box::new(auth_failures.clone())).unwrap();
+        registry
+            .register(Box::new(webhook_deliveries.clone()))
+            .unwrap();
+        registry.register(Box::new(notifications.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(records.clone())).unwrap();
+        registry.register(Box::new(since_update.clone())).unwrap();
+        Metrics {
+            registry,
+            updates,
+            provider_requests,
+            auth_failures,
+            webhook_deliveries,
+            notifications,
+            request_duration,
+            records,
+            since_update,
+            records_listed: Mutex::default(),
+        }
+    }
+
+    /// `changed`, `unchanged`, `rejected` or `failed`.
+    pub fn update(&self, result: &str) {
+        self.updates.with_label_values(&[result]).inc();
+    }
+
+    pub fn provider_request(&self, status: &str) {
+        self.provider_requests.with_label_values(&[status]).inc();
+    }
+
+    pub fn auth_failure(&self) {
+        self.auth_failures.inc();
+    }
+
+    /// `delivered`, `retrying` or `failed`.
+    pub fn webhook_delivery(&self, result: &str) {
+        self.webhook_deliveries.with_label_values(&[result]).inc();
+    }
+
+    /// `sent` or `failed`.
+    pub fn notification(&self, notifier: &str, result: &str) {
+        self.notifications
+            .with_label_values(&[notifier, result])
+            .inc();
+    }
+
+    pub fn request(&self, method: &str, route: &str, status: &str, elapsed: Duration) {
+        self.request_duration
+            .with_label_values(&[method, route, status])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn records_listed(&self, count: usize) {
+        self.records.set(count as i64);
+        *self.records_listed.lock().expect("metrics lock poisoned") = Some(Instant::now());
+    }
+
+    /// Whether the record count is too old to serve.
+    pub fn records_stale(&self) -> bool {
+        self.records_listed
+            .lock()
+            .expect("metrics lock poisoned")
+            .is_none_or(|at| at.elapsed() > RECORDS_MAX_AGE)
+    }
+
+    /// The Prometheus text format.
+    pub fn render(&self, heartbeats: &Heartbeats) -> String {
+        self.since_update.reset();
+        let now = now();
+        for beat in heartbeats.list() {
+            self.since_update
+                .with_label_values(&[&beat.domain, &beat.record_type])
+                .set(now.saturating_sub(beat.last_seen) as f64);
+        }
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
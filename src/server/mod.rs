@@ -1,4 +1,14 @@
 pub mod api;
 pub mod auth;
+pub mod client_cert;
+pub mod health;
+pub mod heartbeat;
+pub mod history;
 pub mod ip;
+pub mod metrics;
+pub mod mqtt;
+pub mod policy;
 pub mod routes;
+pub mod state;
+pub mod trace;
+pub mod webhooks;
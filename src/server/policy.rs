@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::aws::record::Record;
+
+/// Limits on what the API may write, on top of authentication.
+#[derive(Clone, Default)]
+pub struct Policy {
+    /// Refuse private, loopback, link-local and shared (CGNAT) addresses
+    pub public_ips_only: bool,
+    /// Record types that may be created, any when empty
+    pub record_types: Vec<String>,
+    /// Keyed by lowercase domain name
+    pub records: HashMap<String, RecordSettings>,
+    /// How often clients are expected to update, for records that don't set it
+    pub expected_interval: Option<Duration>,
+}
+
+/// Settings pinned for one record name.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordSettings {
+    pub ttl: Option<i64>,
+    pub proxied: Option<bool>,
+    pub comment: Option<String>,
+    /// Refuse IP updates, edits through the admin API still work
+    pub locked: bool,
+    /// Seconds between updates from the record's client, past which it's stale
+    pub expected_interval: Option<u64>,
+    /// Names of the only notifiers told about the record, whatever their `domains`
+    pub notify: Option<Vec<String>>,
+}
+
+impl Policy {
+    pub fn check_type(&self, record_type: &str) -> Result<(), String> {
+        if self.record_types.is_empty()
+            || self
+                .record_types
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(record_type))
+        {
+            Ok(())
+        } else {
+            Err(format!("{} records are not allowed", record_type))
+        }
+    }
+
+    /// Values that aren't addresses (CNAME targets, TXT, ...) always pass.
+    pub fn check_value(&self, value: &str) -> Result<(), String> {
+        match value.parse::<IpAddr>() {
+            Ok(ip) if self.public_ips_only && !is_public(&ip) => {
+                Err(format!("{} is not a public address", ip))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record(&self, domain: &str) -> Option<&RecordSettings> {
+        self.records
+            .get(domain.trim_end_matches('.').to_lowercase().as_str())
+    }
+
+    /// The record's own expected interval, or the default.
+    pub fn expected_interval(&self, domain: &str) -> Option<Duration> {
+        self.record(domain)
+            .and_then(|s| s.expected_interval)
+            .map(Duration::from_secs)
+            .or(self.expected_interval)
+    }
+
+    /// Overwrites the record's TTL, proxied flag and comment with any pinned ones.
+    pub fn apply(&self, record: &mut Record) {
+        if let Some(settings) = self.record(&record.domain) {
+            record.ttl = settings.ttl.unwrap_or(record.ttl);
+            record.proxied = settings.proxied.unwrap_or(record.proxied);
+            if settings.comment.is_some() {
+                record.comment = settings.comment.clone().filter(|c| !c.is_empty());
+            }
+        }
+    }
+}
+
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // fc00::/7 unique local, fe80::/10 link-local, 2001:db8::/32 documentation
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+                || ip.to_ipv4_mapped().is_some())
+        }
+    }
+}
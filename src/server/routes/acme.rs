@@ -0,0 +1,192 @@
+use crate::aws::record::{Record, RecordType};
+use crate::aws::zones::Zones;
+use crate::server::api::ApiConfig;
+use crate::server::client_cert::ClientIdentity;
+use crate::server::state::Current;
+use crate::tls::acme::HttpChallenges;
+use crate::DynIpError::{self, InvalidChallenge, RecordNotPermitted, Unauthorized};
+use actix_web::{web, HttpRequest, HttpResponse, Responder, Result};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+
+const CHALLENGE_PREFIX: &str = "_acme-challenge.";
+/// acme-dns keeps the two most recent values so a wildcard and its apex can be
+/// validated in the same order.
+const ACME_DNS_KEEP: usize = 2;
+
+pub async fn http_challenge(
+    challenges: web::Data<HttpChallenges>,
+    token: web::Path<String>,
+) -> Result<impl Responder> {
+    Ok(match challenges.get(&token) {
+        Some(key_authorization) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(key_authorization),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+/// lego `httpreq` body. The default mode sends `fqdn`/`value`, `HTTPREQ_MODE=RAW`
+/// sends `domain`/`keyAuth` and leaves the digest to us.
+#[derive(Deserialize)]
+pub struct HttpReqBody {
+    pub fqdn: Option<String>,
+    pub value: Option<String>,
+    pub domain: Option<String>,
+    #[serde(rename = "keyAuth")]
+    pub key_auth: Option<String>,
+}
+
+impl HttpReqBody {
+    /// Returns the host being validated and the TXT value.
+    fn challenge(&self) -> Result<(String, String), DynIpError> {
+        match (&self.fqdn, &self.value, &self.domain, &self.key_auth) {
+            (Some(fqdn), Some(value), _, _) => {
+                let name = fqdn.trim_end_matches('.').to_lowercase();
+                let host = name
+                    .strip_prefix(CHALLENGE_PREFIX)
+                    .ok_or_else(|| InvalidChallenge(format!("{} is not a challenge name", fqdn)))?;
+                Ok((host.to_string(), value.clone()))
+            }
+            (_, _, Some(domain), Some(key_auth)) => {
+                let host = domain.trim_start_matches("*.").trim_end_matches('.');
+                let value = URL_SAFE_NO_PAD.encode(digest(&SHA256, key_auth.as_bytes()));
+                Ok((host.to_lowercase(), value))
+            }
+            _ => Err(InvalidChallenge(
+                "Expected fqdn and value, or domain and keyAuth".to_string(),
+            )),
+        }
+    }
+}
+
+/// acme-dns `/update` body.
+#[derive(Deserialize)]
+pub struct AcmeDnsBody {
+    pub subdomain: String,
+    pub txt: String,
+}
+
+pub async fn present(
+    req: HttpRequest,
+    current: Current,
+    credentials: Option<BasicAuth>,
+    body: web::Json<HttpReqBody>,
+) -> Result<impl Responder> {
+    let (route_53, config) = (&current.zones, &current.config);
+    let (host, value) = body.challenge()?;
+    let key = credentials.and_then(|c| c.password().map(|p| p.to_string()));
+    authorize_host(&req, route_53, config, key, &host).await?;
+    create_challenge(route_53, &host, &value).await?;
+    Ok(web::Json(json!({})))
+}
+
+pub async fn cleanup(
+    req: HttpRequest,
+    current: Current,
+    credentials: Option<BasicAuth>,
+    body: web::Json<HttpReqBody>,
+) -> Result<impl Responder> {
+    let (route_53, config) = (&current.zones, &current.config);
+    let (host, value) = body.challenge()?;
+    let key = credentials.and_then(|c| c.password().map(|p| p.to_string()));
+    authorize_host(&req, route_53, config, key, &host).await?;
+    let name = format!("{}{}", CHALLENGE_PREFIX, host);
+    for record in route_53.list_txt_records(&name).await? {
+        if record.content.trim_matches('"') == value {
+            info!("Removing ACME challenge {}", name);
+            route_53
+                .delete_record_by_source_id(&name, &record.id)
+                .await?;
+        }
+    }
+    Ok(web::Json(json!({})))
+}
+
+pub async fn acme_dns_update(
+    req: HttpRequest,
+    current: Current,
+    body: web::Json<AcmeDnsBody>,
+) -> Result<impl Responder> {
+    let (route_53, config) = (&current.zones, &current.config);
+    let host = body
+        .subdomain
+        .trim_end_matches('.')
+        .trim_start_matches(CHALLENGE_PREFIX)
+        .to_lowercase();
+    let key = req
+        .headers()
+        .get("X-Api-Key")
+        .and_then(|h| h.to_str().ok())
+        .map(|s| s.to_string());
+    authorize_host(&req, route_53, config, key, &host).await?;
+    create_challenge(route_53, &host, &body.txt).await?;
+
+    let name = format!("{}{}", CHALLENGE_PREFIX, host);
+    let records = route_53.list_txt_records(&name).await?;
+    let stale = records.len().saturating_sub(ACME_DNS_KEEP);
+    for record in records.iter().take(stale) {
+        route_53
+            .delete_record_by_source_id(&name, &record.id)
+            .await?;
+    }
+    Ok(web::Json(json!({ "txt": body.txt })))
+}
+
+async fn create_challenge(route_53: &Zones, host: &str, value: &str) -> Result<(), DynIpError> {
+    if value.is_empty()
+        || !value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(InvalidChallenge(format!("Invalid TXT value: {}", value)));
+    }
+    let name = format!("{}{}", CHALLENGE_PREFIX, host);
+    let existing = route_53.list_txt_records(&name).await?;
+    if existing
+        .iter()
+        .any(|r| r.content.trim_matches('"') == value)
+    {
+        return Ok(());
+    }
+    info!("Presenting ACME challenge {}", name);
+    route_53
+        .create_record(Record {
+            domain: name,
+            record_type: RecordType::Txt.to_string(),
+            ip: value.to_string(),
+            ..Record::default()
+        })
+        .await?;
+    Ok(())
+}
+
+/// The caller must own `host`: either its client certificate covers it, or it
+/// presents the record ID of `host` as its key.
+async fn authorize_host(
+    req: &HttpRequest,
+    route_53: &Zones,
+    config: &ApiConfig,
+    key: Option<String>,
+    host: &str,
+) -> Result<(), DynIpError> {
+    if let Some(identity) = req.conn_data::<ClientIdentity>() {
+        return if config.client_identities.permits(identity, host) {
+            Ok(())
+        } else {
+            Err(RecordNotPermitted)
+        };
+    }
+    let key = key.filter(|k| !k.is_empty()).ok_or(Unauthorized)?;
+    let records = route_53.list_display_records(&config.salt).await?;
+    match records.iter().find(|r| r.id == key) {
+        Some(record) if record.domain.eq_ignore_ascii_case(host) => Ok(()),
+        Some(_) => Err(RecordNotPermitted),
+        None => Err(Unauthorized),
+    }
+}
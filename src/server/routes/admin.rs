@@ -1,8 +1,14 @@
-use crate::aws::cloudflare::Cloudflare;
-use actix_web::{web, HttpResponse, Responder, Result};
+use crate::server::state::Current;
+use actix_web::{HttpResponse, Responder, Result};
 
 const INDEX_HTML: &str = include_str!("../../../public/index.html");
-pub async fn index(route_53: web::Data<Cloudflare>) -> Result<impl Responder> {
-    let html = INDEX_HTML.replace("<!--DOMAIN-->", &route_53.domain_name);
+pub async fn index(current: Current) -> Result<impl Responder> {
+    let zones: String = current
+        .zones
+        .apexes()
+        .iter()
+        .map(|apex| format!("<option value=\"{0}\">.{0}</option>", apex))
+        .collect();
+    let html = INDEX_HTML.replace("<!--ZONES-->", &zones);
     Ok(HttpResponse::Ok().body(html))
 }
@@ -1,20 +1,67 @@
-use crate::aws::cloudflare::Cloudflare;
-use crate::aws::record::{Record, RrType};
+use crate::aws::record::{DisplayRecord, Record, RrType};
+use crate::aws::zones::Zones;
+use crate::server::api::ApiConfig;
+use crate::server::client_cert::ClientIdentity;
+use crate::server::heartbeat::{Heartbeats, Lease};
+use crate::server::history::{Action, Change, History};
 use crate::server::ip::get_ip_from_request;
-use crate::DynIpError::{DomainHashNotFound, MissingId, MissingIp};
-use crate::{ApiConfig, DomainParse};
-use actix_web::{web, HttpRequest, Responder, Result};
-use addr::parse_domain_name;
+use crate::server::metrics::metrics;
+use crate::server::state::Current;
+use crate::DynIpError;
+use crate::DynIpError::{
+    AmbiguousClientCertificate, DomainHashNotFound, DomainParse, InvalidRecord, MissingId,
+    MissingIp, RecordLocked, RecordNotPermitted,
+};
+use actix_web::{web, HttpRequest, Responder, ResponseError, Result};
+use addr::{parse_dns_name, parse_domain_name};
 use serde::Deserialize;
 use serde_json::json;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tracing::info;
 
 #[derive(Deserialize, Debug)]
 pub struct AddQuery {
     pub domain: String,
+    /// Apex the domain is relative to, needed with several zones
+    pub zone: Option<String>,
     pub ip: Option<IpAddr>,
     pub host: Option<String>,
     pub record_type: Option<String>,
+    /// TXT text, CAA value, or MX/SRV target
+    pub value: Option<String>,
+    pub priority: Option<u16>,
+    pub weight: Option<u16>,
+    pub port: Option<u16>,
+    pub flags: Option<u8>,
+    pub tag: Option<String>,
+    /// Seconds, or 1 for Cloudflare's automatic TTL
+    pub ttl: Option<i64>,
+    pub proxied: Option<bool>,
+    pub comment: Option<String>,
+    /// Seconds the record lives without an update
+    pub lease: Option<u64>,
+    /// Value the record is parked on when its lease runs out, instead of
+    /// deleting it
+    pub fallback: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EditQuery {
+    pub value: Option<String>,
+    pub priority: Option<u16>,
+    pub weight: Option<u16>,
+    pub port: Option<u16>,
+    pub flags: Option<u8>,
+    pub tag: Option<String>,
+    pub ttl: Option<i64>,
+    pub proxied: Option<bool>,
+    /// An empty comment removes it
+    pub comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct IpQuery {
+    pub ip: Option<IpAddr>,
 }
 
 #[derive(Deserialize)]
@@ -24,29 +71,42 @@ pub struct UpdateQuery {
     pub ip: Option<IpAddr>,
 }
 
-pub async fn index(
-    route_53: web::Data<Cloudflare>,
-    config: web::Data<ApiConfig>,
-) -> Result<impl Responder> {
-    let records = route_53.list_display_records(&config.salt).await?;
+pub async fn index(current: Current, heartbeats: web::Data<Heartbeats>) -> Result<impl Responder> {
+    let (route_53, config) = (&current.zones, &current.config);
+    // A zone that fails is left out, the others are still worth listing
+    let mut records = route_53.list_display(&config.salt).await?.records;
+    heartbeats.annotate(&mut records, &config.policy);
     Ok(web::Json(records))
 }
 
 pub async fn destroy(
-    route_53: web::Data<Cloudflare>,
-    config: web::Data<ApiConfig>,
+    current: Current,
+    history: web::Data<History>,
+    heartbeats: web::Data<Heartbeats>,
     id: web::Path<String>,
+    req: HttpRequest,
 ) -> Result<impl Responder> {
-    route_53.delete_record(&config.salt, &id).await?;
+    let (route_53, config) = (&current.zones, &current.config);
+    let record = route_53.delete_record(&config.salt, &id).await?;
+    heartbeats.forget(&record.domain, &record.record_type);
+    heartbeats.end_lease(&record.source_id);
+    history.record(Change::new(
+        Action::Delete,
+        &record,
+        Some(record.ip.clone()),
+        get_ip_from_request(&req),
+    ));
     Ok(web::Json(json!({})))
 }
 
 pub async fn update(
-    route_53: web::Data<Cloudflare>,
-    config: web::Data<ApiConfig>,
+    current: Current,
+    history: web::Data<History>,
+    heartbeats: web::Data<Heartbeats>,
     query: web::Query<UpdateQuery>,
     req: HttpRequest,
 ) -> Result<impl Responder> {
+    let (route_53, config) = (&current.zones, &current.config);
     let query = query.into_inner();
     let ip = query
         .ip
@@ -54,78 +114,377 @@ pub async fn update(
         .or_else(|| get_ip_from_request(&req))
         .ok_or(MissingIp)?;
     let id = query.key.or(query.id).ok_or(MissingId)?;
-    _update_inner(route_53, config, id, ip).await
+    _update_inner(route_53, config, history, heartbeats, &req, id, ip).await
 }
 
 pub async fn update_with_peer_address(
-    route_53: web::Data<Cloudflare>,
-    config: web::Data<ApiConfig>,
+    current: Current,
+    history: web::Data<History>,
+    heartbeats: web::Data<Heartbeats>,
     id: web::Path<String>,
     req: HttpRequest,
 ) -> Result<impl Responder> {
+    let (route_53, config) = (&current.zones, &current.config);
     let ip = get_ip_from_request(&req).ok_or(MissingIp)?;
-    _update_inner(route_53, config, id.into_inner(), ip).await
+    _update_inner(
+        route_53,
+        config,
+        history,
+        heartbeats,
+        &req,
+        id.into_inner(),
+        ip,
+    )
+    .await
 }
 
 pub async fn update_user_supplied(
-    route_53: web::Data<Cloudflare>,
-    config: web::Data<ApiConfig>,
+    current: Current,
+    history: web::Data<History>,
+    heartbeats: web::Data<Heartbeats>,
     id_ip: web::Path<(String, IpAddr)>,
+    req: HttpRequest,
 ) -> Result<impl Responder> {
+    let (route_53, config) = (&current.zones, &current.config);
     let (id, ip) = id_ip.into_inner();
-    _update_inner(route_53, config, id, ip.to_string()).await
+    _update_inner(
+        route_53,
+        config,
+        history,
+        heartbeats,
+        &req,
+        id,
+        ip.to_string(),
+    )
+    .await
+}
+
+/// `PATCH /api/domains` over mTLS: the client certificate picks the record.
+pub async fn update_from_client_certificate(
+    current: Current,
+    history: web::Data<History>,
+    heartbeats: web::Data<Heartbeats>,
+    query: web::Query<IpQuery>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    let (route_53, config) = (&current.zones, &current.config);
+    let identity = req
+        .conn_data::<ClientIdentity>()
+        .cloned()
+        .ok_or(MissingId)?;
+    let ip = query
+        .ip
+        .map(|ip| ip.to_string())
+        .or_else(|| get_ip_from_request(&req))
+        .ok_or(MissingIp)?;
+    // A host may have both an A and an AAAA record, the address picks one
+    let record_type = match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => "AAAA",
+        _ => "A",
+    };
+    let records = route_53.list_display_records(&config.salt).await?;
+    let permitted: Vec<_> = records
+        .iter()
+        .filter(|r| r.record_type.as_str() == record_type)
+        .filter(|r| config.client_identities.permits(&identity, &r.domain))
+        .collect();
+    match permitted.as_slice() {
+        [record] => {
+            let id = record.id.clone();
+            _update_inner(route_53, config, history, heartbeats, &req, id, ip).await
+        }
+        [] => Err(DomainHashNotFound.into()),
+        _ => Err(AmbiguousClientCertificate(permitted.len()).into()),
+    }
 }
 
 async fn _update_inner(
-    route_53: web::Data<Cloudflare>,
-    config: web::Data<ApiConfig>,
+    route_53: &Zones,
+    config: &ApiConfig,
+    history: web::Data<History>,
+    heartbeats: web::Data<Heartbeats>,
+    req: &HttpRequest,
     id: String,
     ip: String,
 ) -> Result<impl Responder> {
+    let (record, _) = update_record(
+        route_53,
+        config,
+        &history,
+        &heartbeats,
+        id,
+        ip,
+        req.conn_data::<ClientIdentity>(),
+        get_ip_from_request(req),
+    )
+    .await?;
+    Ok(web::Json(record))
+}
+
+/// Points record `id` at `ip`, as every update route and MQTT command does,
+/// counting the outcome. Returns the record and whether its value changed.
+#[allow(clippy::too_many_arguments)]
+pub async fn update_record(
+    route_53: &Zones,
+    config: &ApiConfig,
+    history: &History,
+    heartbeats: &Heartbeats,
+    id: String,
+    ip: String,
+    identity: Option<&ClientIdentity>,
+    client: Option<String>,
+) -> Result<(DisplayRecord, bool), DynIpError> {
+    let result = apply_update(route_53, config, history, id, ip, identity, client).await;
+    if let Ok((record, _)) = &result {
+        heartbeats.beat(&record.domain, &record.record_type);
+    }
+    let outcome = match &result {
+        Ok((_, true)) => "changed",
+        Ok((_, false)) => "unchanged",
+        Err(e) if e.status_code().is_client_error() => "rejected",
+        Err(_) => "failed",
+    };
+    metrics().update(outcome);
+    result
+}
+
+async fn apply_update(
+    route_53: &Zones,
+    config: &ApiConfig,
+    history: &History,
+    id: String,
+    ip: String,
+    identity: Option<&ClientIdentity>,
+    client: Option<String>,
+) -> Result<(DisplayRecord, bool), DynIpError> {
     let records = route_53.list_display_records(&config.salt).await?;
 
     if let Some(record) = records.iter().find(|r| r.id == id) {
+        if let Some(identity) = identity {
+            if !config.client_identities.permits(identity, &record.domain) {
+                return Err(RecordNotPermitted);
+            }
+        }
+        let family_matches = match record.record_type.as_str() {
+            "A" => ip.parse::<Ipv4Addr>().is_ok(),
+            "AAAA" => ip.parse::<Ipv6Addr>().is_ok(),
+            other => {
+                return Err(InvalidRecord(format!(
+                    "{} is a {} record, only A and AAAA records take IP updates",
+                    record.domain, other
+                )))
+            }
+        };
+        if !family_matches {
+            return Err(InvalidRecord(format!(
+                "{} is not a valid address for an {} record",
+                ip, record.record_type
+            )));
+        }
+        if config
+            .policy
+            .record(&record.domain)
+            .is_some_and(|s| s.locked)
+        {
+            return Err(RecordLocked);
+        }
+        config.policy.check_value(&ip).map_err(InvalidRecord)?;
+        let old_value = record.ip.clone();
         let mut record: Record = record.into();
         record.ip = ip;
+        config.policy.apply(&mut record);
         route_53.update_record(record.clone()).await?;
         let display_record = record.for_display(&config.salt);
-        return Ok(web::Json(display_record));
+        // Cron'd clients update every few minutes, only real changes are history
+        let changed = display_record.ip != old_value;
+        info!(
+            record = %display_record.domain,
+            record_type = %display_record.record_type,
+            old_ip = %old_value,
+            new_ip = %display_record.ip,
+            client_ip = client.clone().unwrap_or_default(),
+            changed,
+            "Record updated"
+        );
+        if changed {
+            history.record(Change::new(
+                Action::Update,
+                &display_record,
+                Some(old_value),
+                client,
+            ));
+        }
+        return Ok((display_record, changed));
     }
-    Err(DomainHashNotFound.into())
+    Err(DomainHashNotFound)
 }
 
 pub async fn add(
     req: HttpRequest,
-    route_53: web::Data<Cloudflare>,
-    config: web::Data<ApiConfig>,
+    current: Current,
+    history: web::Data<History>,
+    heartbeats: web::Data<Heartbeats>,
     domain_ip: web::Query<AddQuery>,
 ) -> Result<impl Responder> {
+    let (route_53, config) = (&current.zones, &current.config);
     let domain_ip = domain_ip.into_inner();
 
-    let domain = parse_domain_name(&domain_ip.domain)
-        .map_err(|e| DomainParse(e.to_string()))?
-        .to_string();
     let record_type = domain_ip
         .record_type
-        .and_then(|s| RrType::from_str(&s).ok())
+        .map(|s| RrType::from_str(&s))
+        .transpose()
+        .map_err(InvalidRecord)?
         .unwrap_or(RrType::A);
-    let ip = match record_type {
-        RrType::A => domain_ip
+    config
+        .policy
+        .check_type(record_type.as_str())
+        .map_err(InvalidRecord)?;
+    // Service and challenge names have underscore labels, which aren't hostnames
+    let domain = match record_type {
+        RrType::Srv | RrType::Txt => parse_dns_name(&domain_ip.domain)
+            .map_err(|e| DomainParse(e.to_string()))?
+            .to_string(),
+        _ => parse_domain_name(&domain_ip.domain)
+            .map_err(|e| DomainParse(e.to_string()))?
+            .to_string(),
+    };
+    let domain = route_53.qualify(&domain, domain_ip.zone.as_deref())?;
+    let value = match record_type {
+        RrType::A | RrType::Aaaa => domain_ip
             .ip
-            .ok_or_else(|| get_ip_from_request(&req).ok_or(MissingIp))
-            .ok()
             .map(|ip| ip.to_string())
+            .or_else(|| get_ip_from_request(&req))
             .ok_or(MissingIp)?,
-        _ => domain_ip.host.ok_or(MissingIp)?,
+        RrType::Cname => domain_ip.host.ok_or(MissingIp)?,
+        _ => domain_ip
+            .value
+            .or(domain_ip.host)
+            .ok_or_else(|| InvalidRecord(format!("{} requires a value", record_type.as_str())))?,
     };
-    let record = Record {
+    let mut record = Record {
         domain,
-        ip,
         record_type: record_type.as_str().to_string(),
+        priority: domain_ip.priority,
+        weight: domain_ip.weight,
+        port: domain_ip.port,
+        flags: domain_ip.flags,
+        tag: domain_ip.tag,
+        ttl: domain_ip.ttl.unwrap_or(Record::default().ttl),
+        proxied: domain_ip.proxied.unwrap_or_default(),
+        comment: domain_ip.comment.filter(|c| !c.is_empty()),
         ..Record::default()
     };
+    record.set_value(value);
+    config.policy.apply(&mut record);
+    config
+        .policy
+        .check_value(&record.ip)
+        .map_err(InvalidRecord)?;
+    record.validate().map_err(InvalidRecord)?;
+    let lease = match (
+        domain_ip.lease,
+        domain_ip.fallback.filter(|f| !f.is_empty()),
+    ) {
+        (Some(0), _) => return Err(InvalidRecord("lease must be above 0".to_string()).into()),
+        (Some(duration), fallback) => {
+            if let Some(fallback) = &fallback {
+                let mut parked = record.clone();
+                parked.set_value(fallback.clone());
+                config
+                    .policy
+                    .check_value(&parked.ip)
+                    .map_err(InvalidRecord)?;
+                parked
+                    .validate()
+                    .map_err(|e| InvalidRecord(format!("fallback: {}", e)))?;
+            }
+            Some(Lease { duration, fallback })
+        }
+        (None, Some(_)) => return Err(InvalidRecord("fallback needs a lease".to_string()).into()),
+        (None, None) => None,
+    };
+
+    let record = route_53.create_record(record).await?;
+    let mut display_record = record.for_display(&config.salt);
+    heartbeats.created(&display_record, lease);
+    heartbeats.annotate(std::slice::from_mut(&mut display_record), &config.policy);
+    info!(
+        record = %display_record.domain,
+        record_type = %display_record.record_type,
+        value = %display_record.ip,
+        client_ip = get_ip_from_request(&req).unwrap_or_default(),
+        "Record created"
+    );
+    history.record(Change::new(
+        Action::Create,
+        &display_record,
+        None,
+        get_ip_from_request(&req),
+    ));
+
+    Ok(web::Json(display_record))
+}
+
+/// Edits any field of a record other than its name and type.
+pub async fn edit(
+    current: Current,
+    history: web::Data<History>,
+    heartbeats: web::Data<Heartbeats>,
+    id: web::Path<String>,
+    query: web::Query<EditQuery>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    let (route_53, config) = (&current.zones, &current.config);
+    let query = query.into_inner();
+    let records = route_53.list_display_records(&config.salt).await?;
+    let record = records
+        .iter()
+        .find(|r| r.id == *id)
+        .ok_or(DomainHashNotFound)?;
 
-    route_53.create_record(record.clone()).await?;
+    let old_value = record.ip.clone();
+    let mut record: Record = record.into();
+    if let Some(value) = query.value {
+        record.set_value(value);
+    }
+    record.priority = query.priority.or(record.priority);
+    record.weight = query.weight.or(record.weight);
+    record.port = query.port.or(record.port);
+    record.flags = query.flags.or(record.flags);
+    record.tag = query.tag.or(record.tag);
+    record.ttl = query.ttl.unwrap_or(record.ttl);
+    record.proxied = query.proxied.unwrap_or(record.proxied);
+    if let Some(comment) = query.comment {
+        record.comment = Some(comment).filter(|c| !c.is_empty());
+    }
+    // Held to the same policy as records being created and updated
+    config.policy.apply(&mut record);
+    config
+        .policy
+        .check_type(&record.record_type)
+        .map_err(InvalidRecord)?;
+    config
+        .policy
+        .check_value(&record.ip)
+        .map_err(InvalidRecord)?;
+    record.validate().map_err(InvalidRecord)?;
 
-    Ok(web::Json(record.for_display(&config.salt)))
+    route_53.update_record(record.clone()).await?;
+    let display_record = record.for_display(&config.salt);
+    heartbeats.beat(&display_record.domain, &display_record.record_type);
+    info!(
+        record = %display_record.domain,
+        record_type = %display_record.record_type,
+        old_value = %old_value,
+        new_value = %display_record.ip,
+        client_ip = get_ip_from_request(&req).unwrap_or_default(),
+        "Record edited"
+    );
+    history.record(Change::new(
+        Action::Edit,
+        &display_record,
+        Some(old_value),
+        get_ip_from_request(&req),
+    ));
+    Ok(web::Json(display_record))
 }
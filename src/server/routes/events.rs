@@ -0,0 +1,130 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use actix_web::http::header::{HeaderName, CACHE_CONTROL, CONTENT_TYPE};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::stream;
+use serde::Deserialize;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::server::history::{Change, History};
+
+const LAST_EVENT_ID: HeaderName = HeaderName::from_static("last-event-id");
+/// Comments sent while nothing changes, so proxies keep the connection open.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+/// Milliseconds browsers wait before reconnecting.
+const RETRY_MS: u64 = 5000;
+/// Some changes can't be replayed, the client should reload what it shows.
+const RESET: &str = "event: reset\ndata: {}\n\n";
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// For clients that can't set `Last-Event-ID`
+    pub last_event_id: Option<u64>,
+}
+
+/// `GET /api/events`: record changes as Server-Sent Events, each with the
+/// change's `seq` as its ID. A reconnecting client gets what it missed first,
+/// as far back as the history goes.
+pub async fn index(
+    history: web::Data<History>,
+    query: web::Query<EventsQuery>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let last_event_id = req
+        .headers()
+        .get(LAST_EVENT_ID)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .or(query.last_event_id);
+    // Subscribed before reading the backlog, so nothing falls in between
+    let changes = history.subscribe();
+    let last_seq = history.last_seq();
+    let (backlog, reset) = match last_event_id {
+        // From before a lost data dir, or further back than the history
+        Some(id) if id > last_seq => (Vec::new(), true),
+        Some(id) => {
+            let backlog = history.since(id);
+            let gap = backlog.first().is_some_and(|c| c.seq > id + 1);
+            (backlog, gap)
+        }
+        None => (Vec::new(), false),
+    };
+    let after = last_event_id.unwrap_or_default().min(last_seq);
+    HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "text/event-stream"))
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        // Stops nginx buffering the stream
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events(backlog, changes, after, reset))
+}
+
+struct State {
+    preamble: bool,
+    reset: bool,
+    backlog: VecDeque<Change>,
+    changes: broadcast::Receiver<Change>,
+    /// Live changes at or below this were in the backlog
+    last_seq: u64,
+}
+
+fn events(
+    backlog: Vec<Change>,
+    changes: broadcast::Receiver<Change>,
+    after: u64,
+    reset: bool,
+) -> impl futures_util::Stream<Item = Result<Bytes, actix_web::Error>> {
+    let state = State {
+        preamble: true,
+        reset,
+        last_seq: backlog.last().map_or(after, |c| c.seq),
+        backlog: backlog.into(),
+        changes,
+    };
+    stream::unfold(state, |mut state| async move {
+        let chunk = next(&mut state).await?;
+        Some((Ok(Bytes::from(chunk)), state))
+    })
+}
+
+async fn next(state: &mut State) -> Option<String> {
+    if state.preamble {
+        state.preamble = false;
+        return Some(format!("retry: {}\n\n", RETRY_MS));
+    }
+    if state.reset {
+        state.reset = false;
+        return Some(RESET.to_string());
+    }
+    if let Some(change) = state.backlog.pop_front() {
+        return Some(event(&change));
+    }
+    loop {
+        let change = tokio::select! {
+            change = state.changes.recv() => change,
+            _ = tokio::time::sleep(KEEPALIVE) => return Some(": keepalive\n\n".to_string()),
+        };
+        match change {
+            Ok(change) if change.seq <= state.last_seq => continue,
+            Ok(change) => {
+                state.last_seq = change.seq;
+                return Some(event(&change));
+            }
+            // Too far behind to say what was missed
+            Err(RecvError::Lagged(_)) => return Some(RESET.to_string()),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Named after the action, with the change as JSON.
+pub fn event(change: &Change) -> String {
+    format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        change.seq,
+        change.action.as_str(),
+        serde_json::to_string(change).unwrap_or_default()
+    )
+}
@@ -0,0 +1,30 @@
+use actix_web::{web, HttpResponse, Responder, Result};
+use serde_json::json;
+
+use crate::server::health::{Readiness, Status};
+use crate::server::state::Current;
+
+/// The process is up and serving requests.
+pub async fn healthz() -> Result<impl Responder> {
+    Ok(web::Json(json!({ "status": "ok" })))
+}
+
+/// Whether each zone and the data directory are usable, 503 if any isn't.
+/// Unauthenticated, so only the overall status, `/api/readyz` has the details.
+pub async fn readyz(current: Current, readiness: web::Data<Readiness>) -> Result<impl Responder> {
+    let report = readiness.check(&current).await;
+    Ok(respond(report.status).json(json!({ "status": report.status })))
+}
+
+/// The readiness report with each component's error.
+pub async fn report(current: Current, readiness: web::Data<Readiness>) -> Result<impl Responder> {
+    let report = readiness.check(&current).await;
+    Ok(respond(report.status).json(report))
+}
+
+fn respond(status: Status) -> actix_web::HttpResponseBuilder {
+    match status {
+        Status::Ok => HttpResponse::Ok(),
+        Status::Error => HttpResponse::ServiceUnavailable(),
+    }
+}
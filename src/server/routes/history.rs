@@ -0,0 +1,10 @@
+use actix_web::{web, Responder, Result};
+
+use crate::server::history::{History, HistoryQuery};
+
+pub async fn index(
+    history: web::Data<History>,
+    query: web::Query<HistoryQuery>,
+) -> Result<impl Responder> {
+    Ok(web::Json(history.list(&query)))
+}
@@ -0,0 +1,18 @@
+use actix_web::{web, HttpResponse, Responder, Result};
+use tracing::warn;
+
+use crate::server::heartbeat::Heartbeats;
+use crate::server::metrics::metrics;
+use crate::server::state::Current;
+
+pub async fn index(current: Current, heartbeats: web::Data<Heartbeats>) -> Result<impl Responder> {
+    if metrics().records_stale() {
+        // A complete listing sets the count
+        if let Err(e) = current.zones.list().await {
+            warn!("Failed to count records for metrics: {}", e);
+        }
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics().render(&heartbeats)))
+}
@@ -1,2 +1,8 @@
+pub mod acme;
 pub mod admin;
 pub mod domains;
+pub mod events;
+pub mod health;
+pub mod history;
+pub mod metrics;
+pub mod webhooks;
@@ -0,0 +1,10 @@
+use actix_web::{web, Responder, Result};
+
+use crate::server::webhooks::{DeliveryQuery, Webhooks};
+
+pub async fn deliveries(
+    webhooks: web::Data<Webhooks>,
+    query: web::Query<DeliveryQuery>,
+) -> Result<impl Responder> {
+    Ok(web::Json(webhooks.list(&query)))
+}
@@ -0,0 +1,71 @@
+use std::future::{ready, Ready};
+use std::ops::Deref;
+use std::sync::Arc;
+
+use actix_web::dev::Payload;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, FromRequest, HttpRequest};
+use arc_swap::ArcSwap;
+
+use crate::aws::cloudflare::Cloudflare;
+use crate::aws::zones::Zones;
+use crate::config::Settings;
+use crate::error::DynIpError;
+use crate::server::api::ApiConfig;
+
+/// What handlers need that can change while the server runs. It's replaced as
+/// a whole, so a request sees one consistent version from start to finish.
+pub struct Runtime {
+    pub config: ApiConfig,
+    pub zones: Zones,
+}
+
+impl Runtime {
+    /// Connects to every zone, failing on the first that can't be managed.
+    pub async fn connect(settings: &Settings) -> Result<Runtime, DynIpError> {
+        let mut zones = Vec::new();
+        for zone in &settings.zones {
+            let zone = Cloudflare::connect(
+                zone.api_url.clone(),
+                zone.auth.clone(),
+                zone.zone_id.clone(),
+                zone.domain_name.clone(),
+            )
+            .await?;
+            zones.push(zone);
+        }
+        Ok(Runtime {
+            config: settings.api.clone(),
+            zones: Zones::new(zones),
+        })
+    }
+}
+
+pub type Live = ArcSwap<Runtime>;
+
+/// The [`Runtime`] a request started with.
+pub struct Current(Arc<Runtime>);
+
+impl Current {
+    pub fn of(req: &HttpRequest) -> Option<Current> {
+        req.app_data::<web::Data<Live>>()
+            .map(|live| Current(live.load_full()))
+    }
+}
+
+impl Deref for Current {
+    type Target = Runtime;
+
+    fn deref(&self) -> &Runtime {
+        &self.0
+    }
+}
+
+impl FromRequest for Current {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Current, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Current::of(req).ok_or_else(|| ErrorInternalServerError("Runtime not found")))
+    }
+}
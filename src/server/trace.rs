@@ -0,0 +1,131 @@
+use std::future::Future;
+use std::time::Instant;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::Error;
+use opentelemetry::propagation::Extractor;
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::{field, info, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::server::ip::get_ip_from_request;
+use crate::server::metrics::metrics;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Runs each request in a span carrying its request ID, continuing the
+/// caller's trace when it sent a `traceparent`, then logs and measures it. The
+/// request ID comes from `X-Request-Id` when the client sent a usable one, and
+/// is echoed back either way.
+pub fn trace_request<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let started = Instant::now();
+    let request_id = request_id(req.headers());
+    let method = req.method().to_string();
+    let scope = scope(req.path());
+    // The path only, query strings can hold keys
+    let span = tracing::info_span!(
+        "request",
+        otel.name = field::Empty,
+        otel.kind = "server",
+        request_id = %request_id,
+        http.method = %method,
+        http.target = %req.path(),
+        http.route = field::Empty,
+        http.status_code = field::Empty,
+        client.address = field::Empty,
+    );
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&Headers(req.headers()))
+    });
+    let _ = span.set_parent(parent);
+    if let Some(ip) = get_ip_from_request(req.request()) {
+        span.record("client.address", ip);
+    }
+    let response = span.in_scope(|| srv.call(req));
+
+    async move {
+        let response = response.await;
+        let (route, status) = match &response {
+            Ok(response) => (
+                response
+                    .request()
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_string()),
+                response.status(),
+            ),
+            // Middleware errors, such as refused credentials, come back without
+            // the request, so they're labelled with the scope that refused them
+            Err(e) => (scope, e.as_response_error().status_code()),
+        };
+        let elapsed = started.elapsed();
+        let span = Span::current();
+        span.record("otel.name", format!("{} {}", method, route));
+        span.record("http.route", route.as_str());
+        span.record("http.status_code", status.as_u16());
+        metrics().request(&method, &route, status.as_str(), elapsed);
+        info!(
+            status = status.as_u16(),
+            elapsed_ms = elapsed.as_millis() as u64,
+            "{} {} {}",
+            method,
+            route,
+            status.as_u16()
+        );
+        let mut response = response?;
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(REQUEST_ID, value);
+        }
+        Ok(response)
+    }
+    .instrument(span)
+}
+
+/// The first segment of `path`, e.g. `/api` for `/api/domains/{id}`.
+fn scope(path: &str) -> String {
+    let first = path
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    format!("/{}", first)
+}
+
+fn request_id(headers: &HeaderMap) -> String {
+    let given = headers
+        .get(REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LENGTH)
+        .filter(|v| v.bytes().all(|b| b.is_ascii_graphic()));
+    if let Some(given) = given {
+        return given.to_string();
+    }
+    random_id()
+}
+
+/// 32 hex characters.
+pub(crate) fn random_id() -> String {
+    let mut id = [0u8; 16];
+    // Only for correlating, an all-zero ID is better than none
+    let _ = SystemRandom::new().fill(&mut id);
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
@@ -0,0 +1,462 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::future::join_all;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+use tracing::info;
+use tracing::{error, warn};
+
+use crate::server::history::{Change, ChangeFilter};
+use crate::server::metrics::metrics;
+use crate::server::state::Live;
+use crate::server::trace::random_id;
+
+/// Finished deliveries kept for `/api/webhooks/deliveries`.
+const MAX_LOG: usize = 500;
+/// Attempts before a delivery is given up on, about two hours of retries.
+const MAX_ATTEMPTS: u32 = 8;
+const FIRST_RETRY: Duration = Duration::from_secs(30);
+const MAX_RETRY: Duration = Duration::from_secs(3600);
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const EVENT_HEADER: &str = "x-dyn-ip-event";
+pub const DELIVERY_HEADER: &str = "x-dyn-ip-delivery";
+pub const TIMESTAMP_HEADER: &str = "x-dyn-ip-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-dyn-ip-signature";
+
+/// An endpoint told about record changes, from `[[webhooks]]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Webhook {
+    /// Identifies the webhook in queued deliveries, so they follow config edits
+    pub name: String,
+    pub url: String,
+    /// Signs the payloads when set
+    pub secret: Option<String>,
+    pub filter: ChangeFilter,
+}
+
+impl Webhook {
+    pub fn wants(&self, change: &Change) -> bool {
+        self.filter.matches(change)
+    }
+}
+
+/// What's POSTed, the change with an ID shared by every webhook it's sent to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Payload {
+    pub event_id: String,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: String,
+    pub webhook: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    /// Unix seconds
+    pub created_at: u64,
+    /// Unix seconds of the next attempt while pending
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_attempt_at: Option<u64>,
+    /// HTTP status of the last attempt, if it got a response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub payload: Payload,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DeliveryQuery {
+    pub webhook: Option<String>,
+    pub status: Option<DeliveryStatus>,
+    pub limit: Option<usize>,
+}
+
+/// Queued and finished webhook deliveries. Every new state of a delivery is
+/// appended to a JSON lines file so retries carry on after a restart.
+#[derive(Clone)]
+pub struct Webhooks {
+    writer: mpsc::Sender<Job>,
+    client: reqwest::Client,
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// File work for the writer thread, so queueing never waits on disk.
+enum Job {
+    Append(Vec<Delivery>),
+    /// Replace the file with these deliveries
+    Rewrite(Vec<Delivery>),
+    Flush(mpsc::Sender<()>),
+}
+
+#[derive(Default)]
+struct Inner {
+    deliveries: VecDeque<Delivery>,
+    /// Being attempted, so an overlapping round doesn't send them again
+    in_flight: HashSet<String>,
+    lines_on_disk: usize,
+}
+
+impl Webhooks {
+    pub fn load(path: PathBuf) -> Webhooks {
+        let mut inner = Inner::default();
+        if let Ok(contents) = std::fs::read_to_string(&path) {
+            inner.lines_on_disk = read(&path, &contents, &mut inner.deliveries);
+            inner.trim();
+        }
+        let (writer, jobs) = mpsc::channel();
+        std::thread::spawn(move || write_deliveries(path, jobs));
+        Webhooks {
+            writer,
+            client: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .user_agent(concat!("dyn-ip/", env!("CARGO_PKG_VERSION")))
+                .build()
+                .unwrap_or_default(),
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Queues the change for each webhook that wants it.
+    pub fn enqueue(&self, webhooks: &[Webhook], change: &Change) {
+        let payload = Payload {
+            event_id: random_id(),
+            change: change.clone(),
+        };
+        let queued: Vec<Delivery> = webhooks
+            .iter()
+            .filter(|w| w.wants(change))
+            .map(|webhook| Delivery {
+                id: random_id(),
+                webhook: webhook.name.clone(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                created_at: now(),
+                next_attempt_at: Some(now()),
+                last_attempt_at: None,
+                response_status: None,
+                error: None,
+                payload: payload.clone(),
+            })
+            .collect();
+        if queued.is_empty() {
+            return;
+        }
+        let mut inner = self.inner.lock().expect("webhooks lock poisoned");
+        inner.deliveries.extend(queued.iter().cloned());
+        self.save(&mut inner, queued);
+    }
+
+    /// Attempts every pending delivery that's due and not already being
+    /// attempted, all at once so a slow endpoint doesn't hold up the others.
+    pub async fn deliver_due(&self, webhooks: &[Webhook]) {
+        let due: Vec<Delivery> = {
+            let mut inner = self.inner.lock().expect("webhooks lock poisoned");
+            let now = now();
+            let due: Vec<Delivery> = inner
+                .deliveries
+                .iter()
+                .filter(|d| d.status == DeliveryStatus::Pending)
+                .filter(|d| d.next_attempt_at.is_none_or(|at| at <= now))
+                .filter(|d| !inner.in_flight.contains(&d.id))
+                .cloned()
+                .collect();
+            inner.in_flight.extend(due.iter().map(|d| d.id.clone()));
+            due
+        };
+        join_all(due.into_iter().map(|d| self.attempt(webhooks, d))).await;
+    }
+
+    async fn attempt(&self, webhooks: &[Webhook], mut delivery: Delivery) {
+        let result = match webhooks.iter().find(|w| w.name == delivery.webhook) {
+            Some(webhook) => self.send(webhook, &delivery).await,
+            None => Err((None, "webhook is no longer configured".to_string())),
+        };
+        delivery.attempts += 1;
+        delivery.last_attempt_at = Some(now());
+        let event = delivery.payload.change.action.as_str();
+        match result {
+            Ok(status) => {
+                delivery.status = DeliveryStatus::Delivered;
+                delivery.next_attempt_at = None;
+                delivery.response_status = Some(status);
+                delivery.error = None;
+                metrics().webhook_delivery("delivered");
+                info!(
+                    webhook = %delivery.webhook,
+                    delivery = %delivery.id,
+                    event,
+                    status,
+                    "Webhook delivered"
+                );
+            }
+            Err((status, e)) => {
+                delivery.response_status = status;
+                delivery.error = Some(e.clone());
+                let webhook_exists = webhooks.iter().any(|w| w.name == delivery.webhook);
+                if delivery.attempts >= MAX_ATTEMPTS || !webhook_exists {
+                    delivery.status = DeliveryStatus::Failed;
+                    delivery.next_attempt_at = None;
+                    metrics().webhook_delivery("failed");
+                    error!(
+                        "Giving up on webhook {} delivery {} after {} attempts: {}",
+                        delivery.webhook, delivery.id, delivery.attempts, e
+                    );
+                } else {
+                    let retry_in = backoff(delivery.attempts);
+                    delivery.next_attempt_at = Some(now() + retry_in.as_secs());
+                    metrics().webhook_delivery("retrying");
+                    warn!(
+                        "Webhook {} delivery {} failed, retrying in {}s: {}",
+                        delivery.webhook,
+                        delivery.id,
+                        retry_in.as_secs(),
+                        e
+                    );
+                }
+            }
+        }
+        let mut inner = self.inner.lock().expect("webhooks lock poisoned");
+        inner.in_flight.remove(&delivery.id);
+        if let Some(entry) = inner.deliveries.iter_mut().find(|d| d.id == delivery.id) {
+            *entry = delivery.clone();
+            self.save(&mut inner, vec![delivery]);
+        }
+    }
+
+    /// The response status, or the status if any and why it failed.
+    async fn send(
+        &self,
+        webhook: &Webhook,
+        delivery: &Delivery,
+    ) -> Result<u16, (Option<u16>, String)> {
+        let body = serde_json::to_vec(&delivery.payload).map_err(|e| (None, e.to_string()))?;
+        let timestamp = now();
+        let mut request = self
+            .client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, delivery.payload.change.action.as_str())
+            .header(DELIVERY_HEADER, &delivery.id)
+            .header(TIMESTAMP_HEADER, timestamp.to_string());
+        if let Some(secret) = &webhook.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, timestamp, &body));
+        }
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| (None, e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(status.as_u16())
+        } else {
+            Err((
+                Some(status.as_u16()),
+                format!("{} from {}", status, webhook.url),
+            ))
+        }
+    }
+
+    /// Pending first, then newest first.
+    pub fn list(&self, query: &DeliveryQuery) -> Vec<Delivery> {
+        let inner = self.inner.lock().expect("webhooks lock poisoned");
+        let (pending, done): (Vec<_>, Vec<_>) = inner
+            .deliveries
+            .iter()
+            .rev()
+            .filter(|d| query.webhook.as_ref().is_none_or(|w| &d.webhook == w))
+            .filter(|d| query.status.is_none_or(|s| d.status == s))
+            .partition(|d| d.status == DeliveryStatus::Pending);
+        pending
+            .into_iter()
+            .chain(done)
+            .take(query.limit.unwrap_or(MAX_LOG))
+            .cloned()
+            .collect()
+    }
+
+    /// Waits until every delivery state so far is on disk.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.writer.send(Job::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+
+    /// Queues changes as they're recorded and retries what's due, taking the
+    /// webhooks from the live config so reloads apply to queued deliveries.
+    /// Changes are queued by their own task, so slow endpoints can't make
+    /// this subscriber fall behind.
+    pub async fn run(self, live: Arc<Live>, mut changes: broadcast::Receiver<Change>) {
+        let queued = Arc::new(Notify::new());
+        tokio::spawn({
+            let (webhooks, live, queued) = (self.clone(), live.clone(), queued.clone());
+            async move {
+                loop {
+                    match changes.recv().await {
+                        Ok(change) => {
+                            webhooks.enqueue(&live.load().config.webhooks, &change);
+                            queued.notify_one();
+                        }
+                        Err(RecvError::Lagged(missed)) => {
+                            error!("Webhooks fell behind, {} changes weren't sent", missed)
+                        }
+                        Err(RecvError::Closed) => return,
+                    }
+                }
+            }
+        });
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = queued.notified() => {}
+                _ = interval.tick() => {}
+            }
+            let webhooks = self.clone();
+            let runtime = live.load_full();
+            tokio::spawn(async move { webhooks.deliver_due(&runtime.config.webhooks).await });
+        }
+    }
+
+    /// Drops the oldest finished deliveries beyond the log size, then queues
+    /// the `changed` ones for the file, or the whole queue once the file
+    /// holds twice what's kept.
+    fn save(&self, inner: &mut Inner, changed: Vec<Delivery>) {
+        inner.trim();
+        let job = if inner.lines_on_disk >= (inner.deliveries.len() * 2).max(MAX_LOG) {
+            inner.lines_on_disk = inner.deliveries.len();
+            Job::Rewrite(inner.deliveries.iter().cloned().collect())
+        } else {
+            inner.lines_on_disk += changed.len();
+            Job::Append(changed)
+        };
+        // Queued under the lock so the file keeps the order of the updates
+        let _ = self.writer.send(job);
+    }
+}
+
+impl Inner {
+    fn trim(&mut self) {
+        let mut finished = self
+            .deliveries
+            .iter()
+            .filter(|d| d.status != DeliveryStatus::Pending)
+            .count();
+        self.deliveries.retain(|d| {
+            if finished > MAX_LOG && d.status != DeliveryStatus::Pending {
+                finished -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `<timestamp>.<body>`.
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let mut context = hmac::Context::with_key(&key);
+    context.update(timestamp.to_string().as_bytes());
+    context.update(b".");
+    context.update(body);
+    let tag = context.sign();
+    let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+fn backoff(attempts: u32) -> Duration {
+    FIRST_RETRY
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_RETRY)
+}
+
+/// Reads the queue into `deliveries`, later lines replacing earlier states of
+/// the same delivery. Returns the lines read.
+fn read(path: &PathBuf, contents: &str, deliveries: &mut VecDeque<Delivery>) -> usize {
+    let mut lines = 0;
+    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+        lines += 1;
+        match serde_json::from_str::<Delivery>(line) {
+            Ok(delivery) => match deliveries.iter_mut().find(|d| d.id == delivery.id) {
+                Some(entry) => *entry = delivery,
+                None => deliveries.push_back(delivery),
+            },
+            Err(e) => warn!("Skipping unreadable webhook delivery in {:?}: {}", path, e),
+        }
+    }
+    lines
+}
+
+/// Runs until every `Webhooks` clone is gone.
+fn write_deliveries(path: PathBuf, jobs: mpsc::Receiver<Job>) {
+    for job in jobs {
+        let result = match job {
+            Job::Append(deliveries) => append(&path, &deliveries),
+            Job::Rewrite(deliveries) => rewrite(&path, &deliveries),
+            Job::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to save the webhook queue to {:?}: {}", path, e);
+        }
+    }
+}
+
+fn lines(deliveries: &[Delivery]) -> String {
+    let mut contents = String::new();
+    for delivery in deliveries {
+        contents.push_str(&serde_json::to_string(delivery).unwrap_or_default());
+        contents.push('\n');
+    }
+    contents
+}
+
+fn rewrite(path: &PathBuf, deliveries: &[Delivery]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, lines(deliveries))?;
+    std::fs::rename(tmp, path)
+}
+
+fn append(path: &PathBuf, deliveries: &[Delivery]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(lines(deliveries).as_bytes())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rcgen::{CertificateParams, KeyPair as CsrKeyPair};
+use reqwest::{Client, Response};
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+use crate::aws::record::{Record, RecordType};
+use crate::error::DynIpError;
+use crate::server::state::Live;
+use crate::tls::certs::CertStore;
+
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const POLL_ATTEMPTS: usize = 30;
+const POLL_DELAY: Duration = Duration::from_secs(2);
+const DNS_PROPAGATION_DELAY: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChallengeType {
+    Http01,
+    Dns01,
+}
+
+impl ChallengeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::Dns01 => "dns-01",
+        }
+    }
+}
+
+impl std::str::FromStr for ChallengeType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ChallengeType, String> {
+        match s.to_lowercase().as_str() {
+            "http-01" | "http" => Ok(ChallengeType::Http01),
+            "dns-01" | "dns" => Ok(ChallengeType::Dns01),
+            _ => Err(format!("Invalid ACME challenge type: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AcmeConfig {
+    pub domain: String,
+    pub directory_url: String,
+    pub email: Option<String>,
+    pub challenge: ChallengeType,
+    /// Extra root certificate for the directory, e.g. Pebble's minica.
+    pub ca_cert: Option<PathBuf>,
+    pub account_key_path: PathBuf,
+}
+
+/// HTTP-01 key authorizations waiting to be served from
+/// `/.well-known/acme-challenge/{token}`.
+#[derive(Clone, Default)]
+pub struct HttpChallenges {
+    tokens: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl HttpChallenges {
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.tokens
+            .read()
+            .expect("challenge lock poisoned")
+            .get(token)
+            .cloned()
+    }
+
+    fn insert(&self, token: &str, key_authorization: &str) {
+        self.tokens
+            .write()
+            .expect("challenge lock poisoned")
+            .insert(token.to_string(), key_authorization.to_string());
+    }
+
+    fn remove(&self, token: &str) {
+        self.tokens
+            .write()
+            .expect("challenge lock poisoned")
+            .remove(token);
+    }
+}
+
+pub struct Solver {
+    pub challenge: ChallengeType,
+    pub http_challenges: HttpChallenges,
+    pub live: Arc<Live>,
+}
+
+enum Presented {
+    Http(String),
+    /// Record name and ID
+    Dns(String, Option<String>),
+}
+
+impl Solver {
+    async fn present(
+        &self,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<Presented, DynIpError> {
+        match self.challenge {
+            ChallengeType::Http01 => {
+                self.http_challenges.insert(token, key_authorization);
+                Ok(Presented::Http(token.to_string()))
+            }
+            ChallengeType::Dns01 => {
+                let value = b64(digest(&SHA256, key_authorization.as_bytes()).as_ref());
+                let record = self
+                    .live
+                    .load_full()
+                    .zones
+                    .create_record(Record {
+                        domain: format!("_acme-challenge.{}", domain),
+                        record_type: RecordType::Txt.to_string(),
+                        ip: value,
+                        ..Record::default()
+                    })
+                    .await?;
+                tokio::time::sleep(DNS_PROPAGATION_DELAY).await;
+                Ok(Presented::Dns(record.domain, record.source_id))
+            }
+        }
+    }
+
+    async fn cleanup(&self, presented: Presented) {
+        match presented {
+            Presented::Http(token) => self.http_challenges.remove(&token),
+            Presented::Dns(domain, Some(source_id)) => {
+                if let Err(e) = self
+                    .live
+                    .load_full()
+                    .zones
+                    .delete_record_by_source_id(&domain, &source_id)
+                    .await
+                {
+                    warn!("Failed to remove ACME challenge record: {}", e);
+                }
+            }
+            Presented::Dns(_, None) => {}
+        }
+    }
+}
+
+/// Keeps the certificate for the service's own hostname valid, requesting a
+/// new one whenever the current one is missing or close to expiry.
+pub async fn run(config: AcmeConfig, store: Arc<CertStore>, solver: Solver) {
+    loop {
+        let wait = match renew_if_needed(&config, &store, &solver).await {
+            Ok(()) => CHECK_INTERVAL,
+            Err(e) => {
+                error!(
+                    "ACME certificate request for {} failed: {}",
+                    config.domain, e
+                );
+                RETRY_INTERVAL
+            }
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+async fn renew_if_needed(
+    config: &AcmeConfig,
+    store: &CertStore,
+    solver: &Solver,
+) -> Result<(), DynIpError> {
+    if !needs_renewal(&store.cert_path) {
+        return Ok(());
+    }
+    info!(
+        "Requesting certificate for {} from {} using {}",
+        config.domain,
+        config.directory_url,
+        config.challenge.as_str()
+    );
+    let mut client = AcmeClient::new(config).await?;
+    let (chain, key) = client.order_certificate(config, solver).await?;
+    write_file(&store.key_path, key.as_bytes(), true)?;
+    write_file(&store.cert_path, chain.as_bytes(), false)?;
+    store.reload()
+}
+
+fn needs_renewal(cert_path: &Path) -> bool {
+    let Ok(pem) = std::fs::read(cert_path) else {
+        return true;
+    };
+    let Ok((_, pem)) = x509_parser::pem::parse_x509_pem(&pem) else {
+        return true;
+    };
+    let Ok(cert) = pem.parse_x509() else {
+        return true;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    cert.validity().not_after.timestamp() - now < RENEW_BEFORE.as_secs() as i64
+}
+
+fn write_file(path: &Path, contents: &[u8], private: bool) -> Result<(), DynIpError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, contents)?;
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    Ok(())
+}
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+fn acme_error(e: impl std::fmt::Display) -> DynIpError {
+    DynIpError::Acme(e.to_string())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    r#type: String,
+    url: String,
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+trait Pollable {
+    fn status(&self) -> &str;
+}
+
+impl Pollable for Order {
+    fn status(&self) -> &str {
+        &self.status
+    }
+}
+
+impl Pollable for Authorization {
+    fn status(&self) -> &str {
+        &self.status
+    }
+}
+
+/// A minimal RFC 8555 client: one ES256 account key, one identifier per order.
+struct AcmeClient {
+    http: Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(config: &AcmeConfig) -> Result<AcmeClient, DynIpError> {
+        let mut builder = Client::builder();
+        if let Some(ca_cert) = &config.ca_cert {
+            let pem = std::fs::read(ca_cert)?;
+            builder = builder
+                .add_root_certificate(reqwest::Certificate::from_pem(&pem).map_err(acme_error)?);
+        }
+        let http = builder.build().map_err(acme_error)?;
+        let response = http
+            .get(&config.directory_url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(acme_error)?;
+        let directory = response.json::<Directory>().await.map_err(acme_error)?;
+        let rng = SystemRandom::new();
+        let key = load_or_create_account_key(&config.account_key_path, &rng)?;
+        Ok(AcmeClient {
+            http,
+            directory,
+            key,
+            rng,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    async fn order_certificate(
+        &mut self,
+        config: &AcmeConfig,
+        solver: &Solver,
+    ) -> Result<(String, String), DynIpError> {
+        self.register(config.email.as_deref()).await?;
+
+        let new_order = self.directory.new_order.clone();
+        let payload = json!({ "identifiers": [{ "type": "dns", "value": config.domain }] });
+        let response = self.post(&new_order, Some(&payload)).await?;
+        let order_url = location(&response)?;
+        let order = response.json::<Order>().await.map_err(acme_error)?;
+
+        for authorization_url in &order.authorizations {
+            self.authorize(authorization_url, solver).await?;
+        }
+
+        let key_pair = CsrKeyPair::generate().map_err(acme_error)?;
+        let csr = CertificateParams::new(vec![config.domain.clone()])
+            .and_then(|params| params.serialize_request(&key_pair))
+            .map_err(acme_error)?;
+        self.post(&order.finalize, Some(&json!({ "csr": b64(csr.der()) })))
+            .await?;
+
+        let order = self.poll::<Order>(&order_url).await?;
+        let certificate_url = order
+            .certificate
+            .ok_or_else(|| DynIpError::Acme("Order is valid but has no certificate".to_string()))?;
+        let response = self.post(&certificate_url, None).await?;
+        let chain = response.text().await.map_err(acme_error)?;
+        Ok((chain, key_pair.serialize_pem()))
+    }
+
+    async fn register(&mut self, email: Option<&str>) -> Result<(), DynIpError> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let new_account = self.directory.new_account.clone();
+        let response = self.post(&new_account, Some(&payload)).await?;
+        self.kid = Some(location(&response)?);
+        Ok(())
+    }
+
+    async fn authorize(&mut self, url: &str, solver: &Solver) -> Result<(), DynIpError> {
+        let response = self.post(url, None).await?;
+        let authorization = response.json::<Authorization>().await.map_err(acme_error)?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|c| c.r#type == solver.challenge.as_str())
+            .ok_or_else(|| {
+                DynIpError::Acme(format!(
+                    "No {} challenge offered for {}",
+                    solver.challenge.as_str(),
+                    authorization.identifier.value
+                ))
+            })?;
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint());
+        let presented = solver
+            .present(
+                &authorization.identifier.value,
+                &challenge.token,
+                &key_authorization,
+            )
+            .await?;
+        let challenge_url = challenge.url.clone();
+        // Kept as a string across the cleanup await; DynIpError is not Send.
+        let result = self
+            .validate(&challenge_url, url)
+            .await
+            .map_err(|e| e.to_string());
+        solver.cleanup(presented).await;
+        result.map_err(DynIpError::Acme)
+    }
+
+    async fn validate(
+        &mut self,
+        challenge_url: &str,
+        authorization_url: &str,
+    ) -> Result<(), DynIpError> {
+        self.post(challenge_url, Some(&json!({}))).await?;
+        self.poll::<Authorization>(authorization_url).await?;
+        Ok(())
+    }
+
+    async fn poll<T: DeserializeOwned + Pollable>(&mut self, url: &str) -> Result<T, DynIpError> {
+        for _ in 0..POLL_ATTEMPTS {
+            let response = self.post(url, None).await?;
+            let value = response.json::<T>().await.map_err(acme_error)?;
+            match value.status() {
+                "valid" => return Ok(value),
+                "invalid" => return Err(DynIpError::Acme(format!("{} is invalid", url))),
+                _ => tokio::time::sleep(POLL_DELAY).await,
+            }
+        }
+        Err(DynIpError::Acme(format!("Timed out waiting for {}", url)))
+    }
+
+    /// Sends a signed request; a `None` payload is a POST-as-GET.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response, DynIpError> {
+        for _ in 0..3 {
+            let nonce = self.nonce().await?;
+            let body = self.sign(url, &nonce, payload)?;
+            let response = self
+                .http
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .body(body)
+                .send()
+                .await
+                .map_err(acme_error)?;
+            self.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            if text.contains("urn:ietf:params:acme:error:badNonce") {
+                continue;
+            }
+            return Err(DynIpError::Acme(format!(
+                "Request to {} failed with status {}: {}",
+                url, status, text
+            )));
+        }
+        Err(DynIpError::Acme(format!("Repeated badNonce from {}", url)))
+    }
+
+    async fn nonce(&mut self) -> Result<String, DynIpError> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(acme_error)?;
+        replay_nonce(&response)
+            .ok_or_else(|| DynIpError::Acme("Server did not return a Replay-Nonce".to_string()))
+    }
+
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<String, DynIpError> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = b64(protected.to_string());
+        let payload = payload.map(|p| b64(p.to_string())).unwrap_or_default();
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| DynIpError::Acme("Failed to sign request".to_string()))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(signature.as_ref()),
+        })
+        .to_string())
+    }
+
+    fn jwk(&self) -> Value {
+        let (x, y) = self.coordinates();
+        json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y })
+    }
+
+    fn thumbprint(&self) -> String {
+        // RFC 7638 requires the members in lexicographic order without whitespace.
+        let (x, y) = self.coordinates();
+        let jwk = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        b64(digest(&SHA256, jwk.as_bytes()).as_ref())
+    }
+
+    fn coordinates(&self) -> (String, String) {
+        // Uncompressed point: 0x04 || x || y
+        let point = self.key.public_key().as_ref();
+        (b64(&point[1..33]), b64(&point[33..65]))
+    }
+}
+
+fn load_or_create_account_key(path: &Path, rng: &SystemRandom) -> Result<EcdsaKeyPair, DynIpError> {
+    let pkcs8 = match std::fs::read(path) {
+        Ok(pkcs8) => pkcs8,
+        Err(_) => {
+            info!("Creating ACME account key at {:?}", path);
+            let document = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, rng)
+                .map_err(|_| DynIpError::Acme("Failed to generate account key".to_string()))?;
+            write_file(path, document.as_ref(), true)?;
+            document.as_ref().to_vec()
+        }
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, rng)
+        .map_err(|e| DynIpError::Acme(format!("Invalid account key {:?}: {}", path, e)))
+}
+
+fn replay_nonce(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get("Replay-Nonce")
+        .and_then(|h| h.to_str().ok())
+        .map(|s| s.to_string())
+}
+
+fn location(response: &Response) -> Result<String, DynIpError> {
+    response
+        .headers()
+        .get("Location")
+        .and_then(|h| h.to_str().ok())
+        .map(|s| s.to_string())
+        .ok_or_else(|| DynIpError::Acme("Response is missing a Location header".to_string()))
+}
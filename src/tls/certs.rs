@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use tracing::{error, info};
+
+use crate::error::DynIpError;
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Holds the certificate served by the TLS listener and swaps it in place
+/// whenever the files on disk change, so renewals never need a restart.
+#[derive(Debug)]
+pub struct CertStore {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl CertStore {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> CertStore {
+        CertStore {
+            cert_path,
+            key_path,
+            current: RwLock::new(None),
+        }
+    }
+
+    pub fn reload(&self) -> Result<(), DynIpError> {
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().expect("cert store lock poisoned") = Some(Arc::new(key));
+        info!("Loaded TLS certificate from {:?}", self.cert_path);
+        Ok(())
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = std::fs::metadata(&self.cert_path).and_then(|m| m.modified());
+        let key = std::fs::metadata(&self.key_path).and_then(|m| m.modified());
+        cert.ok().zip(key.ok())
+    }
+
+    /// Polls the certificate and key for changes. Polling rather than inotify
+    /// also catches the symlink swaps used by Kubernetes secret mounts.
+    pub async fn watch(self: Arc<Self>) {
+        let mut last_modified = self.modified();
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let modified = self.modified();
+            if modified.is_none() || modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+            if let Err(e) = self.reload() {
+                error!("Keeping previous TLS certificate: {}", e);
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current
+            .read()
+            .expect("cert store lock poisoned")
+            .clone()
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, DynIpError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(DynIpError::Tls(format!(
+            "No certificates found in {:?}",
+            cert_path
+        )));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| DynIpError::Tls(format!("No private key found in {:?}", key_path)))?;
+    let key = any_supported_type(&key).map_err(|e| DynIpError::Tls(e.to_string()))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Builds the listener config. With a `client_ca`, connections must present a
+/// client certificate signed by one of the CAs in that bundle.
+pub fn server_config(
+    store: Arc<CertStore>,
+    client_ca: Option<&Path>,
+) -> Result<ServerConfig, DynIpError> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| DynIpError::Tls(e.to_string()))?;
+    let builder = match client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca_path)?)) {
+                roots
+                    .add(cert?)
+                    .map_err(|e| DynIpError::Tls(e.to_string()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| DynIpError::Tls(format!("Invalid CA bundle {:?}: {}", ca_path, e)))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(store);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
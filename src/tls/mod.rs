@@ -0,0 +1,23 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::tls::acme::AcmeConfig;
+
+pub mod acme;
+pub mod certs;
+
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub listen: SocketAddr,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub acme: Option<AcmeConfig>,
+    pub mtls: Option<MtlsConfig>,
+}
+
+/// A second TLS listener that requires a client certificate signed by `ca_path`.
+#[derive(Clone)]
+pub struct MtlsConfig {
+    pub listen: SocketAddr,
+    pub ca_path: PathBuf,
+}
@@ -0,0 +1,204 @@
+mod common;
+
+use actix_web::dev::Extensions;
+use actix_web::{test, web, App, HttpServer};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use common::cloudflare::Zone;
+use dyn_ip::aws::zones::Zones;
+use dyn_ip::server::client_cert::{ClientIdentity, IdentityMap};
+use dyn_ip::server::routes::acme;
+use dyn_ip::server::state::{Live, Runtime};
+use ring::digest::{digest, SHA256};
+use serde_json::{json, Value};
+
+fn routes(app: &mut web::ServiceConfig) {
+    app.route("/acme/present", web::post().to(acme::present))
+        .route("/acme/cleanup", web::post().to(acme::cleanup))
+        .route("/acme/update", web::post().to(acme::acme_dns_update));
+}
+
+/// A zone with home and office A records, and the ID that authorizes home.
+async fn zone() -> (Zone, Runtime, String) {
+    let zone = Zone::start("example.com").await;
+    zone.add("home.example.com", "A", "198.51.100.1");
+    zone.add("office.example.com", "A", "198.51.100.2");
+    let runtime = common::runtime(Zones::new(vec![zone.cloudflare()]));
+    let records = runtime
+        .zones
+        .list_display_records(&runtime.config.salt)
+        .await
+        .unwrap();
+    let home = records
+        .iter()
+        .find(|r| r.domain == "home.example.com")
+        .unwrap();
+    let id = home.id.clone();
+    (zone, runtime, id)
+}
+
+fn txt_values(zone: &Zone) -> Vec<String> {
+    zone.records()
+        .iter()
+        .filter(|r| r["type"] == "TXT")
+        .map(|r| {
+            format!(
+                "{}={}",
+                r["name"].as_str().unwrap(),
+                r["content"].as_str().unwrap()
+            )
+        })
+        .collect()
+}
+
+fn basic(key: &str) -> (&'static str, String) {
+    (
+        "Authorization",
+        format!("Basic {}", STANDARD.encode(format!("lego:{}", key))),
+    )
+}
+
+#[actix_web::test]
+async fn challenges_need_the_record_id_of_their_host() {
+    let (zone, runtime, home_id) = zone().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(Live::from_pointee(runtime)))
+            .configure(routes),
+    )
+    .await;
+    let app = &app;
+    let call = |path: &str, key: Option<&str>, body: Value| {
+        let mut request = test::TestRequest::post().uri(path).set_json(body);
+        if let Some(key) = key {
+            request = request.insert_header(basic(key));
+        }
+        async move { test::call_service(app, request.to_request()).await.status() }
+    };
+    let home = json!({ "fqdn": "_acme-challenge.home.example.com.", "value": "home-value" });
+    let office = json!({ "fqdn": "_acme-challenge.office.example.com.", "value": "office-value" });
+
+    assert_eq!(call("/acme/present", None, home.clone()).await, 401);
+    assert_eq!(
+        call("/acme/present", Some("unknown"), home.clone()).await,
+        401
+    );
+    assert_eq!(
+        call("/acme/present", Some(&home_id), office.clone()).await,
+        403
+    );
+    assert_eq!(call("/acme/cleanup", Some(&home_id), office).await, 403);
+    let bad = json!({ "fqdn": "home.example.com", "value": "home-value" });
+    assert_eq!(call("/acme/present", Some(&home_id), bad).await, 400);
+    let bad = json!({ "fqdn": "_acme-challenge.home.example.com", "value": "not valid" });
+    assert_eq!(call("/acme/present", Some(&home_id), bad).await, 400);
+    assert!(txt_values(&zone).is_empty());
+
+    for _ in 0..2 {
+        assert_eq!(
+            call("/acme/present", Some(&home_id), home.clone()).await,
+            200
+        );
+    }
+    assert_eq!(
+        txt_values(&zone),
+        ["_acme-challenge.home.example.com=home-value"]
+    );
+
+    // RAW mode sends the key authorization and leaves the digest to the server
+    let raw = json!({ "domain": "*.home.example.com", "keyAuth": "token.thumbprint" });
+    assert_eq!(
+        call("/acme/present", Some(&home_id), raw.clone()).await,
+        200
+    );
+    let digest = URL_SAFE_NO_PAD.encode(digest(&SHA256, b"token.thumbprint"));
+    assert_eq!(
+        txt_values(&zone),
+        [
+            "_acme-challenge.home.example.com=home-value".to_string(),
+            format!("_acme-challenge.home.example.com={}", digest),
+        ]
+    );
+
+    assert_eq!(call("/acme/cleanup", None, home.clone()).await, 401);
+    assert_eq!(call("/acme/cleanup", Some(&home_id), home).await, 200);
+    assert_eq!(call("/acme/cleanup", Some(&home_id), raw).await, 200);
+    assert!(txt_values(&zone).is_empty());
+}
+
+#[actix_web::test]
+async fn acme_dns_keeps_the_two_latest_values() {
+    let (zone, runtime, home_id) = zone().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(Live::from_pointee(runtime)))
+            .configure(routes),
+    )
+    .await;
+    let update = |key: &str, subdomain: &str, txt: &str| {
+        let request = test::TestRequest::post()
+            .uri("/acme/update")
+            .insert_header(("X-Api-Key", key.to_string()))
+            .set_json(json!({ "subdomain": subdomain, "txt": txt }));
+        test::call_service(&app, request.to_request())
+    };
+
+    assert_eq!(
+        update(&home_id, "office.example.com", "one").await.status(),
+        403
+    );
+    assert_eq!(update("", "home.example.com", "one").await.status(), 401);
+    for txt in ["one", "two", "three"] {
+        let response = update(&home_id, "_acme-challenge.home.example.com", txt).await;
+        assert_eq!(response.status(), 200);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body, json!({ "txt": txt }));
+    }
+    assert_eq!(
+        txt_values(&zone),
+        [
+            "_acme-challenge.home.example.com=two",
+            "_acme-challenge.home.example.com=three",
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn client_certificates_cover_their_own_and_mapped_hosts() {
+    let (zone, mut runtime, _) = zone().await;
+    runtime.config.client_identities = IdentityMap::parse("router=*.lab.example.com").unwrap();
+    let live = web::Data::new(Live::from_pointee(runtime));
+    // Every connection presents the router's certificate, as on_connect would record it
+    let server = HttpServer::new(move || App::new().app_data(live.clone()).configure(routes))
+        .on_connect(|_, data: &mut Extensions| {
+            data.insert(ClientIdentity {
+                names: vec!["router".to_string(), "home.example.com".to_string()],
+            });
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("http://{}", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+    let client = reqwest::Client::new();
+    let present = |host: &str| {
+        client
+            .post(format!("{}/acme/present", url))
+            .json(&json!({ "fqdn": format!("_acme-challenge.{}", host), "value": "cert-value" }))
+            // A certificate holder needs no key, and a wrong one doesn't matter
+            .basic_auth("lego", Some("unknown"))
+            .send()
+    };
+
+    assert_eq!(present("home.example.com").await.unwrap().status(), 200);
+    assert_eq!(present("a.lab.example.com").await.unwrap().status(), 200);
+    assert_eq!(present("office.example.com").await.unwrap().status(), 403);
+    assert_eq!(present("a.b.lab.example.com").await.unwrap().status(), 403);
+    assert_eq!(
+        txt_values(&zone),
+        [
+            "_acme-challenge.home.example.com=cert-value",
+            "_acme-challenge.a.lab.example.com=cert-value",
+        ]
+    );
+}
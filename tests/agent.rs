@@ -0,0 +1,142 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::Server;
+use dyn_ip::agent::state::State;
+use dyn_ip::agent::{Agent, AgentConfig};
+use dyn_ip::discovery::Family;
+
+/// A server that reports `ip` as the caller's address and accepts updates.
+async fn server(ip: &Arc<Mutex<String>>) -> Server {
+    let ip = ip.clone();
+    Server::start(move |request| match request.method.as_str() {
+        "GET" => (200, ip.lock().unwrap().clone()),
+        _ => (200, "{}".to_string()),
+    })
+    .await
+}
+
+fn config(server: &str, id: &str, state_file: &Path) -> AgentConfig {
+    AgentConfig {
+        server: server.to_string(),
+        id: Some(id.to_string()),
+        interval: Duration::from_secs(60),
+        refresh: Duration::from_secs(1800),
+        state_file: state_file.to_path_buf(),
+        client_cert: None,
+        client_key: None,
+        ca_cert: None,
+        once: true,
+        family: Family::V4,
+        sources: Vec::new(),
+        quorum: 0,
+    }
+}
+
+fn state_file(name: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("dyn-ip-agent-{}-{}.json", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// `id=ip` for every update the server got.
+fn updates(server: &Server) -> Vec<String> {
+    server
+        .requests()
+        .iter()
+        .filter(|r| r.method == "PATCH")
+        .map(|r| format!("{}={}", r.query("id").unwrap(), r.query("ip").unwrap()))
+        .collect()
+}
+
+#[tokio::test]
+async fn updates_are_only_sent_when_the_ip_changes() {
+    let path = state_file("changes");
+    let ip = Arc::new(Mutex::new("198.51.100.1".to_string()));
+    let server = server(&ip).await;
+    let check = || async {
+        Agent::new(config(&server.url, "home", &path))
+            .unwrap()
+            .run()
+            .await
+    };
+
+    check().await.unwrap();
+    check().await.unwrap();
+    assert_eq!(updates(&server), ["home=198.51.100.1"]);
+
+    *ip.lock().unwrap() = "198.51.100.2".to_string();
+    check().await.unwrap();
+    assert_eq!(updates(&server), ["home=198.51.100.1", "home=198.51.100.2"]);
+    let state = State::load(&path, &format!("{}/", server.url), Some("home"));
+    assert_eq!(state.ip, Some("198.51.100.2".parse().unwrap()));
+    assert!(state.updated_at.is_some());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn unchanged_ips_are_sent_again_once_due_a_refresh() {
+    let path = state_file("refresh");
+    let ip = Arc::new(Mutex::new("198.51.100.1".to_string()));
+    let server = server(&ip).await;
+    let check = || async {
+        Agent::new(config(&server.url, "home", &path))
+            .unwrap()
+            .run()
+            .await
+    };
+
+    check().await.unwrap();
+    check().await.unwrap();
+    assert_eq!(updates(&server), ["home=198.51.100.1"]);
+
+    // Sent longer ago than the refresh
+    let mut state = State::load(&path, &server.url, Some("home"));
+    state.updated_at = state.updated_at.map(|at| at - 1800);
+    state.save(&path).unwrap();
+    check().await.unwrap();
+    assert_eq!(updates(&server), ["home=198.51.100.1", "home=198.51.100.1"]);
+    // Which starts the refresh over
+    check().await.unwrap();
+    assert_eq!(updates(&server).len(), 2);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn state_for_another_server_or_record_is_ignored() {
+    let path = state_file("target");
+    let ip = Arc::new(Mutex::new("198.51.100.1".to_string()));
+    let (first, second) = (server(&ip).await, server(&ip).await);
+    let check = |server: &str, id: &str| {
+        let config = config(server, id, &path);
+        async move { Agent::new(config).unwrap().run().await }
+    };
+
+    check(&first.url, "home").await.unwrap();
+    check(&first.url, "office").await.unwrap();
+    check(&second.url, "office").await.unwrap();
+    check(&second.url, "office").await.unwrap();
+    assert_eq!(
+        updates(&first),
+        ["home=198.51.100.1", "office=198.51.100.1"]
+    );
+    assert_eq!(updates(&second), ["office=198.51.100.1"]);
+
+    // Files from before the server and ID were kept count as another record's
+    std::fs::write(&path, r#"{"ip":"198.51.100.1","updated_at":1}"#).unwrap();
+    assert_eq!(State::load(&path, &second.url, Some("office")).ip, None);
+    std::fs::write(&path, "not json").unwrap();
+    assert_eq!(State::load(&path, &second.url, Some("office")).ip, None);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn agents_need_an_id_or_a_certificate() {
+    let mut config = config("http://127.0.0.1:9", "home", Path::new("unused.json"));
+    config.id = None;
+    assert!(Agent::new(config).is_err());
+}
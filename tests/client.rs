@@ -0,0 +1,196 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use common::Server;
+use dyn_ip::aws::record::DisplayRecord;
+use dyn_ip::client::records::{self, RecordsArgs};
+use dyn_ip::client::{ApiClient, ClientConfig};
+use dyn_ip::error::DynIpError;
+
+#[derive(Parser)]
+struct Cli {
+    #[command(flatten)]
+    records: RecordsArgs,
+}
+
+fn records() -> Vec<DisplayRecord> {
+    vec![
+        common::record("home.example.com"),
+        DisplayRecord {
+            record_type: "AAAA".to_string(),
+            ip: "2001:db8::1".to_string(),
+            id: "aaaa0000000000000000000000000001".to_string(),
+            ..common::record("home.example.com")
+        },
+        common::record("office.example.com"),
+        common::record("office.example.org"),
+    ]
+}
+
+/// Serves [`records`] and answers changes with the first of them.
+async fn server() -> Server {
+    Server::start(|request| match (request.method.as_str(), request.route()) {
+        ("GET", "/api/domains") => (200, serde_json::to_string(&records()).unwrap()),
+        ("GET", "/api/history") => (200, "[]".to_string()),
+        ("DELETE", _) => (200, "{}".to_string()),
+        _ => (200, serde_json::to_string(&records()[0]).unwrap()),
+    })
+    .await
+}
+
+fn client(server: &Server) -> ApiClient {
+    ApiClient::new(&ClientConfig {
+        server: Some(format!("{}/", server.url)),
+        ..ClientConfig::default()
+    })
+    .unwrap()
+}
+
+/// A client config with credentials, pointing at a server that isn't there.
+fn config_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "dyn-ip-client-{}-{}.toml",
+        name,
+        std::process::id()
+    ));
+    std::fs::write(
+        &path,
+        "server = \"http://127.0.0.1:9\"\nusername = \"admin\"\npassword = \"secret\"\n",
+    )
+    .unwrap();
+    path
+}
+
+async fn run(server: &Server, config: &Path, args: &[&str]) -> Result<(), DynIpError> {
+    let mut argv = vec!["dyn-ip", "--config", config.to_str().unwrap(), "--server"];
+    argv.push(&server.url);
+    argv.extend(args);
+    records::run(Cli::try_parse_from(argv).unwrap().records).await
+}
+
+#[tokio::test]
+async fn records_are_found_by_id_or_name() {
+    let server = server().await;
+    let client = client(&server);
+    let resolve = |record: &'static str, record_type: Option<&'static str>| {
+        let client = &client;
+        async move {
+            client
+                .resolve(record, record_type)
+                .await
+                .map(|r| format!("{} {}", r.domain, r.record_type))
+        }
+    };
+    let office = records()[2].id.clone();
+    let by_id = client.resolve(&office, None).await.unwrap();
+    assert_eq!(by_id.domain, "office.example.com");
+
+    let cases = [
+        ("office.example.org", None, Ok("office.example.org A")),
+        ("home", Some("aaaa"), Ok("home.example.com AAAA")),
+        ("home.example.com", Some("A"), Ok("home.example.com A")),
+        ("nas", None, Err(None)),
+        ("office", None, Err(Some("office matches several records"))),
+        ("home", None, Err(Some("home matches several records"))),
+    ];
+    for (record, record_type, expected) in cases {
+        match (resolve(record, record_type).await, expected) {
+            (Ok(found), Ok(expected)) => assert_eq!(found, expected),
+            (Err(DynIpError::DomainHashNotFound), Err(None)) => {}
+            (Err(DynIpError::Client(message)), Err(Some(start))) => {
+                assert!(message.starts_with(start), "{}", message)
+            }
+            (found, _) => panic!("{} {:?}: {:?}", record, record_type, found),
+        }
+    }
+    assert!(server
+        .requests()
+        .iter()
+        .all(|r| r.route() == "/api/domains"));
+}
+
+#[tokio::test]
+async fn failures_carry_the_status_and_body() {
+    let server = Server::reply(409, "home.example.com Matches 2 Records").await;
+    match client(&server).delete("id").await {
+        Err(DynIpError::Client(message)) => {
+            assert_eq!(message, "409 Conflict: home.example.com Matches 2 Records")
+        }
+        other => panic!("{:?}", other),
+    }
+    let server = Server::reply(200, "not json").await;
+    assert!(matches!(
+        client(&server).list().await,
+        Err(DynIpError::Client(m)) if m.starts_with("Unexpected response")
+    ));
+    assert!(ApiClient::new(&ClientConfig::default()).is_err());
+}
+
+#[tokio::test]
+async fn commands_send_what_the_server_expects() {
+    let server = server().await;
+    let config = config_file("commands");
+    let home_aaaa = records()[1].id.clone();
+
+    run(
+        &server,
+        &config,
+        &[
+            "add",
+            "www",
+            "--type",
+            "cname",
+            "--value",
+            "home.example.com",
+            "--lease",
+            "60",
+        ],
+    )
+    .await
+    .unwrap();
+    run(
+        &server,
+        &config,
+        &["update", "home", "--type", "A", "--ttl", "60"],
+    )
+    .await
+    .unwrap();
+    assert!(run(&server, &config, &["update", "home", "--type", "A"])
+        .await
+        .is_err());
+    run(&server, &config, &["delete", "home", "--type", "AAAA"])
+        .await
+        .unwrap();
+    run(&server, &config, &["history", "home.example.com"])
+        .await
+        .unwrap();
+    run(&server, &config, &["history", &home_aaaa, "--limit", "5"])
+        .await
+        .unwrap();
+    std::fs::remove_file(config).unwrap();
+
+    let requests = server.requests();
+    let sent: Vec<_> = requests
+        .iter()
+        .map(|r| format!("{} {}", r.method, r.path))
+        .collect();
+    assert_eq!(
+        sent,
+        [
+            "POST /api/domains?host=home.example.com&domain=www&record_type=CNAME&lease=60"
+                .to_string(),
+            "GET /api/domains".to_string(),
+            format!("PUT /api/domains/{}?ttl=60", records()[0].id),
+            "GET /api/domains".to_string(),
+            format!("DELETE /api/domains/{}", home_aaaa),
+            "GET /api/history?limit=20&domain=home.example.com".to_string(),
+            format!("GET /api/history?limit=5&id={}", home_aaaa),
+        ]
+    );
+    // Credentials come from the config file, the server from the flag
+    assert!(requests
+        .iter()
+        .all(|r| r.header("authorization") == Some("Basic YWRtaW46c2VjcmV0")));
+}
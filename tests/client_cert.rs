@@ -0,0 +1,77 @@
+use dyn_ip::server::client_cert::{ClientIdentity, IdentityMap};
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+
+fn identity(names: &[&str]) -> ClientIdentity {
+    ClientIdentity {
+        names: names.iter().map(|n| n.to_string()).collect(),
+    }
+}
+
+#[test]
+fn mappings_are_parsed_and_normalised() {
+    let map =
+        IdentityMap::parse(" Router = Home.Example.com.|*.lab.example.com , nas=nas.example.com,")
+            .unwrap();
+    assert!(
+        map == IdentityMap::new([
+            (
+                "router".to_string(),
+                vec![
+                    "home.example.com".to_string(),
+                    "*.lab.example.com".to_string()
+                ],
+            ),
+            ("nas".to_string(), vec!["nas.example.com".to_string()]),
+        ])
+    );
+    assert!(IdentityMap::parse("").unwrap() == IdentityMap::default());
+    assert_eq!(
+        IdentityMap::parse("router").err().as_deref(),
+        Some("Invalid identity mapping: router")
+    );
+}
+
+#[test]
+fn names_permit_their_own_record_and_mapped_ones() {
+    let map = IdentityMap::parse("router=home.example.com|*.lab.example.com").unwrap();
+    let router = identity(&["router"]);
+    let cases = [
+        ("home.example.com", true),
+        ("HOME.example.com", true),
+        ("office.example.com", false),
+        ("router", true),
+        ("a.lab.example.com", true),
+        // A wildcard covers exactly one label
+        ("lab.example.com", false),
+        ("a.b.lab.example.com", false),
+        (".lab.example.com", false),
+        ("alab.example.com", false),
+    ];
+    for (domain, expected) in cases {
+        assert_eq!(map.permits(&router, domain), expected, "{}", domain);
+    }
+
+    let map = IdentityMap::default();
+    let host = identity(&["nas.example.com", "*.nas.example.com"]);
+    assert!(map.permits(&host, "nas.example.com"));
+    assert!(map.permits(&host, "Share.NAS.example.com"));
+    assert!(!map.permits(&host, "home.example.com"));
+    assert!(!map.permits(&identity(&["home.example.com"]), "nas.example.com"));
+}
+
+#[test]
+fn identities_are_the_common_name_and_dns_names() {
+    let key = KeyPair::generate().unwrap();
+    let mut params =
+        CertificateParams::new(vec!["Home.Example.com".to_string(), "router".to_string()]).unwrap();
+    params.distinguished_name.push(DnType::CommonName, "Router");
+    let cert = params.self_signed(&key).unwrap();
+    let identity = ClientIdentity::from_der(cert.der()).unwrap();
+    assert_eq!(identity.names, ["home.example.com", "router"]);
+
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.distinguished_name = DistinguishedName::new();
+    let anonymous = params.self_signed(&key).unwrap();
+    assert!(ClientIdentity::from_der(anonymous.der()).is_none());
+    assert!(ClientIdentity::from_der(b"not a certificate").is_none());
+}
@@ -0,0 +1,186 @@
+mod common;
+
+use common::cloudflare::{self, Zone};
+use common::Server;
+use dyn_ip::aws::cloudflare::{Auth, AuthMode, Cloudflare};
+use dyn_ip::aws::record::Record;
+
+#[test]
+fn tokens_are_sent_as_bearer() {
+    let headers = Auth::Token("tk_123".to_string()).headers().unwrap();
+    assert_eq!(headers["authorization"], "Bearer tk_123");
+    assert!(headers.get("x-auth-email").is_none());
+    assert!(headers.get("x-auth-key").is_none());
+}
+
+#[test]
+fn global_keys_are_sent_with_their_email() {
+    let auth = Auth::GlobalKey {
+        email: "ops@example.com".to_string(),
+        key: "0123abcd".to_string(),
+    };
+    assert_eq!(auth.mode(), AuthMode::GlobalKey);
+    let headers = auth.headers().unwrap();
+    assert_eq!(headers["x-auth-email"], "ops@example.com");
+    assert_eq!(headers["x-auth-key"], "0123abcd");
+    assert!(headers.get("authorization").is_none());
+}
+
+#[test]
+fn credentials_must_fit_in_a_header() {
+    assert!(Auth::Token("tk\n123".to_string()).headers().is_err());
+    assert_eq!("global_key".parse(), Ok(AuthMode::GlobalKey));
+    assert!("bearer".parse::<AuthMode>().is_err());
+}
+
+#[tokio::test]
+async fn connecting_looks_up_the_zone_id() {
+    let zone = Zone::start("example.com").await;
+    let auth = Auth::Token(cloudflare::TOKEN.to_string());
+    let connected = Cloudflare::connect(
+        zone.server.url.clone(),
+        auth,
+        None,
+        "example.com".to_string(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(connected.zone_id, cloudflare::ZONE_ID);
+    let paths: Vec<_> = zone.server.requests().into_iter().map(|r| r.path).collect();
+    assert_eq!(
+        paths,
+        [
+            "/user/tokens/verify".to_string(),
+            "/zones?name=example.com".to_string(),
+            format!("/zones/{}", cloudflare::ZONE_ID),
+            format!("/zones/{}/dns_records?per_page=1", cloudflare::ZONE_ID),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn connecting_says_what_to_fix() {
+    let zone = Zone::start("example.com").await;
+    let connect = |auth: Auth, zone_id: Option<&str>, domain: &str| {
+        Cloudflare::connect(
+            zone.server.url.clone(),
+            auth,
+            zone_id.map(str::to_string),
+            domain.to_string(),
+        )
+    };
+    let token = || Auth::Token(cloudflare::TOKEN.to_string());
+
+    let rejected = connect(Auth::Token("tk_bad".to_string()), None, "example.com")
+        .await
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        rejected.contains("the API token was rejected"),
+        "{}",
+        rejected
+    );
+    assert!(
+        rejected.contains("400 Bad Request Invalid request headers"),
+        "{}",
+        rejected
+    );
+
+    let unknown = connect(token(), None, "example.org")
+        .await
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        unknown.contains("example.org: no zone with this name"),
+        "{}",
+        unknown
+    );
+
+    let wrong_id = connect(token(), Some("other"), "example.com")
+        .await
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        wrong_id.contains("zone ID other isn't accessible"),
+        "{}",
+        wrong_id
+    );
+    assert!(
+        wrong_id.contains("403 Forbidden Could not route"),
+        "{}",
+        wrong_id
+    );
+
+    let other_zone = connect(token(), Some(cloudflare::ZONE_ID), "example.net")
+        .await
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        other_zone.contains("belongs to example.com"),
+        "{}",
+        other_zone
+    );
+}
+
+#[tokio::test]
+async fn proxy_errors_keep_their_status_and_body() {
+    let proxy = Server::reply(502, "<html>Bad gateway</html>").await;
+    let error = Cloudflare::connect(
+        proxy.url.clone(),
+        Auth::Token(cloudflare::TOKEN.to_string()),
+        None,
+        "example.com".to_string(),
+    )
+    .await
+    .err()
+    .unwrap()
+    .to_string();
+    assert!(
+        error.contains("502 Bad Gateway with a body that isn't JSON: <html>Bad gateway</html>"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn records_round_trip() {
+    let zone = Zone::start("example.com").await;
+    zone.add("home.example.com", "A", "198.51.100.1");
+    let cloudflare = zone.cloudflare();
+    let created = cloudflare
+        .create_record(Record {
+            domain: "nas.example.com".to_string(),
+            record_type: "AAAA".to_string(),
+            ip: "2001:db8::1".to_string(),
+            ..Record::default()
+        })
+        .await
+        .unwrap();
+    let mut updated = created.clone();
+    updated.ip = "2001:db8::2".to_string();
+    cloudflare.update_record(updated).await.unwrap();
+
+    let records = cloudflare.list_records().await.unwrap();
+    let values: Vec<_> = records
+        .iter()
+        .map(|r| (r.domain.as_str(), r.ip.as_str()))
+        .collect();
+    assert_eq!(
+        values,
+        [
+            ("home.example.com", "198.51.100.1"),
+            ("nas.example.com", "2001:db8::2")
+        ]
+    );
+
+    let source_id = created.source_id.unwrap();
+    cloudflare
+        .delete_record_by_source_id(&source_id)
+        .await
+        .unwrap();
+    assert_eq!(zone.records().len(), 1);
+}
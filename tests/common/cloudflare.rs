@@ -0,0 +1,195 @@
+//! A Cloudflare v4 API stand-in holding one zone's records in memory.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use dyn_ip::aws::cloudflare::{Auth, Cloudflare};
+use serde_json::{json, Value};
+
+use super::{Request, Server};
+
+pub const TOKEN: &str = "tk_good";
+pub const ZONE_ID: &str = "023e105f4ecef8ad9ca31a8372d0c353";
+
+pub struct Zone {
+    pub server: Server,
+    pub domain: String,
+    records: Arc<Mutex<Vec<Value>>>,
+    failing: Arc<AtomicBool>,
+}
+
+impl Zone {
+    /// Answers for `domain` as zone [`ZONE_ID`], accepting only [`TOKEN`].
+    pub async fn start(domain: &str) -> Zone {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let failing = Arc::new(AtomicBool::new(false));
+        let (held, apex, down) = (records.clone(), domain.to_string(), failing.clone());
+        let next_id = AtomicU64::new(1);
+        let server = Server::start(move |request| {
+            if down.load(Ordering::SeqCst) {
+                return error(500, 10000, "Internal server error");
+            }
+            let id = || format!("rec{}", next_id.fetch_add(1, Ordering::SeqCst));
+            respond(request, &apex, &mut held.lock().unwrap(), id)
+        })
+        .await;
+        Zone {
+            server,
+            domain: domain.to_string(),
+            records,
+            failing,
+        }
+    }
+
+    /// A client for this zone, as if connected with the right zone ID.
+    pub fn cloudflare(&self) -> Cloudflare {
+        Cloudflare::new(
+            self.server.url.clone(),
+            Auth::Token(TOKEN.to_string()),
+            ZONE_ID.to_string(),
+            self.domain.clone(),
+        )
+    }
+
+    /// Adds a record as if made in the dashboard, returning its ID.
+    pub fn add(&self, name: &str, record_type: &str, content: &str) -> String {
+        let mut records = self.records.lock().unwrap();
+        let id = format!("dash{}", records.len() + 1);
+        records.push(record(
+            &id,
+            &json!({ "name": name, "type": record_type, "content": content }),
+        ));
+        id
+    }
+
+    /// Answers every request with a 500 while `failing`.
+    pub fn fail(&self, failing: bool) {
+        self.failing.store(failing, Ordering::SeqCst);
+    }
+
+    pub fn records(&self) -> Vec<Value> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+fn record(id: &str, body: &Value) -> Value {
+    json!({
+        "id": id,
+        "name": body["name"],
+        "type": body["type"],
+        "content": body.get("content").cloned().unwrap_or(json!("")),
+        "data": body.get("data"),
+        "priority": body.get("priority"),
+        "ttl": body.get("ttl").cloned().unwrap_or(json!(1)),
+        "proxied": body.get("proxied").cloned().unwrap_or(json!(false)),
+        "proxiable": true,
+        "comment": body.get("comment"),
+        "settings": {},
+        "tags": [],
+        "meta": {},
+        "created_on": format!("2024-01-01T00:00:00.{:06}Z", id.len()),
+        "modified_on": "2024-01-01T00:00:00Z",
+    })
+}
+
+fn ok(result: Value) -> (u16, String) {
+    (
+        200,
+        json!({ "success": true, "errors": [], "messages": [], "result": result }).to_string(),
+    )
+}
+
+fn list(result: Vec<Value>, page: u32) -> (u16, String) {
+    let count = result.len() as u32;
+    let body = json!({
+        "success": true,
+        "errors": [],
+        "messages": [],
+        "result": result,
+        "result_info": {
+            "count": count, "page": page, "per_page": 100, "total_count": count, "total_pages": 1
+        },
+    });
+    (200, body.to_string())
+}
+
+fn error(status: u16, code: u32, message: &str) -> (u16, String) {
+    let body = json!({
+        "success": false,
+        "errors": [{ "code": code, "message": message }],
+        "messages": [],
+        "result": null,
+    });
+    (status, body.to_string())
+}
+
+fn respond(
+    request: &Request,
+    apex: &str,
+    records: &mut Vec<Value>,
+    id: impl FnOnce() -> String,
+) -> (u16, String) {
+    if request.header("authorization") != Some(&format!("Bearer {}", TOKEN)) {
+        return error(400, 6003, "Invalid request headers");
+    }
+    let zone = format!("/zones/{}", ZONE_ID);
+    let route = request.route();
+    let record_id = route
+        .strip_prefix(&format!("{}/dns_records/", zone))
+        .map(str::to_string);
+    match (request.method.as_str(), route) {
+        ("GET", "/user/tokens/verify") => ok(json!({ "id": "token", "status": "active" })),
+        ("GET", "/zones") => match request.query("name") {
+            Some(name) if name == apex => ok(json!([{ "id": ZONE_ID, "name": apex }])),
+            _ => ok(json!([])),
+        },
+        ("GET", r) if r == zone => ok(json!({ "id": ZONE_ID, "name": apex })),
+        ("GET", r) if r.starts_with("/zones/") && !r.contains("/dns_records") => error(
+            403,
+            7003,
+            "Could not route to /zones, perhaps your object identifier is invalid?",
+        ),
+        ("GET", r) if r == format!("{}/dns_records", zone) => {
+            let page = request
+                .query("page")
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(1);
+            let wanted = |r: &&Value| {
+                request
+                    .query("type")
+                    .is_none_or(|t| r["type"] == t.as_str())
+                    && request
+                        .query("name")
+                        .is_none_or(|n| r["name"] == n.as_str())
+            };
+            let mut found: Vec<_> = match page {
+                1 => records.iter().filter(wanted).cloned().collect(),
+                _ => Vec::new(),
+            };
+            if request.query("per_page").as_deref() == Some("1") {
+                found.truncate(1);
+            }
+            list(found, page)
+        }
+        ("POST", r) if r == format!("{}/dns_records", zone) => {
+            let created = record(&id(), &request.json());
+            records.push(created.clone());
+            ok(created)
+        }
+        ("PATCH", _) | ("DELETE", _) if record_id.is_some() => {
+            let record_id = record_id.unwrap();
+            let Some(i) = records.iter().position(|r| r["id"] == record_id.as_str()) else {
+                return error(404, 81044, "Record does not exist.");
+            };
+            if request.method == "DELETE" {
+                records.remove(i);
+                return ok(json!({ "id": record_id }));
+            }
+            let mut body = request.json();
+            body["type"] = records[i]["type"].clone();
+            records[i] = record(&record_id, &body);
+            ok(records[i].clone())
+        }
+        _ => error(404, 7000, "No route for that URI"),
+    }
+}
@@ -0,0 +1,239 @@
+//! Fixtures shared by the integration tests.
+#![allow(dead_code)]
+
+pub mod cloudflare;
+
+use std::sync::{Arc, Mutex};
+
+use dyn_ip::aws::record::DisplayRecord;
+use dyn_ip::aws::zones::Zones;
+use dyn_ip::server::api::ApiConfig;
+use dyn_ip::server::auth::Auth;
+use dyn_ip::server::history::{Action, Change};
+use dyn_ip::server::state::Runtime;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// An A record for `domain` at 198.51.100.2, override fields with
+/// `DisplayRecord { ip: ..., ..record(domain) }`.
+pub fn record(domain: &str) -> DisplayRecord {
+    DisplayRecord {
+        domain: domain.to_string(),
+        record_type: "A".to_string(),
+        ip: "198.51.100.2".to_string(),
+        ttl: 1,
+        id: format!("{:?}", md5::compute(domain)),
+        source_id: format!("source-{}", domain),
+        priority: None,
+        weight: None,
+        port: None,
+        target: None,
+        flags: None,
+        tag: None,
+        proxied: false,
+        comment: None,
+        last_seen: None,
+        expected_interval: None,
+        stale: false,
+        lease_expires_at: None,
+    }
+}
+
+/// `record(domain)` moving from 198.51.100.1 to 198.51.100.2.
+pub fn change(action: Action, domain: &str) -> Change {
+    Change::new(
+        action,
+        &record(domain),
+        Some("198.51.100.1".to_string()),
+        None,
+    )
+}
+
+/// No credentials, identities, policy or subscribers, with "salt" as the salt.
+pub fn api_config() -> ApiConfig {
+    ApiConfig {
+        salt: "salt".to_string(),
+        auth: Auth {
+            username: None,
+            password: None,
+        },
+        trust_proxy_headers: false,
+        client_identities: Default::default(),
+        policy: Default::default(),
+        webhooks: Vec::new(),
+        notifiers: Vec::new(),
+    }
+}
+
+pub fn runtime(zones: Zones) -> Runtime {
+    Runtime {
+        config: api_config(),
+        zones,
+    }
+}
+
+/// A request as the stand-in server received it.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    /// With the query string
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The path without the query string.
+    pub fn route(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+
+    pub fn query(&self, name: &str) -> Option<String> {
+        let query = self.path.split_once('?')?.1;
+        reqwest::Url::parse(&format!("http://localhost/?{}", query))
+            .ok()?
+            .query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+/// What the stand-in server answers with. A `(status, body)` pair is a
+/// reply without extra headers.
+#[derive(Clone, Debug, Default)]
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Reply {
+    pub fn header(mut self, name: &str, value: &str) -> Reply {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+impl From<(u16, String)> for Reply {
+    fn from((status, body): (u16, String)) -> Reply {
+        Reply {
+            status,
+            headers: Vec::new(),
+            body,
+        }
+    }
+}
+
+type Respond = dyn Fn(&Request) -> Reply + Send + Sync;
+
+/// An HTTP server on a random local port that answers with `respond` and
+/// keeps every request it gets.
+pub struct Server {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    pub async fn start<R: Into<Reply>>(
+        respond: impl Fn(&Request) -> R + Send + Sync + 'static,
+    ) -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let respond: Arc<Respond> = Arc::new(move |request| respond(request).into());
+        let kept = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let (respond, kept) = (respond.clone(), kept.clone());
+                tokio::spawn(serve(socket, respond, kept));
+            }
+        });
+        Server { url, requests }
+    }
+
+    /// Answers every request with `status` and `body`.
+    pub async fn reply(status: u16, body: &str) -> Server {
+        let body = body.to_string();
+        Server::start(move |_| (status, body.clone())).await
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The only request, failing if there were more or none.
+    pub fn request(&self) -> Request {
+        let requests = self.requests();
+        assert_eq!(requests.len(), 1, "{:?}", requests);
+        requests[0].clone()
+    }
+}
+
+/// Answers one request, keeping it before replying so it's there once the
+/// client has its response.
+async fn serve(
+    mut socket: TcpStream,
+    respond: Arc<Respond>,
+    kept: Arc<Mutex<Vec<Request>>>,
+) -> Option<()> {
+    let mut raw = Vec::new();
+    let mut buf = [0u8; 4096];
+    let (head, body) = loop {
+        let n = socket.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        raw.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&raw).to_string();
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let length = head
+                .lines()
+                .find_map(|l| {
+                    let (k, v) = l.split_once(':')?;
+                    k.eq_ignore_ascii_case("content-length")
+                        .then(|| v.trim().parse::<usize>().ok())?
+                })
+                .unwrap_or(0);
+            if body.len() >= length {
+                break (head.to_string(), body.to_string());
+            }
+        }
+    };
+    let mut lines = head.lines();
+    let mut start = lines.next()?.split(' ');
+    let request = Request {
+        method: start.next()?.to_string(),
+        path: start.next()?.to_string(),
+        headers: lines
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+            .collect(),
+        body,
+    };
+    let reply = respond(&request);
+    kept.lock().unwrap().push(request);
+    let headers: String = reply
+        .headers
+        .iter()
+        .map(|(k, v)| format!("{}: {}\r\n", k, v))
+        .collect();
+    let response = format!(
+        "HTTP/1.1 {} Stand-in\r\ncontent-type: application/json\r\n{}content-length: {}\r\nconnection: close\r\n\r\n{}",
+        reply.status,
+        headers,
+        reply.body.len(),
+        reply.body
+    );
+    socket.write_all(response.as_bytes()).await.ok()
+}
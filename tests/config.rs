@@ -0,0 +1,378 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use dyn_ip::config::{ServeArgs, Settings};
+
+fn config_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("dyn-ip-{}-{}.toml", name, std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+const BASE: &str = r#"
+[cloudflare]
+zone_id = "zone"
+api_key = "key"
+email = "admin@example.com"
+domain_name = "example.com"
+
+[server]
+listen = ["0.0.0.0:8080", "[::]:8080"]
+salt = "from-file"
+
+[records."home.example.com"]
+ttl = 60
+locked = true
+expected_interval = 300
+"#;
+
+#[test]
+fn flags_override_the_file() {
+    let path = config_file("override", BASE);
+    let settings = Settings::load(ServeArgs {
+        config: Some(path.clone()),
+        salt: Some("from-flag".to_string()),
+        // Blank env vars, as in .env.sample, leave the file's value alone
+        domain_name: Some(String::new()),
+        expected_interval: Some("3600".to_string()),
+        ..ServeArgs::default()
+    })
+    .unwrap_or_else(|e| panic!("{:?}", e));
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(settings.api.salt, "from-flag");
+    assert_eq!(settings.zones[0].domain_name, "example.com");
+    assert_eq!(settings.listen.len(), 2);
+    let home = settings.api.policy.record("Home.Example.com.").unwrap();
+    assert_eq!(home.ttl, Some(60));
+    assert!(home.locked);
+    let policy = &settings.api.policy;
+    assert_eq!(
+        policy.expected_interval("home.example.com"),
+        Some(Duration::from_secs(300))
+    );
+    assert_eq!(
+        policy.expected_interval("office.example.com"),
+        Some(Duration::from_secs(3600))
+    );
+}
+
+#[test]
+fn errors_name_the_offending_key() {
+    let path = config_file(
+        "errors",
+        &format!(
+            "{}\n[tls]\nlisten = \"nope\"\n",
+            BASE.replace("ttl = 60", "ttl = 5")
+        ),
+    );
+    let errors = match Settings::load(ServeArgs {
+        config: Some(path.clone()),
+        acme_challenge: Some("tls-alpn-01".to_string()),
+        ..ServeArgs::default()
+    }) {
+        Ok(_) => panic!("invalid config accepted"),
+        Err(errors) => errors,
+    };
+    std::fs::remove_file(&path).unwrap();
+
+    let file = path.display().to_string();
+    assert!(errors.contains(&format!(
+        "tls.listen in {}: \"nope\" is not an address: invalid socket address syntax",
+        file
+    )));
+    assert!(errors.iter().any(|e| e.starts_with("--acme-challenge: ")));
+    assert!(errors
+        .iter()
+        .any(|e| e.starts_with(&format!("records.\"home.example.com\" in {}: TTL", file))));
+    assert_eq!(errors.len(), 3, "{:?}", errors);
+}
+
+#[test]
+fn zones_are_served_alongside_cloudflare() {
+    let path = config_file(
+        "zones",
+        &format!(
+            "{}\n[[zones]]\nzone_id = \"other\"\napi_key = \"key\"\nemail = \"admin@example.org\"\ndomain_name = \"example.org\"\n\n[[zones]]\nprovider = \"route53\"\ndomain_name = \"example.com\"\n",
+            BASE
+        ),
+    );
+    let errors = match Settings::load(ServeArgs {
+        config: Some(path.clone()),
+        ..ServeArgs::default()
+    }) {
+        Ok(_) => panic!("invalid config accepted"),
+        Err(errors) => errors,
+    };
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(
+        errors.iter().any(|e| e.starts_with("zones[1].provider")),
+        "{:?}",
+        errors
+    );
+    assert!(
+        errors.iter().any(|e| e.contains("example.com")),
+        "{:?}",
+        errors
+    );
+    assert!(
+        !errors.iter().any(|e| e.starts_with("zones[0]")),
+        "{:?}",
+        errors
+    );
+}
+
+#[test]
+fn global_key_needs_an_email() {
+    let path = config_file(
+        "global-key",
+        &BASE
+            .replace("[cloudflare]", "[cloudflare]\nauth = \"global-key\"")
+            .replace("email = \"admin@example.com\"\n", ""),
+    );
+    let errors = match Settings::load(ServeArgs {
+        config: Some(path.clone()),
+        ..ServeArgs::default()
+    }) {
+        Ok(_) => panic!("invalid config accepted"),
+        Err(errors) => errors,
+    };
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        errors,
+        vec!["cloudflare.email is required (or CLOUDFLARE_EMAIL / --email)".to_string()]
+    );
+}
+
+#[test]
+fn notifiers_are_checked_for_their_kind() {
+    let path = config_file(
+        "notifiers",
+        &format!(
+            "{}\n[[notifiers]]\nname = \"chat\"\nkind = \"matrix\"\nurl = \"https://matrix.example.org\"\n\n[[notifiers]]\nname = \"mail\"\nkind = \"smtp\"\nurl = \"smtps://mail.example.com\"\nfrom = \"dyn-ip@example.com\"\nto = [\"not an address\"]\nmessage = \"{{domian}} changed\"\n\n[records.\"office.example.com\"]\nnotify = [\"chat\", \"pager\"]\n",
+            BASE
+        ),
+    );
+    let errors = match Settings::load(ServeArgs {
+        config: Some(path.clone()),
+        ..ServeArgs::default()
+    }) {
+        Ok(_) => panic!("invalid config accepted"),
+        Err(errors) => errors,
+    };
+    std::fs::remove_file(&path).unwrap();
+
+    let file = path.display().to_string();
+    assert!(errors.contains(&format!(
+        "notifiers[0].room in {}: is required for matrix",
+        file
+    )));
+    assert!(errors.contains(&format!(
+        "notifiers[0].token in {}: is required for matrix",
+        file
+    )));
+    assert!(errors
+        .iter()
+        .any(|e| e.starts_with(&format!("notifiers[1].to in {}: \"not an address\"", file))));
+    assert!(errors
+        .iter()
+        .any(|e| e.contains("notifiers[1].message") && e.contains("{domian}")));
+    assert!(errors.contains(&format!(
+        "records.\"office.example.com\".notify in {}: no notifier is named \"pager\"",
+        file
+    )));
+    assert_eq!(errors.len(), 5, "{:?}", errors);
+}
+
+#[test]
+fn mqtt_is_configured_by_section_or_flags() {
+    let path = config_file(
+        "mqtt",
+        &format!(
+            "{}\n[mqtt]\nurl = \"mqtt://broker.lan\"\ntopic_prefix = \"dns/\"\n",
+            BASE
+        ),
+    );
+    let settings = Settings::load(ServeArgs {
+        config: Some(path.clone()),
+        mqtt_commands: Some("true".to_string()),
+        ..ServeArgs::default()
+    })
+    .unwrap_or_else(|e| panic!("{:?}", e));
+    let errors = match Settings::load(ServeArgs {
+        config: Some(path.clone()),
+        mqtt_url: Some("http://broker.lan".to_string()),
+        ..ServeArgs::default()
+    }) {
+        Ok(_) => panic!("invalid config accepted"),
+        Err(errors) => errors,
+    };
+    std::fs::remove_file(&path).unwrap();
+
+    let mqtt = settings.mqtt.unwrap();
+    assert_eq!(mqtt.topic_prefix, "dns");
+    assert_eq!(mqtt.discovery_prefix, "homeassistant");
+    assert!(mqtt.commands);
+    assert_eq!(errors, ["--mqtt-url: must be mqtt or mqtts"]);
+}
+
+#[test]
+fn zones_default_to_the_cloudflare_api_url() {
+    let path = config_file(
+        "api-url",
+        &format!(
+            "{}\n[[zones]]\napi_key = \"key\"\ndomain_name = \"example.org\"\n\n[[zones]]\napi_key = \"key\"\ndomain_name = \"example.net\"\napi_url = \"https://cf.example.net/client/v4/\"\n",
+            BASE
+        ),
+    );
+    let settings = Settings::load(ServeArgs {
+        config: Some(path.clone()),
+        cloudflare_api_url: Some("http://127.0.0.1:9000/client/v4".to_string()),
+        ..ServeArgs::default()
+    })
+    .unwrap_or_else(|e| panic!("{:?}", e));
+    let api_urls: Vec<_> = settings.zones.iter().map(|z| z.api_url.as_str()).collect();
+    assert_eq!(
+        api_urls,
+        [
+            "http://127.0.0.1:9000/client/v4",
+            "http://127.0.0.1:9000/client/v4",
+            "https://cf.example.net/client/v4",
+        ]
+    );
+
+    let errors = match Settings::load(ServeArgs {
+        config: Some(path.clone()),
+        cloudflare_api_url: Some("ftp://cf.example.net".to_string()),
+        ..ServeArgs::default()
+    }) {
+        Ok(_) => panic!("invalid config accepted"),
+        Err(errors) => errors,
+    };
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        errors,
+        ["--cloudflare-api-url: must be http or https"],
+        "{:?}",
+        errors
+    );
+}
+
+#[test]
+fn proxy_headers_are_only_trusted_by_default_without_tls() {
+    let path = config_file("proxy-headers", BASE);
+    let load = |tls_listen: Option<&str>, trust: Option<&str>| {
+        Settings::load(ServeArgs {
+            config: Some(path.clone()),
+            tls_listen: tls_listen.map(str::to_string),
+            tls_cert: Some("cert.pem".to_string()),
+            tls_key: Some("key.pem".to_string()),
+            trust_proxy_headers: trust.map(str::to_string),
+            ..ServeArgs::default()
+        })
+        .unwrap_or_else(|e| panic!("{:?}", e))
+        .api
+        .trust_proxy_headers
+    };
+    let cases = [
+        (None, None, true),
+        (Some("0.0.0.0:8443"), None, false),
+        (Some("0.0.0.0:8443"), Some("true"), true),
+        (None, Some("false"), false),
+    ];
+    for (tls_listen, trust, expected) in cases {
+        assert_eq!(
+            load(tls_listen, trust),
+            expected,
+            "{:?} {:?}",
+            tls_listen,
+            trust
+        );
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn secrets_come_from_the_highest_layer_set() {
+    let secret = std::env::temp_dir().join(format!("dyn-ip-salt-{}", std::process::id()));
+    std::fs::write(&secret, "  from-secret-file\n").unwrap();
+    let empty = std::env::temp_dir().join(format!("dyn-ip-salt-empty-{}", std::process::id()));
+    std::fs::write(&empty, " \n").unwrap();
+    let secret_path = secret.display().to_string();
+    let empty_path = empty.display().to_string();
+
+    let load = |server: String, args: ServeArgs| {
+        let path = config_file(
+            "secrets",
+            &format!(
+                "[cloudflare]\nzone_id = \"zone\"\napi_key = \"key\"\ndomain_name = \"example.com\"\n\n[server]\n{}",
+                server
+            ),
+        );
+        let loaded = Settings::load(ServeArgs {
+            config: Some(path.clone()),
+            ..args
+        });
+        std::fs::remove_file(&path).unwrap();
+        (loaded, path)
+    };
+    let salt = |server: String, args: ServeArgs| match load(server, args).0 {
+        Ok(settings) => Ok(settings.api.salt),
+        Err(errors) => Err(errors.join("; ")),
+    };
+
+    // A flag beats a file named in the config, and a file flag beats a value in it
+    let flag = ServeArgs {
+        salt: Some("from-flag".to_string()),
+        ..ServeArgs::default()
+    };
+    let file_flag = ServeArgs {
+        salt_file: Some(secret_path.clone()),
+        ..ServeArgs::default()
+    };
+    let from_file = format!("salt_file = {:?}\n", secret_path);
+    assert_eq!(
+        salt(from_file.clone(), flag.clone()),
+        Ok("from-flag".to_string())
+    );
+    assert_eq!(
+        salt("salt = \"in-config\"\n".to_string(), file_flag.clone()),
+        Ok("from-secret-file".to_string())
+    );
+    let (loaded, _) = load(from_file, ServeArgs::default());
+    let settings = loaded.unwrap_or_else(|e| panic!("{:?}", e));
+    assert_eq!(settings.api.salt, "from-secret-file");
+    assert!(settings.files.contains(&secret), "{:?}", settings.files);
+
+    // Both in the same layer is ambiguous
+    let both = format!("salt = \"in-config\"\nsalt_file = {:?}\n", secret_path);
+    let (loaded, path) = load(both, ServeArgs::default());
+    assert_eq!(
+        loaded.err(),
+        Some(vec![format!(
+            "server.salt_file in {}: conflicts with server.salt, set only one",
+            path.display()
+        )])
+    );
+    let flags = ServeArgs {
+        salt: Some("from-flag".to_string()),
+        ..file_flag
+    };
+    assert_eq!(
+        salt(String::new(), flags),
+        Err("--salt-file: conflicts with server.salt, set only one".to_string())
+    );
+
+    let empty_flag = ServeArgs {
+        salt_file: Some(empty_path.clone()),
+        ..ServeArgs::default()
+    };
+    assert_eq!(
+        salt(String::new(), empty_flag),
+        Err(format!("--salt-file: {} is empty", empty_path))
+    );
+    std::fs::remove_file(secret).unwrap();
+    std::fs::remove_file(empty).unwrap();
+}
@@ -0,0 +1,278 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use dyn_ip::discovery::{
+    Discovery, Family, InterfaceFilter, Source, IFA_F_DADFAILED, IFA_F_DEPRECATED, IFA_F_TEMPORARY,
+    IFA_F_TENTATIVE,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// Answers every request with `body`, or the caller's address when `None`,
+/// like dyn-ip's own `GET /`.
+async fn http_echo(body: Option<&'static str>) -> Source {
+    let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, peer)) = listener.accept().await {
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let body = body
+                .map(str::to_string)
+                .unwrap_or_else(|| peer.ip().to_string());
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    Source::Http(format!("http://{}/", addr))
+}
+
+/// Replies to Binding requests with an XOR-MAPPED-ADDRESS of the sender.
+async fn stun_server() -> Source {
+    let socket = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            if len < 20 {
+                continue;
+            }
+            let ip = match peer {
+                SocketAddr::V4(peer) => peer.ip().octets(),
+                SocketAddr::V6(_) => continue,
+            };
+            let mut response = vec![0x01, 0x01, 0x00, 0x0c];
+            response.extend_from_slice(&buf[4..20]);
+            response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+            response.extend_from_slice(&(peer.port() ^ 0x2112).to_be_bytes());
+            response.extend(ip.iter().zip(&buf[4..8]).map(|(b, m)| b ^ m));
+            let _ = socket.send_to(&response, peer).await;
+        }
+    });
+    Source::Stun(addr.to_string())
+}
+
+/// Answers every query with a single A record for `answer`.
+async fn dns_server(answer: Ipv4Addr) -> Source {
+    let socket = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            let mut response = buf[..len].to_vec();
+            response[2] = 0x81;
+            response[3] = 0x80;
+            response[7] = 1;
+            response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 0, 0, 4]);
+            response.extend_from_slice(&answer.octets());
+            let _ = socket.send_to(&response, peer).await;
+        }
+    });
+    Source::Dns {
+        resolver: addr.to_string(),
+        name: "myip.opendns.com".to_string(),
+    }
+}
+
+/// A router answering NAT-PMP external address requests and PCP MAP
+/// requests with `wan`.
+async fn gateway(wan: Ipv4Addr) -> String {
+    let socket = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1100];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            let response = match buf[0] {
+                0 if len == 2 => {
+                    let mut response = vec![0, 128, 0, 0, 0, 0, 0, 1];
+                    response.extend_from_slice(&wan.octets());
+                    response
+                }
+                2 if len == 60 => {
+                    let mut response = vec![2, 0x81, 0, 0];
+                    response.extend_from_slice(&buf[4..8]);
+                    response.extend_from_slice(&[0; 16]);
+                    response.extend_from_slice(&buf[24..42]);
+                    response.extend_from_slice(&buf[40..42]);
+                    response.extend_from_slice(&wan.to_ipv6_mapped().octets());
+                    response
+                }
+                _ => continue,
+            };
+            let _ = socket.send_to(&response, peer).await;
+        }
+    });
+    addr.to_string()
+}
+
+#[tokio::test]
+async fn sources_agree() {
+    let sources = vec![
+        http_echo(None).await,
+        stun_server().await,
+        dns_server(Ipv4Addr::LOCALHOST).await,
+    ];
+    let discovery = Discovery::new(sources, 3).unwrap();
+    assert_eq!(discovery.discover(Family::V4).await.unwrap(), LOCALHOST);
+}
+
+#[tokio::test]
+async fn majority_outvotes_a_liar() {
+    let sources = vec![
+        http_echo(None).await,
+        http_echo(Some("203.0.113.7")).await,
+        stun_server().await,
+    ];
+    let discovery = Discovery::new(sources, 0).unwrap();
+    assert_eq!(discovery.quorum(), 2);
+    assert_eq!(discovery.discover(Family::V4).await.unwrap(), LOCALHOST);
+}
+
+#[tokio::test]
+async fn no_quorum_is_an_error() {
+    let sources = vec![
+        http_echo(None).await,
+        dns_server(Ipv4Addr::new(203, 0, 113, 7)).await,
+    ];
+    let discovery = Discovery::new(sources, 2).unwrap();
+    assert!(discovery.discover(Family::V4).await.is_err());
+}
+
+#[tokio::test]
+async fn answers_from_the_wrong_family_are_ignored() {
+    let sources = vec![http_echo(Some("2001:db8::1")).await, stun_server().await];
+    let discovery = Discovery::new(sources, 1).unwrap();
+    assert_eq!(discovery.discover(Family::V4).await.unwrap(), LOCALHOST);
+    let addresses = discovery.discover_all().await;
+    assert_eq!(addresses.v4, Some(LOCALHOST));
+}
+
+#[tokio::test]
+async fn router_reports_its_wan_address() {
+    let router = gateway(Ipv4Addr::new(198, 51, 100, 4)).await;
+    let sources = vec![
+        format!("natpmp:{}", router).parse().unwrap(),
+        format!("pcp:{}", router).parse().unwrap(),
+    ];
+    let discovery = Discovery::new(sources, 2).unwrap();
+    assert_eq!(
+        discovery.discover(Family::V4).await.unwrap(),
+        IpAddr::V4(Ipv4Addr::new(198, 51, 100, 4))
+    );
+    assert!(discovery.discover(Family::V6).await.is_err());
+}
+
+#[tokio::test]
+async fn interface_source_only_answers_ipv6() {
+    let discovery = Discovery::new(vec!["interface".parse().unwrap()], 1).unwrap();
+    assert!(discovery.discover(Family::V4).await.is_err());
+}
+
+#[tokio::test]
+async fn router_behind_cgnat_is_not_trusted() {
+    for wan in [Ipv4Addr::new(100, 64, 3, 9), Ipv4Addr::new(192, 168, 0, 2)] {
+        let router = gateway(wan).await;
+        let sources = vec![
+            format!("natpmp:{}", router).parse().unwrap(),
+            format!("pcp:{}", router).parse().unwrap(),
+        ];
+        let discovery = Discovery::new(sources, 1).unwrap();
+        assert!(discovery.discover(Family::V4).await.is_err());
+    }
+}
+
+#[test]
+fn parses_source_specs() {
+    assert_eq!("upnp".parse::<Source>().unwrap(), Source::Upnp);
+    assert_eq!("natpmp".parse::<Source>().unwrap(), Source::NatPmp(None));
+    assert_eq!(
+        "pcp:192.168.1.1".parse::<Source>().unwrap(),
+        Source::Pcp(Some("192.168.1.1:5351".to_string()))
+    );
+    assert_eq!(
+        "stun:stun.example.com".parse::<Source>().unwrap(),
+        Source::Stun("stun.example.com:3478".to_string())
+    );
+    assert_eq!(
+        "dns:2620:119:35::35".parse::<Source>().unwrap(),
+        Source::Dns {
+            resolver: "[2620:119:35::35]:53".to_string(),
+            name: "myip.opendns.com".to_string()
+        }
+    );
+    assert_eq!(
+        "dns:127.0.0.1:5353/whoami.example"
+            .parse::<Source>()
+            .unwrap(),
+        Source::Dns {
+            resolver: "127.0.0.1:5353".to_string(),
+            name: "whoami.example".to_string()
+        }
+    );
+    assert_eq!(
+        "interface".parse::<Source>().unwrap(),
+        Source::Interface(InterfaceFilter::default())
+    );
+    assert_eq!(
+        "interface:wl*@2001:db8::/32".parse::<Source>().unwrap(),
+        Source::Interface(InterfaceFilter {
+            name: Some("wl*".to_string()),
+            prefix: Some(("2001:db8::".parse().unwrap(), 32)),
+        })
+    );
+    assert!("interface:eth0@2001:db8::/129".parse::<Source>().is_err());
+    assert_eq!(Source::parse_list(&["default"]).unwrap().len(), 5);
+    assert!("ftp://example.com".parse::<Source>().is_err());
+    assert!(Discovery::new(vec![], 0).is_err());
+}
+
+#[test]
+fn only_stable_global_addresses_are_published() {
+    let any = InterfaceFilter::default();
+    let ula = InterfaceFilter::parse("@fd00::/8").unwrap();
+    let wifi = InterfaceFilter::parse("wl*@2001:db8::/32").unwrap();
+    let cases = [
+        // filter, interface, address, flags, published
+        (&any, "eth0", "2001:db8::10", 0, true),
+        (&any, "eth0", "2001:db8::10", IFA_F_TEMPORARY, false),
+        (&any, "eth0", "2001:db8::10", IFA_F_DEPRECATED, false),
+        (&any, "eth0", "2001:db8::10", IFA_F_TENTATIVE, false),
+        (&any, "eth0", "2001:db8::10", IFA_F_DADFAILED, false),
+        // Flags that don't make an address unstable, e.g. IFA_F_PERMANENT
+        (&any, "eth0", "2001:db8::10", 0x80, true),
+        (&any, "eth0", "fd12:3456::10", 0, false),
+        (&any, "eth0", "fc00::10", 0, false),
+        (&any, "eth0", "fe80::10", 0, false),
+        (&any, "lo", "::1", 0, false),
+        (&any, "eth0", "::ffff:198.51.100.1", 0, false),
+        (&any, "eth0", "ff02::1", 0, false),
+        (&ula, "eth0", "fd12:3456::10", 0, true),
+        (&ula, "eth0", "fd12:3456::10", IFA_F_TEMPORARY, false),
+        (&ula, "eth0", "2001:db8::10", 0, false),
+        (&wifi, "wlan0", "2001:db8::10", 0, true),
+        (&wifi, "wlan0", "2001:db9::10", 0, false),
+        (&wifi, "eth0", "2001:db8::10", 0, false),
+        (&wifi, "wlan0", "2001:db8::10", IFA_F_DEPRECATED, false),
+    ];
+    for (filter, name, ip, flags, published) in cases {
+        let ip: Ipv6Addr = ip.parse().unwrap();
+        assert_eq!(
+            filter.accepts(Some(name), &ip, flags),
+            published,
+            "{} {} on {} with flags {:#x}",
+            filter,
+            ip,
+            name,
+            flags
+        );
+    }
+    // An interface the kernel didn't name only passes without a name filter
+    let ip: Ipv6Addr = "2001:db8::10".parse().unwrap();
+    assert!(any.accepts(None, &ip, 0));
+    assert!(!wifi.accepts(None, &ip, 0));
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::{web, App};
+use common::cloudflare::Zone;
+use dyn_ip::aws::zones::Zones;
+use dyn_ip::error::DynIpError;
+use dyn_ip::server::heartbeat::Heartbeats;
+use dyn_ip::server::history::History;
+use dyn_ip::server::policy::RecordSettings;
+use dyn_ip::server::routes::domains::{edit, update_record};
+use dyn_ip::server::state::Live;
+use serde_json::Value;
+
+#[tokio::test]
+async fn only_address_records_take_ip_updates() {
+    let zone = Zone::start("example.com").await;
+    zone.add("home.example.com", "A", "198.51.100.1");
+    zone.add("www.example.com", "CNAME", "home.example.com");
+    let zones = Zones::new(vec![zone.cloudflare()]);
+    let config = common::api_config();
+    let records = zones.list_display_records(&config.salt).await.unwrap();
+    let (history, heartbeats) = (History::default(), Heartbeats::default());
+    let update = |id: &str, ip: &str| {
+        update_record(
+            &zones,
+            &config,
+            &history,
+            &heartbeats,
+            id.to_string(),
+            ip.to_string(),
+            None,
+            None,
+        )
+    };
+
+    let error = update(&records[1].id, "198.51.100.2").await.unwrap_err();
+    match error {
+        DynIpError::InvalidRecord(message) => assert_eq!(
+            message,
+            "www.example.com is a CNAME record, only A and AAAA records take IP updates"
+        ),
+        other => panic!("{:?}", other),
+    }
+    assert!(matches!(
+        update(&records[0].id, "2001:db8::1").await,
+        Err(DynIpError::InvalidRecord(_))
+    ));
+
+    let (record, changed) = update(&records[0].id, "198.51.100.2").await.unwrap();
+    assert!(changed);
+    assert_eq!(record.ip, "198.51.100.2");
+    assert_eq!(zone.records()[0]["content"], "198.51.100.2");
+    assert_eq!(zone.records()[1]["content"], "home.example.com");
+}
+
+#[actix_web::test]
+async fn edits_keep_to_the_policy() {
+    let zone = Zone::start("example.com").await;
+    zone.add("home.example.com", "A", "198.51.100.1");
+    zone.add("www.example.com", "CNAME", "home.example.com");
+    let mut runtime = common::runtime(Zones::new(vec![zone.cloudflare()]));
+    let records = runtime.zones.list_display_records("salt").await.unwrap();
+    let policy = &mut runtime.config.policy;
+    policy.record_types = vec!["A".to_string()];
+    policy.records.insert(
+        "home.example.com".to_string(),
+        RecordSettings {
+            ttl: Some(300),
+            proxied: Some(false),
+            ..RecordSettings::default()
+        },
+    );
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(Live::from_pointee(runtime)))
+            .app_data(web::Data::new(History::default()))
+            .app_data(web::Data::new(Heartbeats::default()))
+            .route("/api/domains/{id}", web::put().to(edit)),
+    )
+    .await;
+    let put = |id: &str, query: &str| {
+        TestRequest::put()
+            .uri(&format!("/api/domains/{}?{}", id, query))
+            .to_request()
+    };
+
+    // Pinned settings win over the edit
+    let response = call_service(&app, put(&records[0].id, "ttl=60&proxied=true")).await;
+    assert_eq!(response.status(), 200);
+    let edited: Value = read_body_json(response).await;
+    assert_eq!(
+        (&edited["ttl"], &edited["proxied"]),
+        (&300.into(), &false.into())
+    );
+    assert_eq!(zone.records()[0]["ttl"], 300);
+    assert_eq!(zone.records()[0]["proxied"], false);
+
+    // Types the policy doesn't allow can't be edited either
+    let response = call_service(&app, put(&records[1].id, "ttl=60")).await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(zone.records()[1]["ttl"], 1);
+}
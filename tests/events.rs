@@ -0,0 +1,90 @@
+mod common;
+
+use std::pin::Pin;
+use std::time::Duration;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::{test, web, App};
+use futures_util::future::poll_fn;
+
+use dyn_ip::aws::record::DisplayRecord;
+use dyn_ip::server::history::{Action, Change, History};
+use dyn_ip::server::routes::events;
+
+fn change(action: Action, ip: &str) -> Change {
+    let record = DisplayRecord {
+        ip: ip.to_string(),
+        ..common::record("home.example.com")
+    };
+    Change::new(action, &record, None, None)
+}
+
+async fn chunk(body: &mut BoxBody) -> String {
+    let next = poll_fn(|cx| Pin::new(&mut *body).poll_next(cx));
+    let bytes = tokio::time::timeout(Duration::from_secs(5), next)
+        .await
+        .expect("no event")
+        .unwrap()
+        .unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn reconnecting_clients_catch_up_then_follow() {
+    let history = History::default();
+    history.record(change(Action::Create, "198.51.100.1"));
+    history.record(change(Action::Update, "198.51.100.2"));
+    history.record(change(Action::Update, "198.51.100.3"));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(history.clone()))
+            .route("/api/events", web::get().to(events::index)),
+    )
+    .await;
+
+    let request = test::TestRequest::get()
+        .uri("/api/events")
+        .insert_header(("Last-Event-ID", "1"))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "text/event-stream"
+    );
+    let mut body = response.into_body();
+    assert_eq!(chunk(&mut body).await, "retry: 5000\n\n");
+    let missed = chunk(&mut body).await;
+    assert!(
+        missed.starts_with("id: 2\nevent: update\ndata: {"),
+        "{}",
+        missed
+    );
+    assert!(missed.contains("\"new_value\":\"198.51.100.2\""));
+    assert!(chunk(&mut body).await.starts_with("id: 3\n"));
+
+    history.record(change(Action::Delete, "198.51.100.3"));
+    assert!(chunk(&mut body).await.starts_with("id: 4\nevent: delete\n"));
+}
+
+#[tokio::test]
+async fn unknown_event_ids_reset_the_client() {
+    let history = History::default();
+    history.record(change(Action::Create, "198.51.100.1"));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(history.clone()))
+            .route("/api/events", web::get().to(events::index)),
+    )
+    .await;
+
+    // From before the data dir was wiped
+    let request = test::TestRequest::get()
+        .uri("/api/events?last_event_id=99")
+        .to_request();
+    let mut body = test::call_service(&app, request).await.into_body();
+    assert_eq!(chunk(&mut body).await, "retry: 5000\n\n");
+    assert_eq!(chunk(&mut body).await, "event: reset\ndata: {}\n\n");
+
+    history.record(change(Action::Update, "198.51.100.2"));
+    assert!(chunk(&mut body).await.starts_with("id: 2\n"));
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use std::time::Duration;
+
+use actix_web::{test, web, App};
+use dyn_ip::aws::zones::Zones;
+use dyn_ip::server::health::{Readiness, Status};
+use dyn_ip::server::routes::health;
+use dyn_ip::server::state::{Live, Runtime};
+
+fn runtime() -> Runtime {
+    common::runtime(Zones::new(Vec::new()))
+}
+
+/// A file where the data directory should be, and the directory it blocks.
+fn blocked_data_dir(name: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+    let file = std::env::temp_dir().join(format!("dyn-ip-readyz-{}-{}", name, std::process::id()));
+    std::fs::write(&file, "").unwrap();
+    let data_dir = file.join("data");
+    (file, data_dir)
+}
+
+fn unblock(file: &std::path::Path) {
+    std::fs::remove_file(file).unwrap();
+    std::fs::create_dir_all(file.join("data")).unwrap();
+}
+
+#[tokio::test]
+async fn reports_are_reused_until_cleared() {
+    let (file, data_dir) = blocked_data_dir("cached");
+    let readiness = Readiness::new(data_dir);
+
+    let report = readiness.check(&runtime()).await;
+    assert_eq!(report.status, Status::Error);
+    assert!(report.components["data_dir"].error.is_some());
+
+    unblock(&file);
+    assert_eq!(readiness.check(&runtime()).await.status, Status::Error);
+    // As after a reload
+    readiness.clear().await;
+    assert_eq!(readiness.check(&runtime()).await.status, Status::Ok);
+    std::fs::remove_dir_all(&file).unwrap();
+}
+
+#[tokio::test]
+async fn reports_expire() {
+    let (file, data_dir) = blocked_data_dir("expiry");
+    let readiness = Readiness::new(data_dir).caching_for(Duration::from_millis(100));
+    assert_eq!(readiness.check(&runtime()).await.status, Status::Error);
+
+    unblock(&file);
+    assert_eq!(readiness.check(&runtime()).await.status, Status::Error);
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(readiness.check(&runtime()).await.status, Status::Ok);
+    std::fs::remove_dir_all(&file).unwrap();
+}
+
+#[actix_web::test]
+async fn details_are_only_on_the_api() {
+    let (file, data_dir) = blocked_data_dir("route");
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(Live::from_pointee(runtime())))
+            .app_data(web::Data::new(Readiness::new(data_dir)))
+            .route("/readyz", web::get().to(health::readyz))
+            .route("/api/readyz", web::get().to(health::report)),
+    )
+    .await;
+
+    let public = test::TestRequest::get().uri("/readyz").to_request();
+    let response = test::call_service(&app, public).await;
+    assert_eq!(response.status(), 503);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body, serde_json::json!({ "status": "error" }));
+
+    let api = test::TestRequest::get().uri("/api/readyz").to_request();
+    let response = test::call_service(&app, api).await;
+    assert_eq!(response.status(), 503);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let error = body["components"]["data_dir"]["error"].as_str().unwrap();
+    assert!(error.contains("is not writable"), "{}", error);
+    std::fs::remove_file(&file).unwrap();
+}
@@ -0,0 +1,339 @@
+mod common;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::cloudflare::Zone;
+use common::record;
+use dyn_ip::aws::record::DisplayRecord;
+use dyn_ip::aws::zones::Zones;
+use dyn_ip::server::heartbeat::{Heartbeat, Heartbeats, Lease};
+use dyn_ip::server::history::{Action, Change, History};
+use dyn_ip::server::policy::{Policy, RecordSettings};
+use dyn_ip::server::state::Runtime;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn policy() -> Policy {
+    let mut policy = Policy {
+        expected_interval: Some(Duration::from_secs(600)),
+        ..Policy::default()
+    };
+    policy.records.insert(
+        "home.example.com".to_string(),
+        RecordSettings {
+            expected_interval: Some(60),
+            ..RecordSettings::default()
+        },
+    );
+    policy
+}
+
+#[test]
+fn missed_only_past_the_interval() {
+    let beat = Heartbeat {
+        domain: "home.example.com".to_string(),
+        record_type: "A".to_string(),
+        last_seen: 1000,
+        stale: false,
+    };
+    assert!(!beat.missed(Duration::from_secs(60), 1060));
+    assert!(beat.missed(Duration::from_secs(60), 1061));
+}
+
+/// `record(domain)` with its own provider ID.
+fn leased(domain: &str, source_id: &str) -> DisplayRecord {
+    DisplayRecord {
+        source_id: source_id.to_string(),
+        ..record(domain)
+    }
+}
+
+#[test]
+fn leases_run_from_the_last_refresh() {
+    let heartbeats = Heartbeats::default();
+    let lease = Lease {
+        duration: 300,
+        fallback: None,
+    };
+    heartbeats.created(&leased("runner.example.com", "rec1"), Some(lease.clone()));
+    heartbeats.created(&leased("home.example.com", "rec2"), None);
+    let runner = heartbeats.lease("rec1").unwrap();
+    let expires_at = runner.renewed_at + 300;
+    assert_eq!(heartbeats.lease_expires_at(&runner), expires_at);
+    assert_eq!(heartbeats.lease("rec2"), None);
+    assert!(heartbeats.expired(expires_at).is_empty());
+    let expired = heartbeats.expired(expires_at + 1);
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].source_id, "rec1");
+
+    // Updates keep the lease
+    heartbeats.beat("runner.example.com", "A");
+    let mut records = [leased("runner.example.com", "rec1")];
+    heartbeats.annotate(&mut records, &Policy::default());
+    assert_eq!(
+        records[0].lease_expires_at,
+        Some(records[0].last_seen.unwrap() + 300)
+    );
+    assert_eq!(heartbeats.lease("rec1").unwrap().lease, lease);
+
+    // Another record of the same name has its own lease, or none
+    heartbeats.created(&leased("runner.example.com", "rec3"), None);
+    assert!(heartbeats.lease("rec1").is_some());
+    let mut records = [
+        leased("runner.example.com", "rec1"),
+        leased("runner.example.com", "rec3"),
+    ];
+    heartbeats.annotate(&mut records, &Policy::default());
+    assert!(records[0].lease_expires_at.is_some());
+    assert_eq!(records[1].lease_expires_at, None);
+}
+
+#[test]
+fn leases_run_a_full_duration_after_the_load() {
+    let path = std::env::temp_dir().join(format!(
+        "dyn-ip-heartbeats-leases-{}.json",
+        std::process::id()
+    ));
+    let before = Heartbeats::load(path.clone(), &History::default());
+    before.created(
+        &leased("runner.example.com", "rec1"),
+        Some(Lease {
+            duration: 300,
+            fallback: None,
+        }),
+    );
+    before.flush();
+    // Renewed long before a restart
+    let saved = std::fs::read_to_string(&path).unwrap();
+    let renewed_at = before.lease("rec1").unwrap().renewed_at;
+    std::fs::write(
+        &path,
+        saved.replace(
+            &format!("\"renewed_at\":{}", renewed_at),
+            &format!("\"renewed_at\":{}", renewed_at - 86_400),
+        ),
+    )
+    .unwrap();
+    let heartbeats = Heartbeats::load(path.clone(), &History::default());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        heartbeats.lease("rec1").unwrap().renewed_at,
+        renewed_at - 86_400
+    );
+
+    assert!(heartbeats.expired(now()).is_empty());
+    assert!(heartbeats.expired(now() + 299).is_empty());
+    assert_eq!(heartbeats.expired(now() + 301).len(), 1);
+}
+
+/// The stand-in zone with two runner records, and the records as dyn-ip lists them.
+async fn runners() -> (Zone, Runtime, Vec<DisplayRecord>) {
+    let zone = Zone::start("example.com").await;
+    zone.add("runner.example.com", "A", "198.51.100.1");
+    zone.add("runner.example.com", "A", "198.51.100.2");
+    let runtime = common::runtime(Zones::new(vec![zone.cloudflare()]));
+    let records = runtime.zones.list_display_records("salt").await.unwrap();
+    (zone, runtime, records)
+}
+
+fn contents(zone: &Zone) -> Vec<String> {
+    zone.records()
+        .iter()
+        .map(|r| r["content"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn expired_leases_delete_only_their_own_record() {
+    let (zone, runtime, records) = runners().await;
+    // Same-name records share an ID, which can't say which one to delete
+    assert_eq!(records[0].id, records[1].id);
+    let heartbeats = Heartbeats::default();
+    let lease = Lease {
+        duration: 300,
+        fallback: None,
+    };
+    heartbeats.created(&records[0], Some(lease));
+    heartbeats.created(&records[1], None);
+    let history = History::default();
+
+    heartbeats.check(&runtime, &history, now() + 301).await;
+    assert_eq!(contents(&zone), ["198.51.100.2"]);
+    assert_eq!(heartbeats.lease(&records[0].source_id), None);
+    // The record left keeps the name's heartbeat
+    assert!(heartbeats.get("runner.example.com", "A").is_some());
+    let deleted = history.list(&Default::default());
+    assert_eq!(deleted.len(), 1);
+    assert_eq!(deleted[0].action, Action::Delete);
+    assert_eq!(deleted[0].old_value.as_deref(), Some("198.51.100.1"));
+    assert_eq!(deleted[0].client.as_deref(), Some("lease"));
+}
+
+#[tokio::test]
+async fn expired_leases_park_on_their_fallback() {
+    let (zone, runtime, records) = runners().await;
+    let heartbeats = Heartbeats::default();
+    let lease = Lease {
+        duration: 300,
+        fallback: Some("192.0.2.1".to_string()),
+    };
+    heartbeats.created(&records[1], Some(lease));
+    let history = History::default();
+
+    heartbeats.check(&runtime, &history, now() + 301).await;
+    assert_eq!(contents(&zone), ["198.51.100.1", "192.0.2.1"]);
+    assert!(heartbeats.lease(&records[1].source_id).unwrap().parked);
+    let parked = history.list(&Default::default());
+    assert_eq!(parked.len(), 1);
+    assert_eq!(parked[0].action, Action::Update);
+    assert_eq!(parked[0].new_value.as_deref(), Some("192.0.2.1"));
+
+    // Parked once, until the host updates it again
+    heartbeats.check(&runtime, &history, now() + 601).await;
+    assert_eq!(history.list(&Default::default()).len(), 1);
+    heartbeats.beat("runner.example.com", "A");
+    assert!(!heartbeats.lease(&records[1].source_id).unwrap().parked);
+
+    // A lease on a record deleted elsewhere is dropped
+    let gone = DisplayRecord {
+        source_id: "gone".to_string(),
+        ..records[0].clone()
+    };
+    heartbeats.created(
+        &gone,
+        Some(Lease {
+            duration: 300,
+            fallback: None,
+        }),
+    );
+    heartbeats.check(&runtime, &history, now() + 301).await;
+    assert_eq!(heartbeats.lease("gone"), None);
+    assert_eq!(contents(&zone), ["198.51.100.1", "192.0.2.1"]);
+}
+
+#[test]
+fn records_are_annotated_with_their_interval() {
+    let heartbeats = Heartbeats::default();
+    heartbeats.beat("home.example.com", "A");
+    let mut records = [record("home.example.com"), record("office.example.com")];
+    heartbeats.annotate(&mut records, &policy());
+
+    assert!(records[0].last_seen.is_some());
+    assert_eq!(records[0].expected_interval, Some(60));
+    assert!(!records[0].stale);
+    // Never refreshed, so there's nothing to have missed
+    assert_eq!(records[1].last_seen, None);
+    assert_eq!(records[1].expected_interval, Some(600));
+    assert!(!records[1].stale);
+
+    let overdue = heartbeats.overdue(&policy(), now() + 61);
+    assert_eq!(overdue.len(), 1);
+    assert_eq!(overdue[0].domain, "home.example.com");
+    assert!(heartbeats
+        .overdue(&Policy::default(), now() + 61)
+        .is_empty());
+}
+
+#[test]
+fn seeded_from_history_then_saved() {
+    let history = History::default();
+    history.record(Change::new(
+        Action::Create,
+        &record("home.example.com"),
+        None,
+        None,
+    ));
+    history.record(Change::new(
+        Action::Create,
+        &record("gone.example.com"),
+        None,
+        None,
+    ));
+    history.record(Change::new(
+        Action::Delete,
+        &record("gone.example.com"),
+        None,
+        None,
+    ));
+
+    let path = std::env::temp_dir().join(format!("dyn-ip-heartbeats-{}.json", std::process::id()));
+    let heartbeats = Heartbeats::load(path.clone(), &history);
+    let domains: Vec<_> = heartbeats.list().into_iter().map(|b| b.domain).collect();
+    assert_eq!(domains, ["home.example.com"]);
+
+    heartbeats.beat("office.example.com", "A");
+    heartbeats.flush();
+    let reloaded = Heartbeats::load(path.clone(), &History::default());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(reloaded.list(), heartbeats.list());
+}
+
+#[test]
+fn nothing_is_overdue_until_an_interval_after_the_load() {
+    let path = std::env::temp_dir().join(format!(
+        "dyn-ip-heartbeats-started-{}.json",
+        std::process::id()
+    ));
+    // Last seen long before a restart
+    let beat = Heartbeat {
+        domain: "home.example.com".to_string(),
+        record_type: "A".to_string(),
+        last_seen: now() - 86_400,
+        stale: false,
+    };
+    let saved = serde_json::json!({ "heartbeats": [beat], "leases": [] });
+    std::fs::write(&path, saved.to_string()).unwrap();
+    let heartbeats = Heartbeats::load(path.clone(), &History::default());
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(heartbeats.overdue(&policy(), now()).is_empty());
+    assert!(heartbeats.overdue(&policy(), now() + 30).is_empty());
+    let overdue = heartbeats.overdue(&policy(), now() + 61);
+    assert_eq!(overdue.len(), 1);
+    // Still shown as stale, only the alert waits
+    let mut records = [record("home.example.com")];
+    heartbeats.annotate(&mut records, &policy());
+    assert!(records[0].stale);
+}
+
+#[tokio::test]
+async fn records_in_a_failing_zone_keep_their_heartbeats_and_leases() {
+    let (zone, _, records) = runners().await;
+    let other = Zone::start("example.org").await;
+    other.add("home.example.org", "A", "198.51.100.9");
+    let mut runtime = common::runtime(Zones::new(vec![zone.cloudflare(), other.cloudflare()]));
+    runtime.config.policy.expected_interval = Some(Duration::from_secs(60));
+    let heartbeats = Heartbeats::default();
+    heartbeats.created(&records[0], None);
+    let lease = Lease {
+        duration: 60,
+        fallback: None,
+    };
+    heartbeats.created(&records[1], Some(lease));
+    let history = History::default();
+
+    // The other zone answers, this one's records are only unknown
+    zone.fail(true);
+    heartbeats.check(&runtime, &history, now() + 61).await;
+    assert!(heartbeats.get("runner.example.com", "A").is_some());
+    assert!(heartbeats.lease(&records[1].source_id).is_some());
+    assert!(history.list(&Default::default()).is_empty());
+
+    // Once it answers without the record, the record is gone
+    zone.fail(false);
+    for record in &records {
+        runtime
+            .zones
+            .delete_record_by_source_id(&record.domain, &record.source_id)
+            .await
+            .unwrap();
+    }
+    heartbeats.check(&runtime, &history, now() + 61).await;
+    assert_eq!(heartbeats.get("runner.example.com", "A"), None);
+    assert_eq!(heartbeats.lease(&records[1].source_id), None);
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use dyn_ip::aws::record::DisplayRecord;
+use dyn_ip::server::history::{Action, Change, History, HistoryQuery};
+
+fn record(domain: &str, record_type: &str, ip: &str) -> DisplayRecord {
+    DisplayRecord {
+        record_type: record_type.to_string(),
+        ip: ip.to_string(),
+        ..common::record(domain)
+    }
+}
+
+#[test]
+fn changes_survive_a_restart() {
+    let dir = std::env::temp_dir().join(format!("dyn-ip-history-{}", std::process::id()));
+    let path = dir.join("history.jsonl");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let history = History::load(path.clone());
+    let home = record("home.example.com", "A", "198.51.100.1");
+    let nas = record("nas.example.com", "AAAA", "2001:db8::1");
+    history.record(Change::new(Action::Create, &home, None, None));
+    history.record(Change::new(
+        Action::Update,
+        &record("home.example.com", "A", "198.51.100.2"),
+        Some(home.ip.clone()),
+        Some("203.0.113.9".to_string()),
+    ));
+    history.record(Change::new(
+        Action::Delete,
+        &nas,
+        Some(nas.ip.clone()),
+        None,
+    ));
+    history.flush();
+
+    let history = History::load(path);
+    let all = history.list(&HistoryQuery::default());
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[0].action, Action::Delete);
+    assert_eq!(all[0].new_value, None);
+    assert_eq!(all[0].seq, 3);
+
+    let home_changes = history.list(&HistoryQuery {
+        domain: Some("home.example.com".to_string()),
+        ..Default::default()
+    });
+    assert_eq!(home_changes.len(), 2);
+    assert_eq!(home_changes[0].old_value.as_deref(), Some("198.51.100.1"));
+    assert_eq!(home_changes[0].new_value.as_deref(), Some("198.51.100.2"));
+    assert_eq!(home_changes[0].client.as_deref(), Some("203.0.113.9"));
+
+    let limited = history.list(&HistoryQuery {
+        record_type: Some("aaaa".to_string()),
+        limit: Some(1),
+        ..Default::default()
+    });
+    assert_eq!(limited.len(), 1);
+    assert_eq!(limited[0].id, nas.id);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn the_file_is_rewritten_once_it_holds_twice_what_is_kept() {
+    let dir = std::env::temp_dir().join(format!("dyn-ip-history-compact-{}", std::process::id()));
+    let path = dir.join("history.jsonl");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let history = History::load(path.clone());
+    let home = record("home.example.com", "A", "198.51.100.1");
+    let lines = |history: &History| {
+        history.flush();
+        std::fs::read_to_string(&path).unwrap().lines().count()
+    };
+    for _ in 0..2000 {
+        history.record(Change::new(Action::Update, &home, None, None));
+    }
+    assert_eq!(lines(&history), 2000);
+    history.record(Change::new(Action::Update, &home, None, None));
+    assert_eq!(lines(&history), 1000);
+    history.record(Change::new(Action::Update, &home, None, None));
+    assert_eq!(lines(&history), 1001);
+
+    let reloaded = History::load(path);
+    assert_eq!(reloaded.last_seq(), 2002);
+    assert_eq!(reloaded.since(0).len(), 1000);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,181 @@
+mod common;
+
+use actix_web::dev::ServiceResponse;
+use actix_web::error::ErrorServiceUnavailable;
+use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use common::cloudflare::Zone;
+use dyn_ip::aws::cloudflare::{Auth, Cloudflare};
+use dyn_ip::aws::zones::Zones;
+use dyn_ip::error::DynIpError;
+use dyn_ip::server::api::validator;
+use dyn_ip::server::auth;
+use dyn_ip::server::heartbeat::Heartbeats;
+use dyn_ip::server::history::History;
+use dyn_ip::server::metrics::metrics;
+use dyn_ip::server::routes::domains::update_record;
+use dyn_ip::server::state::Live;
+use dyn_ip::server::trace;
+
+/// The value of the sample starting with `sample`, 0 before it's first counted.
+/// Tests share the process wide metrics, so they compare counts before and after.
+fn count(sample: &str) -> u64 {
+    metrics()
+        .render(&Heartbeats::default())
+        .lines()
+        .find_map(|line| line.strip_prefix(sample)?.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[test]
+fn renders_counters_and_record_ages() {
+    let changed = count("dyn_ip_updates_total{result=\"changed\"}");
+    metrics().update("changed");
+    let heartbeats = Heartbeats::default();
+    heartbeats.beat("home.example.com", "A");
+    heartbeats.beat("gone.example.com", "A");
+    heartbeats.forget("gone.example.com", "A");
+
+    let text = metrics().render(&heartbeats);
+    assert!(
+        count("dyn_ip_updates_total{result=\"changed\"}") > changed,
+        "{}",
+        text
+    );
+    assert!(text.contains(
+        "dyn_ip_record_seconds_since_update{domain=\"home.example.com\",record_type=\"A\"} 0"
+    ));
+    assert!(!text.contains("gone.example.com"));
+}
+
+fn request_count(route: &str, status: u16) -> u64 {
+    count(&format!(
+        "dyn_ip_http_request_duration_seconds_count{{method=\"GET\",route=\"{}\",status=\"{}\"}}",
+        route, status
+    ))
+}
+
+#[actix_web::test]
+async fn refused_and_failed_requests_are_counted() {
+    let mut runtime = common::runtime(Zones::new(Vec::new()));
+    runtime.config.auth = auth::Auth {
+        username: Some("admin".to_string()),
+        password: Some("secret".to_string()),
+    };
+    let app = init_service(
+        App::new()
+            .wrap_fn(trace::trace_request)
+            .app_data(web::Data::new(Live::from_pointee(runtime)))
+            .service(
+                web::scope("/guarded")
+                    .wrap(HttpAuthentication::with_fn(validator))
+                    .route("/{id}", web::get().to(HttpResponse::Ok)),
+            )
+            .service(
+                web::scope("/failing")
+                    .wrap_fn(|_, _| async {
+                        Err::<ServiceResponse, _>(ErrorServiceUnavailable("down"))
+                    })
+                    .route("/{id}", web::get().to(HttpResponse::Ok)),
+            ),
+    )
+    .await;
+    let failures = count("dyn_ip_auth_failures_total");
+    let refused = request_count("/guarded/{id}", 401);
+    let allowed = request_count("/guarded/{id}", 200);
+    let failed = request_count("/failing", 503);
+
+    for credentials in [None, Some("admin:wrong")] {
+        let mut request = TestRequest::get().uri("/guarded/id");
+        if let Some(credentials) = credentials {
+            request = request.insert_header((
+                "Authorization",
+                format!("Basic {}", STANDARD.encode(credentials)),
+            ));
+        }
+        assert_eq!(call_service(&app, request.to_request()).await.status(), 401);
+    }
+    let request = TestRequest::get().uri("/guarded/id").insert_header((
+        "Authorization",
+        format!("Basic {}", STANDARD.encode("admin:secret")),
+    ));
+    assert_eq!(call_service(&app, request.to_request()).await.status(), 200);
+    // Middleware errors come back without a response
+    let request = TestRequest::get().uri("/failing/id").to_request();
+    assert!(try_call_service(&app, request).await.is_err());
+
+    assert_eq!(count("dyn_ip_auth_failures_total"), failures + 2);
+    assert_eq!(request_count("/guarded/{id}", 401), refused + 2);
+    assert_eq!(request_count("/guarded/{id}", 200), allowed + 1);
+    assert_eq!(request_count("/failing", 503), failed + 1);
+}
+
+#[tokio::test]
+async fn updates_are_counted_by_outcome() {
+    let zone = Zone::start("example.com").await;
+    zone.add("home.example.com", "A", "198.51.100.1");
+    let runtime = common::runtime(Zones::new(vec![zone.cloudflare()]));
+    let (history, heartbeats) = (History::default(), Heartbeats::default());
+    let home = runtime
+        .zones
+        .list_display_records(&runtime.config.salt)
+        .await
+        .unwrap()[0]
+        .id
+        .clone();
+    let outcomes = ["changed", "unchanged", "rejected", "failed"];
+    let before: Vec<u64> = outcomes
+        .iter()
+        .map(|o| count(&format!("dyn_ip_updates_total{{result=\"{}\"}}", o)))
+        .collect();
+
+    let update = |zones: Zones, ip: &'static str| {
+        let (config, history, heartbeats, home) = (&runtime.config, &history, &heartbeats, &home);
+        async move {
+            update_record(
+                &zones,
+                config,
+                history,
+                heartbeats,
+                home.clone(),
+                ip.to_string(),
+                None,
+                None,
+            )
+            .await
+        }
+    };
+    let zones = || Zones::new(vec![zone.cloudflare()]);
+    assert!(update(zones(), "198.51.100.2").await.unwrap().1);
+    assert!(!update(zones(), "198.51.100.2").await.unwrap().1);
+    assert!(update(zones(), "not an ip").await.is_err());
+    let unknown = update_record(
+        &zones(),
+        &runtime.config,
+        &history,
+        &heartbeats,
+        "no-such-record".to_string(),
+        "198.51.100.3".to_string(),
+        None,
+        None,
+    )
+    .await;
+    assert!(matches!(unknown, Err(DynIpError::DomainHashNotFound)));
+    let unreachable = Zones::new(vec![Cloudflare::new(
+        "http://127.0.0.1:9".to_string(),
+        Auth::Token("tk_good".to_string()),
+        "zone".to_string(),
+        "example.com".to_string(),
+    )]);
+    assert!(update(unreachable, "198.51.100.3").await.is_err());
+
+    // A record that isn't there is the client's mistake, not the provider's
+    let expected = [1, 1, 2, 1];
+    for ((outcome, before), expected) in outcomes.iter().zip(before).zip(expected) {
+        let sample = format!("dyn_ip_updates_total{{result=\"{}\"}}", outcome);
+        assert_eq!(count(&sample), before + expected, "{}", outcome);
+    }
+}
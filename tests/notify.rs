@@ -0,0 +1,353 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use common::{record, Server};
+use dyn_ip::aws::zones::Zones;
+use dyn_ip::notify::{
+    check_template, render, Backend, Notifier, Notifiers, SmtpConfig, DEFAULT_MESSAGE,
+    DEFAULT_TITLE,
+};
+use dyn_ip::server::history::{Action, Change, ChangeFilter, History};
+use dyn_ip::server::policy::{Policy, RecordSettings};
+use dyn_ip::server::state::Live;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+fn change() -> Change {
+    Change::new(
+        Action::Update,
+        &record("home.example.com"),
+        Some("198.51.100.1".to_string()),
+        Some("203.0.113.9".to_string()),
+    )
+}
+
+fn notifier(backend: Backend) -> Notifier {
+    Notifier {
+        name: backend.kind().to_string(),
+        backend,
+        filter: ChangeFilter::default(),
+        title: DEFAULT_TITLE.to_string(),
+        message: DEFAULT_MESSAGE.to_string(),
+    }
+}
+
+/// Accepts one message, returning the SMTP conversation from the client's side.
+async fn smtp_server() -> (String, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("smtp://{}", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (read, mut write) = socket.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let mut transcript = String::new();
+        write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+        let mut in_data = false;
+        while let Some(line) = lines.next_line().await.unwrap() {
+            transcript.push_str(&line);
+            transcript.push('\n');
+            let reply: &[u8] = if in_data {
+                if line != "." {
+                    continue;
+                }
+                in_data = false;
+                b"250 queued\r\n"
+            } else {
+                match line
+                    .split(' ')
+                    .next()
+                    .unwrap_or_default()
+                    .to_uppercase()
+                    .as_str()
+                {
+                    "EHLO" => b"250 localhost\r\n",
+                    "DATA" => {
+                        in_data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    "QUIT" => {
+                        write.write_all(b"221 bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => b"250 ok\r\n",
+                }
+            };
+            write.write_all(reply).await.unwrap();
+        }
+        transcript
+    });
+    (url, handle)
+}
+
+#[test]
+fn templates_fill_in_the_change() {
+    let change = change();
+    assert_eq!(
+        render(DEFAULT_MESSAGE, &change),
+        "A home.example.com: 198.51.100.1 -> 198.51.100.2"
+    );
+    assert_eq!(
+        render("{domain} from {client} {unknown} {", &change),
+        "home.example.com from 203.0.113.9 {unknown} {"
+    );
+    assert!(check_template("{domain} {new_value}").is_ok());
+    assert!(check_template("{domian}").unwrap_err().contains("{domian}"));
+    // JSON bodies keep their braces
+    assert!(check_template("{\"text\": \"{domain}\"}").is_ok());
+}
+
+#[tokio::test]
+async fn ntfy_gets_the_title_as_a_header() {
+    let server = Server::reply(200, "{}").await;
+    let ntfy = notifier(Backend::Ntfy {
+        url: format!("{}/dyn-ip", server.url),
+        token: Some("tk_123".to_string()),
+    });
+    assert!(Notifiers::default()
+        .notify(&[ntfy], &Policy::default(), &change())
+        .await
+        .is_empty());
+
+    let request = server.request();
+    assert_eq!(
+        (request.method.as_str(), request.path.as_str()),
+        ("POST", "/dyn-ip")
+    );
+    assert_eq!(request.header("title"), Some("home.example.com update"));
+    assert_eq!(request.header("authorization"), Some("Bearer tk_123"));
+    assert_eq!(
+        request.body,
+        "A home.example.com: 198.51.100.1 -> 198.51.100.2"
+    );
+}
+
+#[tokio::test]
+async fn gotify_and_matrix_get_json() {
+    let server = Server::reply(200, "{}").await;
+    let gotify = notifier(Backend::Gotify {
+        url: format!("{}/", server.url),
+        token: "app-token".to_string(),
+    });
+    assert!(Notifiers::default()
+        .notify(&[gotify], &Policy::default(), &change())
+        .await
+        .is_empty());
+    let request = server.request();
+    assert_eq!(
+        (request.method.as_str(), request.path.as_str()),
+        ("POST", "/message")
+    );
+    assert_eq!(request.header("x-gotify-key"), Some("app-token"));
+    assert_eq!(request.json()["title"], "home.example.com update");
+
+    let server = Server::reply(200, "{}").await;
+    let matrix = notifier(Backend::Matrix {
+        url: server.url.clone(),
+        room: "!room:example.org".to_string(),
+        token: "syt_abc".to_string(),
+    });
+    assert!(Notifiers::default()
+        .notify(&[matrix], &Policy::default(), &change())
+        .await
+        .is_empty());
+    let request = server.request();
+    assert_eq!(request.method, "PUT");
+    assert!(request
+        .path
+        .starts_with("/_matrix/client/v3/rooms/%21room%3Aexample.org/send/m.room.message/"));
+    assert_eq!(request.header("authorization"), Some("Bearer syt_abc"));
+    assert_eq!(request.json()["msgtype"], "m.text");
+}
+
+#[tokio::test]
+async fn http_renders_the_body_template() {
+    let server = Server::reply(200, "{}").await;
+    let http = notifier(Backend::Http {
+        url: format!("{}/hooks/chat", server.url),
+        method: "PUT".to_string(),
+        token: None,
+        headers: vec![("X-Record".to_string(), "{domain}".to_string())],
+        content_type: "application/json".to_string(),
+        body: "{\"text\": \"{domain} is now {new_value}\"}".to_string(),
+    });
+    assert!(Notifiers::default()
+        .notify(&[http], &Policy::default(), &change())
+        .await
+        .is_empty());
+
+    let request = server.request();
+    assert_eq!(
+        (request.method.as_str(), request.path.as_str()),
+        ("PUT", "/hooks/chat")
+    );
+    assert_eq!(request.header("x-record"), Some("home.example.com"));
+    assert_eq!(request.header("content-type"), Some("application/json"));
+    assert_eq!(
+        request.body,
+        "{\"text\": \"home.example.com is now 198.51.100.2\"}"
+    );
+}
+
+#[tokio::test]
+async fn http_escapes_values_in_json_bodies() {
+    let server = Server::reply(200, "{}").await;
+    let http = notifier(Backend::Http {
+        url: format!("{}/hooks/chat", server.url),
+        method: "POST".to_string(),
+        token: None,
+        headers: Vec::new(),
+        content_type: "application/json; charset=utf-8".to_string(),
+        body: "{\"text\": \"{domain} is now {new_value}\"}".to_string(),
+    });
+    let mut txt = record("home.example.com");
+    txt.record_type = "TXT".to_string();
+    txt.ip = r#""v=spf1 -all" \ end"#.to_string();
+    let change = Change::new(Action::Update, &txt, None, None);
+    assert!(Notifiers::default()
+        .notify(&[http], &Policy::default(), &change)
+        .await
+        .is_empty());
+
+    let request = server.request();
+    assert_eq!(
+        request.json()["text"],
+        r#"home.example.com is now "v=spf1 -all" \ end"#
+    );
+}
+
+#[tokio::test]
+async fn smtp_sends_an_email() {
+    let (url, transcript) = smtp_server().await;
+    let smtp = notifier(Backend::Smtp(SmtpConfig {
+        url,
+        username: None,
+        password: None,
+        from: "dyn-ip <dyn-ip@example.com>".to_string(),
+        to: vec!["ops@example.com".to_string()],
+    }));
+    let failures = Notifiers::default()
+        .notify(&[smtp], &Policy::default(), &change())
+        .await;
+    assert!(failures.is_empty(), "{:?}", failures);
+
+    let transcript = transcript.await.unwrap();
+    assert!(transcript.contains("MAIL FROM:<dyn-ip@example.com>"));
+    assert!(transcript.contains("RCPT TO:<ops@example.com>"));
+    assert!(transcript.contains("Subject: home.example.com update"));
+    assert!(transcript.contains("A home.example.com: 198.51.100.1 -> 198.51.100.2"));
+}
+
+#[tokio::test]
+async fn filtered_out_changes_are_not_sent() {
+    let mut ntfy = notifier(Backend::Ntfy {
+        url: "http://127.0.0.1:9/unreachable".to_string(),
+        token: None,
+    });
+    ntfy.filter.domains = vec!["nas.example.com".to_string()];
+    assert!(Notifiers::default()
+        .notify(&[ntfy.clone()], &Policy::default(), &change())
+        .await
+        .is_empty());
+
+    ntfy.filter.domains.clear();
+    let failures = Notifiers::default()
+        .notify(&[ntfy], &Policy::default(), &change())
+        .await;
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].0, "ntfy");
+}
+
+#[tokio::test]
+async fn records_can_pick_their_notifiers() {
+    let server = Server::reply(200, "{}").await;
+    let ntfy = |name: &str| Notifier {
+        name: name.to_string(),
+        ..notifier(Backend::Ntfy {
+            url: format!("{}/{}", server.url, name),
+            token: None,
+        })
+    };
+    let mut phone = ntfy("phone");
+    phone.filter.domains = vec!["nas.example.com".to_string()];
+    let mut pager = ntfy("pager");
+    pager.filter.events = vec![Action::Delete];
+    let everything = ntfy("everything");
+    let notifiers = [phone, pager, everything];
+    let mut policy = Policy::default();
+    policy.records.insert(
+        "home.example.com".to_string(),
+        RecordSettings {
+            notify: Some(vec!["phone".to_string(), "pager".to_string()]),
+            ..RecordSettings::default()
+        },
+    );
+
+    let sent = Notifiers::default()
+        .notify(&notifiers, &policy, &change())
+        .await;
+    assert!(sent.is_empty(), "{:?}", sent);
+    // Only phone: picked by the record despite its domains, pager doesn't want
+    // updates and everything isn't picked
+    let paths: Vec<_> = server.requests().into_iter().map(|r| r.path).collect();
+    assert_eq!(paths, ["/phone"]);
+
+    let office = Change {
+        domain: "office.example.com".to_string(),
+        ..change()
+    };
+    Notifiers::default()
+        .notify(&notifiers, &policy, &office)
+        .await;
+    let paths: Vec<_> = server.requests().into_iter().map(|r| r.path).collect();
+    assert_eq!(paths, ["/phone", "/everything"]);
+}
+
+#[tokio::test]
+async fn a_slow_service_holds_up_no_change() {
+    // Accepts connections and never answers
+    let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let silent_url = format!("http://{}/topic", silent.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = silent.accept().await {
+            held.push(socket);
+        }
+    });
+    let server = Server::reply(200, "{}").await;
+    let mut runtime = common::runtime(Zones::new(Vec::new()));
+    runtime.config.notifiers = vec![
+        Notifier {
+            name: "slow".to_string(),
+            ..notifier(Backend::Ntfy {
+                url: silent_url,
+                token: None,
+            })
+        },
+        notifier(Backend::Ntfy {
+            url: format!("{}/topic", server.url),
+            token: None,
+        }),
+    ];
+    let history = History::default();
+    tokio::spawn(
+        Notifiers::default().run(Arc::new(Live::from_pointee(runtime)), history.subscribe()),
+    );
+
+    let started = Instant::now();
+    // More than a subscriber can fall behind by
+    for _ in 0..300 {
+        history.record(change());
+        tokio::task::yield_now().await;
+    }
+    while server.requests().len() < 300 {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "{} sent",
+            server.requests().len()
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
@@ -0,0 +1,157 @@
+use dyn_ip::aws::cloudflare::record_body;
+use dyn_ip::aws::record::Record;
+use serde_json::json;
+
+fn record(record_type: &str, domain: &str, value: &str) -> Record {
+    let mut record = Record {
+        domain: domain.to_string(),
+        record_type: record_type.to_string(),
+        ttl: 1,
+        ..Record::default()
+    };
+    record.set_value(value.to_string());
+    record
+}
+
+#[test]
+fn values_are_checked_for_their_type() {
+    let cases = [
+        (record("A", "home.example.com", "198.51.100.1"), None),
+        (
+            record("A", "home.example.com", "2001:db8::1"),
+            Some("2001:db8::1 is not an IPv4 address"),
+        ),
+        (record("AAAA", "home.example.com", "2001:db8::1"), None),
+        (
+            record("AAAA", "home.example.com", "198.51.100.1"),
+            Some("198.51.100.1 is not an IPv6 address"),
+        ),
+        (record("CNAME", "www.example.com", "example.com."), None),
+        (
+            record("CNAME", "www.example.com", "bad..host"),
+            Some("Invalid host bad..host"),
+        ),
+        (record("TXT", "example.com", "v=spf1 -all"), None),
+        (
+            record("TXT", "example.com", ""),
+            Some("TXT content must be 1-2048 characters"),
+        ),
+        (
+            record("TXT", "example.com", &"x".repeat(2049)),
+            Some("TXT content must be 1-2048 characters"),
+        ),
+        (
+            record("MX", "example.com", "mail.example.com"),
+            Some("MX requires a priority"),
+        ),
+        (
+            record("SRV", "sip.example.com", "sip.example.com"),
+            Some("SRV names must start with _service._proto"),
+        ),
+        (
+            record("CAA", "example.com", "letsencrypt.org"),
+            Some("CAA requires a tag"),
+        ),
+        (
+            record("NS", "example.com", "ns1.example.com"),
+            Some("Invalid record type: NS"),
+        ),
+    ];
+    for (record, error) in cases {
+        match (record.validate(), error) {
+            (Ok(()), None) => {}
+            (Err(e), Some(expected)) => assert!(
+                e.starts_with(expected),
+                "{} {}: {}",
+                record.record_type,
+                record.ip,
+                e
+            ),
+            (result, _) => panic!("{} {}: {:?}", record.record_type, record.ip, result),
+        }
+    }
+}
+
+#[test]
+fn structured_records_need_every_part() {
+    let mut srv = record("SRV", "_sip._tcp.example.com", "sip.example.com");
+    srv.priority = Some(10);
+    srv.weight = Some(5);
+    assert_eq!(srv.validate(), Err("SRV requires a port".to_string()));
+    srv.port = Some(5060);
+    assert_eq!(srv.validate(), Ok(()));
+    // "." says the service isn't offered
+    srv.set_value(".".to_string());
+    assert_eq!(srv.validate(), Ok(()));
+
+    let mut caa = record("CAA", "example.com", "letsencrypt.org");
+    caa.tag = Some("issuer".to_string());
+    assert!(caa
+        .validate()
+        .unwrap_err()
+        .starts_with("CAA tag must be one of"));
+    caa.tag = Some("issue".to_string());
+    assert_eq!(caa.validate(), Ok(()));
+}
+
+#[test]
+fn settings_are_checked_for_every_type() {
+    let mut a = record("A", "home.example.com", "198.51.100.1");
+    a.ttl = 10;
+    assert!(a.validate().unwrap_err().starts_with("TTL must be 1"));
+    a.ttl = 30;
+    a.proxied = true;
+    assert_eq!(a.validate(), Ok(()));
+    a.comment = Some("c".repeat(101));
+    assert_eq!(
+        a.validate(),
+        Err("Comments are limited to 100 characters".to_string())
+    );
+
+    let mut txt = record("TXT", "example.com", "v=spf1 -all");
+    txt.proxied = true;
+    assert_eq!(
+        txt.validate(),
+        Err("TXT records can't be proxied".to_string())
+    );
+}
+
+#[test]
+fn bodies_put_structured_values_in_data() {
+    let mut a = record("A", "home.example.com", "198.51.100.1");
+    a.comment = Some("router".to_string());
+    assert_eq!(
+        record_body(&a),
+        json!({
+            "type": "A",
+            "name": "home.example.com",
+            "ttl": 1,
+            "proxied": false,
+            "comment": "router",
+            "content": "198.51.100.1"
+        })
+    );
+
+    let mut mx = record("MX", "example.com", "mail.example.com");
+    mx.priority = Some(10);
+    let body = record_body(&mx);
+    assert_eq!(body["content"], "mail.example.com");
+    assert_eq!(body["priority"], 10);
+    assert_eq!(body.get("data"), None);
+
+    let mut srv = record("SRV", "_sip._tcp.example.com", "sip.example.com");
+    (srv.priority, srv.weight, srv.port) = (Some(10), Some(5), Some(5060));
+    let body = record_body(&srv);
+    assert_eq!(
+        body["data"],
+        json!({ "priority": 10, "weight": 5, "port": 5060, "target": "sip.example.com" })
+    );
+    assert_eq!(body.get("content"), None);
+
+    let mut caa = record("CAA", "example.com", "letsencrypt.org");
+    caa.tag = Some("issue".to_string());
+    assert_eq!(
+        record_body(&caa)["data"],
+        json!({ "flags": 0, "tag": "issue", "value": "letsencrypt.org" })
+    );
+}
@@ -0,0 +1,189 @@
+mod common;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use common::cloudflare::{Zone, TOKEN, ZONE_ID};
+use dyn_ip::aws::cloudflare::{Auth, Cloudflare};
+use dyn_ip::aws::zones::Zones;
+use dyn_ip::config::reload::{changes, Reloader};
+use dyn_ip::config::{ServeArgs, Settings};
+use dyn_ip::server::health::Readiness;
+use dyn_ip::server::policy::RecordSettings;
+use dyn_ip::server::state::{Live, Runtime};
+use dyn_ip::server::webhooks::Webhook;
+
+/// A config for the stand-in zone, with `extra` appended to `[server]`.
+fn write_config(path: &PathBuf, zone: &Zone, extra: &str) {
+    let contents = format!(
+        "[cloudflare]\nzone_id = {:?}\napi_key = {:?}\ndomain_name = {:?}\napi_url = {:?}\n\n[server]\ndata_dir = {:?}\n{}",
+        ZONE_ID,
+        TOKEN,
+        zone.domain,
+        zone.server.url,
+        std::env::temp_dir().display().to_string(),
+        extra
+    );
+    std::fs::write(path, contents).unwrap();
+}
+
+struct Running {
+    live: Arc<Live>,
+    reloader: Reloader,
+    config: PathBuf,
+}
+
+async fn start(name: &str, zone: &Zone, extra: &str) -> Running {
+    let config = std::env::temp_dir().join(format!(
+        "dyn-ip-reload-{}-{}.toml",
+        name,
+        std::process::id()
+    ));
+    write_config(&config, zone, extra);
+    let args = ServeArgs {
+        config: Some(config.clone()),
+        ..ServeArgs::default()
+    };
+    let settings = Settings::load(args.clone()).unwrap_or_else(|e| panic!("{:?}", e));
+    let live = Arc::new(Live::from_pointee(
+        Runtime::connect(&settings).await.unwrap(),
+    ));
+    let readiness = Readiness::new(settings.data_dir.clone());
+    let reloader = Reloader::new(args, &settings, live.clone(), readiness);
+    Running {
+        live,
+        reloader,
+        config,
+    }
+}
+
+#[tokio::test]
+async fn the_salt_only_changes_on_a_restart() {
+    let zone = Zone::start("example.com").await;
+    let mut running = start("salt", &zone, "salt = \"first\"\n").await;
+
+    write_config(
+        &running.config,
+        &zone,
+        "salt = \"second\"\ntrust_proxy_headers = false\n",
+    );
+    running.reloader.reload().await;
+    let config = &running.live.load().config;
+    assert_eq!(config.salt, "first");
+    assert!(!config.trust_proxy_headers, "the rest wasn't applied");
+    std::fs::remove_file(&running.config).unwrap();
+}
+
+#[tokio::test]
+async fn invalid_configs_keep_the_running_one() {
+    let zone = Zone::start("example.com").await;
+    let mut running = start("invalid", &zone, "salt = \"salt\"\n").await;
+    let before = running.live.load_full();
+
+    std::fs::write(&running.config, "[server]\nlisten = \"nowhere\"\n").unwrap();
+    running.reloader.reload().await;
+    assert!(Arc::ptr_eq(&before, &running.live.load_full()));
+
+    // Valid, but the zone can't be reached with it
+    let wrong_token = format!(
+        "[cloudflare]\nzone_id = {:?}\napi_key = \"tk_bad\"\ndomain_name = \"example.com\"\napi_url = {:?}\n",
+        ZONE_ID, zone.server.url
+    );
+    std::fs::write(&running.config, wrong_token).unwrap();
+    running.reloader.reload().await;
+    assert!(Arc::ptr_eq(&before, &running.live.load_full()));
+
+    write_config(&running.config, &zone, "trust_proxy_headers = false\n");
+    running.reloader.reload().await;
+    assert!(!running.live.load().config.trust_proxy_headers);
+    std::fs::remove_file(&running.config).unwrap();
+}
+
+#[tokio::test]
+async fn secret_files_named_by_a_reload_are_watched() {
+    let zone = Zone::start("example.com").await;
+    let mut running = start("watched", &zone, "").await;
+    assert_eq!(running.reloader.paths(), [running.config.clone()]);
+
+    let password = running.config.with_extension("password");
+    std::fs::write(&password, "hunter2\n").unwrap();
+    let with_auth = format!(
+        "{}\n[auth]\nusername = \"admin\"\npassword_file = {:?}\n",
+        std::fs::read_to_string(&running.config).unwrap(),
+        password
+    );
+    std::fs::write(&running.config, with_auth).unwrap();
+    running.reloader.reload().await;
+    assert_eq!(
+        running.reloader.paths(),
+        [running.config.clone(), password.clone()]
+    );
+    assert_eq!(
+        running.live.load().config.auth.password.as_deref(),
+        Some("hunter2")
+    );
+
+    // An invalid config doesn't change what's watched
+    std::fs::write(&running.config, "[server]\nlisten = \"nowhere\"\n").unwrap();
+    running.reloader.reload().await;
+    assert_eq!(
+        running.reloader.paths(),
+        [running.config.clone(), password.clone()]
+    );
+    std::fs::remove_file(&running.config).unwrap();
+    std::fs::remove_file(password).unwrap();
+}
+
+fn cloudflare(domain: &str, token: &str) -> Cloudflare {
+    Cloudflare::new(
+        "http://127.0.0.1:9".to_string(),
+        Auth::Token(token.to_string()),
+        format!("zone-{}", domain),
+        domain.to_string(),
+    )
+}
+
+#[test]
+fn changes_are_listed_without_secrets() {
+    let before = common::runtime(Zones::new(vec![
+        cloudflare("example.com", "tk_first"),
+        cloudflare("example.org", "tk_first"),
+    ]));
+    assert!(changes(&before, &before).is_empty());
+
+    let mut now = common::runtime(Zones::new(vec![
+        cloudflare("example.com", "tk_second"),
+        cloudflare("example.net", "tk_second"),
+    ]));
+    now.config.auth.username = Some("admin".to_string());
+    now.config.auth.password = Some("hunter2".to_string());
+    now.config.trust_proxy_headers = true;
+    now.config.webhooks.push(Webhook {
+        name: "ops".to_string(),
+        url: "https://hooks.example.com/dns".to_string(),
+        secret: Some("signing-secret".to_string()),
+        filter: Default::default(),
+    });
+    now.config
+        .policy
+        .records
+        .insert("home.example.com".to_string(), RecordSettings::default());
+
+    let changes = changes(&before, &now);
+    assert_eq!(
+        changes,
+        [
+            "auth.username: \"\" -> \"admin\"",
+            "server.trust_proxy_headers: \"false\" -> \"true\"",
+            "zone example.com credentials changed",
+            "zone example.net added",
+            "zone example.org removed",
+            "auth.password changed",
+            "webhook ops added",
+            "records.\"home.example.com\" added",
+        ]
+    );
+    for secret in ["tk_second", "hunter2", "signing-secret"] {
+        assert!(changes.iter().all(|c| !c.contains(secret)), "{}", secret);
+    }
+}
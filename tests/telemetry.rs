@@ -0,0 +1,72 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use dyn_ip::telemetry::{fmt_layer, LogFormat};
+use tracing::{info, info_span};
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Collects what's logged.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Buffer {
+    fn lines(&self) -> Vec<serde_json::Value> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap_or_else(|e| panic!("{}: {}", e, l)))
+            .collect()
+    }
+}
+
+#[test]
+fn json_lines_carry_their_fields_and_spans() {
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::registry().with(fmt_layer(
+        LogFormat::Json,
+        move || writer.clone(),
+        false,
+    ));
+    tracing::subscriber::with_default(subscriber, || {
+        info_span!("request", request_id = "abc123").in_scope(|| {
+            info!(
+                record = "home.example.com",
+                value = "198.51.100.2",
+                "Updating record"
+            );
+        });
+        info!("Outside any span");
+    });
+
+    let lines = buffer.lines();
+    assert_eq!(lines.len(), 2);
+    let line = &lines[0];
+    assert_eq!(line["level"], "INFO");
+    assert_eq!(line["message"], "Updating record");
+    assert_eq!(line["record"], "home.example.com");
+    assert_eq!(line["value"], "198.51.100.2");
+    assert_eq!(line["spans"][0]["name"], "request");
+    assert_eq!(line["spans"][0]["request_id"], "abc123");
+    assert!(line["timestamp"].is_string());
+    assert_eq!(lines[1]["message"], "Outside any span");
+    assert_eq!(lines[1].get("spans"), None);
+}
+
+#[test]
+fn formats_are_parsed_case_insensitively() {
+    assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+    assert_eq!("text".parse(), Ok(LogFormat::Text));
+    assert!("logfmt".parse::<LogFormat>().is_err());
+}
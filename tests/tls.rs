@@ -0,0 +1,427 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use dyn_ip::aws::zones::Zones;
+use dyn_ip::server::state::Live;
+use dyn_ip::tls::acme::{self, AcmeConfig, ChallengeType, HttpChallenges, Solver};
+use dyn_ip::tls::certs::{self, CertStore};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair, PublicKeyData,
+    SignatureAlgorithm, PKCS_ECDSA_P256_SHA256,
+};
+use ring::digest::{digest, SHA256};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConnection};
+use serde_json::{json, Value};
+use x509_parser::prelude::{FromDer, X509CertificationRequest};
+
+use common::{Reply, Request, Server};
+
+const DOMAIN: &str = "dyn.example.test";
+const TOKEN: &str = "challenge-token";
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dyn-ip-tls-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new() -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        Ca { cert, key }
+    }
+
+    fn issue(&self, key: &impl PublicKeyData) -> Certificate {
+        CertificateParams::new(vec![DOMAIN.to_string()])
+            .unwrap()
+            .signed_by(key, &self.cert, &self.key)
+            .unwrap()
+    }
+
+    /// A new key and a certificate for it, as PEM files at `cert` and `key`.
+    fn write(&self, cert: &Path, key: &Path) -> CertificateDer<'static> {
+        let key_pair = KeyPair::generate().unwrap();
+        let issued = self.issue(&key_pair);
+        std::fs::write(cert, issued.pem()).unwrap();
+        std::fs::write(key, key_pair.serialize_pem()).unwrap();
+        issued.der().clone()
+    }
+
+    fn client(&self, identity: Option<&Ca>) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.cert.der().clone()).unwrap();
+        let builder =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+        let config = match identity {
+            Some(ca) => {
+                let key = KeyPair::generate().unwrap();
+                let cert = ca.issue(&key);
+                let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+                builder
+                    .with_client_auth_cert(vec![cert.der().clone()], key)
+                    .unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Arc::new(config)
+    }
+}
+
+/// Runs a handshake in memory, returning the certificates the server sent.
+fn handshake(
+    store: &Arc<CertStore>,
+    client_ca: Option<&Path>,
+    client: Arc<ClientConfig>,
+) -> Result<Vec<CertificateDer<'static>>, rustls::Error> {
+    let server_config = Arc::new(certs::server_config(store.clone(), client_ca).unwrap());
+    let mut server = Connection::Server(ServerConnection::new(server_config)?);
+    let mut client = Connection::Client(ClientConnection::new(client, DOMAIN.try_into().unwrap())?);
+    for _ in 0..10 {
+        if !client.is_handshaking() && !server.is_handshaking() {
+            break;
+        }
+        transfer(&mut client, &mut server)?;
+        transfer(&mut server, &mut client)?;
+    }
+    match client {
+        Connection::Client(client) if !client.is_handshaking() => {
+            Ok(client.peer_certificates().unwrap_or_default().to_vec())
+        }
+        _ => Err(rustls::Error::General("handshake stalled".to_string())),
+    }
+}
+
+fn transfer(from: &mut Connection, to: &mut Connection) -> Result<(), rustls::Error> {
+    let mut buf = Vec::new();
+    while from.wants_write() {
+        from.write_tls(&mut buf).unwrap();
+    }
+    let mut sent = buf.as_slice();
+    while !sent.is_empty() {
+        to.read_tls(&mut sent).unwrap();
+        to.process_new_packets()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn certificates_are_reloaded_in_place() {
+    let dir = temp_dir("swap");
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    let ca = Ca::new();
+    let store = Arc::new(CertStore::new(cert_path.clone(), key_path.clone()));
+    assert!(store.reload().is_err(), "loaded without files");
+    assert!(handshake(&store, None, ca.client(None)).is_err());
+
+    let first = ca.write(&cert_path, &key_path);
+    store.reload().unwrap();
+    assert_eq!(
+        handshake(&store, None, ca.client(None)).unwrap(),
+        vec![first.clone()]
+    );
+
+    let second = ca.write(&cert_path, &key_path);
+    assert_eq!(handshake(&store, None, ca.client(None)).unwrap(), [first]);
+    store.reload().unwrap();
+    assert_eq!(
+        handshake(&store, None, ca.client(None)).unwrap(),
+        vec![second.clone()]
+    );
+
+    // A broken renewal keeps serving what was there
+    std::fs::write(&cert_path, "not a certificate").unwrap();
+    assert!(store.reload().is_err());
+    assert_eq!(handshake(&store, None, ca.client(None)).unwrap(), [second]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn mtls_needs_a_certificate_from_the_client_ca() {
+    let dir = temp_dir("mtls");
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    let (ca, clients, strangers) = (Ca::new(), Ca::new(), Ca::new());
+    ca.write(&cert_path, &key_path);
+    let client_ca = dir.join("clients.pem");
+    std::fs::write(&client_ca, clients.cert.pem()).unwrap();
+    let store = Arc::new(CertStore::new(cert_path, key_path));
+    store.reload().unwrap();
+
+    assert!(handshake(&store, Some(&client_ca), ca.client(Some(&clients))).is_ok());
+    assert!(handshake(&store, Some(&client_ca), ca.client(None)).is_err());
+    assert!(handshake(&store, Some(&client_ca), ca.client(Some(&strangers))).is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// The public key from a CSR, for signing a certificate to it.
+struct RequestedKey(Vec<u8>);
+
+impl PublicKeyData for RequestedKey {
+    fn der_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn algorithm(&self) -> &SignatureAlgorithm {
+        &PKCS_ECDSA_P256_SHA256
+    }
+}
+
+#[derive(Default)]
+struct Progress {
+    nonces: Vec<String>,
+    issued: usize,
+    refused_nonce: bool,
+    account: Option<Value>,
+    challenge_answered: Option<bool>,
+    chain: Option<String>,
+}
+
+impl Progress {
+    fn nonce(&mut self) -> String {
+        self.issued += 1;
+        let nonce = format!("nonce-{}", self.issued);
+        self.nonces.push(nonce.clone());
+        nonce
+    }
+}
+
+fn b64_json(value: &Value) -> Value {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(value.as_str().unwrap_or_default())
+        .unwrap();
+    if bytes.is_empty() {
+        return Value::Null;
+    }
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+fn thumbprint(jwk: &Value) -> String {
+    let jwk = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+        jwk["x"], jwk["y"]
+    );
+    URL_SAFE_NO_PAD.encode(digest(&SHA256, jwk.as_bytes()))
+}
+
+fn verify(jws: &Value, jwk: &Value) -> bool {
+    let point = |c: &str| URL_SAFE_NO_PAD.decode(jwk[c].as_str().unwrap()).unwrap();
+    let key = [vec![4], point("x"), point("y")].concat();
+    let signed = format!(
+        "{}.{}",
+        jws["protected"].as_str().unwrap(),
+        jws["payload"].as_str().unwrap()
+    );
+    let signature = URL_SAFE_NO_PAD
+        .decode(jws["signature"].as_str().unwrap())
+        .unwrap();
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, key)
+        .verify(signed.as_bytes(), &signature)
+        .is_ok()
+}
+
+/// An RFC 8555 directory that issues for `DOMAIN` from `ca` once the HTTP-01
+/// key authorization is in `challenges`. The first order is refused with a
+/// badNonce, as servers may do at any time.
+fn directory(
+    request: &Request,
+    ca: &Ca,
+    challenges: &HttpChallenges,
+    progress: &mut Progress,
+) -> Reply {
+    let base = format!("http://{}", request.header("host").unwrap());
+    let url = |path: &str| format!("{}{}", base, path);
+    let problem = |status: u16, kind: &str, nonce: String| {
+        let body = json!({ "type": format!("urn:ietf:params:acme:error:{}", kind) });
+        Reply::from((status, body.to_string())).header("replay-nonce", &nonce)
+    };
+    match (request.method.as_str(), request.route()) {
+        ("GET", "/directory") => {
+            let body = json!({
+                "newNonce": url("/nonce"),
+                "newAccount": url("/account"),
+                "newOrder": url("/order"),
+            });
+            return Reply::from((200, body.to_string()));
+        }
+        ("HEAD", "/nonce") => {
+            return Reply::from((200, String::new())).header("replay-nonce", &progress.nonce())
+        }
+        ("POST", _) => {}
+        _ => return Reply::from((404, String::new())),
+    }
+
+    let jws = request.json();
+    let protected = b64_json(&jws["protected"]);
+    let payload = b64_json(&jws["payload"]);
+    let route = request.route();
+    let sent_nonce = protected["nonce"].as_str().unwrap_or_default().to_string();
+    let Some(i) = progress.nonces.iter().position(|n| *n == sent_nonce) else {
+        return problem(400, "badNonce", progress.nonce());
+    };
+    progress.nonces.remove(i);
+    if protected["url"] != url(route).as_str() || protected["alg"] != "ES256" {
+        return problem(400, "malformed", progress.nonce());
+    }
+    let jwk = match (route, &progress.account) {
+        ("/account", _) => protected["jwk"].clone(),
+        (_, Some(account)) if protected["kid"] == url("/account/1").as_str() => account.clone(),
+        _ => return problem(401, "accountDoesNotExist", progress.nonce()),
+    };
+    if !verify(&jws, &jwk) {
+        return problem(403, "unauthorized", progress.nonce());
+    }
+
+    let order = |status: &str, certificate: Option<String>| {
+        json!({
+            "status": status,
+            "authorizations": [url("/authz/1")],
+            "finalize": url("/finalize/1"),
+            "certificate": certificate,
+        })
+    };
+    let (status, body, location) = match route {
+        "/account" => {
+            assert_eq!(payload["contact"], json!(["mailto:admin@example.test"]));
+            progress.account = Some(jwk);
+            (201, json!({ "status": "valid" }), Some(url("/account/1")))
+        }
+        "/order" if !progress.refused_nonce => {
+            progress.refused_nonce = true;
+            return problem(400, "badNonce", progress.nonce());
+        }
+        "/order" => {
+            assert_eq!(
+                payload["identifiers"],
+                json!([{ "type": "dns", "value": DOMAIN }])
+            );
+            (201, order("pending", None), Some(url("/order/1")))
+        }
+        "/authz/1" => {
+            let status = match progress.challenge_answered {
+                None => "pending",
+                Some(true) => "valid",
+                Some(false) => "invalid",
+            };
+            let body = json!({
+                "status": status,
+                "identifier": { "type": "dns", "value": DOMAIN },
+                "challenges": [
+                    { "type": "dns-01", "url": url("/chall/2"), "token": "other" },
+                    { "type": "http-01", "url": url("/chall/1"), "token": TOKEN },
+                ],
+            });
+            (200, body, None)
+        }
+        "/chall/1" => {
+            let expected = format!(
+                "{}.{}",
+                TOKEN,
+                thumbprint(progress.account.as_ref().unwrap())
+            );
+            progress.challenge_answered = Some(challenges.get(TOKEN) == Some(expected));
+            (200, json!({ "status": "processing" }), None)
+        }
+        "/finalize/1" => {
+            let csr = URL_SAFE_NO_PAD
+                .decode(payload["csr"].as_str().unwrap())
+                .unwrap();
+            let (_, csr) = X509CertificationRequest::from_der(&csr).unwrap();
+            let key = RequestedKey(
+                csr.certification_request_info
+                    .subject_pki
+                    .subject_public_key
+                    .data
+                    .to_vec(),
+            );
+            progress.chain = Some(format!("{}{}", ca.issue(&key).pem(), ca.cert.pem()));
+            (200, order("processing", None), None)
+        }
+        "/order/1" => match progress.chain {
+            Some(_) => (200, order("valid", Some(url("/cert/1"))), None),
+            None => (200, order("processing", None), None),
+        },
+        "/cert/1" => {
+            let chain = progress.chain.clone().unwrap();
+            return Reply::from((200, chain)).header("replay-nonce", &progress.nonce());
+        }
+        _ => return problem(404, "malformed", progress.nonce()),
+    };
+    let mut reply =
+        Reply::from((status, body.to_string())).header("replay-nonce", &progress.nonce());
+    if let Some(location) = location {
+        reply = reply.header("location", &location);
+    }
+    reply
+}
+
+#[tokio::test]
+async fn certificates_are_ordered_from_the_directory() {
+    let dir = temp_dir("acme");
+    let ca = Arc::new(Ca::new());
+    let challenges = HttpChallenges::default();
+    let progress = Arc::new(Mutex::new(Progress::default()));
+    let server = {
+        let (ca, challenges, progress) = (ca.clone(), challenges.clone(), progress.clone());
+        Server::start(move |request| {
+            directory(request, &ca, &challenges, &mut progress.lock().unwrap())
+        })
+        .await
+    };
+
+    let (cert_path, key_path) = (dir.join("acme/dyn.crt"), dir.join("acme/dyn.key"));
+    let store = Arc::new(CertStore::new(cert_path.clone(), key_path.clone()));
+    let config = AcmeConfig {
+        domain: DOMAIN.to_string(),
+        directory_url: format!("{}/directory", server.url),
+        email: Some("admin@example.test".to_string()),
+        challenge: ChallengeType::Http01,
+        ca_cert: None,
+        account_key_path: dir.join("acme/account.pk8"),
+    };
+    let solver = Solver {
+        challenge: ChallengeType::Http01,
+        http_challenges: challenges.clone(),
+        live: Arc::new(Live::from_pointee(common::runtime(Zones::new(Vec::new())))),
+    };
+    let ordering = tokio::spawn(acme::run(config, store.clone(), solver));
+
+    let mut served = Err(rustls::Error::General("never served".to_string()));
+    for _ in 0..100 {
+        served = handshake(&store, None, ca.client(None));
+        if served.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    ordering.abort();
+    let served = served.unwrap_or_else(|e| panic!("{:?}: {:?}", e, server.requests()));
+    assert_eq!(served.len(), 2, "chain with the issuer");
+    assert_eq!(progress.lock().unwrap().challenge_answered, Some(true));
+    assert_eq!(challenges.get(TOKEN), None, "challenge left behind");
+    assert!(dir.join("acme/account.pk8").exists());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&key_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
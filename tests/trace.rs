@@ -0,0 +1,37 @@
+use actix_web::{test, web, App, HttpResponse};
+
+use dyn_ip::server::trace::{trace_request, REQUEST_ID};
+
+#[tokio::test]
+async fn request_ids_are_echoed_or_generated() {
+    let app = test::init_service(
+        App::new()
+            .wrap_fn(trace_request)
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let given = test::TestRequest::get()
+        .uri("/")
+        .insert_header((REQUEST_ID, "from-the-proxy"))
+        .to_request();
+    let response = test::call_service(&app, given).await;
+    assert_eq!(
+        response.headers().get(REQUEST_ID).unwrap(),
+        "from-the-proxy"
+    );
+
+    // Not a usable ID, so one is made up
+    let bad = test::TestRequest::get()
+        .uri("/")
+        .insert_header((REQUEST_ID, "two words"))
+        .to_request();
+    let response = test::call_service(&app, bad).await;
+    let generated = response
+        .headers()
+        .get(REQUEST_ID)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert_eq!(generated.len(), 32);
+}
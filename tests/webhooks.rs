@@ -0,0 +1,197 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use common::{change, Server};
+use dyn_ip::aws::zones::Zones;
+use dyn_ip::server::history::{Action, ChangeFilter, History};
+use dyn_ip::server::state::Live;
+use dyn_ip::server::webhooks::{sign, DeliveryQuery, DeliveryStatus, Webhook, Webhooks};
+use tokio::net::TcpListener;
+
+fn webhook(url: &str) -> Webhook {
+    Webhook {
+        name: "firewall".to_string(),
+        url: url.to_string(),
+        secret: Some("s3cret".to_string()),
+        filter: ChangeFilter {
+            events: vec![Action::Update],
+            domains: vec!["home.example.com".to_string()],
+        },
+    }
+}
+
+fn queue_path(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("dyn-ip-webhooks-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir.join("webhooks.json")
+}
+
+#[test]
+fn filters_by_event_and_domain() {
+    let webhook = webhook("http://127.0.0.1/hook");
+    assert!(webhook.wants(&change(Action::Update, "home.example.com")));
+    assert!(webhook.wants(&change(Action::Update, "cam.home.example.com")));
+    assert!(!webhook.wants(&change(Action::Delete, "home.example.com")));
+    assert!(!webhook.wants(&change(Action::Update, "nothome.example.com")));
+    // Alerts are only sent when asked for
+    let everything = ChangeFilter::default();
+    assert!(everything.matches(&change(Action::Delete, "home.example.com")));
+    assert!(!everything.matches(&change(Action::Stale, "home.example.com")));
+    let alerts = ChangeFilter {
+        events: vec![Action::Stale],
+        domains: Vec::new(),
+    };
+    assert!(alerts.matches(&change(Action::Stale, "home.example.com")));
+
+    let webhooks = Webhooks::load(queue_path("filters"));
+    webhooks.enqueue(&[webhook], &change(Action::Create, "home.example.com"));
+    assert!(webhooks.list(&DeliveryQuery::default()).is_empty());
+}
+
+#[tokio::test]
+async fn delivers_signed_payloads() {
+    let receiver = Server::reply(204, "").await;
+    let hooks = [webhook(&format!("{}/hook", receiver.url))];
+    let webhooks = Webhooks::load(queue_path("signed"));
+    let change = change(Action::Update, "home.example.com");
+    webhooks.enqueue(&hooks, &change);
+    webhooks.deliver_due(&hooks).await;
+
+    let request = receiver.request();
+    let header = |name: &str| request.header(name).unwrap().to_string();
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/hook");
+    assert_eq!(header("x-dyn-ip-event"), "update");
+    let timestamp: u64 = header("x-dyn-ip-timestamp").parse().unwrap();
+    assert_eq!(
+        header("x-dyn-ip-signature"),
+        sign("s3cret", timestamp, request.body.as_bytes())
+    );
+    let payload = request.json();
+    assert_eq!(payload["domain"], "home.example.com");
+    assert_eq!(payload["old_value"], "198.51.100.1");
+    assert_eq!(payload["new_value"], "198.51.100.2");
+    assert_eq!(payload["id"], change.id);
+
+    let log = webhooks.list(&DeliveryQuery::default());
+    assert_eq!(log.len(), 1);
+    assert_eq!(log[0].status, DeliveryStatus::Delivered);
+    assert_eq!(log[0].response_status, Some(204));
+    assert_eq!(header("x-dyn-ip-delivery"), log[0].id);
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_retried_after_a_restart() {
+    let receiver = Server::reply(500, "").await;
+    let hooks = [webhook(&format!("{}/hook", receiver.url))];
+    let path = queue_path("retry");
+    let webhooks = Webhooks::load(path.clone());
+    webhooks.enqueue(&hooks, &change(Action::Update, "home.example.com"));
+    webhooks.deliver_due(&hooks).await;
+    receiver.request();
+    webhooks.flush();
+
+    let webhooks = Webhooks::load(path);
+    let pending = webhooks.list(&DeliveryQuery {
+        status: Some(DeliveryStatus::Pending),
+        ..Default::default()
+    });
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].attempts, 1);
+    assert_eq!(pending[0].response_status, Some(500));
+    assert!(pending[0].next_attempt_at.is_some());
+
+    // Backing off, so nothing is due yet
+    webhooks.deliver_due(&hooks).await;
+    assert_eq!(webhooks.list(&DeliveryQuery::default())[0].attempts, 1);
+}
+
+#[test]
+fn each_attempt_is_appended() {
+    let path = queue_path("appended");
+    let hooks = [webhook("http://127.0.0.1:9/hook")];
+    let webhooks = Webhooks::load(path.clone());
+    webhooks.enqueue(&hooks, &change(Action::Update, "home.example.com"));
+    webhooks.enqueue(&hooks, &change(Action::Update, "home.example.com"));
+    webhooks.flush();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(contents.lines().count(), 2);
+
+    let reloaded = Webhooks::load(path.clone());
+    assert_eq!(reloaded.list(&DeliveryQuery::default()).len(), 2);
+    reloaded.enqueue(&hooks, &change(Action::Update, "home.example.com"));
+    reloaded.flush();
+    assert_eq!(
+        Webhooks::load(path).list(&DeliveryQuery::default()).len(),
+        3
+    );
+}
+
+#[tokio::test]
+async fn later_states_replace_earlier_ones_on_load() {
+    let receiver = Server::reply(500, "").await;
+    let hooks = [webhook(&format!("{}/hook", receiver.url))];
+    let path = queue_path("states");
+    let webhooks = Webhooks::load(path.clone());
+    webhooks.enqueue(&hooks, &change(Action::Update, "home.example.com"));
+    webhooks.deliver_due(&hooks).await;
+    webhooks.flush();
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(contents.lines().count(), 2, "queued, then attempted");
+    let reloaded = Webhooks::load(path).list(&DeliveryQuery::default());
+    assert_eq!(reloaded.len(), 1);
+    assert_eq!(reloaded[0].attempts, 1);
+}
+
+#[tokio::test]
+async fn a_slow_endpoint_holds_up_no_other() {
+    // Accepts connections and never answers
+    let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let silent_url = format!("http://{}/hook", silent.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = silent.accept().await {
+            held.push(socket);
+        }
+    });
+    let receiver = Server::reply(204, "").await;
+    let mut runtime = common::runtime(Zones::new(Vec::new()));
+    runtime.config.webhooks = vec![
+        Webhook {
+            name: "slow".to_string(),
+            ..webhook(&silent_url)
+        },
+        webhook(&format!("{}/hook", receiver.url)),
+    ];
+    let history = History::default();
+    let webhooks = Webhooks::load(queue_path("slow"));
+    tokio::spawn(
+        webhooks
+            .clone()
+            .run(Arc::new(Live::from_pointee(runtime)), history.subscribe()),
+    );
+
+    let started = Instant::now();
+    // More than a subscriber can fall behind by, all while "slow" is stuck
+    for _ in 0..300 {
+        history.record(change(Action::Update, "home.example.com"));
+        tokio::task::yield_now().await;
+    }
+    while receiver.requests().len() < 300 {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "{} delivered",
+            receiver.requests().len()
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let slow = webhooks.list(&DeliveryQuery {
+        webhook: Some("slow".to_string()),
+        ..Default::default()
+    });
+    assert_eq!(slow.len(), 300);
+    assert!(slow.iter().all(|d| d.status == DeliveryStatus::Pending));
+}
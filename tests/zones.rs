@@ -0,0 +1,123 @@
+mod common;
+
+use common::cloudflare::{self, Zone};
+use dyn_ip::aws::cloudflare::{Auth, Cloudflare};
+use dyn_ip::aws::zones::Zones;
+use dyn_ip::error::DynIpError;
+
+fn zone(domain_name: &str) -> Cloudflare {
+    Cloudflare::new(
+        "http://127.0.0.1:9".to_string(),
+        Auth::Token(cloudflare::TOKEN.to_string()),
+        format!("{}-id", domain_name),
+        domain_name.to_string(),
+    )
+}
+
+fn nested() -> Zones {
+    Zones::new(vec![zone("example.com"), zone("sub.example.com")])
+}
+
+#[test]
+fn records_go_to_the_longest_matching_apex() {
+    let zones = nested();
+    let apex = |domain: &str| zones.for_domain(domain).map(|z| z.domain_name.as_str());
+    assert_eq!(apex("example.com").unwrap(), "example.com");
+    assert_eq!(apex("home.example.com").unwrap(), "example.com");
+    assert_eq!(apex("sub.example.com").unwrap(), "sub.example.com");
+    assert_eq!(apex("Nas.Sub.Example.com.").unwrap(), "sub.example.com");
+    // A suffix of the name, not a label boundary
+    assert_eq!(apex("notsub.example.com").unwrap(), "example.com");
+    assert!(matches!(apex("example.org"), Err(DynIpError::NoZone(d)) if d == "example.org"));
+    assert!(matches!(apex("badexample.com"), Err(DynIpError::NoZone(_))));
+}
+
+#[test]
+fn names_are_qualified_against_the_given_zone() {
+    let zones = nested();
+    assert_eq!(
+        zones.qualify("nas", Some("sub.example.com")).unwrap(),
+        "nas.sub.example.com"
+    );
+    assert_eq!(
+        zones.qualify("nas", Some("example.com.")).unwrap(),
+        "nas.example.com"
+    );
+    assert_eq!(
+        zones
+            .qualify("nas.sub.example.com", Some("sub.example.com"))
+            .unwrap(),
+        "nas.sub.example.com"
+    );
+    // Already fully qualified for one of the zones
+    assert_eq!(
+        zones.qualify("Home.Example.com", None).unwrap(),
+        "home.example.com"
+    );
+    // Relative, but there's more than one zone it could be in
+    assert!(zones.qualify("nas", None).is_err());
+    assert!(zones.qualify("nas", Some("example.org")).is_err());
+
+    let single = Zones::new(vec![zone("example.com")]);
+    assert_eq!(single.qualify("nas", None).unwrap(), "nas.example.com");
+}
+
+#[tokio::test]
+async fn zones_that_fail_are_left_out_of_listings() {
+    let answering = Zone::start("sub.example.com").await;
+    answering.add("nas.sub.example.com", "A", "198.51.100.1");
+    let zones = Zones::new(vec![zone("example.com"), answering.cloudflare()]);
+    let listing = zones.list().await.unwrap();
+    let names: Vec<_> = listing.records.iter().map(|r| r.domain.as_str()).collect();
+    assert_eq!(names, ["nas.sub.example.com"]);
+    assert_eq!(listing.failed_zones, ["example.com"]);
+    // Records missing from the failed zone may still be there
+    assert!(listing.covers(&zones, "nas.sub.example.com"));
+    assert!(!listing.covers(&zones, "home.example.com"));
+    // Lookups need every zone
+    assert!(zones.list_records().await.is_err());
+
+    // Only an error when nothing answers
+    let unreachable = Zones::new(vec![zone("example.com")]);
+    assert!(unreachable.list().await.is_err());
+}
+
+#[tokio::test]
+async fn deletes_need_exactly_one_match() {
+    let zone = Zone::start("example.com").await;
+    zone.add("home.example.com", "A", "198.51.100.1");
+    zone.add("home.example.com", "A", "198.51.100.2");
+    zone.add("home.example.com", "TXT", "owner=ops");
+    let zones = Zones::new(vec![zone.cloudflare()]);
+    let records = zones.list_display_records("salt").await.unwrap();
+
+    // Both A records hash to the same name-only ID
+    assert_eq!(records[0].id, records[1].id);
+    let error = zones
+        .delete_record("salt", &records[0].id)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, DynIpError::AmbiguousRecord(_, 2)),
+        "{:?}",
+        error
+    );
+    let error = zones
+        .delete_record("salt", "home.example.com")
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, DynIpError::AmbiguousRecord(_, 3)),
+        "{:?}",
+        error
+    );
+    assert_eq!(zone.records().len(), 3);
+
+    let txt = zones.delete_record("salt", &records[2].id).await.unwrap();
+    assert_eq!(txt.record_type, "TXT");
+    assert!(matches!(
+        zones.delete_record("salt", "nas.example.com").await,
+        Err(DynIpError::DomainHashNotFound)
+    ));
+    assert_eq!(zone.records().len(), 2);
+}